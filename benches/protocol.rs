@@ -0,0 +1,93 @@
+//! Benchmarks of the codecs every packet goes through, to notice when changes to the protocol
+//! layer make it slower.
+#![allow(dead_code, unused_imports)]
+
+// Portal has no library target, so the modules are included from its sources
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/protocol/mod.rs"]
+mod protocol;
+
+use std::{borrow::Cow, hint::black_box};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder},
+};
+
+use crate::protocol::{
+    DecoderState, PacketDecoder, PacketEncoder,
+    handshake::{HandshakePacket, NextState, ProtocolVersion},
+    types::{read_string, read_var_int, var_int_size, write_string, write_var_int},
+};
+
+/// Values that take one to five bytes, with the largest and negative values taking five
+const VAR_INTS: [i32; 6] = [0, 300, 70_000, 10_000_000, i32::MAX, -1];
+
+fn var_int(c: &mut Criterion) {
+    let mut group = c.benchmark_group("var_int");
+    for value in VAR_INTS {
+        let mut encoded = Vec::new();
+        write_var_int(value, &mut encoded).unwrap();
+        group.bench_with_input(BenchmarkId::new("read", value), &encoded, |b, encoded| {
+            b.iter(|| read_var_int(&mut black_box(&encoded[..])).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("write", value), &value, |b, &value| {
+            let mut buffer = Vec::with_capacity(5);
+            b.iter(|| {
+                buffer.clear();
+                write_var_int(black_box(value), &mut buffer).unwrap();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("size", value), &value, |b, &value| {
+            b.iter(|| var_int_size(black_box(value)))
+        });
+    }
+    group.finish();
+}
+
+fn string(c: &mut Criterion) {
+    let mut group = c.benchmark_group("string");
+    // A player name, a host name and a large chat message or status
+    for len in [16, 255, 32_767] {
+        let string = "a".repeat(len);
+        let mut encoded = Vec::new();
+        write_string(&string, &mut encoded).unwrap();
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new("read", len), &encoded, |b, encoded| {
+            b.iter(|| read_string(&mut DecoderState::new(black_box(encoded))).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("write", len), &string, |b, string| {
+            let mut buffer = Vec::with_capacity(encoded.len());
+            b.iter(|| {
+                buffer.clear();
+                write_string(black_box(string), &mut buffer).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn handshake(c: &mut Criterion) {
+    let handshake = HandshakePacket {
+        version: ProtocolVersion::V1_21_7,
+        address: Cow::Borrowed("mc.example.com"),
+        port: 25565,
+        next_state: NextState::Login,
+    };
+    let mut encoded = BytesMut::new();
+    PacketEncoder::new()
+        .encode(handshake, &mut encoded)
+        .unwrap();
+    c.bench_function("decode_handshake", |b| {
+        b.iter(|| {
+            let mut decoder = PacketDecoder::<HandshakePacket<'_>>::new();
+            let mut buffer = encoded.clone();
+            decoder.decode(black_box(&mut buffer)).unwrap().unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, var_int, string, handshake);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+//! Feeds arbitrary bytes to the packet decoders in arbitrary chunks, which have to return packets
+//! or errors but never panic. Splitting the input exercises resuming a packet that was only
+//! partially received.
+//!
+//! The input starts with a header: the state to decode packets of (0 handshaking, 1 status,
+//! 2 login), the compression threshold (its lowest bit enables compression, the others are the
+//! threshold), and the number of chunks followed by their sizes.
+//! Everything after the header is the data, of which whatever the chunks leave is received last.
+#![no_main]
+#![allow(dead_code, unused_imports)]
+
+#[path = "../../src/error.rs"]
+mod error;
+#[path = "../../src/protocol/mod.rs"]
+mod protocol;
+
+use libfuzzer_sys::fuzz_target;
+use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+use crate::protocol::{PacketDecoder, Protocol, handshake::HandshakePacket, login, status};
+
+/// Packets larger than this are rejected, which keeps the fuzzer from spending its time on
+/// allocating huge buffers
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+fuzz_target!(|input: &[u8]| {
+    let Some((&[state, compression, chunks], rest)) = input.split_first_chunk::<3>() else {
+        return;
+    };
+    let Some((chunks, data)) = rest.split_at_checked(usize::from(chunks)) else {
+        return;
+    };
+    let compression = (compression & 1 == 1).then_some(usize::from(compression >> 1));
+    match state % 3 {
+        0 => decode::<HandshakePacket<'_>>(compression, chunks, data),
+        1 => decode::<status::ServerBound>(compression, chunks, data),
+        _ => decode::<login::ServerBound<'_>>(compression, chunks, data),
+    }
+});
+
+/// Decodes the data like a connection receiving it in chunks of the given sizes would, stopping at
+/// the first error.
+fn decode<'a, T: Protocol<'a>>(compression: Option<usize>, chunks: &[u8], mut data: &[u8]) {
+    let mut decoder = PacketDecoder::<T>::with_max_size(MAX_PACKET_SIZE);
+    if let Some(threshold) = compression {
+        decoder.enable_compression(threshold);
+    }
+    let mut buffer = BytesMut::new();
+    let sizes = chunks.iter().map(|&size| usize::from(size));
+    for size in sizes.chain([data.len()]) {
+        let (chunk, rest) = data.split_at(size.min(data.len()));
+        data = rest;
+        buffer.extend_from_slice(chunk);
+        loop {
+            match decoder.decode(&mut buffer) {
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+    }
+}
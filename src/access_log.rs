@@ -0,0 +1,287 @@
+use std::{
+    cell::RefCell,
+    fmt::{self, Display, Formatter},
+    net::IpAddr,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{listener::Peer, protocol::handshake::NextState};
+
+/// The tracing target of access log events, so they can be filtered or written elsewhere
+pub const TARGET: &str = "access";
+
+tokio::task_local! {
+    static ENTRY: RefCell<AccessEntry>;
+}
+
+/// What the proxy did with a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// The connection was closed before the proxy decided what to do with it
+    Closed,
+    /// The client was denied by the allow or deny lists
+    Denied,
+    /// The connection limit was reached
+    Limited,
+    /// The client does not speak the Minecraft protocol
+    Invalid,
+    /// No route matched the host the client connected through
+    Unrouted,
+    /// The host the client connected through is blocked
+    Blocked,
+    LegacyPing,
+    Status,
+    Forwarded,
+    /// The player was kept connected while the backend started and then forwarded
+    Held,
+    /// The player started the backend and was disconnected
+    Started,
+    /// The player started the backend and was transferred back to the proxy
+    Transferred,
+    /// The player was disconnected because the backend failed
+    Failed,
+    NotWhitelisted,
+    Unverified,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::Closed => "closed",
+            Action::Denied => "denied",
+            Action::Limited => "limited",
+            Action::Invalid => "invalid",
+            Action::Unrouted => "unrouted",
+            Action::Blocked => "blocked",
+            Action::LegacyPing => "legacy_ping",
+            Action::Status => "status",
+            Action::Forwarded => "forwarded",
+            Action::Held => "held",
+            Action::Started => "started",
+            Action::Transferred => "transferred",
+            Action::Failed => "failed",
+            Action::NotWhitelisted => "not_whitelisted",
+            Action::Unverified => "unverified",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+/// The access log entry of a connection, filled in while the connection is handled
+pub struct AccessEntry {
+    pub client: IpAddr,
+    pub host: Option<String>,
+    pub next_state: Option<NextState>,
+    pub name: Option<String>,
+    pub action: Action,
+    /// Bytes forwarded from the client to the backend
+    pub bytes_received: u64,
+    /// Bytes forwarded from the backend to the client
+    pub bytes_sent: u64,
+    /// How long the connection was open, from being accepted until it was closed
+    pub duration: Duration,
+}
+
+impl AccessEntry {
+    pub fn new(peer: &Peer, action: Action) -> AccessEntry {
+        AccessEntry {
+            client: peer.ip(),
+            host: None,
+            next_state: None,
+            name: None,
+            action,
+            bytes_received: 0,
+            bytes_sent: 0,
+            duration: Duration::ZERO,
+        }
+    }
+
+    pub fn log(&self) {
+        tracing::info!(
+            target: TARGET,
+            client = %self.client,
+            host = self.host.as_deref(),
+            next_state = self.next_state.map(tracing::field::display),
+            name = self.name.as_deref(),
+            action = %self.action,
+            bytes_received = self.bytes_received,
+            bytes_sent = self.bytes_sent,
+            duration_ms = self.duration.as_millis() as u64,
+            "Connection closed"
+        );
+    }
+}
+
+/// Runs the handler of a connection accepted at `accepted` and logs its access log entry once it
+/// is done. The finished entry is passed to `on_close`, even if the handler panics or is dropped
+/// before it completes.
+pub async fn scope<T>(
+    peer: &Peer,
+    accepted: Instant,
+    handler: impl Future<Output = T>,
+    on_close: impl FnOnce(&AccessEntry),
+) -> T {
+    let entry = RefCell::new(AccessEntry::new(peer, Action::Closed));
+    ENTRY
+        .scope(entry, async {
+            let _guard = CloseGuard {
+                peer,
+                accepted,
+                on_close: Some(on_close),
+            };
+            handler.await
+        })
+        .await
+}
+
+/// Finishes the access log entry of a connection when dropped. Tokio drops a task-local scope's
+/// future while the task-local is still set, so the entry can be taken on every way out.
+struct CloseGuard<'a, F: FnOnce(&AccessEntry)> {
+    peer: &'a Peer,
+    accepted: Instant,
+    on_close: Option<F>,
+}
+
+impl<F: FnOnce(&AccessEntry)> Drop for CloseGuard<'_, F> {
+    fn drop(&mut self) {
+        let Some(on_close) = self.on_close.take() else {
+            return;
+        };
+        let closed = AccessEntry::new(self.peer, Action::Closed);
+        let Ok(mut entry) = ENTRY.try_with(|entry| entry.replace(closed)) else {
+            return;
+        };
+        entry.duration = self.accepted.elapsed();
+        // Traces show what was done with the connection as well
+        tracing::Span::current().record("action", tracing::field::display(entry.action));
+        entry.log();
+        on_close(&entry);
+    }
+}
+
+/// Records the bytes forwarded in each direction for the connection handled by the current task.
+pub fn record_bytes(received: u64, sent: u64) {
+    record(|entry| {
+        entry.bytes_received += received;
+        entry.bytes_sent += sent;
+    });
+}
+
+/// Updates the access log entry of the connection handled by the current task.
+pub fn record(update: impl FnOnce(&mut AccessEntry)) {
+    let _ = ENTRY.try_with(|entry| update(&mut entry.borrow_mut()));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::error::Error;
+
+    fn peer() -> Peer {
+        Peer::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 51234))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finishes_the_entry_when_the_handler_returns() {
+        let closed = Arc::new(Mutex::new(None));
+        let on_close = {
+            let closed = Arc::clone(&closed);
+            move |entry: &AccessEntry| {
+                *closed.lock().unwrap() = Some((
+                    entry.host.clone(),
+                    entry.name.clone(),
+                    entry.action,
+                    entry.bytes_received,
+                    entry.bytes_sent,
+                    entry.duration,
+                ))
+            }
+        };
+        let accepted = Instant::now();
+        scope(
+            &peer(),
+            accepted,
+            async {
+                record(|entry| {
+                    entry.host = Some("mc.example.com".to_owned());
+                    entry.next_state = Some(NextState::Login);
+                    entry.name = Some("alex".to_owned());
+                    entry.action = Action::Forwarded;
+                });
+                tokio::time::sleep(Duration::from_secs(3)).await;
+                record_bytes(100, 2000);
+                record_bytes(20, 0);
+            },
+            on_close,
+        )
+        .await;
+
+        assert_eq!(
+            closed.lock().unwrap().take(),
+            Some((
+                Some("mc.example.com".to_owned()),
+                Some("alex".to_owned()),
+                Action::Forwarded,
+                120,
+                2000,
+                Duration::from_secs(3)
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn finishes_the_entry_when_the_handler_is_dropped() {
+        let closed = Arc::new(Mutex::new(None));
+        let on_close = {
+            let closed = Arc::clone(&closed);
+            move |entry: &AccessEntry| *closed.lock().unwrap() = Some(entry.action)
+        };
+        let peer = peer();
+        let handler = scope(
+            &peer,
+            Instant::now(),
+            async {
+                record(|entry| entry.action = Action::Status);
+                std::future::pending::<()>().await
+            },
+            on_close,
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(10), handler)
+                .await
+                .is_err()
+        );
+        assert_eq!(*closed.lock().unwrap(), Some(Action::Status));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn records_the_duration_when_the_handler_fails() {
+        let closed = Arc::new(Mutex::new(None));
+        let on_close = {
+            let closed = Arc::clone(&closed);
+            move |entry: &AccessEntry| *closed.lock().unwrap() = Some(entry.duration)
+        };
+        let accepted = Instant::now();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let result: Result<(), Error> = scope(
+            &peer(),
+            accepted,
+            async {
+                tokio::time::sleep(Duration::from_millis(30)).await;
+                Err(Error::Timeout)
+            },
+            on_close,
+        )
+        .await;
+        assert!(result.is_err());
+        // The time before the handler started counts as well
+        assert_eq!(*closed.lock().unwrap(), Some(Duration::from_millis(50)));
+    }
+}
@@ -0,0 +1,274 @@
+use futures::future::BoxFuture;
+use rand::{RngCore, rngs::OsRng};
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, pkcs8::EncodePublicKey};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use uuid::Uuid;
+
+use crate::{error::Error, protocol::login::EncryptionResponse};
+
+const SESSION_SERVER: &str = "https://sessionserver.mojang.com/session/minecraft/hasJoined";
+/// Vanilla servers use 1024 bit keys, which is also what clients expect
+const KEY_BITS: usize = 1024;
+
+/// The profile of an authenticated player as returned by the session server
+#[derive(Debug, Clone, Deserialize)]
+pub struct GameProfile {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub properties: Vec<ProfileProperty>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// Checks whether a player has joined a server with the session server.
+/// This is a trait so the session server can be replaced, e.g. for testing.
+pub trait SessionService: Send + Sync {
+    /// Returns the player's profile if the player announced joining the server identified by
+    /// `server_hash`, or `None` if the player did not.
+    fn has_joined<'a>(
+        &'a self,
+        name: &'a str,
+        server_hash: &'a str,
+    ) -> BoxFuture<'a, Result<Option<GameProfile>, Error>>;
+}
+
+/// The session server operated by Mojang
+pub struct MojangSessionService {
+    client: reqwest::Client,
+}
+
+impl MojangSessionService {
+    pub fn new() -> MojangSessionService {
+        MojangSessionService {
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl SessionService for MojangSessionService {
+    fn has_joined<'a>(
+        &'a self,
+        name: &'a str,
+        server_hash: &'a str,
+    ) -> BoxFuture<'a, Result<Option<GameProfile>, Error>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(SESSION_SERVER)
+                .query(&[("username", name), ("serverId", server_hash)])
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|error| Error::Other(error.into()))?;
+
+            // The session server answers with an empty response if the player did not join
+            if response.status() == reqwest::StatusCode::NO_CONTENT {
+                return Ok(None);
+            }
+            let profile = response
+                .json()
+                .await
+                .map_err(|error| Error::Other(error.into()))?;
+            Ok(Some(profile))
+        })
+    }
+}
+
+/// Performs the server side of the encryption handshake for online mode logins.
+pub struct Authenticator {
+    private_key: RsaPrivateKey,
+    public_key: Vec<u8>,
+    session: Box<dyn SessionService>,
+}
+
+impl Authenticator {
+    /// Creates an authenticator with a freshly generated key pair.
+    pub fn new(session: Box<dyn SessionService>) -> Result<Authenticator, Error> {
+        let private_key =
+            RsaPrivateKey::new(&mut OsRng, KEY_BITS).map_err(|error| Error::Other(error.into()))?;
+        let public_key = private_key
+            .to_public_key()
+            .to_public_key_der()
+            .map_err(|error| Error::Other(error.into()))?
+            .into_vec();
+
+        Ok(Authenticator {
+            private_key,
+            public_key,
+            session,
+        })
+    }
+
+    /// The public key in DER encoding, as sent in the encryption request
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn verify_token() -> [u8; 4] {
+        let mut token = [0; 4];
+        OsRng.fill_bytes(&mut token);
+        token
+    }
+
+    /// Decrypts the shared secret from an encryption response after checking that the response
+    /// contains the verify token that was sent to the client.
+    pub fn shared_secret(
+        &self,
+        response: &EncryptionResponse<'_>,
+        verify_token: &[u8],
+    ) -> Result<[u8; 16], Error> {
+        let decrypt = |data: &[u8]| {
+            self.private_key
+                .decrypt(Pkcs1v15Encrypt, data)
+                .map_err(|_| Error::from("could not decrypt encryption response"))
+        };
+
+        if decrypt(&response.verify_token)? != verify_token {
+            return Err("verify token does not match".into());
+        }
+        decrypt(&response.shared_secret)?
+            .try_into()
+            .map_err(|_| "shared secret has an invalid length".into())
+    }
+
+    /// Asks the session server whether the player really joined using the given shared secret.
+    pub async fn authenticate(
+        &self,
+        name: &str,
+        shared_secret: &[u8],
+    ) -> Result<Option<GameProfile>, Error> {
+        let hash = server_hash("", shared_secret, &self.public_key);
+        self.session.has_joined(name, &hash).await
+    }
+}
+
+/// Computes the server hash used to identify the server towards the session server.
+/// This is a SHA-1 digest formatted as a signed hexadecimal number, as done by Java's `BigInteger`.
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_key: &[u8]) -> String {
+    let mut digest: [u8; 20] = Sha1::new()
+        .chain_update(server_id.as_bytes())
+        .chain_update(shared_secret)
+        .chain_update(public_key)
+        .finalize()
+        .into();
+
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        // Two's complement of the whole digest
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            let (value, overflow) = (!*byte).overflowing_add(carry as u8);
+            *byte = value;
+            carry = overflow;
+        }
+    }
+
+    let hex = digest
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let hex = hex.trim_start_matches('0');
+    match negative {
+        true => format!("-{}", hex),
+        false => hex.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        sync::{Arc, Mutex},
+    };
+
+    use rsa::{RsaPublicKey, pkcs8::DecodePublicKey};
+
+    use super::*;
+
+    /// Says that every player joined, remembering the server hashes it was asked about
+    #[derive(Default)]
+    struct MockSession {
+        hashes: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SessionService for MockSession {
+        fn has_joined<'a>(
+            &'a self,
+            name: &'a str,
+            server_hash: &'a str,
+        ) -> BoxFuture<'a, Result<Option<GameProfile>, Error>> {
+            self.hashes.lock().unwrap().push(server_hash.to_owned());
+            Box::pin(async move {
+                Ok(Some(GameProfile {
+                    id: Uuid::from_u128(1),
+                    name: name.to_owned(),
+                    properties: Vec::new(),
+                }))
+            })
+        }
+    }
+
+    #[test]
+    fn formats_server_hashes_like_java() {
+        // The examples from the protocol documentation, which hash only the server id
+        assert_eq!(
+            server_hash("Notch", &[], &[]),
+            "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48"
+        );
+        assert_eq!(
+            server_hash("jeb_", &[], &[]),
+            "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1"
+        );
+        assert_eq!(
+            server_hash("simon", &[], &[]),
+            "88e16a1019277b15d58faf0541e11910eb756f6"
+        );
+    }
+
+    #[tokio::test]
+    async fn decrypts_the_response_and_authenticates() {
+        let session = MockSession::default();
+        let hashes = Arc::clone(&session.hashes);
+        let authenticator = Authenticator::new(Box::new(session)).unwrap();
+        let public_key = RsaPublicKey::from_public_key_der(authenticator.public_key()).unwrap();
+        let encrypt = |data: &[u8]| {
+            public_key
+                .encrypt(&mut OsRng, Pkcs1v15Encrypt, data)
+                .unwrap()
+        };
+
+        let verify_token = Authenticator::verify_token();
+        let secret = *b"0123456789abcdef";
+        let response = EncryptionResponse {
+            shared_secret: Cow::Owned(encrypt(&secret)),
+            verify_token: Cow::Owned(encrypt(&verify_token)),
+        };
+        assert_eq!(
+            authenticator
+                .shared_secret(&response, &verify_token)
+                .unwrap(),
+            secret
+        );
+        let other_token = [verify_token[0].wrapping_add(1), 0, 0, 0];
+        assert!(
+            authenticator
+                .shared_secret(&response, &other_token)
+                .is_err()
+        );
+
+        let profile = authenticator.authenticate("alex", &secret).await.unwrap();
+        assert_eq!(profile.unwrap().name, "alex");
+        assert_eq!(
+            *hashes.lock().unwrap(),
+            [server_hash("", &secret, authenticator.public_key())]
+        );
+    }
+}
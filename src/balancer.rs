@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// How long an address that could not be connected to is skipped
+pub const DEFAULT_FAILURE_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Spreads connections over the addresses of a backend in round-robin order, skipping addresses
+/// that recently failed.
+pub struct Balancer {
+    next: AtomicUsize,
+    backoff: Duration,
+    /// When each address last failed
+    failures: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+impl Balancer {
+    pub fn new(backoff: Duration) -> Balancer {
+        Balancer {
+            next: AtomicUsize::new(0),
+            backoff,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the order in which the addresses should be tried for the next connection. Each call
+    /// starts at the healthy address following the one the previous call started at, unless a
+    /// healthy `preferred` address is given. Addresses that recently failed come last, so they are
+    /// only tried if all others fail as well.
+    pub fn order(
+        &self,
+        addresses: Vec<SocketAddr>,
+        preferred: Option<SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        let (mut healthy, failed): (Vec<_>, Vec<_>) = {
+            let mut failures = self.failures.lock().unwrap();
+            failures.retain(|_, failed_at| failed_at.elapsed() < self.backoff);
+            addresses
+                .into_iter()
+                .partition(|address| !failures.contains_key(address))
+        };
+        if let Some(start) = healthy
+            .iter()
+            .position(|address| Some(*address) == preferred)
+        {
+            healthy.rotate_left(start);
+        } else if !healthy.is_empty() {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+            healthy.rotate_left(start);
+        }
+        healthy.extend(failed);
+        healthy
+    }
+
+    pub fn failed(&self, address: SocketAddr) {
+        self.failures
+            .lock()
+            .unwrap()
+            .insert(address, Instant::now());
+    }
+
+    pub fn succeeded(&self, address: SocketAddr) {
+        self.failures.lock().unwrap().remove(&address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses() -> Vec<SocketAddr> {
+        ["10.0.0.1:25565", "10.0.0.2:25565", "10.0.0.3:25565"]
+            .into_iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rotates_through_the_addresses() {
+        let balancer = Balancer::new(DEFAULT_FAILURE_BACKOFF);
+        let [a, b, c] = addresses()[..] else {
+            unreachable!()
+        };
+        assert_eq!(balancer.order(addresses(), None), [a, b, c]);
+        assert_eq!(balancer.order(addresses(), None), [b, c, a]);
+        assert_eq!(balancer.order(addresses(), None), [c, a, b]);
+        assert_eq!(balancer.order(addresses(), None), [a, b, c]);
+        // A preferred address goes first without affecting the rotation
+        assert_eq!(balancer.order(addresses(), Some(c)), [c, a, b]);
+        assert_eq!(balancer.order(addresses(), None), [b, c, a]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tries_failed_addresses_last_until_the_backoff_passed() {
+        let balancer = Balancer::new(Duration::from_secs(10));
+        let [a, b, c] = addresses()[..] else {
+            unreachable!()
+        };
+        balancer.failed(a);
+        assert_eq!(balancer.order(addresses(), None), [b, c, a]);
+        assert_eq!(balancer.order(addresses(), None), [c, b, a]);
+        // A failed address is not preferred either
+        assert_eq!(balancer.order(addresses(), Some(a))[2], a);
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(balancer.order(addresses(), None).len(), 3);
+        assert!(balancer.failures.lock().unwrap().is_empty());
+
+        balancer.failed(b);
+        balancer.succeeded(b);
+        assert!(balancer.failures.lock().unwrap().is_empty());
+    }
+}
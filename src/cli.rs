@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::config::Config;
+
+/// A reverse proxy for minecraft servers that starts the server when a player joins
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// TOML file to read the configuration from. Options given on the command line or in the
+    /// environment take precedence
+    #[arg(long, env = "PORTAL_CONFIG", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub options: Config,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, time::Duration};
+
+    use clap::{CommandFactory, error::ErrorKind};
+    use tracing::Level;
+
+    use super::*;
+    use crate::{listener::ListenAddress, resolver::BackendAddress, testing::temp_dir};
+
+    #[test]
+    fn is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_named_flags() {
+        let cli = Cli::try_parse_from([
+            "portal",
+            "--listen",
+            "0.0.0.0:25565,unix:/run/portal.sock",
+            "--forward",
+            "127.0.0.1:25566",
+            "--start-command",
+            "./start.sh",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(cli.config, None);
+        assert_eq!(
+            cli.options.listen,
+            [
+                ListenAddress::Tcp("0.0.0.0:25565".parse().unwrap()),
+                ListenAddress::Unix("/run/portal.sock".into()),
+            ]
+        );
+        assert_eq!(
+            cli.options.forward,
+            [BackendAddress::Socket("127.0.0.1:25566".parse().unwrap())]
+        );
+        assert_eq!(cli.options.start_command.as_deref(), Some("./start.sh"));
+        assert_eq!(cli.options.log_level, Some(Level::DEBUG));
+    }
+
+    #[test]
+    fn leaves_the_options_to_the_config_file() {
+        let cli = Cli::try_parse_from(["portal", "--config", "portal.toml"]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("portal.toml")));
+        assert!(cli.options.listen.is_empty());
+        assert!(cli.options.forward.is_empty());
+        assert_eq!(cli.options.start_command, None);
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        let error = Cli::try_parse_from(["portal", "--listen", "localhost"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ValueValidation);
+        assert!(
+            error
+                .to_string()
+                .contains("could not parse listen address localhost")
+        );
+
+        let error =
+            Cli::try_parse_from(["portal", "--forward", "mc.example.com:port"]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ValueValidation);
+        assert!(
+            error
+                .to_string()
+                .contains("invalid backend address mc.example.com:port")
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_flags() {
+        let error = Cli::try_parse_from([
+            "portal",
+            "--velocity-secret-file",
+            "secret",
+            "--bungee-forwarding",
+        ])
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn prefers_the_command_line_over_the_environment_over_the_file() {
+        let path = temp_dir("cli").join("portal.toml");
+        fs::write(&path, "stop-timeout = 30\n").unwrap();
+        let path = path.to_str().unwrap();
+        let stop_timeout = |args: &[&str]| {
+            let cli = Cli::try_parse_from(["portal"].iter().chain(args)).unwrap();
+            Config::load(cli.config.as_deref(), &cli.options)
+                .unwrap()
+                .stop_timeout
+        };
+
+        assert_eq!(stop_timeout(&[]), None);
+        assert_eq!(
+            stop_timeout(&["--config", path]),
+            Some(Duration::from_secs(30))
+        );
+        // SAFETY: only this test reads the variable, and no test changes the environment otherwise
+        unsafe { env::set_var("PORTAL_STOP_TIMEOUT", "20") };
+        let from_env = stop_timeout(&["--config", path]);
+        let from_cli = stop_timeout(&["--config", path, "--stop-timeout", "10"]);
+        unsafe { env::remove_var("PORTAL_STOP_TIMEOUT") };
+        assert_eq!(from_env, Some(Duration::from_secs(20)));
+        assert_eq!(from_cli, Some(Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,225 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::instrument;
+
+use crate::{
+    error::Error,
+    protocol::{read_single_packet, status, write_packet},
+};
+
+/// How long a server gets to answer a request of the proxy
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks a server for its status like a client in the server list would and returns the JSON it
+/// answered with. `handshake` is the encoded handshake packet, e.g. the one received from a client,
+/// which has to ask for the status state.
+#[instrument(skip_all)]
+pub async fn status(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    handshake: &[u8],
+) -> Result<Arc<str>, Error> {
+    stream.write_all(handshake).await?;
+    write_packet(stream, &status::ServerBound::StatusRequest).await?;
+
+    let (response, _) =
+        read_single_packet::<status::ClientBound<'_>>(stream, RESPONSE_TIMEOUT).await?;
+    match &*response {
+        status::ClientBound::StatusResponse { json_response } => Ok(Arc::from(&**json_response)),
+        status::ClientBound::PingResponse(_) => {
+            Err("server sent a ping response instead of its status".into())
+        }
+    }
+}
+
+/// A client that talks to the proxy like a Minecraft client would, for tests
+#[cfg(test)]
+pub mod testing {
+    use std::{borrow::Cow, net::SocketAddr};
+
+    use futures::{SinkExt, StreamExt};
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncRead, AsyncWriteExt},
+        net::TcpStream,
+        time::timeout,
+    };
+    use tokio_util::codec::{FramedRead, FramedWrite};
+    use uuid::Uuid;
+
+    use super::RESPONSE_TIMEOUT;
+    use crate::{
+        error::Error,
+        protocol::{
+            PacketDecoder, PacketEncoder, Protocol,
+            handshake::{HandshakePacket, NextState, ProtocolVersion},
+            login, status, write_packet,
+        },
+    };
+
+    /// The payload of the ping sent after the status request
+    const PING_PAYLOAD: i64 = 0x706f7274616c;
+
+    pub struct TestClient {
+        address: SocketAddr,
+        /// The host name and port sent in the handshake
+        host: String,
+        port: u16,
+        version: ProtocolVersion,
+        /// Sent before the handshake, like a proxy in front of the server would
+        proxy_header: Option<Vec<u8>>,
+    }
+
+    impl TestClient {
+        /// Creates a client that connects to `address` through the host `localhost`.
+        pub fn new(address: SocketAddr) -> TestClient {
+            TestClient {
+                address,
+                host: "localhost".to_owned(),
+                port: address.port(),
+                version: ProtocolVersion::V1_21_7,
+                proxy_header: None,
+            }
+        }
+
+        /// Sets the host name sent in the handshake, which chooses the route.
+        pub fn with_host(mut self, host: &str) -> TestClient {
+            self.host = host.to_owned();
+            self
+        }
+
+        pub fn with_version(mut self, version: ProtocolVersion) -> TestClient {
+            self.version = version;
+            self
+        }
+
+        /// Sends a PROXY protocol header before the handshake.
+        pub fn with_proxy_header(mut self, header: &[u8]) -> TestClient {
+            self.proxy_header = Some(header.to_vec());
+            self
+        }
+
+        /// Connects and sends a handshake asking for `next_state`.
+        pub async fn connect(&self, next_state: NextState) -> Result<TcpStream, Error> {
+            let mut stream = TcpStream::connect(self.address).await?;
+            if let Some(header) = &self.proxy_header {
+                stream.write_all(header).await?;
+            }
+            let handshake = HandshakePacket {
+                version: self.version,
+                address: Cow::Borrowed(&self.host),
+                port: self.port,
+                next_state,
+            };
+            write_packet(&mut stream, &handshake).await?;
+            Ok(stream)
+        }
+
+        /// Asks for the status like the server list does and returns the status JSON, after
+        /// checking that the following ping is answered.
+        pub async fn status(&self) -> Result<Value, Error> {
+            let mut stream = self.connect(NextState::Status).await?;
+            let (reader, writer) = stream.split();
+            let mut reader = FramedRead::new(reader, PacketDecoder::<status::ClientBound>::new());
+            let mut writer = FramedWrite::new(writer, PacketEncoder::new());
+
+            writer.send(status::ServerBound::StatusRequest).await?;
+            let json = match next(&mut reader).await? {
+                status::ClientBound::StatusResponse { json_response } => {
+                    serde_json::from_str(&json_response)
+                        .map_err(|error| Error::Other(error.into()))?
+                }
+                status::ClientBound::PingResponse(_) => {
+                    return Err("expected a status response".into());
+                }
+            };
+            writer
+                .send(status::ServerBound::PingRequest(PING_PAYLOAD))
+                .await?;
+            match next(&mut reader).await? {
+                status::ClientBound::PingResponse(PING_PAYLOAD) => Ok(json),
+                _ => Err("expected a ping response with the payload".into()),
+            }
+        }
+
+        /// Starts logging in as `name` and returns the first packet other than the one enabling
+        /// compression, which is usually a disconnect or the login success.
+        pub async fn login(&self, name: &str) -> Result<login::ClientBound<'static>, Error> {
+            let mut stream = self.connect(NextState::Login).await?;
+            let login_start = login::ServerBound::LoginStart(login::LoginStart {
+                name: Cow::Borrowed(name),
+                uuid: Uuid::nil(),
+            });
+            write_packet(&mut stream, &login_start).await?;
+
+            let mut reader = FramedRead::new(stream, PacketDecoder::<login::ClientBound>::new());
+            loop {
+                match next(&mut reader).await? {
+                    login::ClientBound::SetCompression(threshold) if threshold >= 0 => {
+                        reader.decoder_mut().enable_compression(threshold as usize);
+                    }
+                    login::ClientBound::SetCompression(_) => {}
+                    packet => return Ok(packet),
+                }
+            }
+        }
+    }
+
+    /// Reads the next packet, failing if the server closes the connection instead.
+    async fn next<R, T>(reader: &mut FramedRead<R, PacketDecoder<T>>) -> Result<T, Error>
+    where
+        R: AsyncRead + Unpin,
+        T: Protocol<'static>,
+    {
+        let packet = timeout(RESPONSE_TIMEOUT, reader.next())
+            .await?
+            .ok_or("the server closed the connection")??;
+        Ok(packet.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        protocol::{chat::Chat, handshake::ProtocolVersion, login},
+        testing::{TestProxy, free_address},
+    };
+
+    #[tokio::test]
+    async fn fetches_the_starting_status() {
+        let proxy = TestProxy::start(&format!(
+            r#"
+            forward = ["{}"]
+            start-command = "sleep 30"
+            "#,
+            free_address()
+        ))
+        .await;
+        let client = proxy.client();
+
+        // Asking for the status starts the backend
+        let status = client.status().await.unwrap();
+        assert_eq!(
+            status["description"],
+            "Server is starting, please try again in a moment"
+        );
+        assert_eq!(status["version"]["protocol"], 772);
+
+        let login::ClientBound::Disconnect(Chat::Json(reason)) =
+            client.login("alex").await.unwrap()
+        else {
+            panic!("expected a disconnect");
+        };
+        assert!(reason.contains("Server is starting"));
+
+        // The client's version is echoed, whatever host it connected through
+        let status = client
+            .with_host("mc.example.com")
+            .with_version(ProtocolVersion(47))
+            .status()
+            .await
+            .unwrap();
+        assert_eq!(status["version"]["protocol"], 47);
+        proxy.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,895 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Args;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::Level;
+
+use crate::{
+    cidr::Cidr,
+    docker::DOCKER,
+    error::Error,
+    external_process::{Restart, Strategy, check_command},
+    listener::ListenAddress,
+    logging::LogFormat,
+    protocol::chat,
+    registry::DEFAULT_BACKEND,
+    resolver::BackendAddress,
+    routing::{check_host, route_id},
+};
+
+/// The configuration of the proxy. It is read from a TOML file using the names of the command line
+/// options as keys, e.g. `start-command = "./start.sh"`. Each option can also be set using an
+/// environment variable like `PORTAL_START_COMMAND`. The command line takes precedence over the
+/// environment, which takes precedence over the file.
+#[derive(Debug, Default, Args, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Addresses to accept connections on, either a socket address or unix:<path>
+    #[arg(
+        long,
+        env = "PORTAL_LISTEN",
+        value_delimiter = ',',
+        value_name = "ADDRESS"
+    )]
+    #[serde(with = "strings", skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<ListenAddress>,
+    /// Addresses of the backend for hosts without a route, used in turn. Host names are resolved
+    /// using SRV records
+    #[arg(
+        long,
+        env = "PORTAL_FORWARD",
+        value_delimiter = ',',
+        value_name = "ADDRESS"
+    )]
+    #[serde(with = "strings", skip_serializing_if = "Vec::is_empty")]
+    pub forward: Vec<BackendAddress>,
+    /// Command that starts the backend for hosts without a route. `{host}`, `{port}` and
+    /// `{client_ip}` are replaced with those of the connection that started it
+    #[arg(long, env = "PORTAL_START_COMMAND", value_name = "COMMAND")]
+    pub start_command: Option<String>,
+    /// How backends are started: command runs the start command, while docker starts the
+    /// container the start command names and stops it with `docker stop`
+    #[arg(long, env = "PORTAL_START_STRATEGY", value_name = "STRATEGY")]
+    pub start_strategy: Option<Strategy>,
+    /// Backends for players connecting through specific hosts, given as tables like
+    /// `[route."survival.example.com"]`. A host like `*.mc.example.com` routes all of its
+    /// subdomains, unless they have a route of their own. Hosts starting with `~` are regular
+    /// expressions, e.g. `~^(eu|us)\.example\.com$`, which are tried after all other routes.
+    /// Only the config file can set them.
+    /// Players connecting through other hosts are forwarded to `forward`, the default route, or
+    /// get the unknown server message if it is not set.
+    #[arg(skip)]
+    #[serde(with = "pairs", skip_serializing_if = "Vec::is_empty")]
+    pub route: Vec<(String, Route)>,
+    /// Log level, overridden by RUST_LOG
+    #[arg(long, env = "PORTAL_LOG_LEVEL", value_name = "LEVEL")]
+    #[serde(with = "string")]
+    pub log_level: Option<Level>,
+    /// Log format: full, compact, pretty or json
+    #[arg(long, env = "PORTAL_LOG_FORMAT", value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Leave timestamps out of the log, e.g. if the log collector adds its own
+    #[arg(long, env = "PORTAL_NO_LOG_TIMESTAMPS")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub no_log_timestamps: bool,
+    /// Leave the module each event comes from out of the log
+    #[arg(long, env = "PORTAL_NO_LOG_TARGETS")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub no_log_targets: bool,
+    /// OTLP/HTTP endpoint to export traces to, e.g. http://localhost:4318/v1/traces. Requires
+    /// portal to be built with the otlp feature.
+    #[arg(long, env = "PORTAL_OTLP_ENDPOINT", value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Status shown while the backend is offline
+    #[arg(long, env = "PORTAL_STATUS", value_name = "PATH")]
+    pub status: Option<PathBuf>,
+    /// Seconds the status of a running backend is reused before it is queried again
+    #[arg(long, env = "PORTAL_STATUS_CACHE_TTL", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub status_cache_ttl: Option<Duration>,
+    /// Favicon shown in the server list
+    #[arg(long, env = "PORTAL_FAVICON", value_name = "PATH")]
+    pub favicon: Option<PathBuf>,
+    /// Status shown to players connecting through a specific host
+    #[arg(long, env = "PORTAL_HOST_STATUS", value_name = "HOST=PATH", value_parser = parse_key_value::<PathBuf>)]
+    #[serde(with = "pairs", skip_serializing_if = "Vec::is_empty")]
+    pub host_status: Vec<(String, PathBuf)>,
+    /// Message shown to players while the backend starts, as text or JSON
+    #[arg(long, env = "PORTAL_STARTING_MESSAGE", value_name = "MESSAGE")]
+    pub starting_message: Option<String>,
+    /// Message shown to players if the backend failed, as text or JSON
+    #[arg(long, env = "PORTAL_FAILED_MESSAGE", value_name = "MESSAGE")]
+    pub failed_message: Option<String>,
+    /// Message shown to players connecting through a host without a route while there is no
+    /// default route, as text or JSON. Status requests get it as the description
+    #[arg(long, env = "PORTAL_UNKNOWN_SERVER_MESSAGE", value_name = "MESSAGE")]
+    pub unknown_server_message: Option<String>,
+
+    /// Authenticate players with the session server
+    #[arg(long, env = "PORTAL_ONLINE_MODE")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub online_mode: bool,
+    /// Packet size in bytes above which packets sent to players are compressed
+    #[arg(long, env = "PORTAL_COMPRESSION_THRESHOLD", value_name = "BYTES")]
+    pub compression_threshold: Option<usize>,
+    /// File containing the secret used for Velocity modern forwarding
+    #[arg(
+        long,
+        env = "PORTAL_VELOCITY_SECRET_FILE",
+        value_name = "PATH",
+        conflicts_with = "bungee_forwarding"
+    )]
+    pub velocity_secret_file: Option<PathBuf>,
+    /// Pass player information on using BungeeCord forwarding
+    #[arg(long, env = "PORTAL_BUNGEE_FORWARDING")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub bungee_forwarding: bool,
+    /// File of player names and UUIDs that may start the backend
+    #[arg(long, env = "PORTAL_WHITELIST", value_name = "PATH")]
+    pub whitelist: Option<PathBuf>,
+    /// Keep players connected for up to this many seconds while the backend starts
+    #[arg(long = "hold", env = "PORTAL_HOLD", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(rename = "hold", with = "seconds")]
+    pub hold_timeout: Option<Duration>,
+    /// Transfer players back to the proxy after this many seconds while the backend starts
+    #[arg(long, env = "PORTAL_TRANSFER_DELAY", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub transfer_delay: Option<Duration>,
+
+    /// Send a PROXY protocol header to the backend
+    #[arg(long, env = "PORTAL_SEND_PROXY_PROTOCOL")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub send_proxy_protocol: bool,
+    /// Address range of proxies whose PROXY protocol headers are trusted
+    #[arg(
+        long,
+        env = "PORTAL_TRUSTED_PROXY",
+        value_delimiter = ',',
+        value_name = "RANGE"
+    )]
+    #[serde(with = "strings", skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxy: Vec<Cidr>,
+    /// Address range clients may connect from
+    #[arg(
+        long,
+        env = "PORTAL_ALLOW",
+        value_delimiter = ',',
+        value_name = "RANGE"
+    )]
+    #[serde(with = "strings", skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Cidr>,
+    /// Address range clients may not connect from
+    #[arg(long, env = "PORTAL_DENY", value_delimiter = ',', value_name = "RANGE")]
+    #[serde(with = "strings", skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Cidr>,
+    /// Host name whose connections are closed right after the handshake, without starting or
+    /// connecting to a backend. Accepts wildcards and regular expressions like route hosts
+    #[arg(
+        long,
+        env = "PORTAL_BLOCK_HOST",
+        value_delimiter = ',',
+        value_name = "HOST"
+    )]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub block_host: Vec<String>,
+    /// Message players connecting through a blocked host are disconnected with, as text or JSON.
+    /// Without it, the connection is closed without a response
+    #[arg(long, env = "PORTAL_BLOCKED_MESSAGE", value_name = "MESSAGE")]
+    pub blocked_message: Option<String>,
+    /// Maximum number of connections handled at once
+    #[arg(long, env = "PORTAL_MAX_CONNECTIONS", value_name = "COUNT")]
+    pub max_connections: Option<usize>,
+    /// Seconds a client may take to send the handshake
+    #[arg(long, env = "PORTAL_HANDSHAKE_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub handshake_timeout: Option<Duration>,
+    /// Seconds a client may take to send each packet of a server list ping
+    #[arg(long, env = "PORTAL_STATUS_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub status_timeout: Option<Duration>,
+    /// Seconds a client may take to send each packet of the login
+    #[arg(long, env = "PORTAL_LOGIN_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub login_timeout: Option<Duration>,
+    /// Address to serve Prometheus metrics on
+    #[arg(long, env = "PORTAL_METRICS_ADDRESS", value_name = "ADDRESS")]
+    pub metrics_address: Option<SocketAddr>,
+    /// UDP address to answer GameSpy4 queries on, which report the status of the default route
+    #[arg(long, env = "PORTAL_QUERY_ADDRESS", value_name = "ADDRESS")]
+    pub query_address: Option<SocketAddr>,
+
+    /// Seconds connecting to the backend may take
+    #[arg(long, env = "PORTAL_CONNECT_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub connect_timeout: Option<Duration>,
+    /// Seconds a player is sent to the same backend address they were last on
+    #[arg(long, env = "PORTAL_STICKY_SESSIONS", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub sticky_sessions: Option<Duration>,
+    /// Bytes per second forwarded in each direction of a connection
+    #[arg(long, env = "PORTAL_RATE_LIMIT", value_name = "BYTES")]
+    pub rate_limit: Option<NonZeroU64>,
+    /// Seconds a forwarded connection may go without any traffic
+    #[arg(long, env = "PORTAL_CONNECTION_IDLE_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub connection_idle_timeout: Option<Duration>,
+    /// Do not set TCP_NODELAY on sockets
+    #[arg(long, env = "PORTAL_NO_TCP_NODELAY")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub no_tcp_nodelay: bool,
+    /// Seconds a connection may be idle before keepalive probes are sent, 0 disables keepalive
+    #[arg(long = "keepalive", env = "PORTAL_KEEPALIVE", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(rename = "keepalive", with = "seconds")]
+    pub keepalive_time: Option<Duration>,
+    /// Seconds between keepalive probes
+    #[arg(long, env = "PORTAL_KEEPALIVE_INTERVAL", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub keepalive_interval: Option<Duration>,
+
+    /// Seconds without players after which the backend is stopped, 0 keeps it running
+    #[arg(long, env = "PORTAL_IDLE_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub idle_timeout: Option<Duration>,
+    /// Command that stops the backend instead of terminating it
+    #[arg(long, env = "PORTAL_STOP_COMMAND", value_name = "COMMAND")]
+    pub stop_command: Option<String>,
+    /// Seconds the backend may take to stop before it is killed
+    #[arg(long, env = "PORTAL_STOP_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub stop_timeout: Option<Duration>,
+    /// Command run before the backend is started
+    #[arg(long, env = "PORTAL_PRE_START_COMMAND", value_name = "COMMAND")]
+    pub pre_start_command: Option<String>,
+    /// Command run after the backend stopped
+    #[arg(long, env = "PORTAL_POST_STOP_COMMAND", value_name = "COMMAND")]
+    pub post_stop_command: Option<String>,
+    /// Seconds the backend may take to become reachable after starting it
+    #[arg(long, env = "PORTAL_START_TIMEOUT", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub start_timeout: Option<Duration>,
+    /// Seconds after running the start command in which it is not run again, 0 turns this off
+    #[arg(long, env = "PORTAL_START_COOLDOWN", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub start_cooldown: Option<Duration>,
+    /// Regular expression matching the line the backend logs once it is ready, e.g.
+    /// `Done \(.*\)! For help`. Until then, clients are told the backend is starting
+    #[arg(long, env = "PORTAL_READY_PATTERN", value_name = "REGEX")]
+    pub ready_pattern: Option<String>,
+    /// Stop the backend if it does not become reachable within the start timeout
+    #[arg(long, env = "PORTAL_KILL_ON_START_TIMEOUT")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub kill_on_start_timeout: bool,
+    /// When to restart the backend after it exited: never, on-failure or always
+    #[arg(long, env = "PORTAL_RESTART", value_name = "POLICY")]
+    pub restart: Option<Restart>,
+    /// Maximum number of restarts within the restart window
+    #[arg(long, env = "PORTAL_MAX_RESTARTS", value_name = "COUNT")]
+    pub max_restarts: Option<u32>,
+    /// Seconds in which restarts are counted
+    #[arg(long, env = "PORTAL_RESTART_WINDOW", value_name = "SECONDS", value_parser = parse_seconds)]
+    #[serde(with = "seconds")]
+    pub restart_window: Option<Duration>,
+    /// Environment variable set for the commands
+    #[arg(long, env = "PORTAL_ENV", value_name = "KEY=VALUE", value_parser = parse_key_value::<String>)]
+    #[serde(with = "pairs", skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<(String, String)>,
+    /// Do not pass the environment of the proxy on to the commands
+    #[arg(long, env = "PORTAL_CLEAR_ENV")]
+    #[serde(skip_serializing_if = "is_false")]
+    pub clear_env: bool,
+    /// Directory the commands are run in
+    #[arg(long, env = "PORTAL_WORKING_DIR", value_name = "PATH")]
+    pub working_dir: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the configuration file, if any, and applies the options given on the command line or
+    /// in the environment on top of it. Options that are set there replace those in the file,
+    /// while tables like `env` are merged entry by entry.
+    pub fn load(path: Option<&Path>, overrides: &Config) -> Result<Config, Error> {
+        let mut table = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path).map_err(|error| {
+                    Error::Other(format!("could not read {}: {}", path.display(), error).into())
+                })?;
+                // Deserializing the file on its own reports errors with their position in it
+                let file: Config = toml::from_str(&contents).map_err(|error| {
+                    Error::Other(format!("invalid config {}: {}", path.display(), error).into())
+                })?;
+                to_table(&file)?
+            }
+            None => toml::Table::new(),
+        };
+        merge(&mut table, to_table(overrides)?);
+
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|error| Error::Other(Box::new(error)))
+    }
+
+    /// Checks for mistakes that would otherwise only show once a player connects or the server is
+    /// started. All problems are returned together, so they can be fixed in one go.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        // With routes, the default route is optional, but it still needs both of its options
+        let default_route = !self.forward.is_empty() || self.start_command.is_some();
+        let required = [
+            ("listen", self.listen.is_empty(), true),
+            ("forward", self.forward.is_empty(), default_route),
+            ("start-command", self.start_command.is_none(), default_route),
+        ];
+        for (name, missing, needed) in required {
+            if missing && (needed || self.route.is_empty()) {
+                errors.push(format!(
+                    "{} is required, set it in the config file or pass --{}",
+                    name, name
+                ));
+            }
+        }
+
+        if self.otlp_endpoint.is_some() && !cfg!(feature = "otlp") {
+            errors.push("otlp-endpoint: portal was built without the otlp feature".to_owned());
+        }
+        if let Some(dir) = &self.working_dir
+            && !dir.is_dir()
+        {
+            errors.push(format!("working-dir: {} is not a directory", dir.display()));
+        }
+        let docker = self.start_strategy == Some(Strategy::Docker);
+        if docker {
+            // A container is configured in Docker rather than through the proxy
+            let unsupported = [
+                ("stop-command", self.stop_command.is_some()),
+                ("pre-start-command", self.pre_start_command.is_some()),
+                ("post-stop-command", self.post_stop_command.is_some()),
+                ("ready-pattern", self.ready_pattern.is_some()),
+                ("restart", self.restart.is_some()),
+                ("clear-env", self.clear_env),
+                ("working-dir", self.working_dir.is_some()),
+            ];
+            for (name, _) in unsupported.into_iter().filter(|(_, set)| *set) {
+                errors.push(format!(
+                    "{} can not be used with the docker start strategy",
+                    name
+                ));
+            }
+            if let Err(error) = check_command(DOCKER, None) {
+                errors.push(format!("start-strategy: {}", error));
+            }
+        }
+        let commands = [
+            (
+                "start-command",
+                self.start_command.as_ref().filter(|_| !docker),
+            ),
+            ("stop-command", self.stop_command.as_ref()),
+            ("pre-start-command", self.pre_start_command.as_ref()),
+            ("post-stop-command", self.post_stop_command.as_ref()),
+        ];
+        for (name, command) in commands {
+            if let Some(command) = command
+                && let Err(error) = check_command(command, self.working_dir.as_deref())
+            {
+                errors.push(format!("{}: {}", name, error));
+            }
+        }
+        if let Some(pattern) = &self.ready_pattern
+            && let Err(error) = Regex::new(pattern)
+        {
+            errors.push(format!("ready-pattern: {}", error));
+        }
+
+        let files = [
+            ("status", &self.status),
+            ("favicon", &self.favicon),
+            ("whitelist", &self.whitelist),
+            ("velocity-secret-file", &self.velocity_secret_file),
+        ];
+        let files = files
+            .into_iter()
+            .filter_map(|(name, path)| Some((name, path.as_ref()?)))
+            .chain(
+                self.host_status
+                    .iter()
+                    .map(|(_, path)| ("host-status", path)),
+            );
+        for (name, path) in files {
+            if !path.is_file() {
+                errors.push(format!("{}: {} does not exist", name, path.display()));
+            }
+        }
+
+        for host in &self.block_host {
+            if let Err(error) = check_host(host) {
+                errors.push(format!("block-host {}: {}", host, error));
+            }
+        }
+
+        // Routes are looked up by their lowercase host, which also identifies their backend
+        let mut routes = HashMap::new();
+        for (host, route) in &self.route {
+            let id = route_id(host);
+            if let Err(error) = check_host(host) {
+                errors.push(format!("route {}: {}", host, error));
+            }
+            if id == DEFAULT_BACKEND {
+                errors.push(format!(
+                    "route {}: the name is reserved for the default route",
+                    host
+                ));
+            }
+            if let Some(other) = routes.insert(id, host) {
+                errors.push(format!("route {}: conflicts with route {}", host, other));
+            }
+            if route.forward.is_empty() {
+                errors.push(format!("route {}: forward is required", host));
+            }
+            if let Some(dir) = &route.working_dir
+                && !dir.is_dir()
+            {
+                errors.push(format!(
+                    "route {}: working-dir: {} is not a directory",
+                    host,
+                    dir.display()
+                ));
+            }
+            if docker && route.working_dir.is_some() {
+                errors.push(format!(
+                    "route {}: working-dir can not be used with the docker start strategy",
+                    host
+                ));
+            }
+            let working_dir = route.working_dir.as_ref().or(self.working_dir.as_ref());
+            if !docker
+                && let Err(error) =
+                    check_command(&route.start_command, working_dir.map(PathBuf::as_path))
+            {
+                errors.push(format!("route {}: start-command: {}", host, error));
+            }
+            if let Some(path) = &route.status {
+                if !path.is_file() {
+                    errors.push(format!(
+                        "route {}: status: {} does not exist",
+                        host,
+                        path.display()
+                    ));
+                }
+                let host_status = self
+                    .host_status
+                    .iter()
+                    .any(|(status_host, _)| status_host.eq_ignore_ascii_case(host));
+                if host_status {
+                    errors.push(format!("route {}: status is also set in host-status", host));
+                }
+            }
+            if let Some(path) = &route.favicon
+                && !path.is_file()
+            {
+                errors.push(format!(
+                    "route {}: favicon: {} does not exist",
+                    host,
+                    path.display()
+                ));
+            }
+            if let Some(motd) = &route.motd
+                && let Err(error) = chat::parse_message(motd)
+            {
+                errors.push(format!("route {}: motd: {}", host, error));
+            }
+        }
+
+        // Zero turns off the idle timeout and keepalive, but makes no sense for these
+        let durations = [
+            ("hold", self.hold_timeout),
+            ("transfer-delay", self.transfer_delay),
+            ("status-cache-ttl", self.status_cache_ttl),
+            ("handshake-timeout", self.handshake_timeout),
+            ("status-timeout", self.status_timeout),
+            ("login-timeout", self.login_timeout),
+            ("connect-timeout", self.connect_timeout),
+            ("sticky-sessions", self.sticky_sessions),
+            ("connection-idle-timeout", self.connection_idle_timeout),
+            ("keepalive-interval", self.keepalive_interval),
+            ("stop-timeout", self.stop_timeout),
+            ("start-timeout", self.start_timeout),
+            ("restart-window", self.restart_window),
+        ];
+        for (name, duration) in durations {
+            if duration.is_some_and(|duration| duration.is_zero()) {
+                errors.push(format!("{} must be at least one second", name));
+            }
+        }
+        if self.max_connections == Some(0) {
+            errors.push("max-connections must be at least one".to_owned());
+        }
+
+        if self.query_address.is_some() && !default_route {
+            errors.push("query-address requires a default route".to_owned());
+        }
+
+        // The command line rejects this on its own, but the config file does not
+        if self.velocity_secret_file.is_some() && self.bungee_forwarding {
+            errors.push("velocity-secret-file and bungee-forwarding cannot be combined".to_owned());
+        }
+        let forwarding = self.velocity_secret_file.is_some() || self.bungee_forwarding;
+        if self.hold_timeout.is_some() && self.online_mode && !forwarding {
+            errors.push("holding players in online mode requires forwarding".to_owned());
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+fn to_table(config: &Config) -> Result<toml::Table, Error> {
+    toml::Table::try_from(config).map_err(|error| Error::Other(Box::new(error)))
+}
+
+/// Merges `overrides` into `table`. Nested tables are merged, all other values are replaced.
+fn merge(table: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (table.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(overrides)) => {
+                merge(table, overrides)
+            }
+            (_, value) => {
+                table.insert(key, value);
+            }
+        }
+    }
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .map(Duration::from_secs)
+        .map_err(|_| format!("invalid number of seconds: {}", value))
+}
+
+fn parse_key_value<T: From<String>>(value: &str) -> Result<(String, T), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected <key>=<value>, got {}", value))?;
+    Ok((key.to_owned(), T::from(value.to_owned())))
+}
+
+/// A backend that players connecting through a specific host are forwarded to. Options not set
+/// here, like the stop command, are shared with the default route.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Route {
+    /// Addresses of the backend, used in turn
+    #[serde(with = "strings")]
+    pub forward: Vec<BackendAddress>,
+    /// Command that starts the backend, or its container with the docker start strategy
+    pub start_command: String,
+    /// Status shown while the backend is offline, instead of `status`
+    pub status: Option<PathBuf>,
+    /// PNG image shown as the icon of the server, instead of `favicon`
+    pub favicon: Option<PathBuf>,
+    /// Description shown in the server list, as text or JSON. It replaces the one of the status
+    pub motd: Option<String>,
+    /// Environment variables set for the commands in addition to `env`
+    #[serde(default, with = "pairs", skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<(String, String)>,
+    /// Directory the commands are run in instead of `working-dir`
+    pub working_dir: Option<PathBuf>,
+}
+
+/// Durations given as a number of seconds
+mod seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(duration) => s.serialize_some(&duration.as_secs()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(d)?.map(Duration::from_secs))
+    }
+}
+
+/// Values given in the same form as on the command line
+mod string {
+    use std::{fmt::Display, str::FromStr};
+
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &Option<T>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => s.collect_str(value),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr<Err: Display>,
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(d)?
+            .map(|value| value.parse().map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Lists of values given in the same form as on the command line
+mod strings {
+    use std::{fmt::Display, str::FromStr};
+
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<T: Display, S: Serializer>(values: &[T], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(values.iter().map(ToString::to_string))
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Vec<T>, D::Error>
+    where
+        T: FromStr<Err: Display>,
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|value| value.parse().map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+/// Key value pairs given as a table
+mod pairs {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        values: &[(String, T)],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_map(values.iter().map(|(key, value)| (key, value)))
+    }
+
+    pub fn deserialize<'de, T, D>(d: D) -> Result<Vec<(String, T)>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(BTreeMap::<String, T>::deserialize(d)?.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    const CONFIG: &str = r#"
+listen = ["0.0.0.0:25565", "unix:/run/portal.sock"]
+forward = ["127.0.0.1:25566"]
+start-command = "./start.sh"
+log-level = "debug"
+log-format = "json"
+hold = 30
+idle-timeout = 600
+env = { JAVA_OPTS = "-Xmx4G" }
+
+[route."creative.example.com"]
+forward = ["mc.example.com:25567"]
+start-command = "./creative.sh"
+motd = "Creative"
+"#;
+
+    /// Writes the config to a file and loads it with the overrides.
+    fn load(contents: &str, overrides: &Config) -> Result<Config, Error> {
+        let path = temp_dir("config").join("portal.toml");
+        fs::write(&path, contents).unwrap();
+        Config::load(Some(&path), overrides)
+    }
+
+    #[test]
+    fn reads_the_file() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            config.listen,
+            [
+                ListenAddress::Tcp("0.0.0.0:25565".parse().unwrap()),
+                ListenAddress::Unix("/run/portal.sock".into()),
+            ]
+        );
+        assert_eq!(
+            config.forward,
+            [BackendAddress::Socket("127.0.0.1:25566".parse().unwrap())]
+        );
+        assert_eq!(config.start_command.as_deref(), Some("./start.sh"));
+        assert_eq!(config.log_level, Some(Level::DEBUG));
+        assert_eq!(config.log_format, Some(LogFormat::Json));
+        assert_eq!(config.hold_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(config.env, [("JAVA_OPTS".to_owned(), "-Xmx4G".to_owned())]);
+
+        let [(host, route)] = &config.route[..] else {
+            panic!("expected one route, got {:?}", config.route);
+        };
+        assert_eq!(host, "creative.example.com");
+        assert_eq!(
+            route.forward,
+            [BackendAddress::Host {
+                host: "mc.example.com".to_owned(),
+                port: Some(25567),
+            }]
+        );
+        assert_eq!(route.start_command, "./creative.sh");
+        assert_eq!(route.motd.as_deref(), Some("Creative"));
+        assert_eq!(route.status, None);
+    }
+
+    #[test]
+    fn round_trips() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let serialized = toml::to_string(&config).unwrap();
+        let deserialized: Config = toml::from_str(&serialized).unwrap();
+        assert_eq!(to_table(&deserialized).unwrap(), to_table(&config).unwrap());
+        // Options that are not set are left out
+        assert!(!serialized.contains("status"));
+        assert!(!serialized.contains("online-mode"));
+    }
+
+    #[test]
+    fn applies_the_overrides_on_top_of_the_file() {
+        let overrides = Config {
+            start_command: Some("./other.sh".to_owned()),
+            env: vec![("EULA".to_owned(), "true".to_owned())],
+            ..Config::default()
+        };
+        let config = load(CONFIG, &overrides).unwrap();
+        assert_eq!(config.start_command.as_deref(), Some("./other.sh"));
+        assert_eq!(
+            config.forward,
+            [BackendAddress::Socket("127.0.0.1:25566".parse().unwrap())]
+        );
+        // Tables are merged
+        assert_eq!(
+            config.env,
+            [
+                ("EULA".to_owned(), "true".to_owned()),
+                ("JAVA_OPTS".to_owned(), "-Xmx4G".to_owned()),
+            ]
+        );
+        assert_eq!(config.route.len(), 1);
+    }
+
+    #[test]
+    fn names_missing_fields() {
+        let error = load(
+            "[route.\"creative.example.com\"]\nforward = [\"127.0.0.1:25567\"]\n",
+            &Config::default(),
+        )
+        .unwrap_err();
+        let message = error.to_string();
+        assert!(message.starts_with("invalid config "), "{}", message);
+        assert!(message.contains("portal.toml"), "{}", message);
+        assert!(
+            message.contains("missing field `start-command`"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let error = toml::from_str::<Config>("hold-timeout = 10").unwrap_err();
+        assert!(error.to_string().contains("unknown field `hold-timeout`"));
+    }
+
+    /// Validates a config file, returning the errors.
+    fn validate(contents: &str) -> Vec<String> {
+        let config: Config = toml::from_str(contents).unwrap();
+        config.validate().err().unwrap_or_default()
+    }
+
+    #[test]
+    fn accepts_a_valid_config() {
+        let errors = validate(
+            r#"
+listen = ["127.0.0.1:25565"]
+forward = ["127.0.0.1:25566"]
+start-command = "true"
+"#,
+        );
+        assert_eq!(errors, Vec::<String>::new());
+    }
+
+    #[test]
+    fn requires_the_core_options() {
+        assert_eq!(
+            validate(""),
+            [
+                "listen is required, set it in the config file or pass --listen",
+                "forward is required, set it in the config file or pass --forward",
+                "start-command is required, set it in the config file or pass --start-command",
+            ]
+        );
+        // Routes make the default route optional, but not half of it
+        let errors = validate(
+            r#"
+listen = ["127.0.0.1:25565"]
+start-command = "true"
+
+[route."a.example.com"]
+forward = ["127.0.0.1:25566"]
+start-command = "true"
+"#,
+        );
+        assert_eq!(
+            errors,
+            ["forward is required, set it in the config file or pass --forward"]
+        );
+    }
+
+    #[test]
+    fn reports_all_errors() {
+        let errors = validate(
+            r#"
+listen = ["127.0.0.1:25565"]
+forward = ["127.0.0.1:25566"]
+start-command = "./does-not-exist.sh"
+status = "/does/not/exist.json"
+hold = 0
+max-connections = 0
+
+[route."A.example.com"]
+forward = []
+start-command = ""
+
+[route."a.example.com."]
+forward = ["127.0.0.1:25567"]
+start-command = "true"
+"#,
+        );
+        assert_eq!(
+            errors,
+            [
+                "start-command: program ./does-not-exist.sh does not exist",
+                "status: /does/not/exist.json does not exist",
+                "route A.example.com: forward is required",
+                "route A.example.com: start-command: command is empty",
+                "route a.example.com.: conflicts with route A.example.com",
+                "hold must be at least one second",
+                "max-connections must be at least one",
+            ]
+        );
+
+        // The message of the regex error spans several lines
+        let errors = validate("listen = [\"127.0.0.1:25565\"]\nready-pattern = \"Done (\"");
+        assert!(
+            errors
+                .iter()
+                .any(|error| error.starts_with("ready-pattern: regex parse error")),
+            "{:?}",
+            errors
+        );
+    }
+}
@@ -0,0 +1,609 @@
+use std::{
+    process::Stdio,
+    sync::{
+        self, Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use tokio::{
+    process::Command,
+    sync::Mutex,
+    task::{self, JoinHandle},
+    time::{self, Instant, MissedTickBehavior, timeout},
+};
+use tracing::{Instrument, instrument};
+
+use crate::{
+    error::Error,
+    external_process::{DEFAULT_STOP_TIMEOUT, ProcessStatus, Spawn, StartStrategy, Trigger},
+    lifecycle::{self, Event},
+};
+
+/// The program that manages the containers
+pub const DOCKER: &str = "docker";
+
+/// How long `docker start` and `docker inspect` may take
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the state of a running container is inspected
+const INSPECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The state of the container, its exit code and its health if it has a health check
+const INSPECT_FORMAT: &str =
+    "{{.State.Status}} {{.State.ExitCode}} {{if .State.Health}}{{.State.Health.Status}}{{end}}";
+
+/// Runs `docker` commands. Tests replace it to script what Docker answers.
+pub trait CommandRunner: Send + Sync {
+    /// Runs `docker` with the arguments and environment variables and returns its standard
+    /// output, failing if it does not exit successfully within `duration`.
+    fn run<'a>(
+        &'a self,
+        args: &'a [&'a str],
+        env: &'a [(String, String)],
+        duration: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>>;
+}
+
+/// Runs the `docker` program found on the `PATH`
+pub struct DockerCli;
+
+impl CommandRunner for DockerCli {
+    fn run<'a>(
+        &'a self,
+        args: &'a [&'a str],
+        env: &'a [(String, String)],
+        duration: Duration,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            let mut command = Command::new(DOCKER);
+            command
+                .args(args)
+                .envs(env.iter().map(|(key, value)| (key, value)))
+                .stdin(Stdio::null())
+                .kill_on_drop(true);
+            let output = timeout(duration, command.output())
+                .await?
+                .map_err(Error::Spawn)?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(Error::Other(
+                    format!("docker {} failed: {}", args[0], stderr.trim()).into(),
+                ));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+    }
+}
+
+/// What Docker last said about the container
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Inspected {
+    status: ProcessStatus,
+    /// Whether the container is running and healthy, if it has a health check
+    ready: bool,
+}
+
+impl Inspected {
+    /// Parses the output of `docker inspect` with [`INSPECT_FORMAT`].
+    fn parse(output: &str) -> Result<Inspected, Error> {
+        let mut fields = output.split_whitespace();
+        let (Some(state), Some(exit_code)) = (fields.next(), fields.next()) else {
+            return Err(Error::Other(
+                format!("unexpected docker inspect output: {output:?}").into(),
+            ));
+        };
+        let health = fields.next();
+        let exit_code = exit_code.parse::<i32>().ok();
+        let status = match state {
+            "created" => ProcessStatus::NotStarted,
+            "restarting" => ProcessStatus::Starting,
+            "running" | "paused" => ProcessStatus::Running,
+            "exited" | "dead" => ProcessStatus::Exited(exit_code),
+            "removing" => ProcessStatus::Exited(None),
+            state => {
+                return Err(Error::Other(
+                    format!("unknown container state {state}").into(),
+                ));
+            }
+        };
+        Ok(Inspected {
+            status,
+            ready: state == "running" && health.is_none_or(|health| health == "healthy"),
+        })
+    }
+}
+
+/// The container and how to reach Docker, shared with the task watching the container
+struct Docker {
+    /// The name or id of the container
+    container: String,
+    /// What the backend is called in lifecycle events
+    name: String,
+    /// Environment variables set for the `docker` commands, e.g. `DOCKER_HOST`
+    env: Vec<(String, String)>,
+    runner: Box<dyn CommandRunner>,
+    inspected: sync::Mutex<Inspected>,
+}
+
+impl Docker {
+    async fn docker(&self, args: &[&str], duration: Duration) -> Result<String, Error> {
+        self.runner.run(args, &self.env, duration).await
+    }
+
+    /// Asks Docker for the state of the container and remembers it.
+    async fn inspect(&self) -> Result<Inspected, Error> {
+        let args = ["inspect", "--format", INSPECT_FORMAT, &self.container];
+        let inspected = match self.docker(&args, COMMAND_TIMEOUT).await {
+            Ok(output) => Inspected::parse(&output),
+            Err(error) => Err(error),
+        };
+        // A container that can not be inspected, e.g. because it was removed, can not be started
+        let inspected = inspected.inspect_err(|_| {
+            self.set_inspected(Inspected {
+                status: ProcessStatus::Failed,
+                ready: false,
+            })
+        })?;
+        self.set_inspected(inspected);
+        Ok(inspected)
+    }
+
+    fn set_inspected(&self, inspected: Inspected) {
+        *self.inspected.lock().unwrap() = inspected;
+    }
+
+    /// Inspects the container until it is not running anymore, so its status follows changes
+    /// made outside of the proxy.
+    async fn watch(&self) {
+        let container = &self.container;
+        let mut ticks = time::interval(INSPECT_INTERVAL);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            let status = match self.inspect().await {
+                Ok(inspected) => inspected.status,
+                Err(error) => {
+                    tracing::warn!(%container, %error, "Could not inspect container");
+                    return;
+                }
+            };
+            match status {
+                ProcessStatus::Starting | ProcessStatus::Running => {}
+                ProcessStatus::Exited(Some(0)) => {
+                    tracing::info!(%container, "Container stopped");
+                    lifecycle::emit(&self.name, Event::Stopped);
+                    return;
+                }
+                status => {
+                    tracing::warn!(%container, ?status, "Container exited");
+                    lifecycle::emit(&self.name, Event::Crashed);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// A backend that runs in an existing Docker container, which is started with `docker start` and
+/// stopped with `docker stop`. Its status is what `docker inspect` says, so it follows changes
+/// made outside of the proxy. It is ready while the container is running and healthy, after which
+/// players are forwarded once its port is reachable.
+pub struct DockerContainer {
+    docker: Arc<Docker>,
+    stop_timeout: Duration,
+    /// How long after a start attempt further attempts are ignored
+    start_cooldown: Duration,
+    /// When the container was last started, whether or not it succeeded
+    last_start: sync::Mutex<Option<Instant>>,
+    /// Whether the proxy started the container, so it is stopped when the proxy shuts down
+    started: AtomicBool,
+    /// The task inspecting the running container
+    watcher: sync::Mutex<Option<JoinHandle<()>>>,
+    /// Held while the container is started or stopped
+    lock: Mutex<()>,
+}
+
+impl DockerContainer {
+    pub fn new(container: String) -> DockerContainer {
+        DockerContainer {
+            docker: Arc::new(Docker {
+                name: container.clone(),
+                container,
+                env: Vec::new(),
+                runner: Box::new(DockerCli),
+                inspected: sync::Mutex::new(Inspected {
+                    status: ProcessStatus::NotStarted,
+                    ready: false,
+                }),
+            }),
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            start_cooldown: Duration::ZERO,
+            last_start: sync::Mutex::new(None),
+            started: AtomicBool::new(false),
+            watcher: sync::Mutex::new(None),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Only called while building the container, before the `Docker` is shared.
+    fn docker_mut(&mut self) -> &mut Docker {
+        Arc::get_mut(&mut self.docker).expect("Docker is not shared yet")
+    }
+
+    /// Sets what the backend is called in lifecycle events, which is the container otherwise.
+    pub fn with_name(mut self, name: &str) -> DockerContainer {
+        self.docker_mut().name = name.to_owned();
+        self
+    }
+
+    /// Sets an environment variable for the `docker` commands.
+    pub fn with_env(mut self, key: String, value: String) -> DockerContainer {
+        self.docker_mut().env.push((key, value));
+        self
+    }
+
+    /// Runs the `docker` commands with `runner` instead of the `docker` program.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn with_runner(mut self, runner: impl CommandRunner + 'static) -> DockerContainer {
+        self.docker_mut().runner = Box::new(runner);
+        self
+    }
+
+    /// Sets how long the container gets to exit after being asked to stop before Docker kills it.
+    pub fn with_stop_timeout(mut self, stop_timeout: Duration) -> DockerContainer {
+        self.stop_timeout = stop_timeout;
+        self
+    }
+
+    /// Ignores start attempts for `cooldown` after the container was started.
+    pub fn with_start_cooldown(mut self, cooldown: Duration) -> DockerContainer {
+        self.start_cooldown = cooldown;
+        self
+    }
+
+    /// Watches the running container unless it is watched already.
+    fn watch(&self) {
+        let mut watcher = self.watcher.lock().unwrap();
+        if watcher
+            .as_ref()
+            .is_some_and(|watcher| !watcher.is_finished())
+        {
+            return;
+        }
+        let docker = Arc::clone(&self.docker);
+        *watcher = Some(task::spawn(
+            async move { docker.watch().await }.in_current_span(),
+        ));
+    }
+
+    fn stop_watching(&self) {
+        if let Some(watcher) = self.watcher.lock().unwrap().take() {
+            watcher.abort();
+        }
+    }
+
+    /// Starts the container unless it is already running or was started within the cooldown.
+    #[instrument(skip_all)]
+    async fn start(&self) -> Result<Spawn, Error> {
+        let container = &self.docker.container;
+        // Stopping can take up to the stop timeout, which is not worth waiting for
+        if self.docker.inspected.lock().unwrap().status == ProcessStatus::Stopping {
+            tracing::debug!(%container, "Container is being stopped, not starting it");
+            return Ok(Spawn::Stopping);
+        }
+        let _lock = self.lock.lock().await;
+        let last_start = *self.last_start.lock().unwrap();
+        if last_start.is_some_and(|last_start| last_start.elapsed() < self.start_cooldown) {
+            tracing::debug!(%container, "Container was started recently, cooling down");
+            return Ok(Spawn::CoolingDown);
+        }
+        // The container may have been started or have exited outside of the proxy
+        if self.docker.inspect().await?.status == ProcessStatus::Running {
+            tracing::debug!(%container, "Container is already running");
+            self.watch();
+            return Ok(Spawn::Running);
+        }
+        *self.last_start.lock().unwrap() = Some(Instant::now());
+
+        lifecycle::emit(&self.docker.name, Event::StartRequested);
+        tracing::debug!(%container, "Starting container");
+        self.docker
+            .docker(&["start", container], COMMAND_TIMEOUT)
+            .await?;
+        lifecycle::emit(&self.docker.name, Event::StartSpawned);
+        self.started.store(true, Ordering::Relaxed);
+        self.docker.inspect().await?;
+        self.watch();
+        Ok(Spawn::Started)
+    }
+
+    /// Stops the container with `docker stop`, which kills it after the stop timeout.
+    #[instrument(skip_all)]
+    async fn stop_container(&self) -> Result<(), Error> {
+        let container = &self.docker.container;
+        let _lock = self.lock.lock().await;
+        tracing::debug!(%container, "Stopping container");
+        // The container stopping is reported here rather than by the watcher
+        self.stop_watching();
+        self.docker.set_inspected(Inspected {
+            status: ProcessStatus::Stopping,
+            ready: false,
+        });
+        let time = self.stop_timeout.as_secs().to_string();
+        // Docker waits for the stop timeout itself before killing the container
+        let duration = self.stop_timeout + COMMAND_TIMEOUT;
+        let stopped = self
+            .docker
+            .docker(&["stop", "--time", &time, container], duration)
+            .await;
+        // Even if stopping failed, the container is not left marked as stopping
+        let inspected = self.docker.inspect().await;
+        stopped?;
+        self.started.store(false, Ordering::Relaxed);
+        inspected?;
+        lifecycle::emit(&self.docker.name, Event::Stopped);
+        Ok(())
+    }
+}
+
+impl StartStrategy for DockerContainer {
+    fn spawn_once<'a>(&'a self, _trigger: &'a Trigger) -> BoxFuture<'a, Result<Spawn, Error>> {
+        Box::pin(self.start())
+    }
+
+    fn stop(&self) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(async move {
+            self.stop_container().await?;
+            Ok(true)
+        })
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            if self.started.load(Ordering::Relaxed) {
+                self.stop_container().await?;
+            }
+            self.stop_watching();
+            Ok(())
+        })
+    }
+
+    fn reset(&self) -> BoxFuture<'_, bool> {
+        // Docker restarts containers according to their own policy, so they never fail here
+        Box::pin(async { false })
+    }
+
+    fn status(&self) -> ProcessStatus {
+        match self.lock.try_lock() {
+            Ok(_) => self.docker.inspected.lock().unwrap().status,
+            // The lock is held while the container is started or stopped, only the latter is marked
+            Err(_) => match self.docker.inspected.lock().unwrap().status {
+                ProcessStatus::Stopping => ProcessStatus::Stopping,
+                _ => ProcessStatus::Starting,
+            },
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        self.docker.inspected.lock().unwrap().ready
+    }
+}
+
+impl Drop for DockerContainer {
+    fn drop(&mut self) {
+        self.stop_watching();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    /// Answers like Docker for a single container, whose inspected state the test can change
+    #[derive(Clone, Default)]
+    struct MockDocker {
+        /// What `docker inspect` prints, or `None` if the container does not exist
+        state: Arc<sync::Mutex<Option<String>>>,
+        /// The commands that were run, without their arguments
+        calls: Arc<sync::Mutex<Vec<String>>>,
+        /// How long `docker stop` takes
+        stop_delay: Duration,
+    }
+
+    impl MockDocker {
+        fn with_state(state: &str) -> MockDocker {
+            let docker = MockDocker::default();
+            docker.set_state(state);
+            docker
+        }
+
+        fn set_state(&self, state: &str) {
+            *self.state.lock().unwrap() = Some(state.to_owned());
+        }
+
+        fn calls(&self, command: &str) -> usize {
+            let calls = self.calls.lock().unwrap();
+            calls.iter().filter(|call| *call == command).count()
+        }
+    }
+
+    impl CommandRunner for MockDocker {
+        fn run<'a>(
+            &'a self,
+            args: &'a [&'a str],
+            _env: &'a [(String, String)],
+            _duration: Duration,
+        ) -> BoxFuture<'a, Result<String, Error>> {
+            Box::pin(async move {
+                self.calls.lock().unwrap().push(args[0].to_owned());
+                if args[0] == "stop" {
+                    time::sleep(self.stop_delay).await;
+                }
+                let mut state = self.state.lock().unwrap();
+                if state.is_none() {
+                    return Err("docker failed: No such container".into());
+                }
+                match args[0] {
+                    "start" => *state = Some("running 0 ".to_owned()),
+                    "stop" => *state = Some("exited 0 ".to_owned()),
+                    _ => {}
+                }
+                Ok(format!("{}\n", state.as_deref().unwrap()))
+            })
+        }
+    }
+
+    fn trigger() -> Trigger {
+        Trigger {
+            host: "mc.example.com".to_owned(),
+            port: 25565,
+            client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            waiting: 1,
+        }
+    }
+
+    fn container(docker: &MockDocker) -> DockerContainer {
+        DockerContainer::new("minecraft".to_owned()).with_runner(docker.clone())
+    }
+
+    #[test]
+    fn parses_the_inspected_state() {
+        let parse = |output| Inspected::parse(output).unwrap();
+        assert_eq!(
+            parse("running 0 \n"),
+            Inspected {
+                status: ProcessStatus::Running,
+                ready: true
+            }
+        );
+        assert!(!parse("running 0 starting").ready);
+        assert!(parse("running 0 healthy").ready);
+        assert!(!parse("paused 0").ready);
+        assert_eq!(parse("exited 137").status, ProcessStatus::Exited(Some(137)));
+        assert_eq!(parse("created 0").status, ProcessStatus::NotStarted);
+        assert_eq!(parse("restarting 1").status, ProcessStatus::Starting);
+        assert!(Inspected::parse("").is_err());
+        assert!(Inspected::parse("sleeping 0").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn starts_and_stops_the_container() {
+        let docker = MockDocker::with_state("exited 0 ");
+        let container = container(&docker);
+        assert!(!container.is_ready());
+
+        assert!(matches!(
+            container.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        ));
+        assert_eq!(docker.calls("start"), 1);
+        assert_eq!(container.status(), ProcessStatus::Running);
+        assert!(container.is_ready());
+
+        assert!(container.stop().await.unwrap());
+        assert_eq!(docker.calls("stop"), 1);
+        assert_eq!(container.status(), ProcessStatus::Exited(Some(0)));
+        assert!(!container.is_ready());
+        // It was already stopped, so shutting down has nothing left to do
+        container.shutdown().await.unwrap();
+        assert_eq!(docker.calls("stop"), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_wait_for_a_stop_to_start_again() {
+        let docker = MockDocker {
+            stop_delay: Duration::from_secs(10),
+            ..MockDocker::with_state("exited 0 ")
+        };
+        let container = Arc::new(container(&docker));
+        container.spawn_once(&trigger()).await.unwrap();
+
+        let stop = task::spawn({
+            let container = Arc::clone(&container);
+            async move { container.stop().await }
+        });
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(container.status(), ProcessStatus::Stopping);
+        assert!(!container.is_ready());
+        assert!(matches!(
+            container.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Stopping
+        ));
+        assert_eq!(docker.calls("start"), 1);
+
+        assert!(stop.await.unwrap().unwrap());
+        assert_eq!(container.status(), ProcessStatus::Exited(Some(0)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaves_a_container_running_that_was_started_outside() {
+        let docker = MockDocker::with_state("running 0 ");
+        let container = container(&docker);
+
+        assert!(matches!(
+            container.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Running
+        ));
+        assert_eq!(docker.calls("start"), 0);
+        assert!(container.is_ready());
+        container.shutdown().await.unwrap();
+        assert_eq!(docker.calls("stop"), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn does_not_start_again_during_the_cooldown() {
+        let docker = MockDocker::with_state("exited 0 ");
+        let container = container(&docker).with_start_cooldown(Duration::from_secs(60));
+
+        container.spawn_once(&trigger()).await.unwrap();
+        docker.set_state("exited 1 ");
+        assert!(matches!(
+            container.spawn_once(&trigger()).await.unwrap(),
+            Spawn::CoolingDown
+        ));
+        assert_eq!(docker.calls("start"), 1);
+
+        time::advance(Duration::from_secs(60)).await;
+        assert!(matches!(
+            container.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        ));
+        assert_eq!(docker.calls("start"), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_a_container_that_exited_outside() {
+        let docker = MockDocker::with_state("exited 0 ");
+        let container = container(&docker);
+        container.spawn_once(&trigger()).await.unwrap();
+        assert!(container.is_ready());
+
+        // Killed without the proxy knowing, e.g. by `docker kill`
+        docker.set_state("exited 137 ");
+        time::sleep(INSPECT_INTERVAL * 2).await;
+        assert_eq!(container.status(), ProcessStatus::Exited(Some(137)));
+        assert!(!container.is_ready());
+
+        assert!(matches!(
+            container.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        ));
+        assert_eq!(docker.calls("start"), 2);
+        assert!(container.is_ready());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_for_a_missing_container() {
+        let docker = MockDocker::default();
+        let container = container(&docker);
+
+        assert!(container.spawn_once(&trigger()).await.is_err());
+        assert_eq!(container.status(), ProcessStatus::Failed);
+        assert!(!container.is_ready());
+    }
+}
@@ -6,10 +6,16 @@ use std::{
 
 use tokio::time::error::Elapsed;
 
+use crate::protocol::ProtocolError;
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
     Timeout,
+    /// A peer sent data that does not follow the protocol
+    Protocol(ProtocolError),
+    /// A command could not be spawned, e.g. because its program does not exist
+    Spawn(io::Error),
     // TODO: Eventually replace all others with dedicated errors
     Other(Box<dyn StdError + Send + Sync + 'static>),
 }
@@ -20,6 +26,16 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<ProtocolError> for Error {
+    fn from(value: ProtocolError) -> Self {
+        // Io errors concern the connection rather than the protocol
+        match value {
+            ProtocolError::Io(error) => Error::Io(error),
+            error => Error::Protocol(error),
+        }
+    }
+}
+
 impl From<Elapsed> for Error {
     fn from(_: Elapsed) -> Self {
         Error::Timeout
@@ -31,6 +47,8 @@ impl Display for Error {
         match self {
             Error::Io(error) => write!(f, "io error: {}", error),
             Error::Timeout => write!(f, "timeout"),
+            Error::Protocol(error) => write!(f, "protocol error: {}", error),
+            Error::Spawn(error) => write!(f, "could not spawn process: {}", error),
             Error::Other(error) => write!(f, "{}", error),
         }
     }
@@ -41,6 +59,8 @@ impl StdError for Error {
         match self {
             Error::Io(error) => Some(error),
             Error::Timeout => None,
+            Error::Protocol(error) => Some(error),
+            Error::Spawn(error) => Some(error),
             Error::Other(error) => Some(error.as_ref()),
         }
     }
@@ -51,3 +71,35 @@ impl From<&str> for Error {
         Error::Other(value.into())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProtocolState;
+
+    #[test]
+    fn names_the_state_and_id_of_unknown_packets() {
+        let error = Error::from(ProtocolError::UnknownPacket {
+            state: ProtocolState::Login,
+            id: 0x2a,
+        });
+        assert!(matches!(error, Error::Protocol(_)));
+        assert_eq!(
+            error.to_string(),
+            "protocol error: unknown packet id 0x2a in state login"
+        );
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn keeps_io_errors_apart_from_protocol_errors() {
+        let error = Error::from(ProtocolError::Io(io::ErrorKind::UnexpectedEof.into()));
+        assert!(matches!(error, Error::Io(_)));
+
+        let error = Error::Spawn(io::ErrorKind::NotFound.into());
+        assert_eq!(
+            error.to_string(),
+            "could not spawn process: entity not found"
+        );
+    }
+}
@@ -1,61 +1,1579 @@
+use std::{
+    collections::VecDeque,
+    env, iter,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::{
+        self, Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use tokio::{
-    process::Command,
-    sync::Mutex,
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader},
+    process::{Child, Command},
+    sync::{Mutex, MutexGuard},
     task::{self, JoinHandle},
+    time::{self, Instant, timeout},
 };
 use tracing::{Instrument, instrument};
 
-use crate::error::Error;
+use crate::{
+    error::Error,
+    lifecycle::{self, Event},
+};
+
+/// How long the process gets to exit after being asked to stop before it is killed
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The delay before the first restart, which doubles with every recent restart
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_RESTARTS: u32 = 5;
+pub const DEFAULT_RESTART_WINDOW: Duration = Duration::from_secs(60);
+/// The placeholders that are replaced in the start command, see [`Trigger::placeholder`]
+const PLACEHOLDERS: [&str; 3] = ["host", "port", "client_ip"];
+/// Longer lines of output are logged in several parts
+const MAX_LINE_LENGTH: u64 = 8192;
 
 pub struct ExternalProcess {
+    /// What the process is called in lifecycle events
+    name: Arc<str>,
+    start: Arc<CommandLine>,
+    /// Asks the process to exit gracefully, otherwise it is sent `SIGTERM`
+    stop: Option<CommandLine>,
+    /// Run before the process is started, which is aborted if this fails
+    pre_start: Option<CommandLine>,
+    /// Run once the process exited and is not restarted
+    post_stop: Option<Arc<CommandLine>>,
+    environment: Arc<Environment>,
+    stop_timeout: Duration,
+    restart: RestartPolicy,
+    /// How long after a start attempt further attempts are ignored
+    start_cooldown: Duration,
+    /// A line of output that shows the process is ready, before which it is not connected to
+    ready_pattern: Option<Arc<Regex>>,
+    /// When the start command was last run, whether or not it succeeded
+    last_start: sync::Mutex<Option<Instant>>,
+    state: Mutex<Option<Running>>,
+    /// The state shared with the last spawned process, which can be read while `state` is locked
+    shared: sync::Mutex<Option<Arc<RunningShared>>>,
+}
+
+/// The connection that caused the process to be started, which the start command is told about
+/// through environment variables
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    /// The host name the client connected through
+    pub host: String,
+    /// The port the client connected through, as sent in its handshake
+    pub port: u16,
+    pub client_ip: IpAddr,
+    /// The number of players waiting for the process, including the client if it wants to join
+    pub waiting: usize,
+}
+
+impl Trigger {
+    fn env_vars(&self) -> [(String, String); 4] {
+        [
+            ("PORTAL_REQUEST_HOST".to_owned(), self.host.clone()),
+            ("PORTAL_REQUEST_PORT".to_owned(), self.port.to_string()),
+            ("PORTAL_CLIENT_IP".to_owned(), self.client_ip.to_string()),
+            ("PORTAL_WAITING".to_owned(), self.waiting.to_string()),
+        ]
+    }
+
+    /// Returns the value of a placeholder in the start command, or `None` if it is unknown.
+    fn placeholder(&self, name: &str) -> Option<String> {
+        match name {
+            "host" => Some(self.host.clone()),
+            "port" => Some(self.port.to_string()),
+            "client_ip" => Some(self.client_ip.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of [`ExternalProcess::spawn_once`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spawn {
+    /// The start command was run
+    Started,
+    /// The previous process is still running
+    Running,
+    /// The process failed and is not started until it is reset
+    Failed,
+    /// The start command was run too recently to be run again
+    CoolingDown,
+    /// The process is being stopped and is not started again until it exited
+    Stopping,
+}
+
+/// Starts and stops a backend. This is a trait so backends can be run other ways than as a child
+/// process of the proxy, e.g. as a Docker container.
+pub trait StartStrategy: Send + Sync {
+    /// Starts the backend unless it is already running or should not be started right now.
+    fn spawn_once<'a>(&'a self, trigger: &'a Trigger) -> BoxFuture<'a, Result<Spawn, Error>>;
+    /// Stops the backend. Returns whether anything was done.
+    fn stop(&self) -> BoxFuture<'_, Result<bool, Error>>;
+    /// Stops the backend if it was started by the proxy, e.g. because the proxy shuts down.
+    fn shutdown(&self) -> BoxFuture<'_, Result<(), Error>>;
+    /// Forgets that the backend failed, so it can be started again. Returns whether it had failed.
+    fn reset(&self) -> BoxFuture<'_, bool>;
+    /// Returns what the backend is currently doing without waiting for it.
+    fn status(&self) -> ProcessStatus;
+    /// Returns whether the backend is ready to be connected to once it is reachable.
+    fn is_ready(&self) -> bool;
+
+    /// Returns whether the backend is currently running, which includes starting and stopping.
+    fn is_running(&self) -> bool {
+        matches!(
+            self.status(),
+            ProcessStatus::Starting | ProcessStatus::Running | ProcessStatus::Stopping
+        )
+    }
+}
+
+/// How backends are started
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// The start command is run as a child process of the proxy
+    Command,
+    /// The start command names a Docker container, which is started and stopped
+    Docker,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(Strategy::Command),
+            "docker" => Ok(Strategy::Docker),
+            _ => Err("start strategy must be one of command or docker".into()),
+        }
+    }
+}
+
+/// Whether the process is restarted when it exits without being stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    Never,
+    /// Only restart the process if it exited with an error
+    OnFailure,
+    Always,
+}
+
+impl FromStr for Restart {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Restart::Never),
+            "on-failure" => Ok(Restart::OnFailure),
+            "always" => Ok(Restart::Always),
+            _ => Err("restart policy must be one of never, on-failure or always".into()),
+        }
+    }
+}
+
+/// What the process is currently doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessStatus {
+    NotStarted,
+    /// The process is being spawned or waiting to be restarted
+    Starting,
+    Running,
+    /// The process was asked to stop and has not exited yet
+    Stopping,
+    /// The process exited with the given exit code, which is `None` if it was killed by a signal
+    Exited(Option<i32>),
+    /// The process exited too often to be restarted again and is not started until it is reset
+    Failed,
+}
+
+/// When and how often the process is restarted
+#[derive(Debug, Clone, Copy)]
+struct RestartPolicy {
+    when: Restart,
+    /// The number of restarts within the window after which the process is given up on
+    max_restarts: u32,
+    window: Duration,
+}
+
+/// A command line split into the program and its arguments
+struct CommandLine {
+    /// The command as given, used for logging
     command: String,
-    state: Mutex<Option<JoinHandle<()>>>,
+    program: String,
+    args: Vec<String>,
+}
+
+/// The environment both the start and the stop command are run in
+#[derive(Debug, Clone, Default)]
+struct Environment {
+    /// Whether the variables of the proxy's environment are not passed on
+    clear: bool,
+    vars: Vec<(String, String)>,
+    /// Commands are run in the proxy's working directory if this is not set
+    current_dir: Option<PathBuf>,
+}
+
+/// Watches the output of a process for the line that shows it is ready
+#[derive(Clone)]
+struct ReadyWatch {
+    pattern: Arc<Regex>,
+    shared: Arc<RunningShared>,
+}
+
+/// A spawned process along with the task that waits for it to exit and restarts it if necessary
+struct Running {
+    task: JoinHandle<()>,
+    shared: Arc<RunningShared>,
+}
+
+/// The state shared between a [`Running`] process and the task supervising it
+struct RunningShared {
+    /// What the process is called in lifecycle events
+    name: Arc<str>,
+    /// The id of the current process, zero if it is unknown
+    pid: AtomicU32,
+    /// Set once the process is being stopped, so it is not restarted
+    stopping: AtomicBool,
+    /// Set once the process logged a line matching the ready pattern, reset when it is restarted
+    ready: AtomicBool,
+    status: sync::Mutex<ProcessStatus>,
 }
 
 impl ExternalProcess {
-    pub fn new(command: String) -> ExternalProcess {
-        ExternalProcess {
-            command,
+    /// Creates a process from a command line, which is split into the program and its arguments
+    /// like a shell would do it. The `{host}`, `{port}` and `{client_ip}` placeholders in the words
+    /// are replaced with the values of the trigger each time the process is started, `{{` and `}}`
+    /// stand for literal braces.
+    pub fn new(command: String) -> Result<ExternalProcess, Error> {
+        let start = CommandLine::parse(command)?;
+        for word in iter::once(&start.program).chain(&start.args) {
+            substitute(word, |name| {
+                if !PLACEHOLDERS.contains(&name) {
+                    tracing::warn!(
+                        command = %start.command,
+                        placeholder = name,
+                        "Unknown placeholder in the start command is left as it is"
+                    );
+                }
+                None
+            });
+        }
+        Ok(ExternalProcess {
+            name: Arc::from(start.command.as_str()),
+            start: Arc::new(start),
+            stop: None,
+            pre_start: None,
+            post_stop: None,
+            environment: Arc::new(Environment::default()),
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            restart: RestartPolicy {
+                when: Restart::Never,
+                max_restarts: 0,
+                window: DEFAULT_RESTART_WINDOW,
+            },
+            start_cooldown: Duration::ZERO,
+            ready_pattern: None,
+            last_start: sync::Mutex::new(None),
             state: Mutex::new(None),
+            shared: sync::Mutex::new(None),
+        })
+    }
+
+    /// Sets what the process is called in lifecycle events, which is its start command otherwise.
+    pub fn with_name(mut self, name: &str) -> ExternalProcess {
+        self.name = Arc::from(name);
+        self
+    }
+
+    /// Sets a command that is run to stop the process gracefully.
+    pub fn with_stop_command(mut self, command: String) -> Result<ExternalProcess, Error> {
+        self.stop = Some(CommandLine::parse(command)?);
+        Ok(self)
+    }
+
+    /// Sets a command that is run before the process is started.
+    /// The process is not started if the command fails.
+    pub fn with_pre_start_command(mut self, command: String) -> Result<ExternalProcess, Error> {
+        self.pre_start = Some(CommandLine::parse(command)?);
+        Ok(self)
+    }
+
+    /// Sets a command that is run after the process exited, unless it is restarted.
+    pub fn with_post_stop_command(mut self, command: String) -> Result<ExternalProcess, Error> {
+        self.post_stop = Some(Arc::new(CommandLine::parse(command)?));
+        Ok(self)
+    }
+
+    /// Sets an environment variable for the commands.
+    pub fn with_env(mut self, key: String, value: String) -> ExternalProcess {
+        Arc::make_mut(&mut self.environment).vars.push((key, value));
+        self
+    }
+
+    /// Keeps the commands from inheriting the proxy's environment variables.
+    /// Only variables set with [`ExternalProcess::with_env`] are passed on.
+    pub fn with_cleared_env(mut self) -> ExternalProcess {
+        Arc::make_mut(&mut self.environment).clear = true;
+        self
+    }
+
+    /// Sets the working directory for the commands.
+    pub fn with_current_dir(mut self, path: PathBuf) -> ExternalProcess {
+        Arc::make_mut(&mut self.environment).current_dir = Some(path);
+        self
+    }
+
+    /// Sets how long the process gets to exit after being asked to stop before it is killed.
+    pub fn with_stop_timeout(mut self, stop_timeout: Duration) -> ExternalProcess {
+        self.stop_timeout = stop_timeout;
+        self
+    }
+
+    /// Restarts the process when it exits on its own, up to `max_restarts` times within `window`.
+    /// The process is considered failed if it exits more often than that.
+    pub fn with_restart(
+        mut self,
+        restart: Restart,
+        max_restarts: u32,
+        window: Duration,
+    ) -> ExternalProcess {
+        self.restart = RestartPolicy {
+            when: restart,
+            max_restarts,
+            window,
+        };
+        self
+    }
+
+    /// Ignores start attempts for `cooldown` after the start command was run, so clients that keep
+    /// reconnecting do not run an expensive or failing command over and over.
+    pub fn with_start_cooldown(mut self, cooldown: Duration) -> ExternalProcess {
+        self.start_cooldown = cooldown;
+        self
+    }
+
+    /// Only considers the process ready once a line of its output matches `pattern`, e.g. once a
+    /// server logged that it is done loading rather than as soon as its port is open.
+    pub fn with_ready_pattern(mut self, pattern: Regex) -> ExternalProcess {
+        self.ready_pattern = Some(Arc::new(pattern));
+        self
+    }
+
+    /// Returns whether the process is ready according to the ready pattern.
+    /// Without a pattern, and once the process is not running anymore, there is nothing to wait
+    /// for and it always counts as ready.
+    pub fn is_ready(&self) -> bool {
+        if self.ready_pattern.is_none() {
+            return true;
+        }
+        match self.state.try_lock() {
+            Ok(state) => state
+                .as_ref()
+                .is_none_or(|running| match running.shared.status() {
+                    ProcessStatus::Starting | ProcessStatus::Running => {
+                        running.shared.ready.load(Ordering::Relaxed)
+                    }
+                    ProcessStatus::Stopping => false,
+                    _ => true,
+                }),
+            // The lock is held while the process is spawned or stopped
+            Err(_) => false,
         }
     }
 
+    /// Returns what the process is currently doing without waiting for it.
+    pub fn status(&self) -> ProcessStatus {
+        match self.state.try_lock() {
+            Ok(state) => match state.as_ref() {
+                Some(running) => running.shared.status(),
+                None => ProcessStatus::NotStarted,
+            },
+            // The lock is held while the process is spawned or stopped, only the latter is marked
+            Err(_) => match self.stopping() {
+                true => ProcessStatus::Stopping,
+                false => ProcessStatus::Starting,
+            },
+        }
+    }
+
+    /// Returns whether the last spawned process is being stopped.
+    fn stopping(&self) -> bool {
+        self.shared
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|shared| shared.status() == ProcessStatus::Stopping)
+    }
+
+    /// Forgets that the process failed, so it can be started again.
+    /// Returns whether the process had failed.
+    pub async fn reset(&self) -> bool {
+        let mut lock = self.state.lock().await;
+        lock.take_if(|running| {
+            running.task.is_finished()
+                && *running.shared.status.lock().unwrap() == ProcessStatus::Failed
+        })
+        .is_some()
+    }
+
+    /// Runs the start command unless the process is still running, has failed, or was started
+    /// within the start cooldown. The pre-start and start command, as well as restarts, get
+    /// variables describing the trigger in their environment.
     #[instrument(skip_all)]
-    pub async fn spawn_once(&self) -> Result<bool, Error> {
+    pub async fn spawn_once(&self, trigger: &Trigger) -> Result<Spawn, Error> {
+        let command = &self.start.command;
+        // Stopping can take up to the stop timeout, which is not worth waiting for
+        if self.stopping() {
+            tracing::debug!(%command, "External process is being stopped, not starting it");
+            return Ok(Spawn::Stopping);
+        }
         let mut lock = self.state.lock().await;
-        if let Some(task) = lock.as_mut() {
-            if !task.is_finished() {
-                tracing::debug!(command = %&self.command, "Previous child process is still running");
-                return Ok(false);
-            }
-            task.await.expect("Panic in external process task");
-            tracing::debug!(command = %&self.command, "Previous child process finished");
-        }
-
-        let mut process = Command::new(&self.command).kill_on_drop(true).spawn()?;
-        tracing::debug!(command = %&self.command, pid = process.id(), "External process created");
-        *lock = Some(task::spawn(
-            async move {
-                match process.wait().await {
-                    // TODO: Can we somehow get a reference to self.command in here for tracing?
-                    //  Is there a joining join handle that blocks on drop?
-                    Ok(status) => {
-                        tracing::debug!(status = status.code(), "External process finished")
-                    }
-                    Err(error) => tracing::debug!(%error, "Error waiting for external process"),
-                }
+        if let Some(running) = lock.as_mut() {
+            if !running.task.is_finished() {
+                tracing::debug!(%command, "Previous child process is still running");
+                return Ok(Spawn::Running);
+            }
+            if *running.shared.status.lock().unwrap() == ProcessStatus::Failed {
+                tracing::debug!(%command, "External process failed, not starting it until reset");
+                return Ok(Spawn::Failed);
+            }
+        }
+        {
+            let mut last_start = self.last_start.lock().unwrap();
+            if last_start.is_some_and(|last_start| last_start.elapsed() < self.start_cooldown) {
+                tracing::debug!(%command, "External process was started recently, cooling down");
+                return Ok(Spawn::CoolingDown);
             }
+            *last_start = Some(Instant::now());
+        }
+        if let Some(running) = lock.as_mut() {
+            (&mut running.task)
+                .await
+                .expect("Panic in external process task");
+            tracing::debug!(%command, "Previous child process finished");
+        }
+        lifecycle::emit(&self.name, Event::StartRequested);
+
+        let start = Arc::new(self.start.substitute(trigger)?);
+        let mut environment = Environment::clone(&self.environment);
+        environment.vars.extend(trigger.env_vars());
+        let environment = Arc::new(environment);
+        if let Some(pre_start) = &self.pre_start {
+            tracing::debug!(command = %pre_start.command, "Running pre-start command");
+            pre_start.run(&environment).await?;
+        }
+        let shared = Arc::new(RunningShared {
+            name: Arc::clone(&self.name),
+            pid: AtomicU32::new(0),
+            stopping: AtomicBool::new(false),
+            ready: AtomicBool::new(false),
+            status: sync::Mutex::new(ProcessStatus::Running),
+        });
+        let ready = self.ready_watch(&shared);
+        let process = start.spawn(&environment, ready.clone())?;
+        tracing::debug!(command = %start.command, pid = process.id(), "External process created");
+        lifecycle::emit(&self.name, Event::StartSpawned);
+        shared
+            .pid
+            .store(process.id().unwrap_or(0), Ordering::Relaxed);
+        let task = task::spawn(
+            supervise(
+                process,
+                start,
+                environment,
+                self.restart,
+                ready,
+                self.post_stop.clone(),
+                Arc::clone(&shared),
+            )
             .in_current_span(),
-        ));
+        );
+        *self.shared.lock().unwrap() = Some(Arc::clone(&shared));
+        *lock = Some(Running { task, shared });
+
+        Ok(Spawn::Started)
+    }
+
+    /// Returns what the output of a process has to be watched for, if anything.
+    fn ready_watch(&self, shared: &Arc<RunningShared>) -> Option<ReadyWatch> {
+        self.ready_pattern.as_ref().map(|pattern| ReadyWatch {
+            pattern: Arc::clone(pattern),
+            shared: Arc::clone(shared),
+        })
+    }
 
+    /// Stops the process by running the stop command, or by sending it `SIGTERM` if there is none.
+    /// A process that does not exit within the stop timeout is killed.
+    /// Returns whether anything was done, which is not the case if there is neither a stop
+    /// command nor a running process.
+    #[instrument(skip_all)]
+    pub async fn stop(&self) -> Result<bool, Error> {
+        let lock = self.state.lock().await;
+        let running = lock.as_ref().filter(|running| !running.task.is_finished());
+        if running.is_none() && self.stop.is_none() {
+            return Ok(false);
+        }
+        self.terminate(lock).await?;
         Ok(true)
     }
+
+    /// Stops the process if it was started by this proxy, e.g. because the proxy shuts down.
+    /// Unlike [`ExternalProcess::stop`], this never runs the stop command for a backend that was
+    /// started some other way.
+    #[instrument(skip_all)]
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let lock = self.state.lock().await;
+        if lock
+            .as_ref()
+            .is_some_and(|running| !running.task.is_finished())
+        {
+            self.terminate(lock).await?;
+        }
+        Ok(())
+    }
+
+    /// Asks the process to exit and waits for it for the stop timeout, then kills it.
+    async fn terminate(&self, mut lock: MutexGuard<'_, Option<Running>>) -> Result<(), Error> {
+        let running = lock.as_ref().filter(|running| !running.task.is_finished());
+        // A process that already exited on its own is finished up by its supervising task
+        let exited =
+            running.is_some_and(|running| running.shared.stopping.swap(true, Ordering::Relaxed));
+        let pid = running
+            .filter(|_| !exited)
+            .and_then(|running| running.shared.pid());
+
+        match (&self.stop, pid) {
+            (Some(stop), _) => {
+                tracing::debug!(command = %stop.command, "Running stop command");
+                let status = timeout(
+                    self.stop_timeout,
+                    stop.to_command(&self.environment)?
+                        .kill_on_drop(true)
+                        .status(),
+                )
+                .await;
+                match status {
+                    Ok(status) => tracing::debug!(status = status?.code(), "Stop command finished"),
+                    Err(_) => tracing::warn!(command = %stop.command, "Stop command timed out"),
+                }
+                // Otherwise the process reports that it stopped once it exits
+                if running.is_none() {
+                    lifecycle::emit(&self.name, Event::Stopped);
+                }
+            }
+            (None, Some(pid)) => {
+                tracing::debug!(command = %self.start.command, %pid, "Terminating external process");
+                signal::kill(pid, Signal::SIGTERM).map_err(|error| Error::Other(error.into()))?;
+            }
+            (None, None) => {}
+        }
+
+        // A task that was waited for can not be polled again, so it is removed along with its state
+        if let Some(mut running) = lock.take_if(|running| !running.task.is_finished())
+            && timeout(self.stop_timeout, &mut running.task).await.is_err()
+        {
+            tracing::warn!(command = %self.start.command, "External process did not stop in time, killing it");
+            // The task reaps the killed process. Aborting it also kills the process because the
+            // child is dropped, which is all that can be done if its id is unknown.
+            match running.shared.pid() {
+                Some(pid) => {
+                    if let Err(error) = signal::kill(pid, Signal::SIGKILL) {
+                        tracing::warn!(%pid, %error, "Could not kill external process");
+                        running.task.abort();
+                    }
+                }
+                None => running.task.abort(),
+            }
+            if (&mut running.task).await.is_err() {
+                running.shared.set_status(ProcessStatus::Exited(None));
+                lifecycle::emit(&self.name, Event::Stopped);
+            }
+        }
+
+        if !exited && let Some(post_stop) = &self.post_stop {
+            run_post_stop(post_stop, &self.environment).await;
+        }
+        Ok(())
+    }
+}
+
+impl StartStrategy for ExternalProcess {
+    fn spawn_once<'a>(&'a self, trigger: &'a Trigger) -> BoxFuture<'a, Result<Spawn, Error>> {
+        Box::pin(ExternalProcess::spawn_once(self, trigger))
+    }
+
+    fn stop(&self) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(ExternalProcess::stop(self))
+    }
+
+    fn shutdown(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(ExternalProcess::shutdown(self))
+    }
+
+    fn reset(&self) -> BoxFuture<'_, bool> {
+        Box::pin(ExternalProcess::reset(self))
+    }
+
+    fn status(&self) -> ProcessStatus {
+        ExternalProcess::status(self)
+    }
+
+    fn is_ready(&self) -> bool {
+        ExternalProcess::is_ready(self)
+    }
+}
+
+impl RunningShared {
+    /// Returns the id of the current process if it is known.
+    fn pid(&self) -> Option<Pid> {
+        match self.pid.load(Ordering::Relaxed) {
+            0 => None,
+            pid => i32::try_from(pid).ok().map(Pid::from_raw),
+        }
+    }
+
+    /// Returns what the process is doing. A process that is still running once it is being
+    /// stopped counts as stopping.
+    fn status(&self) -> ProcessStatus {
+        match *self.status.lock().unwrap() {
+            ProcessStatus::Starting | ProcessStatus::Running
+                if self.stopping.load(Ordering::Relaxed) =>
+            {
+                ProcessStatus::Stopping
+            }
+            status => status,
+        }
+    }
+
+    fn set_status(&self, status: ProcessStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+}
+
+/// Waits for the process to exit and restarts it according to the restart policy.
+/// Runs the post-stop command once the process is not restarted anymore.
+async fn supervise(
+    process: Child,
+    start: Arc<CommandLine>,
+    environment: Arc<Environment>,
+    restart: RestartPolicy,
+    ready: Option<ReadyWatch>,
+    post_stop: Option<Arc<CommandLine>>,
+    shared: Arc<RunningShared>,
+) {
+    restart_on_exit(process, &start, &environment, restart, ready, &shared).await;
+
+    // A process that is being stopped is finished up by whoever stops it
+    if !shared.stopping.swap(true, Ordering::Relaxed)
+        && let Some(post_stop) = post_stop
+    {
+        run_post_stop(&post_stop, &environment).await;
+    }
+}
+
+/// Restarts the process according to the restart policy until it is stopped or not restarted.
+async fn restart_on_exit(
+    mut process: Child,
+    start: &CommandLine,
+    environment: &Environment,
+    restart: RestartPolicy,
+    ready: Option<ReadyWatch>,
+    shared: &RunningShared,
+) {
+    let command = &start.command;
+    // The times of the restarts within the window
+    let mut restarts = VecDeque::new();
+    loop {
+        let (success, code) = match process.wait().await {
+            Ok(status) => {
+                tracing::debug!(%command, status = status.code(), "External process finished");
+                (status.success(), status.code())
+            }
+            Err(error) => {
+                tracing::debug!(%command, %error, "Error waiting for external process");
+                (false, None)
+            }
+        };
+        shared.set_status(ProcessStatus::Exited(code));
+        let event = match success || shared.stopping.load(Ordering::Relaxed) {
+            true => Event::Stopped,
+            false => Event::Crashed,
+        };
+        lifecycle::emit(&shared.name, event);
+
+        let wanted = match restart.when {
+            Restart::Never => false,
+            Restart::OnFailure => !success,
+            Restart::Always => true,
+        };
+        if !wanted || shared.stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        while restarts
+            .front()
+            .is_some_and(|restarted: &Instant| restarted.elapsed() >= restart.window)
+        {
+            restarts.pop_front();
+        }
+        let recent = restarts.len() as u32;
+        if recent >= restart.max_restarts {
+            tracing::error!(
+                %command,
+                restarts = recent,
+                window = ?restart.window,
+                "External process keeps exiting, giving up until it is reset"
+            );
+            shared.set_status(ProcessStatus::Failed);
+            return;
+        }
+
+        let backoff = MAX_BACKOFF.min(INITIAL_BACKOFF * 2u32.saturating_pow(recent));
+        restarts.push_back(Instant::now());
+        tracing::info!(%command, ?backoff, restarts = recent + 1, "Restarting external process");
+        shared.set_status(ProcessStatus::Starting);
+        time::sleep(backoff).await;
+        // The process may have been stopped while waiting
+        if shared.stopping.load(Ordering::Relaxed) {
+            shared.set_status(ProcessStatus::Exited(code));
+            return;
+        }
+
+        // The restarted process has to log that it is ready again
+        shared.ready.store(false, Ordering::Relaxed);
+        process = match start.spawn(environment, ready.clone()) {
+            Ok(process) => process,
+            Err(error) => {
+                tracing::error!(%command, %error, "Could not restart external process");
+                shared.set_status(ProcessStatus::Exited(code));
+                return;
+            }
+        };
+        lifecycle::emit(&shared.name, Event::StartSpawned);
+        shared
+            .pid
+            .store(process.id().unwrap_or(0), Ordering::Relaxed);
+        shared.set_status(ProcessStatus::Running);
+    }
+}
+
+async fn run_post_stop(post_stop: &CommandLine, environment: &Environment) {
+    tracing::debug!(command = %post_stop.command, "Running post-stop command");
+    if let Err(error) = post_stop.run(environment).await {
+        tracing::warn!(command = %post_stop.command, %error, "Post-stop command failed");
+    }
+}
+
+impl CommandLine {
+    fn parse(command: String) -> Result<CommandLine, Error> {
+        let mut words = split_command(&command)?.into_iter();
+        let program = words.next().ok_or("command is empty")?;
+        Ok(CommandLine {
+            command,
+            program,
+            args: words.collect(),
+        })
+    }
+
+    /// Returns this command line with the placeholders replaced by the values of the trigger.
+    /// Each word is replaced on its own, so a value can not add arguments. Host names with
+    /// characters other than letters, digits, `.`, `-` and `_` are rejected, as the host is chosen
+    /// by the client and could otherwise inject code into commands run by a shell.
+    fn substitute(&self, trigger: &Trigger) -> Result<CommandLine, Error> {
+        let mut uses_host = false;
+        let mut value = |name: &str| {
+            uses_host |= name == "host";
+            trigger.placeholder(name)
+        };
+        let program = substitute(&self.program, &mut value);
+        let args = self
+            .args
+            .iter()
+            .map(|arg| substitute(arg, &mut value))
+            .collect();
+        let command = substitute(&self.command, &mut value);
+        let safe_host = !trigger.host.is_empty()
+            && trigger
+                .host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'));
+        if uses_host && !safe_host {
+            return Err(Error::Other(
+                format!(
+                    "host {:?} can not be used in the start command",
+                    trigger.host
+                )
+                .into(),
+            ));
+        }
+        Ok(CommandLine {
+            command,
+            program,
+            args,
+        })
+    }
+
+    /// Creates a command to run this command line in `environment`.
+    /// Fails if the working directory does not exist, which would otherwise surface as a confusing
+    /// "file not found" error for the program.
+    fn to_command(&self, environment: &Environment) -> Result<Command, Error> {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        if environment.clear {
+            command.env_clear();
+        }
+        command.envs(environment.vars.iter().map(|(key, value)| (key, value)));
+        if let Some(dir) = &environment.current_dir {
+            if !dir.is_dir() {
+                return Err(Error::Other(
+                    format!("working directory {} does not exist", dir.display()).into(),
+                ));
+            }
+            command.current_dir(dir);
+        }
+        Ok(command)
+    }
+
+    /// Runs this command line to completion and fails if it does not exit successfully.
+    async fn run(&self, environment: &Environment) -> Result<(), Error> {
+        let status = self.spawn(environment, None)?.wait().await?;
+        if !status.success() {
+            return Err(Error::Other(
+                format!("command `{}` failed with {}", self.command, status).into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Spawns this command line as a process whose output is logged and, if given, watched for the
+    /// line that shows it is ready.
+    fn spawn(&self, environment: &Environment, ready: Option<ReadyWatch>) -> Result<Child, Error> {
+        let mut process = self
+            .to_command(environment)?
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(Error::Spawn)?;
+        let pid = process.id();
+        if let Some(stdout) = process.stdout.take() {
+            task::spawn(log_output(stdout, "stdout", pid, ready.clone()).in_current_span());
+        }
+        if let Some(stderr) = process.stderr.take() {
+            task::spawn(log_output(stderr, "stderr", pid, ready).in_current_span());
+        }
+        Ok(process)
+    }
+}
+
+/// Logs the output of a process line by line until the process closes the stream, marking the
+/// process as ready once a line matches the ready pattern.
+/// At most one line is held in memory at a time.
+async fn log_output(
+    output: impl AsyncRead + Unpin,
+    stream: &'static str,
+    pid: Option<u32>,
+    ready: Option<ReadyWatch>,
+) {
+    let mut output = BufReader::new(output);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match (&mut output)
+            .take(MAX_LINE_LENGTH)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(error) => {
+                tracing::debug!(stream, pid, %error, "Could not read output of external process");
+                return;
+            }
+        }
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        tracing::info!(stream, pid, "{}", line);
+        if let Some(ready) = &ready
+            && !ready.shared.ready.load(Ordering::Relaxed)
+            && ready.pattern.is_match(line)
+        {
+            tracing::info!(pid, "External process is ready");
+            ready.shared.ready.store(true, Ordering::Relaxed);
+        }
+    }
 }
 
 impl Drop for ExternalProcess {
     fn drop(&mut self) {
-        if let Some(state) = self.state.get_mut() {
-            state.abort();
+        if let Some(running) = self.state.get_mut() {
+            running.task.abort();
+        }
+    }
+}
+
+/// Checks that a command line can be run before it is needed, i.e. that it is not empty and its
+/// program exists. Programs given without a path are looked up in `PATH`, relative paths are
+/// resolved against the working directory the command will run in.
+pub fn check_command(command: &str, working_dir: Option<&Path>) -> Result<(), Error> {
+    let words = split_command(command)?;
+    let program = words.first().ok_or("command is empty")?;
+    // A program chosen by a placeholder is only known once the command is run
+    if program.contains('{') {
+        return Ok(());
+    }
+    let program = Path::new(program);
+    let exists = match program.components().count() {
+        1 => env::var_os("PATH")
+            .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file())),
+        _ => match working_dir {
+            Some(dir) => dir.join(program).is_file(),
+            None => program.is_file(),
+        },
+    };
+    if !exists {
+        return Err(Error::Other(
+            format!("program {} does not exist", program.display()).into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Replaces the `{name}` placeholders in `text` with what `value` returns for their name. `{{` and
+/// `}}` stand for literal braces, placeholders for which `value` returns `None` are left as they
+/// are.
+fn substitute(text: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find(['{', '}']) {
+        result.push_str(&rest[..index]);
+        rest = &rest[index..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            result.push_str(&rest[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+        // A placeholder ends at the next closing brace, unless another one is opened before
+        let end = rest[1..].find(['{', '}']).map(|end| end + 1);
+        if rest.starts_with('{')
+            && let Some(end) = end.filter(|&end| rest[end..].starts_with('}'))
+        {
+            match value(&rest[1..end]) {
+                Some(value) => result.push_str(&value),
+                None => result.push_str(&rest[..=end]),
+            }
+            rest = &rest[end + 1..];
+        } else {
+            result.push_str(&rest[..1]);
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Splits a command line into words.
+/// Words are separated by whitespace unless it is quoted or escaped. Single quotes preserve
+/// everything up to the closing quote, while backslashes still escape `"`, `\`, `$` and `` ` ``
+/// inside double quotes. There is no expansion of variables or globs.
+fn split_command(command: &str) -> Result<Vec<String>, Error> {
+    let mut words = Vec::new();
+    // Quotes can produce empty words, so a word can not be detected by being non-empty
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated single quote in command".into()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("unterminated double quote in command".into()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated double quote in command".into()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_default().push(c),
+                None => return Err("command ends with an escape character".into()),
+            },
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::testing::{CapturedLogs, temp_dir, trigger, wait_for_file};
+
+    /// Waits until the process has the status, failing the test if it takes too long.
+    async fn wait_for_status(process: &ExternalProcess, status: ProcessStatus) {
+        let result = timeout(Duration::from_secs(10), async {
+            while process.status() != status {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(result.is_ok(), "status is {:?}", process.status());
+    }
+
+    #[test]
+    fn splits_commands_into_words() {
+        let split = |command| split_command(command).unwrap();
+        assert_eq!(
+            split("./start.sh --world overworld"),
+            ["./start.sh", "--world", "overworld"]
+        );
+        assert_eq!(
+            split(r#"java -jar "My Server.jar"   nogui"#),
+            ["java", "-jar", "My Server.jar", "nogui"]
+        );
+        // Single quotes keep backslashes and double quotes
+        assert_eq!(split(r#"echo 'a \ "b"' ''"#), ["echo", r#"a \ "b""#, ""]);
+        assert_eq!(
+            split(r#"echo "\"quoted\" \n" escaped\ space"#),
+            ["echo", r#""quoted" \n"#, "escaped space"]
+        );
+        assert!(split("").is_empty());
+
+        assert!(split_command("echo 'unterminated").is_err());
+        assert!(split_command(r#"echo "unterminated"#).is_err());
+        assert!(split_command("echo \\").is_err());
+    }
+
+    #[tokio::test]
+    async fn stop_runs_the_stop_command_and_waits_for_the_exit() {
+        let dir = temp_dir("stop-command");
+        // Exits a moment after the stop command created the file, like a server saving the world
+        let process = ExternalProcess::new(
+            "sh -c 'while [ ! -e stop ]; do sleep 0.05; done; sleep 0.2; touch exited'".to_owned(),
+        )
+        .unwrap()
+        .with_stop_command("touch stop".to_owned())
+        .unwrap()
+        .with_current_dir(dir.clone());
+
+        process.spawn_once(&trigger()).await.unwrap();
+        assert!(process.stop().await.unwrap());
+        assert!(dir.join("stop").exists());
+        assert!(dir.join("exited").exists());
+        assert!(!process.is_running());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_restarting_after_the_cap() {
+        let dir = temp_dir("restart-cap");
+        let process = ExternalProcess::new("sh -c 'echo run >> runs; exit 1'".to_owned())
+            .unwrap()
+            .with_current_dir(dir.clone())
+            .with_restart(Restart::OnFailure, 2, Duration::from_secs(60));
+
+        process.spawn_once(&trigger()).await.unwrap();
+        // The backoff passes in paused time, but the processes take real time to exit
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while process.status() != ProcessStatus::Failed {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "{:?}",
+                process.status()
+            );
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        // The first run and two restarts
+        assert_eq!(
+            fs::read_to_string(dir.join("runs"))
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        assert_eq!(process.spawn_once(&trigger()).await.unwrap(), Spawn::Failed);
+
+        // Nothing is restarted anymore
+        time::sleep(Duration::from_secs(120)).await;
+        assert_eq!(
+            fs::read_to_string(dir.join("runs"))
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        assert!(process.reset().await);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn passes_the_configured_environment() {
+        let dir = temp_dir("env");
+        let process = ExternalProcess::new(r#"sh -c 'echo "$EULA $HOME" > env'"#.to_owned())
+            .unwrap()
+            .with_env("EULA".to_owned(), "true".to_owned())
+            .with_current_dir(dir.clone());
+        process.spawn_once(&trigger()).await.unwrap();
+        wait_for_status(&process, ProcessStatus::Exited(Some(0))).await;
+        let home = std::env::var("HOME").unwrap_or_default();
+        assert_eq!(
+            fs::read_to_string(dir.join("env")).unwrap(),
+            format!("true {home}\n")
+        );
+
+        // Without the proxy's variables, only the configured ones are set
+        let process = process.with_cleared_env();
+        process.spawn_once(&trigger()).await.unwrap();
+        wait_for_status(&process, ProcessStatus::Exited(Some(0))).await;
+        assert_eq!(fs::read_to_string(dir.join("env")).unwrap(), "true \n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_in_the_working_directory() {
+        let dir = temp_dir("working-dir");
+        let process = ExternalProcess::new("sh -c 'pwd > cwd'".to_owned())
+            .unwrap()
+            .with_current_dir(dir.clone());
+        process.spawn_once(&trigger()).await.unwrap();
+        wait_for_status(&process, ProcessStatus::Exited(Some(0))).await;
+        let cwd = fs::read_to_string(dir.join("cwd")).unwrap();
+        assert_eq!(
+            Path::new(cwd.trim_end()).canonicalize().unwrap(),
+            dir.canonicalize().unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        let error = process.spawn_once(&trigger()).await.unwrap_err();
+        assert!(error.to_string().contains("does not exist"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn logs_the_output_line_by_line() {
+        let logs = CapturedLogs::default();
+        // The test runs on a single thread, so the tasks reading the output log here as well
+        let _guard = logs.install();
+
+        let process =
+            ExternalProcess::new("sh -c 'echo Loading world; echo Oops >&2'".to_owned()).unwrap();
+        process.spawn_once(&trigger()).await.unwrap();
+        wait_for_status(&process, ProcessStatus::Exited(Some(0))).await;
+        // The output may still be read after the process exited
+        let mut lines = Vec::new();
+        for _ in 0..100 {
+            lines = logs
+                .contents()
+                .lines()
+                .filter(|line| line.contains("stream="))
+                .map(str::to_owned)
+                .collect();
+            if lines.len() == 2 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(lines.len(), 2, "{lines:?}");
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.contains("Loading world") && line.contains("stream=\"stdout\""))
+        );
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.contains("Oops") && line.contains("stream=\"stderr\""))
+        );
+    }
+
+    #[tokio::test]
+    async fn kills_a_process_that_ignores_sigterm() {
+        let dir = temp_dir("sigkill");
+        let process = ExternalProcess::new(
+            r#"sh -c 'trap "" TERM; echo $$ > pid.tmp; mv pid.tmp pid; while true; do sleep 0.05; done'"#.to_owned(),
+        )
+        .unwrap()
+        .with_current_dir(dir.clone())
+        .with_stop_timeout(Duration::from_millis(300));
+        process.spawn_once(&trigger()).await.unwrap();
+        // The trap is set once the process id was written
+        wait_for_file(&dir.join("pid")).await;
+        let pid = Pid::from_raw(
+            fs::read_to_string(dir.join("pid"))
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap(),
+        );
+
+        let start = time::Instant::now();
+        process.shutdown().await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(!process.is_running());
+        // The killed process was reaped, so it does not exist anymore
+        assert!(signal::kill(pid, None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn does_not_wait_for_a_stop_to_start_again() {
+        let dir = temp_dir("stopping");
+        let process = Arc::new(
+            ExternalProcess::new(
+                r#"sh -c 'trap "" TERM; touch trapped; while true; do sleep 0.05; done'"#
+                    .to_owned(),
+            )
+            .unwrap()
+            .with_current_dir(dir.clone())
+            .with_stop_timeout(Duration::from_secs(1)),
+        );
+        process.spawn_once(&trigger()).await.unwrap();
+        wait_for_file(&dir.join("trapped")).await;
+
+        let stop = task::spawn({
+            let process = Arc::clone(&process);
+            async move { process.stop().await }
+        });
+        wait_for_status(&process, ProcessStatus::Stopping).await;
+        assert!(process.is_running());
+        let start = time::Instant::now();
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Stopping
+        );
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // The process ignores SIGTERM, so it is killed after the stop timeout
+        assert!(stop.await.unwrap().unwrap());
+        assert!(!process.is_running());
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        process.shutdown().await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_transitions_around_spawn_and_exit() {
+        let process = ExternalProcess::new("sh -c 'sleep 0.2; exit 3'".to_owned()).unwrap();
+        assert_eq!(process.status(), ProcessStatus::NotStarted);
+        assert!(!process.is_running());
+
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        assert_eq!(process.status(), ProcessStatus::Running);
+        assert!(process.is_running());
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Running
+        );
+
+        wait_for_status(&process, ProcessStatus::Exited(Some(3))).await;
+        assert!(!process.is_running());
+        // The exited process is waited for before it is started again
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        assert_eq!(process.status(), ProcessStatus::Running);
+        process.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn starts_again_after_being_stopped() {
+        let process = ExternalProcess::new("sleep 30".to_owned()).unwrap();
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        assert!(process.stop().await.unwrap());
+        assert!(!process.is_running());
+        // Nothing is left to stop
+        assert!(!process.stop().await.unwrap());
+
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        assert_eq!(process.status(), ProcessStatus::Running);
+        process.shutdown().await.unwrap();
+        assert!(!process.is_running());
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        process.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_the_hooks_around_the_process() {
+        let dir = temp_dir("hooks");
+        let process = ExternalProcess::new(
+            "sh -c 'echo start >> log; sleep 0.1; echo exit >> log'".to_owned(),
+        )
+        .unwrap()
+        .with_pre_start_command("sh -c 'echo pre-start >> log'".to_owned())
+        .unwrap()
+        .with_post_stop_command("sh -c 'echo post-stop >> log'".to_owned())
+        .unwrap()
+        .with_current_dir(dir.clone());
+
+        process.spawn_once(&trigger()).await.unwrap();
+        // The post-stop command runs once the process exited on its own
+        wait_for_file(&dir.join("log")).await;
+        let expected = "pre-start\nstart\nexit\npost-stop\n";
+        let result = timeout(Duration::from_secs(10), async {
+            while fs::read_to_string(dir.join("log")).unwrap() != expected {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(
+            result.is_ok(),
+            "{}",
+            fs::read_to_string(dir.join("log")).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn does_not_start_if_the_pre_start_command_fails() {
+        let dir = temp_dir("failing-hook");
+        let process = ExternalProcess::new("touch started".to_owned())
+            .unwrap()
+            .with_pre_start_command("false".to_owned())
+            .unwrap()
+            .with_current_dir(dir.clone());
+
+        assert!(process.spawn_once(&trigger()).await.is_err());
+        assert_eq!(process.status(), ProcessStatus::NotStarted);
+        assert!(!dir.join("started").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn does_not_start_again_during_the_cooldown() {
+        let dir = temp_dir("cooldown");
+        let process = ExternalProcess::new("sh -c 'echo started >> starts'".to_owned())
+            .unwrap()
+            .with_current_dir(dir.clone())
+            .with_start_cooldown(Duration::from_millis(500));
+        let starts = || {
+            fs::read_to_string(dir.join("starts"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        wait_for_status(&process, ProcessStatus::Exited(Some(0))).await;
+        // The process already exited, but was started too recently
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::CoolingDown
+        );
+        assert_eq!(starts(), 1);
+
+        time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        wait_for_status(&process, ProcessStatus::Exited(Some(0))).await;
+        assert_eq!(starts(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn is_ready_once_the_output_matches_the_pattern() {
+        let dir = temp_dir("ready-pattern");
+        let script = "echo Preparing level; touch loading; \
+            while [ ! -e go ]; do sleep 0.05; done; \
+            echo Done \\(1.234s\\)! For help; exec sleep 30";
+        let process = ExternalProcess::new(format!("sh -c '{}'", script))
+            .unwrap()
+            .with_current_dir(dir.clone())
+            .with_ready_pattern(Regex::new(r"Done \(.*\)! For help").unwrap());
+        assert!(process.is_ready());
+
+        assert_eq!(
+            process.spawn_once(&trigger()).await.unwrap(),
+            Spawn::Started
+        );
+        wait_for_file(&dir.join("loading")).await;
+        time::sleep(Duration::from_millis(100)).await;
+        // Other lines do not count
+        assert!(!process.is_ready());
+        assert_eq!(process.status(), ProcessStatus::Running);
+
+        fs::write(dir.join("go"), "").unwrap();
+        let result = timeout(Duration::from_secs(5), async {
+            while !process.is_ready() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(result.is_ok(), "the process did not become ready");
+        // Exiting would make it count as ready as well
+        assert_eq!(process.status(), ProcessStatus::Running);
+        process.shutdown().await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tells_the_start_command_about_the_trigger() {
+        let dir = temp_dir("trigger-env");
+        let script = r#"echo "$PORTAL_REQUEST_HOST $PORTAL_REQUEST_PORT $PORTAL_CLIENT_IP $PORTAL_WAITING" > trigger"#;
+        let process = ExternalProcess::new(format!("sh -c '{}'", script))
+            .unwrap()
+            .with_current_dir(dir.clone())
+            // The variables are set even without the environment of the proxy
+            .with_cleared_env();
+        let trigger = Trigger {
+            host: "survival.example.com".to_owned(),
+            port: 25566,
+            client_ip: "192.0.2.7".parse().unwrap(),
+            waiting: 3,
+        };
+        process.spawn_once(&trigger).await.unwrap();
+        wait_for_status(&process, ProcessStatus::Exited(Some(0))).await;
+        assert_eq!(
+            fs::read_to_string(dir.join("trigger")).unwrap(),
+            "survival.example.com 25566 192.0.2.7 3\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn substitutes_the_placeholders() {
+        let start = CommandLine::parse(
+            "docker start mc-{host} --port={port} --allow {client_ip} '{{literal}}' {unknown}"
+                .to_owned(),
+        )
+        .unwrap();
+        let start = start.substitute(&trigger()).unwrap();
+        assert_eq!(start.program, "docker");
+        assert_eq!(
+            start.args,
+            [
+                "start",
+                "mc-mc.example.com",
+                "--port=25565",
+                "--allow",
+                "127.0.0.1",
+                "{literal}",
+                "{unknown}",
+            ]
+        );
+    }
+
+    #[test]
+    fn handles_braces_that_are_not_placeholders() {
+        let value = |name: &str| (name == "host").then(|| "mc.example.com".to_owned());
+        assert_eq!(substitute("{{host}}", value), "{host}");
+        assert_eq!(substitute("{{{host}}}", value), "{mc.example.com}");
+        assert_eq!(substitute("}}{host", value), "}{host");
+        assert_eq!(substitute("{a{host}}", value), "{amc.example.com}");
+        assert_eq!(substitute("{}", value), "{}");
+        assert_eq!(substitute("${host}/", value), "$mc.example.com/");
+    }
+
+    #[test]
+    fn rejects_hosts_that_are_unsafe_in_commands() {
+        let start = CommandLine::parse("sh -c 'start {host}'".to_owned()).unwrap();
+        let trigger = Trigger {
+            host: "a;reboot".to_owned(),
+            ..trigger()
+        };
+        assert!(start.substitute(&trigger).is_err());
+        // Commands without the host do not care
+        let start = CommandLine::parse("start {port}".to_owned()).unwrap();
+        assert!(start.substitute(&trigger).is_ok());
+    }
+
+    #[test]
+    fn warns_about_unknown_placeholders() {
+        let logs = CapturedLogs::default();
+        let _guard = logs.install();
+        ExternalProcess::new("./start.sh {host} {world}".to_owned()).unwrap();
+        let contents = logs.contents();
+        assert!(
+            contents.contains("Unknown placeholder in the start command is left as it is"),
+            "{}",
+            contents
+        );
+        assert!(contents.contains("placeholder=\"world\""), "{}", contents);
+        assert_eq!(contents.lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn emits_lifecycle_events_in_order() {
+        let logs = CapturedLogs::default();
+        let _guard = logs.install();
+        let process = ExternalProcess::new("sleep 30".to_owned())
+            .unwrap()
+            .with_name("survival");
+        process.spawn_once(&trigger()).await.unwrap();
+        process.shutdown().await.unwrap();
+
+        let contents = logs.contents();
+        let events: Vec<_> = contents
+            .lines()
+            .filter(|line| line.contains(" lifecycle: "))
+            .collect();
+        assert_eq!(events.len(), 3, "{contents}");
+        for (line, event) in events
+            .iter()
+            .zip(["start_requested", "start_spawned", "stopped"])
+        {
+            assert!(
+                line.ends_with(&format!(r#"backend="survival" event={event}"#)),
+                "{line}"
+            );
         }
     }
 }
@@ -0,0 +1,169 @@
+use std::net::IpAddr;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::{
+    auth::GameProfile,
+    protocol::types::{write_string, write_var_int},
+};
+
+/// The plugin channel a Velocity-compatible backend uses to ask for the player's information
+pub const VELOCITY_CHANNEL: &str = "velocity:player_info";
+/// The version of the forwarding format without the chat session keys of 1.19
+const VELOCITY_VERSION: u8 = 1;
+
+/// How the player's identity is passed on to the backend.
+#[derive(Debug)]
+pub enum Forwarding {
+    /// The backend sees the proxy's address and authenticates players on its own
+    None,
+    /// Velocity's modern forwarding, signed with a secret shared with the backend
+    Velocity { secret: Vec<u8> },
+    /// BungeeCord's legacy forwarding, which appends the player's information to the handshake.
+    /// The backend can not verify that it comes from the proxy, so it must not be reachable
+    /// otherwise.
+    Bungee,
+}
+
+/// Returns the UUID the vanilla server assigns to a player in offline mode.
+/// This is a version 3 UUID of `OfflinePlayer:<name>` without a namespace, matching Java's
+/// `UUID.nameUUIDFromBytes`.
+pub fn offline_uuid(name: &str) -> Uuid {
+    let digest = Md5::new()
+        .chain_update(b"OfflinePlayer:")
+        .chain_update(name.as_bytes())
+        .finalize();
+    uuid::Builder::from_md5_bytes(digest.into()).into_uuid()
+}
+
+/// Returns the profile of a player that has not been authenticated.
+pub fn offline_profile(name: &str) -> GameProfile {
+    GameProfile {
+        id: offline_uuid(name),
+        name: name.to_owned(),
+        properties: Vec::new(),
+    }
+}
+
+/// Builds the answer to a Velocity player info request: an HMAC-SHA256 signature followed by the
+/// signed forwarding data.
+pub fn velocity_player_info(secret: &[u8], address: IpAddr, profile: &GameProfile) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_velocity_data(&mut data, address, profile).expect("writing to a vector can not fail");
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&data);
+    let mut payload = mac.finalize().into_bytes().to_vec();
+    payload.extend_from_slice(&data);
+    payload
+}
+
+/// Returns the handshake address for BungeeCord's forwarding: the host the client connected to,
+/// the client's address, the player's UUID without hyphens and the profile properties as JSON,
+/// separated by null characters.
+pub fn bungee_address(host: &str, address: IpAddr, profile: &GameProfile) -> String {
+    let mut forwarded = format!("{}\0{}\0{}", host, address, profile.id.simple());
+    if !profile.properties.is_empty() {
+        let properties =
+            serde_json::to_string(&profile.properties).expect("properties can be serialized");
+        forwarded.push('\0');
+        forwarded.push_str(&properties);
+    }
+    forwarded
+}
+
+fn write_velocity_data(
+    data: &mut Vec<u8>,
+    address: IpAddr,
+    profile: &GameProfile,
+) -> std::io::Result<()> {
+    write_var_int(VELOCITY_VERSION as i32, data)?;
+    write_string(&address.to_string(), data)?;
+    data.write_u128::<BigEndian>(profile.id.as_u128())?;
+    write_string(&profile.name, data)?;
+    write_var_int(profile.properties.len() as i32, data)?;
+    for property in &profile.properties {
+        write_string(&property.name, data)?;
+        write_string(&property.value, data)?;
+        data.write_u8(property.signature.is_some() as u8)?;
+        if let Some(signature) = &property.signature {
+            write_string(signature, data)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auth::ProfileProperty;
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn notch() -> GameProfile {
+        GameProfile {
+            id: Uuid::from_u128(0x069a79f444e94726a5befca90e38aaf5),
+            name: "Notch".to_owned(),
+            properties: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn signs_the_velocity_player_info() {
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let payload = velocity_player_info(b"secret", address, &notch());
+
+        let mut data = vec![1, 9];
+        data.extend_from_slice(b"127.0.0.1");
+        data.extend_from_slice(&0x069a79f444e94726a5befca90e38aaf5u128.to_be_bytes());
+        data.push(5);
+        data.extend_from_slice(b"Notch");
+        data.push(0);
+        assert_eq!(payload[32..], data);
+        // HMAC-SHA256 of the data with the key "secret", computed independently
+        let signature = "0db3697041bcf9d105be093c6e4dc70c41d56964ee95c06033ba33330b7804bf";
+        let hex = payload[..32]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        assert_eq!(hex, signature);
+    }
+
+    #[test]
+    fn derives_offline_uuids_like_the_vanilla_server() {
+        assert_eq!(
+            offline_uuid("Notch").to_string(),
+            "b50ad385-829d-3141-a216-7e7d7539ba7f"
+        );
+        assert_eq!(offline_profile("Notch").id, offline_uuid("Notch"));
+    }
+
+    #[test]
+    fn appends_the_player_to_the_bungee_address() {
+        let address = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let forwarded = bungee_address("mc.example.com", address, &notch());
+        assert_eq!(
+            forwarded.split('\0').collect::<Vec<_>>(),
+            [
+                "mc.example.com",
+                "203.0.113.7",
+                "069a79f444e94726a5befca90e38aaf5"
+            ]
+        );
+
+        let mut profile = notch();
+        profile.properties.push(ProfileProperty {
+            name: "textures".to_owned(),
+            value: "e30=".to_owned(),
+            signature: None,
+        });
+        let forwarded = bungee_address("mc.example.com", address, &profile);
+        let segments = forwarded.split('\0').collect::<Vec<_>>();
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments[3], r#"[{"name":"textures","value":"e30="}]"#);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    pin::pin,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::{sync::Notify, time};
+
+/// How long the backend may be without players before it is stopped
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Keeps track of the connections forwarded to the backend to find out when it is idle.
+pub struct IdleMonitor {
+    timeout: Duration,
+    active: AtomicUsize,
+    /// Incremented whenever a connection is opened or closed or the idle period is reset
+    generation: AtomicU64,
+    /// The generation at which the last idle period ended
+    reported: AtomicU64,
+    changed: Notify,
+}
+
+/// Marks a forwarded connection as active until it is dropped
+pub struct ActiveConnection<'a> {
+    monitor: &'a IdleMonitor,
+}
+
+impl IdleMonitor {
+    pub fn new(timeout: Duration) -> IdleMonitor {
+        IdleMonitor {
+            timeout,
+            active: AtomicUsize::new(0),
+            // Starting the proxy counts as activity, so a backend that is already running is
+            // stopped if nobody joins
+            generation: AtomicU64::new(1),
+            reported: AtomicU64::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Registers a forwarded connection, which also restarts the idle period once it is closed.
+    pub fn connection(&self) -> ActiveConnection<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.notify();
+        ActiveConnection { monitor: self }
+    }
+
+    /// Returns the number of connections currently forwarded to the backend.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Restarts the idle period, e.g. because the backend was just started.
+    pub fn reset(&self) {
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until there have been no active connections for the whole idle timeout.
+    /// Each idle period is only reported once, the next one starts with the next activity.
+    pub async fn wait_idle(&self) {
+        loop {
+            // Registering for notifications before looking at the counter ensures that no change
+            // in between is missed
+            let mut changed = pin!(self.changed.notified());
+            changed.as_mut().enable();
+
+            let generation = self.generation.load(Ordering::Relaxed);
+            if self.active.load(Ordering::Relaxed) > 0
+                || generation == self.reported.load(Ordering::Relaxed)
+            {
+                changed.await;
+                continue;
+            }
+            if time::timeout(self.timeout, changed).await.is_err() {
+                self.reported.store(generation, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.monitor.active.fetch_sub(1, Ordering::Relaxed);
+        self.monitor.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn reports_idle_periods_once() {
+        let monitor = IdleMonitor::new(Duration::from_secs(60));
+        let start = Instant::now();
+        monitor.wait_idle().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+
+        // The next period only starts with the next activity
+        assert!(
+            time::timeout(Duration::from_secs(600), monitor.wait_idle())
+                .await
+                .is_err()
+        );
+        let start = Instant::now();
+        monitor.reset();
+        monitor.wait_idle().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn is_not_idle_while_connections_are_open() {
+        let monitor = IdleMonitor::new(Duration::from_secs(60));
+        let connection = monitor.connection();
+        assert_eq!(monitor.active_connections(), 1);
+        assert!(
+            time::timeout(Duration::from_secs(600), monitor.wait_idle())
+                .await
+                .is_err()
+        );
+
+        time::sleep(Duration::from_secs(30)).await;
+        let start = Instant::now();
+        drop(connection);
+        assert_eq!(monitor.active_connections(), 0);
+        monitor.wait_idle().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn counts_connections_until_they_are_dropped() {
+        let monitor = IdleMonitor::new(Duration::from_secs(60));
+        let first = monitor.connection();
+        let second = monitor.connection();
+        assert_eq!(monitor.active_connections(), 2);
+        drop(first);
+        assert_eq!(monitor.active_connections(), 1);
+
+        // A handler that panics still gives up its connection
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _connection = monitor.connection();
+            panic!("handler failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(monitor.active_connections(), 1);
+        drop(second);
+        assert_eq!(monitor.active_connections(), 0);
+    }
+}
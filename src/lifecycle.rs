@@ -0,0 +1,41 @@
+use std::fmt::{self, Display, Formatter};
+
+/// The tracing target of lifecycle events, so they can be filtered or written elsewhere
+pub const TARGET: &str = "lifecycle";
+
+/// A transition in the lifecycle of a backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The start command is about to be run
+    StartRequested,
+    /// The process was spawned, either when starting or when restarting it
+    StartSpawned,
+    /// The backend became reachable after it was started
+    Ready,
+    /// The backend is stopped because it has been idle for too long
+    IdleStopRequested,
+    /// The process exited successfully or because it was asked to, or the stop command finished
+    /// for a backend that was not started by the proxy
+    Stopped,
+    /// The process exited with an error or was killed without being asked to stop
+    Crashed,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let event = match self {
+            Event::StartRequested => "start_requested",
+            Event::StartSpawned => "start_spawned",
+            Event::Ready => "ready",
+            Event::IdleStopRequested => "idle_stop_requested",
+            Event::Stopped => "stopped",
+            Event::Crashed => "crashed",
+        };
+        write!(f, "{}", event)
+    }
+}
+
+/// Logs a lifecycle event of the backend with the given id.
+pub fn emit(backend: &str, event: Event) {
+    tracing::info!(target: TARGET, backend, event = %event, "Backend lifecycle event");
+}
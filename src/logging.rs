@@ -0,0 +1,234 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::{
+    EnvFilter, Layer,
+    fmt::{self, FormatEvent, FormatFields, MakeWriter, format::Format, time::SystemTime},
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+};
+
+use crate::error::Error;
+
+/// How log events are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// A line per event with the fields of its spans
+    Full,
+    /// A shorter line per event
+    Compact,
+    /// Several lines per event, easier to read for humans
+    Pretty,
+    /// A JSON object per line, as expected by many log collectors. The fields of the spans an event
+    /// belongs to, such as the connection id, client and host, are included in `spans`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("log format must be one of full, compact, pretty or json".into()),
+        }
+    }
+}
+
+/// Options for writing log events to stdout
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    /// Events below this level are left out, unless `RUST_LOG` says otherwise
+    pub level: Level,
+    pub format: LogFormat,
+    pub timestamps: bool,
+    /// Whether events include the module they come from
+    pub targets: bool,
+    /// Where spans are exported to with OTLP/HTTP, if anywhere
+    #[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Exports the spans that are still buffered when dropped, so it has to be kept until the proxy
+/// exits
+#[derive(Default)]
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otlp")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take()
+            && let Err(error) = provider.shutdown()
+        {
+            tracing::warn!(%error, "Could not export the remaining spans");
+        }
+    }
+}
+
+impl LogOptions {
+    /// Installs the global subscriber. Directives in `RUST_LOG` take precedence over the level.
+    /// Fails if spans should be exported, but the exporter could not be created.
+    pub fn init(&self) -> Result<Telemetry, Error> {
+        let filter = EnvFilter::builder()
+            .with_default_directive(self.level.into())
+            .from_env_lossy();
+        let registry =
+            tracing_subscriber::registry().with(self.layer(std::io::stdout).with_filter(filter));
+        #[cfg(feature = "otlp")]
+        if let Some(endpoint) = &self.otlp_endpoint {
+            let (layer, provider) = crate::otlp::layer(endpoint, self.level)?;
+            registry.with(layer).init();
+            return Ok(Telemetry {
+                provider: Some(provider),
+            });
+        }
+        registry.init();
+        Ok(Telemetry::default())
+    }
+
+    /// Creates the layer that formats events and writes them to `writer`.
+    fn layer<S, W>(&self, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let layer = fmt::layer().with_writer(writer).with_target(self.targets);
+        match self.format {
+            LogFormat::Full => self.with_timestamps(layer),
+            LogFormat::Compact => self.with_timestamps(layer.compact()),
+            LogFormat::Pretty => self.with_timestamps(layer.pretty()),
+            LogFormat::Json => self.with_timestamps(layer.json()),
+        }
+    }
+
+    fn with_timestamps<S, N, L, W>(
+        &self,
+        layer: fmt::Layer<S, N, Format<L, SystemTime>, W>,
+    ) -> Box<dyn Layer<S> + Send + Sync>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        N: for<'w> FormatFields<'w> + Send + Sync + 'static,
+        L: Send + Sync + 'static,
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+        Format<L, SystemTime>: FormatEvent<S, N>,
+        Format<L, ()>: FormatEvent<S, N>,
+    {
+        match self.timestamps {
+            true => layer.boxed(),
+            false => layer.without_time().boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::testing::{CapturedLogs, MockBackend, TestProxy};
+
+    /// Creates a subscriber that writes JSON to the returned logs.
+    fn json_subscriber(timestamps: bool, targets: bool) -> (impl Subscriber, CapturedLogs) {
+        let options = LogOptions {
+            level: Level::INFO,
+            format: LogFormat::Json,
+            timestamps,
+            targets,
+            otlp_endpoint: None,
+        };
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let layer = options.layer(move || writer.clone());
+        (tracing_subscriber::registry().with(layer), logs)
+    }
+
+    /// Logs an event within a connection span as JSON and returns the lines written.
+    fn log(timestamps: bool, targets: bool) -> Vec<String> {
+        let (subscriber, logs) = json_subscriber(timestamps, targets);
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("connection", conn_id = 7).entered();
+            tracing::info!(host = "mc.example.com", "Handshake received");
+        });
+        logs.contents().lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn writes_a_json_object_per_line() {
+        let lines = log(true, true);
+        assert_eq!(lines.len(), 1);
+        let event: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["target"], "portal::logging::tests");
+        assert!(event["timestamp"].is_string());
+        assert_eq!(event["fields"]["message"], "Handshake received");
+        assert_eq!(event["fields"]["host"], "mc.example.com");
+        assert_eq!(event["spans"][0]["name"], "connection");
+        assert_eq!(event["spans"][0]["conn_id"], 7);
+    }
+
+    #[test]
+    fn leaves_out_timestamps_and_targets() {
+        let event: Value = serde_json::from_str(&log(false, false)[0]).unwrap();
+        assert_eq!(event.get("timestamp"), None);
+        assert_eq!(event.get("target"), None);
+    }
+
+    #[tokio::test]
+    async fn writes_the_access_log_as_json() {
+        let (subscriber, logs) = json_subscriber(true, true);
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let backend = MockBackend::new()
+            .with_status(r#"{"version":{"name":"1.21.7","protocol":772},"description":"Live"}"#)
+            .start();
+        let proxy = TestProxy::start(&format!(
+            r#"
+            forward = ["{}"]
+            start-command = "true"
+            "#,
+            backend.address
+        ))
+        .await;
+        proxy.client().status().await.unwrap();
+        proxy.shutdown().await.unwrap();
+
+        let events = logs
+            .contents()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let access = events
+            .iter()
+            .find(|event| event["target"] == "access" && event["fields"]["action"] == "status")
+            .unwrap();
+        assert_eq!(access["level"], "INFO");
+        assert_eq!(access["fields"]["message"], "Connection closed");
+        assert_eq!(access["fields"]["client"], "127.0.0.1");
+        assert_eq!(access["fields"]["host"], "localhost");
+        assert_eq!(access["fields"]["next_state"], "status");
+        assert!(access["fields"]["duration_ms"].is_u64());
+        // The fields of the connection's span
+        assert!(access["span"]["conn_id"].is_u64());
+        assert_eq!(access["span"]["action"], "status");
+
+        // Events of the handler carry the connection id and the peer
+        let spans = events
+            .iter()
+            .find(|event| event["fields"]["next_state"] == "status" && event["target"] == "portal")
+            .map(|event| event["spans"].as_array().unwrap())
+            .unwrap();
+        assert_eq!(spans[0]["name"], "connection");
+        assert!(spans[0]["conn_id"].is_u64());
+        let handler = &spans[1];
+        assert_eq!(handler["name"], "connection_handler");
+        assert!(handler["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+    }
+}
@@ -1,53 +1,315 @@
-use std::{borrow::Cow, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io::Cursor,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroU64,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 
+use clap::Parser;
 use futures::{SinkExt, StreamExt};
+use regex::Regex;
 use tokio::{
-    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    task,
-    time::timeout,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    signal::{
+        self,
+        unix::{SignalKind, signal},
+    },
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{self, JoinSet},
+    time::{Instant, timeout, timeout_at},
 };
-use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::instrument;
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, FramedRead, FramedWrite},
+    sync::CancellationToken,
+    task::TaskTracker,
+};
+use tracing::{Instrument, Level, instrument};
+use uuid::Uuid;
 
 use crate::{
+    access_log::{AccessEntry, Action},
+    auth::{Authenticator, GameProfile, MojangSessionService},
+    balancer::Balancer,
+    cidr::Cidr,
+    cli::Cli,
+    config::{Config, Route},
+    docker::DockerContainer,
     error::Error,
-    external_process::ExternalProcess,
+    external_process::{
+        DEFAULT_MAX_RESTARTS, DEFAULT_RESTART_WINDOW, DEFAULT_STOP_TIMEOUT, ExternalProcess,
+        ProcessStatus, Restart, Spawn, StartStrategy, Strategy, Trigger,
+    },
+    forwarding::Forwarding,
+    idle::{DEFAULT_IDLE_TIMEOUT, IdleMonitor},
+    lifecycle::Event,
+    listener::{Connection, ListenAddress, Listener, Peer},
+    logging::{LogFormat, LogOptions},
+    metrics::Metrics,
+    probe::PROBE_INTERVAL,
     protocol::{
-        PacketDecoder, PacketEncoder,
-        handshake::{HandshakePacket, NextState},
-        login, status,
+        EncryptedStream, Packet, PacketDecoder, PacketEncoder, ProtocolError,
+        chat::{self, Chat},
+        configuration,
+        handshake::{self, HandshakePacket, NextState, ProtocolVersion},
+        legacy::{self, LegacyPing},
+        login, read_single_packet, status, write_packet,
     },
+    query::{GetStatus, QueryStatus},
+    registry::{Backend, DEFAULT_BACKEND, ProcessRegistry},
+    resolver::{Resolver, SystemDns},
+    routing::{HostList, Router, route_id},
+    server_status::{
+        DEFAULT_STATUS_CACHE_TTL, ServerState, StatusCache, StatusMap, StatusTemplate,
+    },
+    socket::{DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_TIME, SocketOptions},
+    sticky::{Player, StickySessions},
+    throttle::Throttled,
+    whitelist::Whitelist,
 };
 
+mod access_log;
+mod auth;
+mod balancer;
+mod cidr;
+mod cli;
+mod client;
+mod config;
+mod docker;
 mod error;
 mod external_process;
+mod forwarding;
+mod idle;
+mod lifecycle;
+mod listener;
+mod logging;
+mod metrics;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod probe;
 mod protocol;
+mod proxy_protocol;
+mod query;
+mod registry;
+mod relay;
+mod resolver;
+mod routing;
+mod server_status;
+mod socket;
+mod sticky;
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
+mod throttle;
+mod whitelist;
 
-const STATUS_RESPONSE: &'static str = r#"{
-    "version": {
-        "name": "1.21.7",
-        "protocol": 772
-    },
-    "players": {
-        "max": 0,
-        "online": 0
-    },
-    "description": "Not a Minecraft server",
-    "enforceSecureProfile": false
-}"#;
+const LEGACY_MOTD: &str = "Server is starting";
+/// The version reported to legacy clients that do not send their own, like a 1.6.4 server does
+const LEGACY_PROTOCOL: u8 = 78;
+const LEGACY_VERSION: &str = "1.6.4";
+
+const STARTING_MESSAGE: &str = "Server is starting, please try again later";
+const UNVERIFIED_MESSAGE: &str = "Failed to verify username!";
+const NOT_WHITELISTED_MESSAGE: &str = "You are not whitelisted on this server!";
+const FAILED_MESSAGE: &str = "Server failed to start, please contact an administrator";
+const UNKNOWN_SERVER_MESSAGE: &str = "There is no server at this address";
+
+/// How long open connections may take to finish when the proxy shuts down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How long a backend may take to become reachable after it was started
+const DEFAULT_START_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How long connecting to a backend may take before it is considered down
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a client may take to send the next packet before the connection is closed
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The time a client has to complete the status exchange
+const STATUS_BUDGET: Duration = Duration::from_secs(10);
+
+/// Answers a server list ping from a client older than 1.7.
+/// `request` holds the bytes the client sent so far, starting with the legacy ping byte.
 #[instrument(skip_all)]
-async fn status_handler<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
+async fn legacy_ping_handler(
+    mut request: Vec<u8>,
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    peer: &Peer,
+    shared: &Shared,
+) -> Result<(), Error> {
+    // Like the vanilla server, the form of the ping is told apart by what arrived together with its
+    // first byte. Only the 1.6 form says how long it is, so only its rest is waited for. Reading it
+    // before answering avoids resetting the connection due to unread data when the socket is
+    // closed.
+    let deadline = Instant::now() + shared.handshake_timeout;
+    let ping = loop {
+        if let Some(ping) = legacy::parse_ping(&request)? {
+            break ping;
+        }
+        if timeout_at(deadline, reader.read_buf(&mut request)).await?? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    };
+
+    let response = match ping {
+        LegacyPing::Basic => legacy::status_response(LEGACY_MOTD, 0, 0),
+        LegacyPing::Extended => {
+            legacy::extended_status_response(LEGACY_PROTOCOL, LEGACY_VERSION, LEGACY_MOTD, 0, 0)
+        }
+        LegacyPing::PingHost {
+            protocol,
+            host,
+            port,
+        } => {
+            tracing::debug!(peer = %peer, server = %host, port, protocol, "Client pinged host");
+            if shared.blocked_hosts.contains(&host) {
+                tracing::debug!(peer = %peer, server = %host, "Host is blocked");
+                access_log::record(|entry| {
+                    entry.host = Some(host);
+                    entry.action = Action::Blocked;
+                });
+                return Ok(());
+            }
+            let routed = shared.router.route(&host).is_some();
+            access_log::record(|entry| entry.host = Some(host));
+            if !routed {
+                access_log::record(|entry| entry.action = Action::Unrouted);
+                return Ok(());
+            }
+            // The client's version is echoed so the server is not listed as incompatible
+            legacy::extended_status_response(protocol, LEGACY_VERSION, LEGACY_MOTD, 0, 0)
+        }
+    };
+    writer.write_all(&response).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// State shared between all connections
+struct Shared {
+    backends: ProcessRegistry,
+    /// Chooses the backend by the host the client connected through
+    router: Router,
+    /// Connections through these hosts are closed right after the handshake
+    blocked_hosts: HostList,
+    /// The disconnect message for players connecting through a blocked host, who are disconnected
+    /// without one if it is not set
+    blocked_message: Option<Chat<'static>>,
+    statuses: StatusMap,
+    /// Set if players have to be authenticated before the backend is started
+    authenticator: Option<Authenticator>,
+    /// Compression is enabled for logins if set
+    compression_threshold: Option<usize>,
+    /// The disconnect message for players that start the backend
+    starting_message: Chat<'static>,
+    /// The disconnect message for players that could not be authenticated
+    unverified_message: Chat<'static>,
+    /// Set if only these players may start the backend
+    whitelist: Option<Whitelist>,
+    /// The disconnect message for players that are not whitelisted
+    not_whitelisted_message: Chat<'static>,
+    /// How long players are kept connected while the backend starts instead of being disconnected
+    /// right away. Clients give up on their own after about 30 seconds.
+    hold_timeout: Option<Duration>,
+    /// Set if modern clients are transferred back to the proxy instead of being disconnected, after
+    /// waiting at most this long for the backend. The backend has to accept transfers.
+    transfer_delay: Option<Duration>,
+    /// The disconnect message for players while the backend is failed
+    failed_message: Chat<'static>,
+    /// The disconnect message for players connecting through a host without a route
+    unknown_server_message: Chat<'static>,
+    /// The status for hosts without a route, which shows the unknown server message
+    unknown_server_status: StatusTemplate,
+    /// How the player's identity is passed on to the backend
+    forwarding: Forwarding,
+    /// The bytes per second forwarded in each direction of a connection, unlimited if not set
+    rate_limit: Option<u64>,
+    /// How long a forwarded connection may go without any bytes in either direction
+    connection_idle_timeout: Option<Duration>,
+    /// Options set on the sockets of clients and of connections to the backend
+    socket_options: SocketOptions,
+    /// How long a client may take to send anything up to and including the handshake
+    handshake_timeout: Duration,
+    /// How long a client may take to send each packet of the status exchange
+    status_timeout: Duration,
+    /// How long a client may take to send each packet during the login
+    login_timeout: Duration,
+    /// How long the backend may take to become reachable after it was started
+    start_timeout: Duration,
+    /// Whether a backend that does not become reachable in time is stopped
+    kill_on_start_timeout: bool,
+    /// Whether connections to the backend start with a PROXY protocol header with the client's
+    /// address. The backend has to expect the header.
+    proxy_protocol: bool,
+    /// The proxies in front of this one whose PROXY protocol headers are trusted
+    trusted_proxies: Vec<Cidr>,
+    /// The addresses clients may connect from, any address if empty
+    allowed: Vec<Cidr>,
+    /// The addresses clients may not connect from
+    denied: Vec<Cidr>,
+    metrics: Arc<Metrics>,
+    /// Limits the number of connections handled at the same time if set
+    connection_limit: Option<Arc<Semaphore>>,
+}
+
+impl Shared {
+    /// Returns the number of connections currently forwarded to any backend.
+    /// Each backend counts its connections to find out when it is idle.
+    fn active_connections(&self) -> usize {
+        self.backends
+            .backends()
+            .iter()
+            .map(|backend| backend.idle.active_connections())
+            .sum()
+    }
+
+    /// Returns the status of each backend that has been created. A backend that is running but
+    /// has not logged that it is ready yet counts as starting.
+    fn backend_states(&self) -> Vec<(String, ProcessStatus)> {
+        self.backends
+            .backends()
+            .iter()
+            .map(|backend| {
+                let status = match backend.process.status() {
+                    ProcessStatus::Running if !backend.process.is_ready() => {
+                        ProcessStatus::Starting
+                    }
+                    status => status,
+                };
+                (backend.id.clone(), status)
+            })
+            .collect()
+    }
+}
+
+#[instrument(skip_all)]
+async fn status_handler<'a, Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
     mut reader: FramedRead<Read, PacketDecoder<status::ServerBound>>,
-    mut writer: FramedWrite<Write, PacketEncoder<status::ClientBound<'_>>>,
+    mut writer: FramedWrite<Write, PacketEncoder<status::ClientBound<'a>>>,
+    json_response: &'a str,
+    read_timeout: Duration,
+    budget: Duration,
 ) -> Result<(), Error> {
+    // Besides the timeout for each read, the whole exchange has to finish within the budget, so a
+    // client can not keep the connection open by trickling in packets.
+    let deadline = Instant::now() + budget;
     let mut status_sent = false;
     let mut ping_sent = false;
-    while let Some(req) = timeout(Duration::from_secs(5), reader.next()).await?
-        && !ping_sent
-    {
-        // TODO: When up, just forward
+    while !ping_sent {
+        let read_deadline = Instant::min(Instant::now() + read_timeout, deadline);
+        let Some(req) = timeout_at(read_deadline, reader.next()).await? else {
+            break;
+        };
         let req = req?;
         let resp = match *req {
             status::ServerBound::StatusRequest => {
@@ -57,7 +319,7 @@ async fn status_handler<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
                 }
                 status_sent = true;
                 status::ClientBound::StatusResponse {
-                    json_response: Cow::Borrowed(STATUS_RESPONSE),
+                    json_response: Cow::Borrowed(json_response),
                 }
             }
             status::ServerBound::PingRequest(timestamp) => {
@@ -72,89 +334,1116 @@ async fn status_handler<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
     Ok(())
 }
 
+/// Reads the next packet from a framed reader, treating the end of the stream as an error.
+async fn next_packet<Read: AsyncRead + Unpin, T>(
+    reader: &mut FramedRead<Read, PacketDecoder<T>>,
+    read_timeout: Duration,
+) -> Result<Packet<T>, Error>
+where
+    PacketDecoder<T>: Decoder<Item = Packet<T>, Error = ProtocolError>,
+{
+    timeout(read_timeout, reader.next())
+        .await?
+        .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))?
+        .map_err(Error::from)
+}
+
+/// Sends a disconnect packet with the given reason and closes the connection.
+/// Compression is enabled beforehand if a threshold is given.
+async fn disconnect<Write: AsyncWrite + Unpin>(
+    mut writer: FramedWrite<Write, PacketEncoder<login::ClientBound<'_>>>,
+    reason: &Chat<'_>,
+    compression_threshold: Option<usize>,
+) -> Result<(), Error> {
+    if let Some(threshold) = compression_threshold {
+        let threshold = i32::try_from(threshold).unwrap_or(i32::MAX);
+        writer
+            .send(login::ClientBound::SetCompression(threshold))
+            .await?;
+        writer.encoder_mut().enable_compression(threshold as usize);
+    }
+    writer
+        .send(login::ClientBound::Disconnect(reason.clone()))
+        .await?;
+    writer.close().await?;
+    Ok(())
+}
+
+/// Performs the encryption handshake with the client and asks the session server whether the player
+/// is who they claim to be. Returns the player's profile if they are, along with the shared secret
+/// that everything sent afterwards is encrypted with.
+async fn authenticate<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
+    reader: &mut FramedRead<Read, PacketDecoder<login::ServerBound<'_>>>,
+    writer: &mut FramedWrite<Write, PacketEncoder<login::ClientBound<'_>>>,
+    authenticator: &Authenticator,
+    name: &str,
+    read_timeout: Duration,
+) -> Result<(Option<GameProfile>, [u8; 16]), Error> {
+    let verify_token = Authenticator::verify_token();
+    writer
+        .send(login::ClientBound::EncryptionRequest(
+            login::EncryptionRequest {
+                server_id: Cow::Borrowed(""),
+                public_key: Cow::Owned(authenticator.public_key().to_vec()),
+                verify_token: Cow::Owned(verify_token.to_vec()),
+                should_authenticate: true,
+            },
+        ))
+        .await?;
+
+    let packet = next_packet(reader, read_timeout).await?;
+    let login::ServerBound::EncryptionResponse(ref response) = *packet else {
+        return Err("expected an encryption response packet".into());
+    };
+    let shared_secret = authenticator.shared_secret(response, &verify_token)?;
+    drop(packet);
+
+    let profile = authenticator.authenticate(name, &shared_secret).await?;
+    Ok((profile, shared_secret))
+}
+
 #[instrument(skip_all)]
 async fn login_handler<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
     mut reader: FramedRead<Read, PacketDecoder<login::ServerBound<'_>>>,
     mut writer: FramedWrite<Write, PacketEncoder<login::ClientBound<'_>>>,
+    handshake: &Packet<HandshakePacket<'_>>,
+    peer: &Peer,
+    local: Option<SocketAddr>,
+    shared: &Arc<Shared>,
+    backend: &Arc<Backend>,
 ) -> Result<(), Error> {
-    let mut disconnect_sent = false;
-    while let Some(req) = timeout(Duration::from_secs(5), reader.next()).await?
-        && !disconnect_sent
-    {
-        let req = req?;
-        let resp = match *req {
-            login::ServerBound::LoginStart(ref login_start) => {
-                tracing::info!(
-                    name = display(&login_start.name),
-                    uuid = display(login_start.uuid),
-                    "Player connected"
-                );
-                disconnect_sent = true;
-                login::ClientBound::Disconnect(Cow::Borrowed(
-                    "\"Server is starting, please try again later\"",
-                ))
+    let version = handshake.version;
+    if !version.is_modern() {
+        // The disconnect message is still understood, anything beyond that may not be
+        tracing::debug!(%version, "Client uses an older protocol version");
+    }
+
+    let packet = next_packet(&mut reader, shared.login_timeout).await?;
+    let login_start = match *packet {
+        login::ServerBound::LoginStart(ref login_start) => login_start,
+        // Only valid after a login success
+        login::ServerBound::LoginAcknowledged => {
+            return Err("client acknowledged a login that did not succeed".into());
+        }
+        _ => return Err("expected a login start packet".into()),
+    };
+    tracing::info!(
+        name = display(&login_start.name),
+        uuid = display(login_start.uuid),
+        version = %version,
+        "Player connected"
+    );
+    let name = login_start.name.to_string();
+    let uuid = login_start.uuid;
+    let login_start = packet.buffer();
+    drop(packet);
+    access_log::record(|entry| entry.name = Some(name.clone()));
+
+    let Some(authenticator) = &shared.authenticator else {
+        if !is_whitelisted(shared, &name, uuid) {
+            let message = &shared.not_whitelisted_message;
+            return disconnect(writer, message, shared.compression_threshold).await;
+        }
+        let profile = forwarding::offline_profile(&name);
+        let player = Player::new(&name, uuid);
+        if let Some(mut forward) =
+            hold_player(shared, backend, handshake, peer, local, &player).await
+        {
+            let _connection = backend.idle.connection();
+            access_log::record(|entry| entry.action = Action::Held);
+            let reader = Cursor::new(reader.read_buffer_mut().split()).chain(reader.into_inner());
+            if matches!(shared.forwarding, Forwarding::None) {
+                forward_handshake(&mut forward, handshake, None).await?;
+                forward.write_all(&login_start).await?;
+                access_log::record_bytes(login_start.len() as u64, 0);
+                return relay(io::join(reader, writer.into_inner()), forward, shared).await;
             }
-        };
-        writer.send(resp).await?;
+            return forward_login(
+                reader,
+                writer.into_inner(),
+                forward,
+                handshake,
+                peer,
+                &profile,
+                shared,
+            )
+            .await;
+        }
+        return send_away(reader, writer, handshake, &profile, peer, shared, backend).await;
+    };
+
+    let (profile, shared_secret) = authenticate(
+        &mut reader,
+        &mut writer,
+        authenticator,
+        &name,
+        shared.login_timeout,
+    )
+    .await?;
+    // Bytes the client sent after the encryption response are already encrypted
+    let reader = EncryptedStream::new(
+        Cursor::new(reader.read_buffer_mut().split()).chain(reader.into_inner()),
+        &shared_secret,
+    );
+    let writer = FramedWrite::new(
+        EncryptedStream::new(writer.into_inner(), &shared_secret),
+        PacketEncoder::new(),
+    );
+
+    match profile {
+        Some(profile) => {
+            tracing::info!(name = %profile.name, uuid = %profile.id, "Player authenticated");
+            // The UUID sent by the client is only trusted once the player is authenticated
+            if !is_whitelisted(shared, &profile.name, profile.id) {
+                let message = &shared.not_whitelisted_message;
+                return disconnect(writer, message, shared.compression_threshold).await;
+            }
+            // Only reached with forwarding enabled, as the backend could not authenticate the
+            // player on an already encrypted connection otherwise
+            let player = Player::new(&profile.name, profile.id);
+            if let Some(forward) =
+                hold_player(shared, backend, handshake, peer, local, &player).await
+            {
+                let _connection = backend.idle.connection();
+                access_log::record(|entry| entry.action = Action::Held);
+                return forward_login(
+                    reader,
+                    writer.into_inner(),
+                    forward,
+                    handshake,
+                    peer,
+                    &profile,
+                    shared,
+                )
+                .await;
+            }
+            let reader = FramedRead::new(reader, PacketDecoder::new());
+            send_away(reader, writer, handshake, &profile, peer, shared, backend).await
+        }
+        None => {
+            tracing::info!(name = %name, "Player could not be authenticated");
+            access_log::record(|entry| entry.action = Action::Unverified);
+            disconnect(
+                writer,
+                &shared.unverified_message,
+                shared.compression_threshold,
+            )
+            .await
+        }
     }
+}
+
+/// Starts the backend and sends the player away until it is ready. If transfers are enabled,
+/// modern clients are logged in and transferred back to the proxy once the backend is reachable or
+/// the transfer delay has elapsed. Everyone else is disconnected with a message.
+async fn send_away<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
+    mut reader: FramedRead<Read, PacketDecoder<login::ServerBound<'_>>>,
+    mut writer: FramedWrite<Write, PacketEncoder<login::ClientBound<'_>>>,
+    handshake: &HandshakePacket<'_>,
+    profile: &GameProfile,
+    peer: &Peer,
+    shared: &Arc<Shared>,
+    backend: &Arc<Backend>,
+) -> Result<(), Error> {
+    let message = join_backend(shared, backend, handshake, peer).await;
+    let transfer_delay = match shared.transfer_delay {
+        Some(delay) if handshake.version.is_modern() && backend.process.is_running() => delay,
+        _ => return disconnect(writer, message, shared.compression_threshold).await,
+    };
+
+    // The transfer packet is only understood in the configuration state that follows the login
+    writer
+        .send(login::ClientBound::LoginSuccess(login::LoginSuccess {
+            uuid: profile.id,
+            name: Cow::Borrowed(&profile.name),
+            properties: Vec::new(),
+        }))
+        .await?;
+    let packet = next_packet(&mut reader, shared.login_timeout).await?;
+    let login::ServerBound::LoginAcknowledged = *packet else {
+        return Err("expected a login acknowledged packet".into());
+    };
+    drop(packet);
 
+    let start = Instant::now();
+    let _ = probe::wait_reachable(backend, PROBE_INTERVAL, transfer_delay).await;
+    tracing::info!(
+        peer = %peer,
+        waited = ?start.elapsed(),
+        "Transferring the player back to the proxy"
+    );
+    access_log::record(|entry| entry.action = Action::Transferred);
+    let mut writer = FramedWrite::new(
+        writer.into_inner(),
+        PacketEncoder::<configuration::ClientBound<'_>>::new(),
+    );
+    writer
+        .send(configuration::ClientBound::Transfer(
+            configuration::Transfer {
+                host: Cow::Borrowed(handshake.host()),
+                port: handshake.port.into(),
+            },
+        ))
+        .await?;
+    writer.flush().await?;
+
+    // The client closes the connection once it received the packet. Closing it first could
+    // discard the packet due to the unread configuration packets sent by the client.
+    let mut reader = reader.into_inner();
+    let _ = timeout(shared.login_timeout, io::copy(&mut reader, &mut io::sink())).await;
     writer.close().await?;
     Ok(())
 }
 
+/// Returns whether the player may start the backend, which everyone may without a whitelist.
+fn is_whitelisted(shared: &Shared, name: &str, uuid: Uuid) -> bool {
+    let Some(whitelist) = &shared.whitelist else {
+        return true;
+    };
+    let whitelisted = whitelist.contains(name, uuid);
+    if !whitelisted {
+        tracing::info!(name = %name, uuid = %uuid, "Player is not whitelisted");
+        access_log::record(|entry| entry.action = Action::NotWhitelisted);
+    }
+    whitelisted
+}
+
+/// Logs a player into a backend that expects the player's identity to be forwarded.
+/// The proxy reads the login start itself and authenticates the player if online mode is enabled,
+/// so that it can pass the player's profile on before relaying the rest of the connection.
 #[instrument(skip_all)]
-async fn connection_handler(
+async fn forwarded_login<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
+    reader: Read,
+    writer: Write,
+    forward: TcpStream,
+    handshake: &Packet<HandshakePacket<'_>>,
+    peer: &Peer,
+    shared: &Shared,
+) -> Result<(), Error> {
+    let mut reader = FramedRead::new(reader, PacketDecoder::<login::ServerBound<'_>>::new());
+    let mut writer = FramedWrite::new(writer, PacketEncoder::<login::ClientBound<'_>>::new());
+
+    let packet = next_packet(&mut reader, shared.login_timeout).await?;
+    let login::ServerBound::LoginStart(ref login_start) = *packet else {
+        return Err("expected a login start packet".into());
+    };
+    let name = login_start.name.to_string();
+    drop(packet);
+    access_log::record(|entry| entry.name = Some(name.clone()));
+
+    let Some(authenticator) = &shared.authenticator else {
+        let profile = forwarding::offline_profile(&name);
+        let reader = Cursor::new(reader.read_buffer_mut().split()).chain(reader.into_inner());
+        return forward_login(
+            reader,
+            writer.into_inner(),
+            forward,
+            handshake,
+            peer,
+            &profile,
+            shared,
+        )
+        .await;
+    };
+
+    let (profile, shared_secret) = authenticate(
+        &mut reader,
+        &mut writer,
+        authenticator,
+        &name,
+        shared.login_timeout,
+    )
+    .await?;
+    // Bytes the client sent after the encryption response are already encrypted
+    let reader = EncryptedStream::new(
+        Cursor::new(reader.read_buffer_mut().split()).chain(reader.into_inner()),
+        &shared_secret,
+    );
+    let writer = EncryptedStream::new(writer.into_inner(), &shared_secret);
+
+    match profile {
+        Some(profile) => {
+            tracing::info!(name = %profile.name, uuid = %profile.id, "Player authenticated");
+            forward_login(reader, writer, forward, handshake, peer, &profile, shared).await
+        }
+        None => {
+            tracing::info!(name = %name, "Player could not be authenticated");
+            access_log::record(|entry| entry.action = Action::Unverified);
+            let writer = FramedWrite::new(writer, PacketEncoder::new());
+            disconnect(writer, &shared.unverified_message, None).await
+        }
+    }
+}
+
+/// Logs the player into the backend, forwarding their identity, and relays the connection.
+async fn forward_login<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
+    reader: Read,
+    mut writer: Write,
+    mut forward: TcpStream,
+    handshake: &Packet<HandshakePacket<'_>>,
+    peer: &Peer,
+    profile: &GameProfile,
+    shared: &Shared,
+) -> Result<(), Error> {
+    let forwarding = &shared.forwarding;
+    let rewritten = match forwarding {
+        Forwarding::Bungee => Some(HandshakePacket {
+            address: Cow::Owned(forwarding::bungee_address(
+                handshake.host(),
+                peer.ip(),
+                profile,
+            )),
+            ..**handshake
+        }),
+        _ => None,
+    };
+    forward_handshake(&mut forward, handshake, rewritten.as_ref()).await?;
+    let login_start = write_packet(
+        &mut forward,
+        &login::ServerBound::LoginStart(login::LoginStart {
+            name: Cow::Borrowed(&profile.name),
+            uuid: profile.id,
+        }),
+    )
+    .await?;
+    access_log::record_bytes(login_start as u64, 0);
+
+    if let Forwarding::Velocity { secret } = forwarding {
+        let (packet, leftover) =
+            read_single_packet::<login::ClientBound<'_>>(&mut forward, client::RESPONSE_TIMEOUT)
+                .await?;
+        match &*packet {
+            login::ClientBound::LoginPluginRequest(request)
+                if request.channel == forwarding::VELOCITY_CHANNEL =>
+            {
+                let data = forwarding::velocity_player_info(secret, peer.ip(), profile);
+                write_packet(
+                    &mut forward,
+                    &login::ServerBound::LoginPluginResponse(login::LoginPluginResponse {
+                        message_id: request.message_id,
+                        successful: true,
+                        data: Some(Cow::Owned(data)),
+                    }),
+                )
+                .await?;
+            }
+            _ => {
+                // The client gets to see whatever the backend sent instead
+                tracing::warn!("Backend did not ask for the player's information");
+                let buffer = packet.buffer();
+                writer.write_all(&buffer).await?;
+                access_log::record_bytes(0, buffer.len() as u64);
+            }
+        }
+        writer.write_all(&leftover).await?;
+        access_log::record_bytes(0, leftover.len() as u64);
+    }
+
+    relay(io::join(reader, writer), forward, shared).await
+}
+
+/// Relays data between the client and the backend until both of them closed the connection or
+/// the connection is idle for too long.
+async fn relay(
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    mut forward: TcpStream,
+    shared: &Shared,
+) -> Result<(), Error> {
+    let idle_timeout = shared.connection_idle_timeout;
+    let (received, sent, result) = match shared.rate_limit {
+        Some(rate) => relay::copy(Throttled::new(client, rate), &mut forward, idle_timeout).await,
+        None => relay::copy(client, &mut forward, idle_timeout).await,
+    };
+    access_log::record_bytes(received, sent);
+    Ok(result?)
+}
+
+/// Resets failed backends whenever the proxy receives `SIGUSR1`, so they are started again.
+async fn reset_handler(shared: Arc<Shared>) -> Result<(), Error> {
+    let mut reset = signal(SignalKind::user_defined1())?;
+    while reset.recv().await.is_some() {
+        let mut any = false;
+        for backend in shared.backends.backends() {
+            if backend.process.reset().await {
+                tracing::info!(backend = %backend.id, "Failed backend was reset");
+                any = true;
+            }
+        }
+        if !any {
+            tracing::info!("No backend has failed, nothing to reset");
+        }
+    }
+    Ok(())
+}
+
+/// Reloads the whitelist whenever the proxy receives `SIGHUP`.
+async fn whitelist_handler(shared: Arc<Shared>) -> Result<(), Error> {
+    let mut reload = signal(SignalKind::hangup())?;
+    while reload.recv().await.is_some() {
+        let Some(whitelist) = &shared.whitelist else {
+            tracing::info!("No whitelist is configured, nothing to reload");
+            continue;
+        };
+        match whitelist.reload() {
+            Ok(()) => tracing::info!(entries = whitelist.len(), "Whitelist reloaded"),
+            Err(error) => tracing::error!(%error, "Could not reload the whitelist"),
+        }
+    }
+    Ok(())
+}
+
+/// Accepts connections until accepting fails or the proxy shuts down and handles each of them in a
+/// task tracked by `connections`.
+async fn accept_loop(
+    listener: Listener,
+    shared: Arc<Shared>,
+    connections: TaskTracker,
+    shutdown: CancellationToken,
+) -> Result<(), Error> {
+    loop {
+        let connection = tokio::select! {
+            connection = listener.accept() => connection?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let accepted = Instant::now();
+        shared.metrics.connection_accepted();
+        let peer = connection.peer();
+        if !is_allowed(&shared, peer.ip()) {
+            tracing::debug!(%peer, "Rejected connection from a disallowed address");
+            AccessEntry::new(&peer, Action::Denied).log();
+            continue;
+        }
+        tracing::debug!(%peer, "Accepted connection");
+        // The permit is held until the handler finishes
+        let permit = match &shared.connection_limit {
+            Some(limit) => match Arc::clone(limit).try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    tracing::warn!(%peer, "Connection limit reached, dropping the connection");
+                    AccessEntry::new(&peer, Action::Limited).log();
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let shared = Arc::clone(&shared);
+        match connection {
+            Connection::Tcp(socket, address) => spawn_connection(
+                &connections,
+                Arc::clone(&shared.metrics),
+                peer,
+                accepted,
+                permit,
+                tcp_connection_handler(socket, address, shared),
+            ),
+            Connection::Unix(socket) => spawn_connection(
+                &connections,
+                Arc::clone(&shared.metrics),
+                peer,
+                accepted,
+                permit,
+                connection_handler(socket, &Peer::Unix, None, shared),
+            ),
+        }
+    }
+}
+
+/// Handles a connection in a task. Everything logged while handling it is tagged with an id
+/// unique to the connection, so that log lines of concurrent connections can be told apart.
+/// `accepted` is when the connection was accepted, from which its duration is measured.
+fn spawn_connection(
+    connections: &TaskTracker,
+    metrics: Arc<Metrics>,
+    peer: Peer,
+    accepted: Instant,
+    permit: Option<OwnedSemaphorePermit>,
+    handler: impl Future<Output = Result<(), Error>> + Send + 'static,
+) {
+    static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+    let conn_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let span = tracing::info_span!("connection", conn_id, action = tracing::field::Empty);
+    connections.spawn(
+        async move {
+            let _permit = permit;
+            let handler = async {
+                if let Err(err) = handler.await {
+                    tracing::error!(error = %err, peer = %peer, "Error in connection handler")
+                }
+            };
+            access_log::scope(&peer, accepted, handler, |entry| {
+                metrics.connection_closed(entry)
+            })
+            .await;
+        }
+        .instrument(span),
+    );
+}
+
+/// Returns whether a client at the address may connect. Denied addresses are rejected even if
+/// they are also allowed, and all addresses are allowed while the allow list is empty.
+fn is_allowed(shared: &Shared, address: IpAddr) -> bool {
+    if shared
+        .denied
+        .iter()
+        .any(|network| network.contains(address))
+    {
+        return false;
+    }
+    shared.allowed.is_empty()
+        || shared
+            .allowed
+            .iter()
+            .any(|network| network.contains(address))
+}
+
+/// Waits until the proxy is asked to exit with Ctrl+C or `SIGTERM`.
+async fn shutdown_signal() -> Result<(), Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+/// Starts the backend for a player that wants to join and returns the disconnect message for them.
+async fn join_backend<'a>(
+    shared: &'a Arc<Shared>,
+    backend: &Arc<Backend>,
+    handshake: &HandshakePacket<'_>,
+    peer: &Peer,
+) -> &'a Chat<'static> {
+    match start_backend(shared, backend, handshake, peer).await {
+        ServerState::Failed => {
+            access_log::record(|entry| entry.action = Action::Failed);
+            &shared.failed_message
+        }
+        _ => {
+            access_log::record(|entry| entry.action = Action::Started);
+            backend.waiting_players.fetch_add(1, Ordering::Relaxed);
+            &shared.starting_message
+        }
+    }
+}
+
+/// Starts the backend and keeps the player connected until it is reachable if hold mode is enabled.
+/// Returns a connection to the backend, or `None` if the player has to be disconnected instead.
+async fn hold_player(
+    shared: &Arc<Shared>,
+    backend: &Arc<Backend>,
+    handshake: &HandshakePacket<'_>,
+    peer: &Peer,
+    local: Option<SocketAddr>,
+    player: &Player,
+) -> Option<TcpStream> {
+    let hold_timeout = shared.hold_timeout?;
+    if start_backend(shared, backend, handshake, peer).await != ServerState::Starting {
+        return None;
+    }
+
+    tracing::info!(peer = %peer, "Holding the player until the backend is ready");
+    if probe::wait_reachable(backend, PROBE_INTERVAL, hold_timeout)
+        .await
+        .is_err()
+    {
+        tracing::info!(peer = %peer, "Backend did not become ready while holding the player");
+        return None;
+    }
+    match connect_backend(shared, backend, peer, local, Some(player)).await {
+        Ok(forward) => Some(forward),
+        Err(error) => {
+            tracing::warn!(%error, "Could not connect to the backend after holding the player");
+            None
+        }
+    }
+}
+
+/// Runs the start command for the client that sent the handshake unless the backend is already
+/// starting and returns its state.
+async fn start_backend(
+    shared: &Arc<Shared>,
+    backend: &Arc<Backend>,
+    handshake: &HandshakePacket<'_>,
+    peer: &Peer,
+) -> ServerState {
+    tracing::debug!(peer = %peer, backend = %backend.id, "Running start command");
+    // A joining client is waiting as well, although it is only counted after the start
+    let joining = handshake.next_state != NextState::Status;
+    let trigger = Trigger {
+        host: handshake.host().to_owned(),
+        port: handshake.port,
+        client_ip: peer.ip(),
+        waiting: backend.waiting_players.load(Ordering::Relaxed) + usize::from(joining),
+    };
+    let start = Instant::now();
+    match backend.process.spawn_once(&trigger).await {
+        Ok(Spawn::Started) => {
+            tracing::debug!(peer = %peer, "Connection started the backend");
+            shared.metrics.start_command_run();
+            backend.waiting_players.store(0, Ordering::Relaxed);
+            backend.idle.reset();
+            task::spawn(watch_startup(
+                Arc::clone(shared),
+                Arc::clone(backend),
+                start,
+            ));
+        }
+        Ok(Spawn::CoolingDown) => {
+            tracing::debug!(peer = %peer, "Start command was run recently, not running it again");
+        }
+        Ok(Spawn::Stopping) => {
+            tracing::debug!(peer = %peer, "Backend is being stopped, not starting it again yet");
+        }
+        Ok(Spawn::Running | Spawn::Failed) => {}
+        Err(error) => tracing::error!(%error, "Could not run start command"),
+    }
+
+    // The start command may have failed or exited right away, so we look at the process itself
+    process_state(backend)
+}
+
+/// Returns the state of a backend that is not reachable according to its process.
+fn process_state(backend: &Backend) -> ServerState {
+    match backend.process.status() {
+        ProcessStatus::Starting | ProcessStatus::Running => ServerState::Starting,
+        ProcessStatus::NotStarted | ProcessStatus::Stopping | ProcessStatus::Exited(_) => {
+            ServerState::Offline
+        }
+        ProcessStatus::Failed => ServerState::Failed,
+    }
+}
+
+/// Returns the status of the default route for queries. It matches the status shown in the server
+/// list, except that the backend is not started.
+async fn query_status(shared: &Shared) -> Result<QueryStatus, Error> {
+    let backend = shared.backends.get(DEFAULT_BACKEND)?;
+    if let Some(status) = backend.status_cache.fresh() {
+        return Ok(QueryStatus::from_status(&status));
+    }
+    let state = match backend.connect().await {
+        Ok(_) => ServerState::Online,
+        Err(_) => process_state(&backend),
+    };
+    let status = shared.statuses.default_status().render(
+        ProtocolVersion::V1_21_7,
+        state,
+        backend.waiting_players.load(Ordering::Relaxed),
+    );
+    Ok(QueryStatus::from_status(&status))
+}
+
+/// Waits for a backend that was started at `start` to become reachable and records how long that
+/// took. A backend that is not reachable within the start timeout is considered stuck, which is
+/// logged and optionally ends the start command.
+async fn watch_startup(shared: Arc<Shared>, backend: Arc<Backend>, start: Instant) {
+    match probe::wait_reachable(&backend, PROBE_INTERVAL, shared.start_timeout).await {
+        Ok(_) => {
+            let elapsed = start.elapsed();
+            tracing::info!(backend = %backend.id, ?elapsed, "Backend is ready");
+            shared.metrics.backend_started(&backend.id, elapsed);
+            lifecycle::emit(&backend.id, Event::Ready);
+        }
+        Err(_) => {
+            tracing::error!(
+                timeout = ?shared.start_timeout,
+                "Backend did not become reachable after starting it"
+            );
+            if shared.kill_on_start_timeout {
+                tracing::info!("Stopping the backend that did not start");
+                if let Err(error) = backend.process.shutdown().await {
+                    tracing::error!(%error, "Could not stop the backend");
+                }
+            }
+        }
+    }
+}
+
+/// Returns the address of the client. Connections from trusted proxies have to start with a PROXY
+/// protocol header containing the address, headers from anyone else are rejected.
+async fn client_address(
+    socket: &mut TcpStream,
+    peer: SocketAddr,
+    shared: &Shared,
+) -> Result<SocketAddr, Error> {
+    if shared.trusted_proxies.is_empty() {
+        return Ok(peer);
+    }
+    if !shared
+        .trusted_proxies
+        .iter()
+        .any(|network| network.contains(peer.ip()))
+    {
+        if timeout(shared.handshake_timeout, proxy_protocol::has_header(socket)).await?? {
+            return Err("received a PROXY protocol header from an untrusted peer".into());
+        }
+        return Ok(peer);
+    }
+
+    match timeout(
+        shared.handshake_timeout,
+        proxy_protocol::read_header(socket),
+    )
+    .await??
+    {
+        Some(client) => {
+            tracing::debug!(proxy = %peer, client = %client, "Received client address from proxy");
+            Ok(client)
+        }
+        None => Ok(peer),
+    }
+}
+
+/// Connects to the backend on behalf of a client at `peer` that connected to the proxy at `local`.
+async fn connect_backend(
+    shared: &Shared,
+    backend: &Backend,
+    peer: &Peer,
+    local: Option<SocketAddr>,
+    player: Option<&Player>,
+) -> io::Result<TcpStream> {
+    let mut forward = backend.connect_player(player).await?;
+    shared.socket_options.apply(&forward)?;
+    if shared.proxy_protocol {
+        let header = match peer.socket_addr().zip(local) {
+            Some((peer, local)) => proxy_protocol::v2_header(peer, local),
+            None => proxy_protocol::v2_local_header(),
+        };
+        forward.write_all(&header).await?;
+    }
+    Ok(forward)
+}
+
+/// Sends the handshake to the backend.
+/// A rewritten handshake is encoded from scratch, otherwise the bytes received from the client are
+/// replayed as is.
+async fn forward_handshake(
+    forward: &mut (impl AsyncWrite + Unpin),
+    original: &Packet<HandshakePacket<'_>>,
+    rewritten: Option<&HandshakePacket<'_>>,
+) -> Result<(), Error> {
+    let written = match rewritten {
+        Some(handshake) => write_packet(forward, handshake).await?,
+        None => {
+            let buffer = original.buffer();
+            forward.write_all(&buffer).await?;
+            buffer.len()
+        }
+    };
+    access_log::record_bytes(written as u64, 0);
+    Ok(())
+}
+
+/// Returns the status of the backend if it is up, using a cached response if there is one.
+/// If the backend is up but does not answer the status request, the configured status is returned.
+async fn live_status(
+    handshake: &Packet<HandshakePacket<'_>>,
+    shared: &Arc<Shared>,
+    backend: &Arc<Backend>,
+    peer: &Peer,
+    local: Option<SocketAddr>,
+) -> Option<Arc<str>> {
+    if let Some((status, refresh)) = backend.status_cache.get() {
+        if refresh {
+            let shared = Arc::clone(shared);
+            let backend = Arc::clone(backend);
+            let (handshake, peer) = (handshake.buffer(), *peer);
+            task::spawn(
+                async move { refresh_status(&shared, &backend, &handshake, &peer, local).await }
+                    .in_current_span(),
+            );
+        }
+        return Some(status);
+    }
+
+    let mut forward = connect_backend(shared, backend, peer, local, None)
+        .await
+        .ok()?;
+    match client::status(&mut forward, &handshake.buffer()).await {
+        Ok(status) => {
+            backend.status_cache.put(Arc::clone(&status));
+            Some(status)
+        }
+        Err(error) => {
+            tracing::warn!(%error, "Could not query the status of the backend");
+            let status = shared.statuses.get(handshake.host(), &backend.id).render(
+                handshake.version,
+                ServerState::Online,
+                0,
+            );
+            Some(Arc::from(status))
+        }
+    }
+}
+
+/// Queries the status of the backend again to replace the cached one. If that fails, the backend
+/// is likely down, so the cached status is dropped and the configured status is shown instead.
+async fn refresh_status(
+    shared: &Shared,
+    backend: &Backend,
+    handshake: &[u8],
+    peer: &Peer,
+    local: Option<SocketAddr>,
+) {
+    let status = async {
+        let mut forward = connect_backend(shared, backend, peer, local, None).await?;
+        client::status(&mut forward, handshake).await
+    };
+    match status.await {
+        Ok(status) => backend.status_cache.put(status),
+        Err(error) => {
+            tracing::debug!(%error, "Could not refresh the status of the backend");
+            backend.status_cache.clear();
+        }
+    }
+}
+
+/// Reads the login start the client sent after the handshake to find out who the player is. The
+/// returned buffer holds the packet followed by everything read after it, so the connection can be
+/// handled as if it was never read.
+async fn peek_player(
+    reader: &mut (impl AsyncRead + Unpin),
+    leftover: BytesMut,
+    read_timeout: Duration,
+) -> Result<(Option<Player>, BytesMut), Error> {
+    let mut reader = Cursor::new(leftover).chain(reader);
+    let (packet, rest) =
+        read_single_packet::<login::ServerBound<'_>>(&mut reader, read_timeout).await?;
+    let player = match &*packet {
+        login::ServerBound::LoginStart(login_start) => {
+            Some(Player::new(&login_start.name, login_start.uuid))
+        }
+        _ => None,
+    };
+
+    let (cursor, _) = reader.into_inner();
+    let mut buffer = BytesMut::from(&packet.buffer()[..]);
+    buffer.extend_from_slice(&rest);
+    buffer.extend_from_slice(&cursor.get_ref()[cursor.position() as usize..]);
+    Ok((player, buffer))
+}
+
+/// Handles a connection accepted on a TCP socket.
+async fn tcp_connection_handler(
     mut socket: TcpStream,
-    peer: &SocketAddr,
-    forward_addr: &SocketAddr,
-    start_command: Arc<ExternalProcess>,
+    peer: SocketAddr,
+    shared: Arc<Shared>,
 ) -> Result<(), Error> {
-    let (read_half, write_half) = socket.split();
+    shared.socket_options.apply(&socket)?;
+    let local = socket.local_addr()?;
+    let client = client_address(&mut socket, peer, &shared).await?;
+    // The accept loop only checked the proxy, so a client behind it is checked here
+    if client != peer && !is_allowed(&shared, client.ip()) {
+        let client = Peer::Tcp(client);
+        tracing::debug!(proxy = %peer, %client, "Rejected connection from a disallowed address");
+        AccessEntry::new(&client, Action::Denied).log();
+        return Ok(());
+    }
+    connection_handler(socket, &Peer::Tcp(client), Some(local), shared).await
+}
 
-    let mut reader = FramedRead::new(read_half, PacketDecoder::<HandshakePacket<'_>>::new());
-    // The FramedRead interface is not really ideal for single packets, but oh well
-    let handshake_packet = timeout(Duration::from_secs(5), reader.next())
-        .await?
-        .ok_or(io::Error::from(io::ErrorKind::UnexpectedEof))
-        .and_then(|r| r)?;
+/// Tells a client that connected through a host without a route that there is no server, either
+/// in the status response or by disconnecting it during login.
+async fn unknown_server<Read: AsyncRead + Unpin, Write: AsyncWrite + Unpin>(
+    reader: Read,
+    writer: Write,
+    leftover: BytesMut,
+    handshake: &HandshakePacket<'_>,
+    shared: &Shared,
+) -> Result<(), Error> {
+    match handshake.next_state {
+        NextState::Status => {
+            let status =
+                shared
+                    .unknown_server_status
+                    .render(handshake.version, ServerState::Offline, 0);
+            status_handler(
+                FramedRead::new(Cursor::new(leftover).chain(reader), PacketDecoder::new()),
+                FramedWrite::new(writer, PacketEncoder::new()),
+                &status,
+                shared.status_timeout,
+                STATUS_BUDGET,
+            )
+            .await
+        }
+        NextState::Login | NextState::Transfer => {
+            let writer = FramedWrite::new(writer, PacketEncoder::new());
+            disconnect(writer, &shared.unknown_server_message, None).await
+        }
+    }
+}
+
+/// Handles a client connection on a socket of any kind.
+/// `local` is the address the client connected to, if the socket has one.
+/// The client and the host it connected through are fields of the span, so that every event of
+/// the connection carries them.
+#[instrument(skip_all, fields(peer = %peer, host, next_state))]
+async fn connection_handler(
+    socket: impl AsyncRead + AsyncWrite + Unpin,
+    peer: &Peer,
+    local: Option<SocketAddr>,
+    shared: Arc<Shared>,
+) -> Result<(), Error> {
+    // Clients behind a trusted proxy are logged with their own address
+    access_log::record(|entry| entry.client = peer.ip());
+    let (mut read_half, write_half) = io::split(socket);
+    // The first bytes are read to detect legacy pings and are put in front of the rest again
+    let mut first = Vec::with_capacity(512);
+    if timeout(shared.handshake_timeout, read_half.read_buf(&mut first)).await?? == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if first[0] == legacy::LEGACY_PING {
+        tracing::info!(peer = %peer, "Handling legacy server list ping");
+        access_log::record(|entry| entry.action = Action::LegacyPing);
+        return legacy_ping_handler(first, read_half, write_half, peer, &shared).await;
+    }
+    // Scanners would otherwise keep the connection open until the handshake timeout
+    if !handshake::could_be_handshake(&first) {
+        tracing::debug!(peer = %peer, "Client does not speak the Minecraft protocol");
+        access_log::record(|entry| entry.action = Action::Invalid);
+        return Ok(());
+    }
+    let mut read_half = Cursor::new(first).chain(read_half);
+
+    let (handshake_packet, leftover) =
+        read_single_packet::<HandshakePacket<'_>>(&mut read_half, shared.handshake_timeout).await?;
 
     tracing::info!(
         peer = %peer,
-        server = %&handshake_packet.address,
+        server = %handshake_packet.host(),
         port = %handshake_packet.port,
         next_state = %handshake_packet.next_state,
         "Handling new connection from client"
     );
+    tracing::Span::current()
+        .record("host", handshake_packet.host())
+        .record(
+            "next_state",
+            tracing::field::display(handshake_packet.next_state),
+        );
+    access_log::record(|entry| {
+        entry.host = Some(handshake_packet.host().to_owned());
+        entry.next_state = Some(handshake_packet.next_state);
+    });
+
+    // Scanners probe random host names, which should not cost more than the handshake
+    if shared.blocked_hosts.contains(handshake_packet.host()) {
+        tracing::debug!(peer = %peer, server = %handshake_packet.host(), "Host is blocked");
+        access_log::record(|entry| entry.action = Action::Blocked);
+        return match &shared.blocked_message {
+            Some(message) if handshake_packet.next_state != NextState::Status => {
+                let writer = FramedWrite::new(write_half, PacketEncoder::new());
+                disconnect(writer, message, None).await
+            }
+            _ => Ok(()),
+        };
+    }
 
-    // TODO: At this point, we should look at the actual server location
-    if let Ok(mut forward) = TcpStream::connect(forward_addr).await {
+    let Some(id) = shared.router.route(handshake_packet.host()) else {
+        tracing::info!(peer = %peer, server = %handshake_packet.host(), "No route for server");
+        access_log::record(|entry| entry.action = Action::Unrouted);
+        return unknown_server(read_half, write_half, leftover, &handshake_packet, &shared).await;
+    };
+    let backend = shared.backends.get(id)?;
+    let next_state = handshake_packet.next_state;
+
+    // Returning players are sent to the address they were last forwarded to, which requires
+    // knowing who they are before connecting
+    let (player, leftover) = match next_state {
+        NextState::Login | NextState::Transfer if backend.sticky.is_some() => {
+            peek_player(&mut read_half, leftover, shared.login_timeout).await?
+        }
+        _ => (None, leftover),
+    };
+
+    // Status requests are answered by the proxy itself, using the backend's status if it is up
+    if next_state == NextState::Status
+        && let Some(status) = live_status(&handshake_packet, &shared, &backend, peer, local).await
+    {
+        drop(handshake_packet);
+        access_log::record(|entry| entry.action = Action::Status);
+        let reader = Cursor::new(leftover).chain(read_half);
+        return status_handler(
+            FramedRead::new(reader, PacketDecoder::new()),
+            FramedWrite::new(write_half, PacketEncoder::new()),
+            &status,
+            shared.status_timeout,
+            STATUS_BUDGET,
+        )
+        .await;
+    }
+
+    if next_state != NextState::Status
+        && let Ok(mut forward) =
+            connect_backend(&shared, &backend, peer, local, player.as_ref()).await
+    {
+        let forward_addr = forward.peer_addr()?;
         tracing::debug!(peer = %peer, forward = %forward_addr, "Successfully connected to backend");
-        forward.write_all(&handshake_packet.buffer()).await?;
+        let _connection = backend.idle.connection();
+        access_log::record(|entry| entry.action = Action::Forwarded);
+        // The player's identity is only known once they sent the login start
+        if !matches!(shared.forwarding, Forwarding::None) {
+            let reader = Cursor::new(leftover).chain(read_half);
+            return forwarded_login(
+                reader,
+                write_half,
+                forward,
+                &handshake_packet,
+                peer,
+                &shared,
+            )
+            .await;
+        }
+
+        forward_handshake(&mut forward, &handshake_packet, None).await?;
         drop(handshake_packet);
+        forward.write_all(&leftover).await?;
+        access_log::record_bytes(leftover.len() as u64, 0);
 
-        io::copy_bidirectional(&mut socket, &mut forward).await?;
-        return Ok(());
+        return relay(io::join(read_half, write_half), forward, &shared).await;
     }
 
-    tracing::debug!(peer = %peer, backend = %forward_addr, "Forward is down, running start command");
-    start_command.spawn_once().await?;
+    tracing::debug!(peer = %peer, backend = %backend.id, "Forward is down");
 
-    // We drop the handshake packet as soon as possible to reclaim space in the receive buffer
-    let next_state = handshake_packet.next_state;
-    drop(handshake_packet);
+    let version = handshake_packet.version;
+    let status_template = shared.statuses.get(handshake_packet.host(), &backend.id);
 
+    // Packets the client sent right after the handshake are still in the leftover buffer
+    let reader = Cursor::new(leftover).chain(read_half);
     match next_state {
         NextState::Status => {
+            access_log::record(|entry| entry.action = Action::Status);
+            // Pings do not tell who is asking, so with a whitelist only players may start the
+            // backend
+            let state = match shared.whitelist {
+                Some(_) => process_state(&backend),
+                None => start_backend(&shared, &backend, &handshake_packet, peer).await,
+            };
+            // We drop the handshake packet as soon as possible to free its buffer
+            drop(handshake_packet);
+            let status = status_template.render(
+                version,
+                state,
+                backend.waiting_players.load(Ordering::Relaxed),
+            );
             status_handler(
-                reader.map_decoder(|_| PacketDecoder::new()),
+                FramedRead::new(reader, PacketDecoder::new()),
                 FramedWrite::new(write_half, PacketEncoder::new()),
+                &status,
+                shared.status_timeout,
+                STATUS_BUDGET,
             )
             .await?
         }
         NextState::Login | NextState::Transfer => {
+            // The handshake is replayed to the backend if the player is held until it is ready
             login_handler(
-                reader.map_decoder(|_| PacketDecoder::new()),
+                FramedRead::new(reader, PacketDecoder::new()),
                 FramedWrite::new(write_half, PacketEncoder::new()),
+                &handshake_packet,
+                peer,
+                local,
+                &shared,
+                &backend,
             )
             .await?
         }
@@ -165,34 +1454,360 @@ async fn connection_handler(
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt::init();
-
-    // Preliminary command line handling, will be improved later
-    let args = std::env::args().collect::<Vec<_>>();
-    if args.len() != 4 {
-        eprintln!(
-            "Usage: {} <listen address> <forward address> <start command>",
-            args[0]
+    let cli = Cli::parse();
+    let config = Config::load(cli.config.as_deref(), &cli.options)?;
+    if let Err(errors) = config.validate() {
+        for error in &errors {
+            eprintln!("error: {}", error);
+        }
+        return Err(Error::Other("the configuration is invalid".into()));
+    }
+    let _telemetry = LogOptions {
+        level: config.log_level.unwrap_or(Level::INFO),
+        format: config.log_format.unwrap_or(LogFormat::Full),
+        timestamps: !config.no_log_timestamps,
+        targets: !config.no_log_targets,
+        otlp_endpoint: config.otlp_endpoint.clone(),
+    }
+    .init()?;
+
+    run(config, shutdown_signal()).await
+}
+
+/// Runs the proxy with a valid configuration until accepting connections fails or `shutdown`
+/// completes, then stops the backends it started and waits for the connections to finish.
+async fn run(
+    config: Config,
+    shutdown_signal: impl Future<Output = Result<(), Error>>,
+) -> Result<(), Error> {
+    let socket_options = SocketOptions {
+        nodelay: !config.no_tcp_nodelay,
+        // A keepalive time of zero disables keepalive
+        keepalive: match config.keepalive_time.unwrap_or(DEFAULT_KEEPALIVE_TIME) {
+            time if time.is_zero() => None,
+            time => Some((
+                time,
+                config
+                    .keepalive_interval
+                    .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL),
+            )),
+        },
+    };
+    let whitelist = match &config.whitelist {
+        Some(path) => Some(Whitelist::load(path.clone())?),
+        None => None,
+    };
+    let forwarding = match &config.velocity_secret_file {
+        Some(path) => {
+            let secret = std::fs::read_to_string(path)?;
+            Forwarding::Velocity {
+                secret: secret.trim().as_bytes().to_vec(),
+            }
+        }
+        None if config.bungee_forwarding => Forwarding::Bungee,
+        None => Forwarding::None,
+    };
+    let starting_message = chat::parse_message(
+        config
+            .starting_message
+            .as_deref()
+            .unwrap_or(STARTING_MESSAGE),
+    )?;
+    let failed_message =
+        chat::parse_message(config.failed_message.as_deref().unwrap_or(FAILED_MESSAGE))?;
+    let unknown_server_message = chat::parse_message(
+        config
+            .unknown_server_message
+            .as_deref()
+            .unwrap_or(UNKNOWN_SERVER_MESSAGE),
+    )?;
+    let unknown_server_status = StatusTemplate::message(unknown_server_message.to_value());
+
+    let resolver = Arc::new(Resolver::new(
+        Box::new(SystemDns::new()?),
+        resolver::DEFAULT_CACHE_TTL,
+    ));
+    let favicon = config.favicon.as_deref();
+    let mut statuses = StatusMap::new(server_status::load_status(
+        config.status.as_deref(),
+        favicon,
+    ));
+    for (host, path) in &config.host_status {
+        statuses.insert(host, server_status::load_status(Some(path), favicon));
+    }
+    // Routes without a status of their own get the global one with their favicon and MOTD
+    for (host, route) in &config.route {
+        if route.status.is_none() && route.favicon.is_none() && route.motd.is_none() {
+            continue;
+        }
+        let mut status = server_status::load_status(
+            route.status.as_deref().or(config.status.as_deref()),
+            route.favicon.as_deref().or(favicon),
         );
-        return Err("invalid command line arguments".into());
+        if let Some(motd) = &route.motd {
+            status.set_description(chat::parse_message(motd)?.to_value());
+        }
+        statuses.insert_route(&route_id(host), status);
     }
 
-    let listen_addr =
-        SocketAddr::from_str(&args[1]).map_err(|_| "could not parse listen address")?;
-    let forward_addr =
-        SocketAddr::from_str(&args[2]).map_err(|_| "could not parse forward address")?;
-    let start_command = Arc::new(ExternalProcess::new(args[3].clone()));
+    // The route of each backend by its id
+    let mut routes: HashMap<String, Route> = config
+        .route
+        .iter()
+        .map(|(host, route)| (route_id(host), route.clone()))
+        .collect();
+    let blocked_hosts = HostList::new(config.block_host.iter().map(String::as_str))?;
+    let blocked_message = config
+        .blocked_message
+        .as_deref()
+        .map(chat::parse_message)
+        .transpose()?;
+    let router = Router::new(
+        config.route.iter().map(|(host, _)| host.as_str()),
+        config.start_command.is_some(),
+    )?;
+    if let Some(start_command) = &config.start_command {
+        let route = Route {
+            forward: config.forward.clone(),
+            start_command: start_command.clone(),
+            status: None,
+            env: Vec::new(),
+            favicon: None,
+            motd: None,
+            working_dir: None,
+        };
+        routes.insert(DEFAULT_BACKEND.to_owned(), route);
+    }
+    let start_strategy = config.start_strategy.unwrap_or(Strategy::Command);
+    let stop_timeout = config.stop_timeout.unwrap_or(DEFAULT_STOP_TIMEOUT);
+    let restart = config.restart.unwrap_or(Restart::Never);
+    let max_restarts = config.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS);
+    let restart_window = config.restart_window.unwrap_or(DEFAULT_RESTART_WINDOW);
+    let start_cooldown = config.start_cooldown.unwrap_or_default();
+    let ready_pattern = config
+        .ready_pattern
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .map_err(|error| Error::Other(error.into()))?;
+    let stop_command = config.stop_command.clone();
+    let pre_start_command = config.pre_start_command.clone();
+    let post_stop_command = config.post_stop_command.clone();
+    let clear_env = config.clear_env;
+    let working_dir = config.working_dir.clone();
+    let env = config.env.clone();
+    let sticky_sessions = config.sticky_sessions;
+    let connect_timeout = config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    let idle_timeout = config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    let status_cache_ttl = config.status_cache_ttl.unwrap_or(DEFAULT_STATUS_CACHE_TTL);
+    let backend_ids: Vec<String> = routes.keys().cloned().collect();
+    let backends = ProcessRegistry::new(move |id| {
+        let route = routes.get(id).ok_or("no route for the backend")?;
+        // Variables of the route come last, so they take precedence
+        let route_env = env.iter().chain(&route.env);
+        let process: Box<dyn StartStrategy> = match start_strategy {
+            Strategy::Docker => {
+                let mut container = DockerContainer::new(route.start_command.clone())
+                    .with_name(id)
+                    .with_stop_timeout(stop_timeout)
+                    .with_start_cooldown(start_cooldown);
+                for (key, value) in route_env {
+                    container = container.with_env(key.clone(), value.clone());
+                }
+                Box::new(container)
+            }
+            Strategy::Command => {
+                let mut process = ExternalProcess::new(route.start_command.clone())?
+                    .with_name(id)
+                    .with_stop_timeout(stop_timeout)
+                    .with_restart(restart, max_restarts, restart_window)
+                    .with_start_cooldown(start_cooldown);
+                if let Some(ready_pattern) = &ready_pattern {
+                    process = process.with_ready_pattern(ready_pattern.clone());
+                }
+                if let Some(stop_command) = &stop_command {
+                    process = process.with_stop_command(stop_command.clone())?;
+                }
+                if let Some(pre_start_command) = &pre_start_command {
+                    process = process.with_pre_start_command(pre_start_command.clone())?;
+                }
+                if let Some(post_stop_command) = &post_stop_command {
+                    process = process.with_post_stop_command(post_stop_command.clone())?;
+                }
+                if clear_env {
+                    process = process.with_cleared_env();
+                }
+                if let Some(working_dir) = route.working_dir.as_ref().or(working_dir.as_ref()) {
+                    process = process.with_current_dir(working_dir.clone());
+                }
+                for (key, value) in route_env {
+                    process = process.with_env(key.clone(), value.clone());
+                }
+                Box::new(process)
+            }
+        };
 
-    let listener = TcpListener::bind(listen_addr).await?;
-    tracing::info!(address = %listen_addr, "Accepting TCP connections");
+        Ok(Backend {
+            id: id.to_owned(),
+            addresses: route.forward.clone(),
+            resolver: Arc::clone(&resolver),
+            balancer: Balancer::new(balancer::DEFAULT_FAILURE_BACKOFF),
+            sticky: sticky_sessions.map(StickySessions::new),
+            connect_timeout,
+            process,
+            // An idle timeout of zero keeps the backend running
+            idle: IdleMonitor::new(idle_timeout),
+            status_cache: StatusCache::new(status_cache_ttl),
+            waiting_players: AtomicUsize::new(0),
+        })
+    });
+    // Creating the backends right away reports errors in the commands at startup and stops
+    // backends that are already running if nobody joins
+    for id in &backend_ids {
+        backends.get(id)?;
+    }
 
-    loop {
-        let (socket, peer) = listener.accept().await?;
-        let cmd = Arc::clone(&start_command);
+    let shared = Arc::new(Shared {
+        backends,
+        router,
+        blocked_hosts,
+        blocked_message,
+        statuses,
+        authenticator: match config.online_mode {
+            true => Some(Authenticator::new(Box::new(MojangSessionService::new()))?),
+            false => None,
+        },
+        compression_threshold: config.compression_threshold,
+        starting_message,
+        unverified_message: Chat::Text(Cow::Borrowed(UNVERIFIED_MESSAGE)),
+        whitelist,
+        not_whitelisted_message: Chat::Text(Cow::Borrowed(NOT_WHITELISTED_MESSAGE)),
+        failed_message,
+        unknown_server_message,
+        unknown_server_status,
+        hold_timeout: config.hold_timeout,
+        transfer_delay: config.transfer_delay,
+        forwarding,
+        handshake_timeout: config.handshake_timeout.unwrap_or(DEFAULT_READ_TIMEOUT),
+        status_timeout: config.status_timeout.unwrap_or(DEFAULT_READ_TIMEOUT),
+        login_timeout: config.login_timeout.unwrap_or(DEFAULT_READ_TIMEOUT),
+        start_timeout: config.start_timeout.unwrap_or(DEFAULT_START_TIMEOUT),
+        kill_on_start_timeout: config.kill_on_start_timeout,
+        rate_limit: config.rate_limit.map(NonZeroU64::get),
+        connection_idle_timeout: config.connection_idle_timeout,
+        socket_options,
+        proxy_protocol: config.send_proxy_protocol,
+        trusted_proxies: config.trusted_proxy,
+        allowed: config.allow,
+        denied: config.deny,
+        metrics: Arc::new(Metrics::new()),
+        connection_limit: config
+            .max_connections
+            .map(|count| Arc::new(Semaphore::new(count))),
+    });
+
+    if let Some(address) = config.metrics_address {
+        let listener = TcpListener::bind(address).await.map_err(|error| {
+            Error::Other(format!("could not listen on {}: {}", address, error).into())
+        })?;
+        tracing::info!(%address, "Serving metrics");
+        let metrics = Arc::clone(&shared.metrics);
+        let metrics_shared = Arc::clone(&shared);
+        let active_connections = Arc::new({
+            let shared = Arc::clone(&metrics_shared);
+            move || shared.active_connections()
+        });
+        let backend_states = Arc::new(move || metrics_shared.backend_states());
+        task::spawn(async move {
+            let served = metrics::serve(listener, metrics, active_connections, backend_states);
+            if let Err(error) = served.await {
+                tracing::error!(%error, "Could not serve metrics");
+            }
+        });
+    }
+
+    if let Some(address) = config.query_address {
+        let socket = UdpSocket::bind(address).await.map_err(|error| {
+            Error::Other(format!("could not listen on {}: {}", address, error).into())
+        })?;
+        tracing::info!(%address, "Answering queries");
+        // Queries report the address players connect to, which is not known for Unix sockets
+        let server = config
+            .listen
+            .iter()
+            .find_map(|address| match address {
+                ListenAddress::Tcp(address) => Some(*address),
+                ListenAddress::Unix(_) => None,
+            })
+            .unwrap_or(address);
+        let query_shared = Arc::clone(&shared);
+        let status: Arc<GetStatus> = Arc::new(move || {
+            let shared = Arc::clone(&query_shared);
+            Box::pin(async move { query_status(&shared).await })
+        });
         task::spawn(async move {
-            if let Err(err) = connection_handler(socket, &peer, &forward_addr, cmd).await {
-                tracing::error!(error = %err, peer = %peer, "Error in connection handler")
+            if let Err(error) = query::serve(socket, server, status).await {
+                tracing::error!(%error, "Could not answer queries");
             }
         });
     }
+
+    let whitelist_shared = Arc::clone(&shared);
+    task::spawn(async move {
+        if let Err(error) = whitelist_handler(whitelist_shared).await {
+            tracing::error!(%error, "Could not handle reload signals");
+        }
+    });
+
+    let reset_shared = Arc::clone(&shared);
+    task::spawn(async move {
+        if let Err(error) = reset_handler(reset_shared).await {
+            tracing::error!(%error, "Could not handle reset signals");
+        }
+    });
+
+    let shutdown = CancellationToken::new();
+    let connections = TaskTracker::new();
+    let mut listeners = JoinSet::new();
+    for address in config.listen {
+        let listener = Listener::bind(&address).await.map_err(|error| {
+            Error::Other(format!("could not listen on {}: {}", address, error).into())
+        })?;
+        tracing::info!(%address, "Accepting connections");
+        listeners.spawn(accept_loop(
+            listener,
+            Arc::clone(&shared),
+            connections.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    tokio::select! {
+        result = shutdown_signal => result?,
+        Some(result) = listeners.join_next() => {
+            result.expect("Panic in accept loop")?;
+        }
+    }
+
+    tracing::info!("Shutting down");
+    shutdown.cancel();
+    while let Some(result) = listeners.join_next().await {
+        if let Err(error) = result.expect("Panic in accept loop") {
+            tracing::error!(%error, "Error while accepting connections");
+        }
+    }
+    // A backend that fails to stop must not keep the others running
+    for backend in shared.backends.backends() {
+        if let Err(error) = backend.process.shutdown().await {
+            tracing::error!(backend = %backend.id, %error, "Could not stop the backend");
+        }
+    }
+    connections.close();
+    if timeout(SHUTDOWN_TIMEOUT, connections.wait()).await.is_err() {
+        tracing::warn!(
+            connections = connections.len(),
+            "Connections did not finish in time, closing them"
+        );
+    }
+    Ok(())
 }
@@ -0,0 +1,318 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{CONTENT_TYPE, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::{Registry, Unit},
+};
+use tokio::{net::TcpListener, task};
+
+use crate::{
+    access_log::{AccessEntry, Action},
+    error::Error,
+    external_process::ProcessStatus,
+};
+
+/// Returns the number of connections currently forwarded to a backend
+pub type ActiveConnections = dyn Fn() -> usize + Send + Sync;
+/// Returns the status of the process of each backend by the backend's id
+pub type BackendStates = dyn Fn() -> Vec<(String, ProcessStatus)> + Send + Sync;
+
+const CONTENT_TYPE_OPENMETRICS: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct StateLabels {
+    next_state: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct DirectionLabels {
+    direction: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct BackendLabels {
+    backend: String,
+}
+
+/// The metrics of the proxy, exposed in the Prometheus text format
+pub struct Metrics {
+    registry: Registry,
+    connections: Counter,
+    handled_connections: Family<StateLabels, Counter>,
+    start_commands: Counter,
+    forwarded_connections: Gauge,
+    forwarded_bytes: Family<DirectionLabels, Counter>,
+    connection_duration: Family<StateLabels, Histogram, fn() -> Histogram>,
+    backend_state: Family<BackendLabels, Gauge>,
+    start_duration: Family<BackendLabels, Histogram, fn() -> Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let mut registry = Registry::with_prefix("portal");
+        let connections = Counter::default();
+        registry.register(
+            "connections",
+            "Connections accepted by the proxy",
+            connections.clone(),
+        );
+        let handled_connections = Family::<StateLabels, Counter>::default();
+        registry.register(
+            "handled_connections",
+            "Connections that were closed, by the state the client asked for",
+            handled_connections.clone(),
+        );
+        let start_commands = Counter::default();
+        registry.register(
+            "start_commands",
+            "Times the start command was run",
+            start_commands.clone(),
+        );
+        let forwarded_connections = Gauge::default();
+        registry.register(
+            "forwarded_connections",
+            "Connections currently forwarded to a backend",
+            forwarded_connections.clone(),
+        );
+        let forwarded_bytes = Family::<DirectionLabels, Counter>::default();
+        registry.register(
+            "forwarded_bytes",
+            "Bytes forwarded between clients and backends, counted when a connection is closed",
+            forwarded_bytes.clone(),
+        );
+        let connection_duration =
+            Family::<StateLabels, Histogram, _>::new_with_constructor(duration_histogram as _);
+        registry.register_with_unit(
+            "connection_duration",
+            "How long connections were open, by the state the client asked for",
+            Unit::Seconds,
+            connection_duration.clone(),
+        );
+        let backend_state = Family::<BackendLabels, Gauge>::default();
+        registry.register(
+            "backend_state",
+            "State of the process of each backend: 0 offline, 1 starting, 2 running, 3 failed or 4 stopping",
+            backend_state.clone(),
+        );
+        let start_duration =
+            Family::<BackendLabels, Histogram, _>::new_with_constructor(start_histogram as _);
+        registry.register_with_unit(
+            "start_duration",
+            "How long backends took to become reachable after the start command was run",
+            Unit::Seconds,
+            start_duration.clone(),
+        );
+
+        Metrics {
+            registry,
+            connections,
+            handled_connections,
+            start_commands,
+            forwarded_connections,
+            forwarded_bytes,
+            connection_duration,
+            backend_state,
+            start_duration,
+        }
+    }
+
+    pub fn connection_accepted(&self) {
+        self.connections.inc();
+    }
+
+    pub fn start_command_run(&self) {
+        self.start_commands.inc();
+    }
+
+    /// Records how long a backend took to become reachable after it was started.
+    pub fn backend_started(&self, backend: &str, duration: Duration) {
+        self.start_duration
+            .get_or_create(&BackendLabels {
+                backend: backend.to_owned(),
+            })
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Counts a closed connection using what was recorded for the access log.
+    pub fn connection_closed(&self, entry: &AccessEntry) {
+        let next_state = match (entry.next_state, entry.action) {
+            (Some(next_state), _) => next_state.to_string(),
+            (None, Action::LegacyPing) => "legacy_ping".to_owned(),
+            (None, _) => "none".to_owned(),
+        };
+        let labels = StateLabels { next_state };
+        self.handled_connections.get_or_create(&labels).inc();
+        self.connection_duration
+            .get_or_create(&labels)
+            .observe(entry.duration.as_secs_f64());
+        self.forwarded_bytes
+            .get_or_create(&DirectionLabels {
+                direction: "to_backend",
+            })
+            .inc_by(entry.bytes_received);
+        self.forwarded_bytes
+            .get_or_create(&DirectionLabels {
+                direction: "to_client",
+            })
+            .inc_by(entry.bytes_sent);
+    }
+
+    /// Encodes the metrics. The number of forwarded connections and the states of the backends are
+    /// tracked by the backends, so they are passed in rather than counted here.
+    fn encode(&self, active_connections: usize, backends: &[(String, ProcessStatus)]) -> String {
+        self.forwarded_connections.set(active_connections as i64);
+        for (id, status) in backends {
+            let state = match status {
+                ProcessStatus::NotStarted | ProcessStatus::Exited(_) => 0,
+                ProcessStatus::Starting => 1,
+                ProcessStatus::Running => 2,
+                ProcessStatus::Failed => 3,
+                ProcessStatus::Stopping => 4,
+            };
+            self.backend_state
+                .get_or_create(&BackendLabels {
+                    backend: id.clone(),
+                })
+                .set(state);
+        }
+        let mut output = String::new();
+        encode(&mut output, &self.registry).expect("writing to a string can not fail");
+        output
+    }
+}
+
+/// Creates a histogram for connection durations, with buckets from 10 milliseconds to about eleven
+/// hours to cover both status pings and long play sessions.
+fn duration_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.01, 4.0, 12))
+}
+
+/// Creates a histogram for backend start times, with buckets from a second to about 17 minutes.
+fn start_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(1.0, 2.0, 11))
+}
+
+/// Answers requests for `/metrics` on the listener.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    active_connections: Arc<ActiveConnections>,
+    backend_states: Arc<BackendStates>,
+) -> Result<(), Error> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        let active_connections = Arc::clone(&active_connections);
+        let backend_states = Arc::clone(&backend_states);
+        task::spawn(async move {
+            let service = service_fn(|request| {
+                let response = respond(&request, &metrics, active_connections(), &backend_states());
+                async move { Ok::<_, Infallible>(response) }
+            });
+            let connection = http1::Builder::new().serve_connection(TokioIo::new(socket), service);
+            if let Err(error) = connection.await {
+                tracing::debug!(%error, "Error while serving metrics");
+            }
+        });
+    }
+}
+
+fn respond(
+    request: &Request<Incoming>,
+    metrics: &Metrics,
+    active_connections: usize,
+    backend_states: &[(String, ProcessStatus)],
+) -> Response<Full<Bytes>> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        let mut response = Response::new(Full::default());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return response;
+    }
+
+    let mut response = Response::new(Full::from(
+        metrics.encode(active_connections, backend_states),
+    ));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(CONTENT_TYPE_OPENMETRICS),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::*;
+    use crate::{listener::Peer, protocol::handshake::NextState, testing::http_get};
+
+    /// Serves the metrics on a free local address, with a fixed number of forwarded connections
+    /// and backend states.
+    async fn serve_locally(
+        metrics: &Arc<Metrics>,
+        active_connections: usize,
+        backends: Vec<(String, ProcessStatus)>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        task::spawn(serve(
+            listener,
+            Arc::clone(metrics),
+            Arc::new(move || active_connections),
+            Arc::new(move || backends.clone()),
+        ));
+        address
+    }
+
+    #[tokio::test]
+    async fn serves_the_counters() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.connection_accepted();
+        metrics.start_command_run();
+        let peer = Peer::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 51234));
+        let mut entry = AccessEntry::new(&peer, Action::Forwarded);
+        entry.next_state = Some(NextState::Login);
+        entry.bytes_received = 100;
+        entry.bytes_sent = 2000;
+        metrics.connection_closed(&entry);
+        let address = serve_locally(&metrics, 0, Vec::new()).await;
+
+        let (status, body) = http_get(address, "/metrics").await;
+        assert!(status.contains("200"), "{status}");
+        for line in [
+            "portal_connections_total 1",
+            "portal_start_commands_total 1",
+            r#"portal_handled_connections_total{next_state="login"} 1"#,
+            r#"portal_forwarded_bytes_total{direction="to_backend"} 100"#,
+            r#"portal_forwarded_bytes_total{direction="to_client"} 2000"#,
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{line} is missing in\n{body}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn only_serves_the_metrics_path() {
+        let metrics = Arc::new(Metrics::new());
+        let address = serve_locally(&metrics, 0, Vec::new()).await;
+        let (status, _) = http_get(address, "/").await;
+        assert!(status.contains("404"), "{status}");
+    }
+}
@@ -0,0 +1,128 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::{Layer, filter::Targets, registry::LookupSpan};
+
+use crate::error::Error;
+
+/// The name the proxy reports itself as to the collector
+const SERVICE_NAME: &str = "portal";
+
+/// Creates a layer that exports the spans of the proxy to an OTLP/HTTP endpoint, along with the
+/// provider that has to be shut down to export the spans that are still buffered.
+pub fn layer<S>(endpoint: &str, level: Level) -> Result<(impl Layer<S>, SdkTracerProvider), Error>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|error| Error::Other(error.into()))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(SERVICE_NAME).build())
+        .build();
+    Ok((provider_layer(&provider, level), provider))
+}
+
+/// Creates a layer that passes the spans to the provider.
+/// Only spans of the proxy itself are exported, since those of the HTTP client sending them would
+/// otherwise be exported too.
+fn provider_layer<S>(provider: &SdkTracerProvider, level: Level) -> impl Layer<S> + use<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(SERVICE_NAME))
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), level))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use opentelemetry::Value;
+    use opentelemetry_sdk::{
+        error::OTelSdkResult,
+        trace::{SpanData, SpanExporter},
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+    use crate::testing::{MockBackend, TestProxy};
+
+    /// Keeps the exported spans in memory
+    #[derive(Debug, Clone, Default)]
+    struct MockExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MockExporter {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    /// Returns the value of a span attribute.
+    fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == key)
+            .map(|attribute| attribute.value.clone())
+    }
+
+    #[tokio::test]
+    async fn exports_the_spans_of_a_connection() {
+        let exporter = MockExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber =
+            tracing_subscriber::registry().with(provider_layer(&provider, Level::INFO));
+        let guard = tracing::subscriber::set_default(subscriber);
+        let backend = MockBackend::new()
+            .with_status(r#"{"version":{"name":"1.21.7","protocol":772},"description":"Live"}"#)
+            .start();
+        let proxy = TestProxy::start(&format!(
+            r#"
+            forward = ["{}"]
+            start-command = "true"
+            "#,
+            backend.address
+        ))
+        .await;
+        proxy.client().status().await.unwrap();
+        proxy.shutdown().await.unwrap();
+        drop(guard);
+        provider.force_flush().unwrap();
+
+        let spans = exporter.0.lock().unwrap();
+        // The readiness check of the proxy also opens a connection, which never gets a host
+        let handler = spans
+            .iter()
+            .find(|span| span.name == "connection_handler" && attribute(span, "host").is_some())
+            .unwrap();
+        assert_eq!(attribute(handler, "host"), Some(Value::from("localhost")));
+        assert_eq!(
+            attribute(handler, "next_state"),
+            Some(Value::from("status"))
+        );
+        let connection = spans
+            .iter()
+            .find(|span| span.span_context.span_id() == handler.parent_span_id)
+            .unwrap();
+        assert_eq!(connection.name, "connection");
+        assert_eq!(attribute(connection, "action"), Some(Value::from("status")));
+        assert!(attribute(connection, "conn_id").is_some());
+        let status = spans
+            .iter()
+            .find(|span| span.name == "status_handler")
+            .unwrap();
+        assert_eq!(status.parent_span_id, handler.span_context.span_id());
+        assert_eq!(
+            status.span_context.trace_id(),
+            connection.span_context.trace_id()
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::{error::Error, registry::Backend};
+
+/// The interval in which a starting backend is probed
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tries to connect to the backend every `interval` until a connection succeeds or `duration` has
+/// elapsed. The established connection is returned so it can be used right away.
+pub async fn wait_reachable(
+    backend: &Backend,
+    interval: Duration,
+    duration: Duration,
+) -> Result<TcpStream, Error> {
+    let deadline = Instant::now() + duration;
+    let mut ticks = time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticks.tick().await;
+        match time::timeout_at(deadline, backend.connect()).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(error)) => {
+                tracing::trace!(%error, "Backend is not reachable yet")
+            }
+            Err(_) => return Err(Error::Timeout),
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::Timeout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockBackend, backend, free_address};
+
+    #[tokio::test]
+    async fn succeeds_once_the_backend_accepts_connections() {
+        let running = MockBackend::new()
+            .with_delay(Duration::from_millis(300))
+            .start();
+        let backend = backend(&[running.address]);
+        assert!(backend.connect().await.is_err());
+
+        let start = Instant::now();
+        let stream = wait_reachable(&backend, Duration::from_millis(50), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), running.address);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn times_out_if_the_backend_stays_unreachable() {
+        let backend = backend(&[free_address()]);
+        let result = wait_reachable(
+            &backend,
+            Duration::from_millis(50),
+            Duration::from_millis(300),
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Timeout)));
+    }
+}
@@ -0,0 +1,128 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::protocol::ProtocolError;
+
+/// A text component as used by chat and disconnect messages.
+/// Only the commonly used styling is modelled, other fields are kept in `other` so that
+/// components read from a configuration file are sent to the client unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatComponent {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub text: String,
+    /// A named color like `gold` or a hex color like `#ff8800`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bold: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub italic: Option<bool>,
+    /// Children that are displayed after this component and inherit its style
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra: Vec<ChatComponent>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// A message in one of the forms a server can send it in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Chat<'a> {
+    /// Plain text without any styling
+    Text(Cow<'a, str>),
+    Component(Cow<'a, ChatComponent>),
+    /// A message that is already in JSON text format, e.g. one received from a server
+    Json(Cow<'a, str>),
+}
+
+impl Chat<'_> {
+    /// Returns the message in the JSON text format that is sent to clients.
+    pub fn to_json(&self) -> Cow<'_, str> {
+        match self {
+            Chat::Text(text) => Cow::Owned(Value::from(text.as_ref()).to_string()),
+            Chat::Component(component) => Cow::Owned(
+                serde_json::to_string(component).expect("chat components can be serialized"),
+            ),
+            Chat::Json(json) => Cow::Borrowed(json),
+        }
+    }
+
+    /// Returns the message as a JSON value, e.g. to use it in a status response.
+    pub fn to_value(&self) -> Value {
+        serde_json::from_str(&self.to_json()).expect("chat messages are valid JSON")
+    }
+}
+
+/// Parses a configured message.
+/// A message that is a JSON object or array is taken to be a chat component, anything else is
+/// treated as plain text.
+pub fn parse_message(message: &str) -> Result<Chat<'static>, ProtocolError> {
+    let invalid = |_| ProtocolError::InvalidField("chat component");
+    let trimmed = message.trim_start();
+    if trimmed.starts_with('{') {
+        let component = serde_json::from_str(message).map_err(invalid)?;
+        return Ok(Chat::Component(Cow::Owned(component)));
+    }
+    if trimmed.starts_with('[') {
+        let components: Value = serde_json::from_str(message).map_err(invalid)?;
+        return Ok(Chat::Json(Cow::Owned(components.to_string())));
+    }
+
+    Ok(Chat::Text(Cow::Owned(message.to_owned())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_messages_become_valid_json() {
+        for message in [
+            r#"The server says "hi""#,
+            "Line one\nLine two",
+            "Backslash \\ and ünïcödé",
+        ] {
+            let chat = parse_message(message).unwrap();
+            assert_eq!(chat, Chat::Text(Cow::Borrowed(message)));
+            let json: Value = serde_json::from_str(&chat.to_json()).unwrap();
+            assert_eq!(json, message);
+        }
+        assert!(parse_message("{ not json").is_err());
+    }
+
+    #[test]
+    fn styled_components_serialize_like_the_client_expects() {
+        let component = ChatComponent {
+            text: "Server is starting".to_owned(),
+            color: Some("gold".to_owned()),
+            bold: Some(true),
+            extra: vec![ChatComponent {
+                text: ", please wait".to_owned(),
+                italic: Some(false),
+                ..ChatComponent::default()
+            }],
+            ..ChatComponent::default()
+        };
+        let json: Value =
+            serde_json::from_str(&Chat::Component(Cow::Owned(component)).to_json()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "text": "Server is starting",
+                "color": "gold",
+                "bold": true,
+                "extra": [{"text": ", please wait", "italic": false}]
+            })
+        );
+
+        // Fields that are not modelled are kept
+        let configured = r#"{"text":"Hover me","underlined":true,"hoverEvent":{"action":"show_text","contents":"Hi"}}"#;
+        let chat = parse_message(configured).unwrap();
+        assert!(matches!(chat, Chat::Component(_)));
+        assert_eq!(
+            chat.to_value(),
+            serde_json::from_str::<Value>(configured).unwrap()
+        );
+    }
+}
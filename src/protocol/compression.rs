@@ -0,0 +1,44 @@
+use std::io::{Read, Write};
+
+use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
+use tokio_util::bytes::Bytes;
+
+use crate::protocol::ProtocolError;
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// Decompresses a zlib stream that is expected to inflate to exactly `len` bytes.
+pub fn decompress(data: &[u8], len: usize) -> Result<Bytes, ProtocolError> {
+    let mut result = Vec::with_capacity(len);
+    // Reading one byte more than expected lets us detect streams that inflate to more than `len`
+    // without decompressing all of it.
+    ZlibDecoder::new(data)
+        .take(len as u64 + 1)
+        .read_to_end(&mut result)?;
+
+    if result.len() != len {
+        return Err(ProtocolError::InvalidField("data length"));
+    }
+    Ok(Bytes::from(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_to_the_expected_length_only() {
+        let data = b"hello world ".repeat(100);
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+
+        assert!(decompress(&compressed, data.len() - 1).is_err());
+        assert!(decompress(&compressed, data.len() + 1).is_err());
+        assert!(decompress(b"not zlib", 8).is_err());
+    }
+}
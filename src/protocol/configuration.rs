@@ -0,0 +1,115 @@
+use std::{borrow::Cow, io};
+
+use crate::protocol::{
+    Protocol, ProtocolError, ProtocolState,
+    types::{read_string, read_var_int, string_size, var_int_size, write_string, write_var_int},
+};
+
+use super::DecoderState;
+
+/// Tells the client to connect to another server. Available since 1.20.5, the server the client is
+/// transferred to has to accept transfers.
+#[derive(Debug)]
+pub struct Transfer<'a> {
+    pub host: Cow<'a, str>,
+    pub port: i32,
+}
+
+/// Packets sent to the client in the configuration state, which follows a successful login.
+/// Only the packets used by the proxy are supported.
+#[derive(Debug)]
+pub enum ClientBound<'a> {
+    Transfer(Transfer<'a>),
+}
+
+impl<'a> Protocol<'a> for ClientBound<'a> {
+    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> Result<Self, ProtocolError> {
+        match number {
+            0x0b => {
+                let host = read_string(src)?;
+                let port = read_var_int(src)?;
+                Ok(ClientBound::Transfer(Transfer {
+                    host: Cow::Owned(host.to_owned()),
+                    port,
+                }))
+            }
+            _ => Err(ProtocolError::UnknownPacket {
+                state: ProtocolState::Configuration,
+                id: number,
+            }),
+        }
+    }
+
+    fn packet_number(&self) -> i32 {
+        match self {
+            ClientBound::Transfer(_) => 0x0b,
+        }
+    }
+
+    fn encoded_size(&self) -> usize {
+        match self {
+            ClientBound::Transfer(transfer) => {
+                string_size(&transfer.host) + var_int_size(transfer.port)
+            }
+        }
+    }
+
+    fn encode_packet(&self, writer: &mut impl io::Write) -> Result<(), ProtocolError> {
+        match self {
+            ClientBound::Transfer(transfer) => {
+                write_string(&transfer.host, writer)?;
+                write_var_int(transfer.port, writer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder, Encoder},
+    };
+
+    use super::*;
+    use crate::protocol::{PacketDecoder, PacketEncoder};
+
+    #[test]
+    fn transfer_round_trips() {
+        let transfer = ClientBound::Transfer(Transfer {
+            host: Cow::Borrowed("mc.example.com"),
+            port: 25565,
+        });
+        let mut buffer = BytesMut::new();
+        PacketEncoder::new().encode(transfer, &mut buffer).unwrap();
+
+        // Length, packet id, host and port as a VarInt
+        let mut expected = vec![19, 0x0b, 14];
+        expected.extend_from_slice(b"mc.example.com");
+        expected.extend_from_slice(&[0xdd, 0xc7, 0x01]);
+        assert_eq!(buffer[..], expected);
+
+        let packet = PacketDecoder::<ClientBound>::new()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+        assert!(buffer.is_empty());
+        let ClientBound::Transfer(transfer) = packet.into_inner();
+        assert_eq!(transfer.host, "mc.example.com");
+        assert_eq!(transfer.port, 25565);
+    }
+
+    #[test]
+    fn rejects_unknown_packets() {
+        let mut buffer = BytesMut::from(&[1, 0x03][..]);
+        let result = PacketDecoder::<ClientBound>::new().decode(&mut buffer);
+        assert!(matches!(
+            result,
+            Err(ProtocolError::UnknownPacket {
+                state: ProtocolState::Configuration,
+                id: 0x03
+            })
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use aes::{
+    Aes128,
+    cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, inout::InOutBuf},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+type Encryptor = cfb8::Encryptor<Aes128>;
+type Decryptor = cfb8::Decryptor<Aes128>;
+
+/// A stream adapter that applies the AES-128/CFB8 stream cipher used after a successful encryption
+/// handshake.
+/// Everything read from the inner stream is decrypted and everything written to it is encrypted.
+/// The adapter sits below the packet codecs, i.e. `FramedRead::new(EncryptedStream::new(...), ..)`.
+pub struct EncryptedStream<S> {
+    inner: S,
+    encryptor: Encryptor,
+    decryptor: Decryptor,
+    /// Encrypted bytes that have been accepted from the caller but not yet written to the inner
+    /// stream
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<S> EncryptedStream<S> {
+    /// Wraps a stream using the given shared secret, which is used as both key and IV.
+    pub fn new(inner: S, shared_secret: &[u8; 16]) -> EncryptedStream<S> {
+        EncryptedStream {
+            inner,
+            encryptor: Encryptor::new(shared_secret.into(), shared_secret.into()),
+            decryptor: Decryptor::new(shared_secret.into(), shared_secret.into()),
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+}
+
+fn encrypt(encryptor: &mut Encryptor, data: &mut [u8]) {
+    let (blocks, _) = InOutBuf::from(data).into_chunks();
+    encryptor.encrypt_blocks_inout_mut(blocks);
+}
+
+fn decrypt(decryptor: &mut Decryptor, data: &mut [u8]) {
+    let (blocks, _) = InOutBuf::from(data).into_chunks();
+    decryptor.decrypt_blocks_inout_mut(blocks);
+}
+
+impl<S: AsyncWrite + Unpin> EncryptedStream<S> {
+    /// Writes pending bytes to the inner stream until there are none left.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EncryptedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        decrypt(&mut this.decryptor, &mut buf.filled_mut()[start..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // The cipher state advances with every byte, so bytes can only be accepted once all
+        // previously encrypted bytes have been handed to the inner stream.
+        ready!(this.poll_drain(cx))?;
+
+        this.pending.extend_from_slice(buf);
+        encrypt(&mut this.encryptor, &mut this.pending);
+
+        // The data has been accepted at this point, so a pending write is not reported here
+        if let Poll::Ready(Err(error)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(error));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const SECRET: [u8; 16] = *b"0123456789abcdef";
+
+    #[tokio::test]
+    async fn decrypts_what_was_encrypted() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        // The pipe is smaller than the data, so writes have to wait for the reader
+        let (client, server) = io::duplex(64);
+        let mut client = EncryptedStream::new(client, &SECRET);
+        let mut server = EncryptedStream::new(server, &SECRET);
+
+        let written = data.clone();
+        let writer = tokio::spawn(async move {
+            for chunk in written.chunks(1000) {
+                client.write_all(chunk).await.unwrap();
+            }
+            client.shutdown().await.unwrap();
+        });
+        let mut read = Vec::new();
+        server.read_to_end(&mut read).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[tokio::test]
+    async fn sends_encrypted_bytes() {
+        let (client, mut server) = io::duplex(1024);
+        let mut client = EncryptedStream::new(client, &SECRET);
+        client.write_all(b"hello world").await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut wire = [0; 11];
+        server.read_exact(&mut wire).await.unwrap();
+        assert_ne!(&wire, b"hello world");
+        let mut decryptor = Decryptor::new(&SECRET.into(), &SECRET.into());
+        decrypt(&mut decryptor, &mut wire);
+        assert_eq!(&wire, b"hello world");
+    }
+}
@@ -0,0 +1,57 @@
+use std::{
+    error::Error as StdError,
+    fmt::{self, Display, Formatter},
+    io,
+};
+
+use crate::protocol::ProtocolState;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The packet id is not known (or not supported) in the given protocol state.
+    UnknownPacket {
+        state: ProtocolState,
+        id: i32,
+    },
+    InvalidString,
+    VarIntTooLong,
+    /// The declared packet length exceeds the configured maximum packet size.
+    TooLarge {
+        len: usize,
+    },
+    /// A field of the packet contains a value that is not valid.
+    InvalidField(&'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(value: io::Error) -> Self {
+        ProtocolError::Io(value)
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownPacket { state, id } => {
+                write!(f, "unknown packet id {:#04x} in state {}", id, state)
+            }
+            ProtocolError::InvalidString => write!(f, "string is not valid UTF-8"),
+            ProtocolError::VarIntTooLong => write!(f, "var int takes up too many bytes"),
+            ProtocolError::TooLarge { len } => {
+                write!(f, "packet length {} exceeds the maximum packet size", len)
+            }
+            ProtocolError::InvalidField(field) => write!(f, "invalid value for {}", field),
+            ProtocolError::Io(error) => write!(f, "io error: {}", error),
+        }
+    }
+}
+
+impl StdError for ProtocolError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ProtocolError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
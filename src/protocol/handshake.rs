@@ -6,13 +6,17 @@ use std::{
 };
 
 use crate::protocol::{
-    DecoderState, Protocol,
+    DEFAULT_MAX_PACKET_SIZE, DecoderState, Protocol, ProtocolError, ProtocolState,
     types::{read_string, read_var_int, string_size, var_int_size, write_string, write_var_int},
 };
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-#[derive(Debug, Clone, Copy)]
+/// The length of the smallest possible handshake: the packet id, a one byte version, an empty
+/// host, the port and the next state
+const MIN_HANDSHAKE_LENGTH: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NextState {
     Status,
     Login,
@@ -29,21 +33,77 @@ impl Display for NextState {
     }
 }
 
+/// The protocol version number sent by the client in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion(pub i32);
+
+impl ProtocolVersion {
+    /// The protocol version of Minecraft 1.21.7
+    pub const V1_21_7: ProtocolVersion = ProtocolVersion(772);
+
+    /// Returns whether the client uses at least the protocol version Portal is written against.
+    pub fn is_modern(self) -> bool {
+        self >= ProtocolVersion::V1_21_7
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Debug)]
 pub struct HandshakePacket<'a> {
-    pub version: i32,
+    pub version: ProtocolVersion,
     pub address: Cow<'a, str>,
     pub port: u16,
     pub next_state: NextState,
 }
 
+impl HandshakePacket<'_> {
+    /// Returns the host name the client connected to, without any data appended to it.
+    pub fn host(&self) -> &str {
+        split_address(&self.address).0
+    }
+}
+
+/// Returns whether `data` can be the start of a handshake packet. Only the length and the packet
+/// id are checked, which already tells HTTP requests, TLS handshakes and most other traffic apart
+/// from Minecraft clients. Data that is too short to tell is assumed to be a handshake.
+/// Legacy pings are not handshakes and have to be detected before.
+pub fn could_be_handshake(data: &[u8]) -> bool {
+    let mut length = 0;
+    // The maximum packet size fits into three bytes of a VarInt
+    for (i, &byte) in data.iter().take(3).enumerate() {
+        length |= usize::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            let id = data.get(i + 1);
+            return (MIN_HANDSHAKE_LENGTH..=DEFAULT_MAX_PACKET_SIZE).contains(&length)
+                && id.is_none_or(|&id| id == 0);
+        }
+    }
+    data.len() < 3
+}
+
+/// Splits the address field of a handshake into the host name and the segments appended to it.
+/// Forge clients append `\0FML\0` (or `\0FML2\0` and more), BungeeCord IP forwarding appends
+/// `\0<ip>\0<uuid>\0<properties>`. The host is everything before the first null byte.
+pub fn split_address(address: &str) -> (&str, impl Iterator<Item = &str>) {
+    let (host, extra) = match address.split_once('\0') {
+        Some((host, extra)) => (host, Some(extra)),
+        None => (address, None),
+    };
+    (host, extra.into_iter().flat_map(|extra| extra.split('\0')))
+}
+
 impl<'a> Protocol<'a> for HandshakePacket<'a> {
-    fn decode_packet(number: i32, src: &mut DecoderState<'a>) -> io::Result<Self> {
+    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> Result<Self, ProtocolError> {
         if number != 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid packet number",
-            ));
+            return Err(ProtocolError::UnknownPacket {
+                state: ProtocolState::Handshaking,
+                id: number,
+            });
         }
 
         let version = read_var_int(src)?;
@@ -53,19 +113,21 @@ impl<'a> Protocol<'a> for HandshakePacket<'a> {
             1 => NextState::Status,
             2 => NextState::Login,
             3 => NextState::Transfer,
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "unknown next state identifier",
-                ));
-            }
+            _ => return Err(ProtocolError::InvalidField("next state")),
         };
 
+        if src.has_remaining() {
+            tracing::warn!(
+                remaining = src.remaining(),
+                "Handshake packet contains trailing bytes"
+            );
+        }
+
         Ok(HandshakePacket {
-            version: version,
-            address: Cow::Borrowed(address),
-            port: port,
-            next_state: next_state,
+            version: ProtocolVersion(version),
+            address: Cow::Owned(address.to_owned()),
+            port,
+            next_state,
         })
     }
 
@@ -74,11 +136,11 @@ impl<'a> Protocol<'a> for HandshakePacket<'a> {
     }
 
     fn encoded_size(&self) -> usize {
-        var_int_size(self.version) + string_size(&self.address) + mem::size_of::<u16>() + 1
+        var_int_size(self.version.0) + string_size(&self.address) + mem::size_of::<u16>() + 1
     }
 
-    fn encode_packet(&self, writer: &mut impl io::Write) -> io::Result<()> {
-        write_var_int(self.version, writer)?;
+    fn encode_packet(&self, writer: &mut impl io::Write) -> Result<(), ProtocolError> {
+        write_var_int(self.version.0, writer)?;
         write_string(&self.address, writer)?;
         writer.write_u16::<BigEndian>(self.port)?;
         write_var_int(
@@ -92,3 +154,105 @@ impl<'a> Protocol<'a> for HandshakePacket<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder, Encoder},
+    };
+
+    use super::*;
+    use crate::protocol::{PacketDecoder, PacketEncoder};
+
+    fn handshake(address: &str) -> HandshakePacket<'_> {
+        HandshakePacket {
+            version: ProtocolVersion::V1_21_7,
+            address: Cow::Owned(address.to_owned()),
+            port: 25565,
+            next_state: NextState::Login,
+        }
+    }
+
+    #[test]
+    fn strips_appended_data_from_the_host() {
+        assert_eq!(handshake("mc.example.com").host(), "mc.example.com");
+        let (_, mut extra) = split_address("mc.example.com");
+        assert_eq!(extra.next(), None);
+
+        let forge = handshake("mc.example.com\0FML2\0");
+        assert_eq!(forge.host(), "mc.example.com");
+        let (_, extra) = split_address(&forge.address);
+        assert_eq!(extra.collect::<Vec<_>>(), ["FML2", ""]);
+
+        let segments = ["203.0.113.7", "069a79f444e94726a5befca90e38aaf5", "[]"];
+        let address = format!("mc.example.com\0{}", segments.join("\0"));
+        let bungee = handshake(&address);
+        assert_eq!(bungee.host(), "mc.example.com");
+        let (_, extra) = split_address(&bungee.address);
+        assert_eq!(extra.collect::<Vec<_>>(), segments);
+    }
+
+    #[test]
+    fn surfaces_the_protocol_version() {
+        // Version 47, "mc", port 25565, status
+        let data = [0x2f, 2, b'm', b'c', 0x63, 0xdd, 1];
+        let mut state = DecoderState::new(&data);
+        let packet = HandshakePacket::decode_packet(0, &mut state).unwrap();
+        assert_eq!(packet.version, ProtocolVersion(47));
+        assert!(!packet.version.is_modern());
+        assert_eq!(packet.version.to_string(), "47");
+        assert!(matches!(packet.next_state, NextState::Status));
+
+        assert!(ProtocolVersion::V1_21_7.is_modern());
+        assert!(ProtocolVersion(773).is_modern());
+    }
+
+    #[test]
+    fn re_encodes_to_the_received_bytes() {
+        let mut received = BytesMut::new();
+        PacketEncoder::new()
+            .encode(handshake("mc.example.com\0FML2\0"), &mut received)
+            .unwrap();
+        let original = received.clone().freeze();
+
+        let packet = PacketDecoder::<HandshakePacket>::new()
+            .decode(&mut received)
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.buffer(), original);
+        let decoded = HandshakePacket {
+            version: packet.version,
+            address: packet.address.clone(),
+            port: packet.port,
+            next_state: packet.next_state,
+        };
+        let mut encoded = BytesMut::new();
+        PacketEncoder::new().encode(decoded, &mut encoded).unwrap();
+        assert_eq!(encoded, original);
+    }
+
+    #[test]
+    fn tells_other_traffic_apart_from_handshakes() {
+        let mut data = BytesMut::new();
+        PacketEncoder::new()
+            .encode(handshake("mc.example.com"), &mut data)
+            .unwrap();
+        for end in 1..=data.len() {
+            assert!(could_be_handshake(&data[..end]), "{:?}", &data[..end]);
+        }
+
+        assert!(!could_be_handshake(
+            b"GET / HTTP/1.1\r\nHost: mc.example.com\r\n"
+        ));
+        // A TLS record with a ClientHello
+        assert!(!could_be_handshake(&[
+            0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00
+        ]));
+        assert!(!could_be_handshake(b"SSH-2.0-OpenSSH_9.6\r\n"));
+        // Too short to be a handshake, or not its packet id
+        assert!(!could_be_handshake(&[0x02, 0x00, 0x00]));
+        assert!(!could_be_handshake(&[0x10, 0x01]));
+        assert!(!could_be_handshake(&[0xff, 0xff, 0xff, 0x7f]));
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{self, Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::protocol::ProtocolError;
+
+/// The first byte sent by clients using the server list ping from before Minecraft 1.7.
+/// A modern handshake only starts with this byte if it is at least 254 bytes long, which does not
+/// happen in practice. The vanilla server relies on the same assumption.
+pub const LEGACY_PING: u8 = 0xfe;
+
+/// The byte following the ping from clients since 1.4, which understand the extended response
+const EXTENDED_PING: u8 = 0x01;
+/// The plugin message 1.6 clients append to the ping
+const PLUGIN_MESSAGE: u8 = 0xfa;
+const PING_HOST_CHANNEL: &str = "MC|PingHost";
+/// The longest host name accepted in a ping, the same as in a modern handshake
+const MAX_HOST_LENGTH: u16 = 255;
+
+const KICK_PACKET: u8 = 0xff;
+
+/// A server list ping from a client older than 1.7
+#[derive(Debug, PartialEq, Eq)]
+pub enum LegacyPing {
+    /// Sent by clients before 1.4
+    Basic,
+    /// Sent by 1.4 and 1.5 clients
+    Extended,
+    /// Sent by 1.6 clients, which also say which server they are pinging
+    PingHost {
+        protocol: u8,
+        host: String,
+        port: u16,
+    },
+}
+
+/// Parses a legacy ping starting with the `0xfe` byte. Returns `None` if the ping is not complete
+/// yet, which only happens for the 1.6 form, as the others are not followed by anything.
+pub fn parse_ping(data: &[u8]) -> Result<Option<LegacyPing>, ProtocolError> {
+    match data {
+        [LEGACY_PING] => return Ok(Some(LegacyPing::Basic)),
+        [LEGACY_PING, EXTENDED_PING] => return Ok(Some(LegacyPing::Extended)),
+        [LEGACY_PING, EXTENDED_PING, PLUGIN_MESSAGE, ..] => {}
+        _ => return Err(ProtocolError::InvalidField("legacy ping")),
+    }
+
+    match parse_ping_host(&mut Cursor::new(&data[3..])) {
+        Ok(ping) => Ok(Some(ping)),
+        Err(ProtocolError::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Parses the plugin message of a 1.6 ping.
+fn parse_ping_host(src: &mut impl Read) -> Result<LegacyPing, ProtocolError> {
+    let channel_length = src.read_u16::<BigEndian>()?;
+    if channel_length as usize != PING_HOST_CHANNEL.len() {
+        return Err(ProtocolError::InvalidField("plugin channel"));
+    }
+    if read_string(src, channel_length)? != PING_HOST_CHANNEL {
+        return Err(ProtocolError::InvalidField("plugin channel"));
+    }
+
+    let data_length = src.read_u16::<BigEndian>()?;
+    let protocol = src.read_u8()?;
+    let host_length = src.read_u16::<BigEndian>()?;
+    if host_length > MAX_HOST_LENGTH || data_length != 7 + 2 * host_length {
+        return Err(ProtocolError::InvalidField("ping host length"));
+    }
+    let host = read_string(src, host_length)?;
+    let port = src.read_i32::<BigEndian>()?;
+    Ok(LegacyPing::PingHost {
+        protocol,
+        host,
+        port: u16::try_from(port).map_err(|_| ProtocolError::InvalidField("port"))?,
+    })
+}
+
+/// Reads a string of `length` UTF-16 code units.
+fn read_string(src: &mut impl Read, length: u16) -> Result<String, ProtocolError> {
+    let units = (0..length)
+        .map(|_| src.read_u16::<BigEndian>())
+        .collect::<Result<Vec<_>, _>>()?;
+    String::from_utf16(&units).map_err(|_| ProtocolError::InvalidString)
+}
+
+/// Builds the status response for a legacy ping as understood by clients from before 1.4.
+/// The fields are separated by `§`, which is why it must not appear in the message of the day.
+pub fn status_response(motd: &str, online: u32, max: u32) -> Vec<u8> {
+    let motd = motd.replace('§', "");
+    kick_packet(&format!("{}§{}§{}", motd, online, max))
+}
+
+/// Builds the status response for a legacy ping as understood by clients since 1.4.
+/// The fields are separated by null characters, so the message of the day may contain formatting
+/// codes.
+pub fn extended_status_response(
+    protocol: u8,
+    version: &str,
+    motd: &str,
+    online: u32,
+    max: u32,
+) -> Vec<u8> {
+    let motd = motd.replace('\0', "");
+    kick_packet(&format!(
+        "§1\0{}\0{}\0{}\0{}\0{}",
+        protocol, version, motd, online, max
+    ))
+}
+
+/// Encodes a kick packet, which is also used to transport legacy status responses.
+fn kick_packet(message: &str) -> Vec<u8> {
+    let units = message.encode_utf16().collect::<Vec<_>>();
+    let mut packet = Vec::with_capacity(3 + 2 * units.len());
+    packet.push(KICK_PACKET);
+    packet
+        .write_u16::<BigEndian>(units.len() as u16)
+        .expect("writing to a vector can not fail");
+    for unit in units {
+        packet
+            .write_u16::<BigEndian>(unit)
+            .expect("writing to a vector can not fail");
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ping sent by a 1.6.4 client for `localhost:25565`
+    const PING_HOST: &[u8] = &[
+        0xfe, 0x01, 0xfa, 0x00, 0x0b, 0x00, 0x4d, 0x00, 0x43, 0x00, 0x7c, 0x00, 0x50, 0x00, 0x69,
+        0x00, 0x6e, 0x00, 0x67, 0x00, 0x48, 0x00, 0x6f, 0x00, 0x73, 0x00, 0x74, 0x00, 0x19, 0x4a,
+        0x00, 0x09, 0x00, 0x6c, 0x00, 0x6f, 0x00, 0x63, 0x00, 0x61, 0x00, 0x6c, 0x00, 0x68, 0x00,
+        0x6f, 0x00, 0x73, 0x00, 0x74, 0x00, 0x00, 0x63, 0xdd,
+    ];
+
+    /// Decodes the message of a kick packet, checking its length.
+    fn kick_message(packet: &[u8]) -> String {
+        assert_eq!(packet[0], KICK_PACKET);
+        let length = u16::from_be_bytes([packet[1], packet[2]]) as usize;
+        assert_eq!(packet.len(), 3 + 2 * length);
+        let units = packet[3..]
+            .chunks(2)
+            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+            .collect::<Vec<_>>();
+        String::from_utf16(&units).unwrap()
+    }
+
+    #[test]
+    fn answers_pings_from_before_1_6() {
+        assert_eq!(parse_ping(&[0xfe]).unwrap(), Some(LegacyPing::Basic));
+        assert_eq!(
+            parse_ping(&[0xfe, 0x01]).unwrap(),
+            Some(LegacyPing::Extended)
+        );
+        assert!(parse_ping(&[0xfe, 0x02]).is_err());
+
+        let response = extended_status_response(127, "1.21.7", "A §aMinecraft\0 Server", 3, 20);
+        let fields = ["§1", "127", "1.21.7", "A §aMinecraft Server", "3", "20"];
+        assert_eq!(kick_message(&response), fields.join("\0"));
+        let response = status_response("A §aMinecraft Server", 3, 20);
+        assert_eq!(kick_message(&response), "A aMinecraft Server§3§20");
+    }
+
+    #[test]
+    fn parses_the_host_of_1_6_pings() {
+        assert_eq!(
+            parse_ping(PING_HOST).unwrap(),
+            Some(LegacyPing::PingHost {
+                protocol: 74,
+                host: "localhost".to_owned(),
+                port: 25565,
+            })
+        );
+        // The ping may arrive in several parts
+        for end in 3..PING_HOST.len() {
+            assert_eq!(parse_ping(&PING_HOST[..end]).unwrap(), None);
+        }
+
+        let mut wrong_channel = PING_HOST.to_vec();
+        wrong_channel[6] = b'X';
+        assert!(parse_ping(&wrong_channel).is_err());
+        let mut wrong_length = PING_HOST.to_vec();
+        wrong_length[28] = 0x20;
+        assert!(parse_ping(&wrong_length).is_err());
+    }
+}
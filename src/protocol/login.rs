@@ -9,8 +9,12 @@ use tracing::warn;
 use uuid::Uuid;
 
 use crate::protocol::{
-    Protocol,
-    types::{read_string, string_size, write_string},
+    Protocol, ProtocolError, ProtocolState,
+    chat::Chat,
+    types::{
+        byte_array_size, read_byte_array, read_string, read_var_int, string_size, var_int_size,
+        write_byte_array, write_string, write_var_int,
+    },
 };
 
 use super::DecoderState;
@@ -21,42 +25,89 @@ pub struct LoginStart<'a> {
     pub uuid: Uuid,
 }
 
+#[derive(Debug)]
+pub struct EncryptionResponse<'a> {
+    /// The shared secret, encrypted with the server's public key
+    pub shared_secret: Cow<'a, [u8]>,
+    /// The verify token, encrypted with the server's public key
+    pub verify_token: Cow<'a, [u8]>,
+}
+
+#[derive(Debug)]
+pub struct LoginPluginResponse<'a> {
+    /// The id of the request this is a response to
+    pub message_id: i32,
+    /// Whether the client understood the request
+    pub successful: bool,
+    /// The payload, only present if the request was successful
+    pub data: Option<Cow<'a, [u8]>>,
+}
+
 #[derive(Debug)]
 pub enum ServerBound<'a> {
     LoginStart(LoginStart<'a>),
+    EncryptionResponse(EncryptionResponse<'a>),
+    LoginPluginResponse(LoginPluginResponse<'a>),
+    /// Sent in response to a login success, switches the connection to the configuration state
+    LoginAcknowledged,
 }
 
 impl<'a> Protocol<'a> for ServerBound<'a> {
-    fn decode_packet(number: i32, src: &mut DecoderState<'a>) -> io::Result<Self> {
+    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> Result<Self, ProtocolError> {
         match number {
             0 => {
                 let name = read_string(src)?;
                 let uuid = src.read_u128::<BigEndian>()?;
                 Ok(ServerBound::LoginStart(LoginStart {
-                    name: Cow::Borrowed(name),
+                    name: Cow::Owned(name.to_owned()),
                     uuid: Uuid::from_u128(uuid),
                 }))
             }
-            1..4 => {
+            1 => {
+                let shared_secret = read_byte_array(src)?;
+                let verify_token = read_byte_array(src)?;
+                Ok(ServerBound::EncryptionResponse(EncryptionResponse {
+                    shared_secret: Cow::Owned(shared_secret.to_owned()),
+                    verify_token: Cow::Owned(verify_token.to_owned()),
+                }))
+            }
+            2 => {
+                let message_id = read_var_int(src)?;
+                let successful = src.read_u8()? != 0;
+                let data = match successful {
+                    true => Some(Cow::Owned(src.rest().to_owned())),
+                    false => None,
+                };
+                Ok(ServerBound::LoginPluginResponse(LoginPluginResponse {
+                    message_id,
+                    successful,
+                    data,
+                }))
+            }
+            3 => Ok(ServerBound::LoginAcknowledged),
+            4 => {
                 warn!(
                     "Tried to decode a valid but unsupported packet type {}",
                     number
                 );
-                Err(io::Error::new(
-                    io::ErrorKind::Unsupported,
-                    "unsupported packet type",
-                ))
-            }
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid packet type",
-            )),
+                Err(ProtocolError::UnknownPacket {
+                    state: ProtocolState::Login,
+                    id: number,
+                })
+            }
+            _ => Err(ProtocolError::UnknownPacket {
+                state: ProtocolState::Login,
+                id: number,
+            }),
         }
     }
 
     fn packet_number(&self) -> i32 {
         match self {
             ServerBound::LoginStart(_) => 0,
+            ServerBound::EncryptionResponse(_) => 1,
+            ServerBound::LoginPluginResponse(_) => 2,
+            ServerBound::LoginAcknowledged => 3,
         }
     }
 
@@ -65,67 +116,414 @@ impl<'a> Protocol<'a> for ServerBound<'a> {
             ServerBound::LoginStart(login_start) => {
                 string_size(&login_start.name) + mem::size_of::<u128>()
             }
+            ServerBound::EncryptionResponse(response) => {
+                byte_array_size(&response.shared_secret) + byte_array_size(&response.verify_token)
+            }
+            ServerBound::LoginPluginResponse(response) => {
+                var_int_size(response.message_id)
+                    + mem::size_of::<u8>()
+                    + response.data.as_ref().map_or(0, |data| data.len())
+            }
+            ServerBound::LoginAcknowledged => 0,
         }
     }
 
-    fn encode_packet(&self, writer: &mut impl Write) -> io::Result<()> {
+    fn encode_packet(&self, writer: &mut impl Write) -> Result<(), ProtocolError> {
         match self {
             ServerBound::LoginStart(login_start) => {
                 write_string(&login_start.name, writer)?;
                 writer.write_u128::<BigEndian>(login_start.uuid.as_u128())?;
             }
+            ServerBound::EncryptionResponse(response) => {
+                write_byte_array(&response.shared_secret, writer)?;
+                write_byte_array(&response.verify_token, writer)?;
+            }
+            ServerBound::LoginPluginResponse(response) => {
+                write_var_int(response.message_id, writer)?;
+                writer.write_u8(response.successful as u8)?;
+                if let Some(data) = &response.data {
+                    writer.write_all(data)?;
+                }
+            }
+            ServerBound::LoginAcknowledged => {}
         }
         Ok(())
     }
 }
 
+#[derive(Debug)]
+pub struct EncryptionRequest<'a> {
+    /// Always empty for current versions of the game
+    pub server_id: Cow<'a, str>,
+    /// The server's public key in DER encoding
+    pub public_key: Cow<'a, [u8]>,
+    pub verify_token: Cow<'a, [u8]>,
+    /// Whether the client should authenticate with the session server
+    pub should_authenticate: bool,
+}
+
+/// A property of a player's profile, e.g. the skin
+#[derive(Debug)]
+pub struct Property<'a> {
+    pub name: Cow<'a, str>,
+    pub value: Cow<'a, str>,
+    pub signature: Option<Cow<'a, str>>,
+}
+
+impl<'a> Property<'a> {
+    fn decode(src: &mut DecoderState<'_>) -> Result<Self, ProtocolError> {
+        let name = read_string(src)?;
+        let value = read_string(src)?;
+        let signature = match src.read_u8()? {
+            0 => None,
+            _ => Some(Cow::Owned(read_string(src)?.to_owned())),
+        };
+        Ok(Property {
+            name: Cow::Owned(name.to_owned()),
+            value: Cow::Owned(value.to_owned()),
+            signature,
+        })
+    }
+
+    fn encoded_size(&self) -> usize {
+        string_size(&self.name)
+            + string_size(&self.value)
+            + mem::size_of::<u8>()
+            + self.signature.as_deref().map_or(0, string_size)
+    }
+
+    fn encode(&self, writer: &mut impl Write) -> Result<(), ProtocolError> {
+        write_string(&self.name, writer)?;
+        write_string(&self.value, writer)?;
+        writer.write_u8(self.signature.is_some() as u8)?;
+        if let Some(signature) = &self.signature {
+            write_string(signature, writer)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct LoginSuccess<'a> {
+    pub uuid: Uuid,
+    pub name: Cow<'a, str>,
+    pub properties: Vec<Property<'a>>,
+}
+
+#[derive(Debug)]
+pub struct LoginPluginRequest<'a> {
+    /// Chosen by the server, the client answers with the same id
+    pub message_id: i32,
+    /// The identifier of the plugin channel
+    pub channel: Cow<'a, str>,
+    pub data: Cow<'a, [u8]>,
+}
+
 #[derive(Debug)]
 pub enum ClientBound<'a> {
-    Disconnect(Cow<'a, str>),
+    Disconnect(Chat<'a>),
+    EncryptionRequest(EncryptionRequest<'a>),
+    LoginSuccess(LoginSuccess<'a>),
+    /// Enables compression for all following packets. Packets with at least this many bytes are
+    /// compressed, a negative value disables compression.
+    SetCompression(i32),
+    LoginPluginRequest(LoginPluginRequest<'a>),
 }
 
 impl<'a> Protocol<'a> for ClientBound<'a> {
-    fn decode_packet(number: i32, src: &mut DecoderState<'a>) -> io::Result<Self> {
+    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> Result<Self, ProtocolError> {
         match number {
             0 => {
                 let reason = read_string(src)?;
-                Ok(ClientBound::Disconnect(Cow::Borrowed(reason)))
+                Ok(ClientBound::Disconnect(Chat::Json(Cow::Owned(
+                    reason.to_owned(),
+                ))))
+            }
+            1 => {
+                let server_id = read_string(src)?;
+                let public_key = read_byte_array(src)?;
+                let verify_token = read_byte_array(src)?;
+                let should_authenticate = src.read_u8()? != 0;
+                Ok(ClientBound::EncryptionRequest(EncryptionRequest {
+                    server_id: Cow::Owned(server_id.to_owned()),
+                    public_key: Cow::Owned(public_key.to_owned()),
+                    verify_token: Cow::Owned(verify_token.to_owned()),
+                    should_authenticate,
+                }))
+            }
+            2 => {
+                let uuid = src.read_u128::<BigEndian>()?;
+                let name = read_string(src)?;
+                let count = read_var_int(src)?;
+                if count < 0 {
+                    return Err(ProtocolError::InvalidField("property count"));
+                }
+                // Every property takes up at least three bytes, which bounds the allocation
+                let mut properties =
+                    Vec::with_capacity(usize::min(count as usize, src.remaining() / 3));
+                for _ in 0..count {
+                    properties.push(Property::decode(src)?);
+                }
+                Ok(ClientBound::LoginSuccess(LoginSuccess {
+                    uuid: Uuid::from_u128(uuid),
+                    name: Cow::Owned(name.to_owned()),
+                    properties,
+                }))
+            }
+            3 => {
+                let threshold = read_var_int(src)?;
+                Ok(ClientBound::SetCompression(threshold))
+            }
+            4 => {
+                let message_id = read_var_int(src)?;
+                let channel = read_string(src)?;
+                Ok(ClientBound::LoginPluginRequest(LoginPluginRequest {
+                    message_id,
+                    channel: Cow::Owned(channel.to_owned()),
+                    data: Cow::Owned(src.rest().to_owned()),
+                }))
             }
-            1..5 => {
+            5 => {
                 warn!(
                     "Tried to decode a valid but unsupported packet type {}",
                     number
                 );
-                Err(io::Error::new(
-                    io::ErrorKind::Unsupported,
-                    "unsupported packet type",
-                ))
-            }
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "invalid packet type",
-            )),
+                Err(ProtocolError::UnknownPacket {
+                    state: ProtocolState::Login,
+                    id: number,
+                })
+            }
+            _ => Err(ProtocolError::UnknownPacket {
+                state: ProtocolState::Login,
+                id: number,
+            }),
         }
     }
 
     fn packet_number(&self) -> i32 {
         match self {
             ClientBound::Disconnect(_) => 0,
+            ClientBound::EncryptionRequest(_) => 1,
+            ClientBound::LoginSuccess(_) => 2,
+            ClientBound::SetCompression(_) => 3,
+            ClientBound::LoginPluginRequest(_) => 4,
         }
     }
 
     fn encoded_size(&self) -> usize {
         match self {
-            ClientBound::Disconnect(reason) => string_size(&reason),
+            ClientBound::Disconnect(reason) => string_size(&reason.to_json()),
+            ClientBound::EncryptionRequest(request) => {
+                string_size(&request.server_id)
+                    + byte_array_size(&request.public_key)
+                    + byte_array_size(&request.verify_token)
+                    + mem::size_of::<u8>()
+            }
+            ClientBound::LoginSuccess(success) => {
+                mem::size_of::<u128>()
+                    + string_size(&success.name)
+                    + var_int_size(success.properties.len() as i32)
+                    + success
+                        .properties
+                        .iter()
+                        .map(Property::encoded_size)
+                        .sum::<usize>()
+            }
+            ClientBound::SetCompression(threshold) => var_int_size(*threshold),
+            ClientBound::LoginPluginRequest(request) => {
+                var_int_size(request.message_id)
+                    + string_size(&request.channel)
+                    + request.data.len()
+            }
         }
     }
 
-    fn encode_packet(&self, writer: &mut impl io::Write) -> io::Result<()> {
+    fn encode_packet(&self, writer: &mut impl io::Write) -> Result<(), ProtocolError> {
         match self {
             ClientBound::Disconnect(reason) => {
-                write_string(&reason, writer)?;
+                write_string(&reason.to_json(), writer)?;
+            }
+            ClientBound::EncryptionRequest(request) => {
+                write_string(&request.server_id, writer)?;
+                write_byte_array(&request.public_key, writer)?;
+                write_byte_array(&request.verify_token, writer)?;
+                writer.write_u8(request.should_authenticate as u8)?;
+            }
+            ClientBound::LoginSuccess(success) => {
+                writer.write_u128::<BigEndian>(success.uuid.as_u128())?;
+                write_string(&success.name, writer)?;
+                write_var_int(success.properties.len() as i32, writer)?;
+                for property in &success.properties {
+                    property.encode(writer)?;
+                }
+            }
+            ClientBound::SetCompression(threshold) => {
+                write_var_int(*threshold, writer)?;
+            }
+            ClientBound::LoginPluginRequest(request) => {
+                write_var_int(request.message_id, writer)?;
+                write_string(&request.channel, writer)?;
+                writer.write_all(&request.data)?;
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::{
+        bytes::BytesMut,
+        codec::{Decoder, Encoder},
+    };
+
+    use super::*;
+    use crate::protocol::{PacketDecoder, PacketEncoder};
+
+    /// Encodes the packet and decodes it again.
+    fn round_trip<'a, T: Protocol<'a>>(packet: T) -> T {
+        let mut buffer = BytesMut::new();
+        PacketEncoder::new().encode(packet, &mut buffer).unwrap();
+        let packet = PacketDecoder::<T>::new()
+            .decode(&mut buffer)
+            .unwrap()
+            .unwrap();
+        assert!(buffer.is_empty());
+        packet.into_inner()
+    }
+
+    #[test]
+    fn encryption_packets_round_trip() {
+        let request = round_trip(ClientBound::EncryptionRequest(EncryptionRequest {
+            server_id: Cow::Borrowed(""),
+            public_key: Cow::Borrowed(&[0x30, 0x81, 0x9f, 0x30]),
+            verify_token: Cow::Borrowed(&[1, 2, 3, 4]),
+            should_authenticate: true,
+        }));
+        let ClientBound::EncryptionRequest(request) = request else {
+            panic!("expected an encryption request, got {request:?}");
+        };
+        assert_eq!(request.server_id, "");
+        assert_eq!(*request.public_key, [0x30, 0x81, 0x9f, 0x30]);
+        assert_eq!(*request.verify_token, [1, 2, 3, 4]);
+        assert!(request.should_authenticate);
+
+        let response = round_trip(ServerBound::EncryptionResponse(EncryptionResponse {
+            shared_secret: Cow::Owned(vec![7; 128]),
+            verify_token: Cow::Owned(vec![9; 128]),
+        }));
+        let ServerBound::EncryptionResponse(response) = response else {
+            panic!("expected an encryption response, got {response:?}");
+        };
+        assert_eq!(*response.shared_secret, [7; 128]);
+        assert_eq!(*response.verify_token, [9; 128]);
+    }
+
+    #[test]
+    fn set_compression_round_trips() {
+        for threshold in [256, -1] {
+            let packet = round_trip(ClientBound::SetCompression(threshold));
+            assert!(
+                matches!(packet, ClientBound::SetCompression(decoded) if decoded == threshold),
+                "{packet:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn login_success_round_trips() {
+        let uuid = Uuid::from_u128(0x069a79f444e94726a5befca90e38aaf5);
+        let packet = round_trip(ClientBound::LoginSuccess(LoginSuccess {
+            uuid,
+            name: Cow::Borrowed("Notch"),
+            properties: Vec::new(),
+        }));
+        let ClientBound::LoginSuccess(success) = packet else {
+            panic!("expected a login success, got {packet:?}");
+        };
+        assert_eq!(success.uuid, uuid);
+        assert_eq!(success.name, "Notch");
+        assert!(success.properties.is_empty());
+
+        let packet = round_trip(ClientBound::LoginSuccess(LoginSuccess {
+            uuid,
+            name: Cow::Borrowed("Notch"),
+            properties: vec![
+                Property {
+                    name: Cow::Borrowed("textures"),
+                    value: Cow::Borrowed("eyJ0aW1lc3RhbXAiOjB9"),
+                    signature: Some(Cow::Borrowed("c2lnbmF0dXJl")),
+                },
+                Property {
+                    name: Cow::Borrowed("other"),
+                    value: Cow::Borrowed(""),
+                    signature: None,
+                },
+            ],
+        }));
+        let ClientBound::LoginSuccess(success) = packet else {
+            panic!("expected a login success, got {packet:?}");
+        };
+        let [signed, unsigned] = &success.properties[..] else {
+            panic!("expected two properties, got {:?}", success.properties);
+        };
+        assert_eq!(signed.name, "textures");
+        assert_eq!(signed.value, "eyJ0aW1lc3RhbXAiOjB9");
+        assert_eq!(signed.signature.as_deref(), Some("c2lnbmF0dXJl"));
+        assert_eq!(unsigned.name, "other");
+        assert_eq!(unsigned.value, "");
+        assert_eq!(unsigned.signature, None);
+    }
+
+    #[test]
+    fn login_plugin_messages_round_trip() {
+        let packet = round_trip(ClientBound::LoginPluginRequest(LoginPluginRequest {
+            message_id: 300,
+            channel: Cow::Borrowed("velocity:player_info"),
+            data: Cow::Borrowed(&[4]),
+        }));
+        let ClientBound::LoginPluginRequest(request) = packet else {
+            panic!("expected a login plugin request, got {packet:?}");
+        };
+        assert_eq!(request.message_id, 300);
+        assert_eq!(request.channel, "velocity:player_info");
+        assert_eq!(*request.data, [4]);
+
+        let packet = round_trip(ServerBound::LoginPluginResponse(LoginPluginResponse {
+            message_id: 300,
+            successful: true,
+            data: Some(Cow::Borrowed(&[1, 2, 3])),
+        }));
+        let ServerBound::LoginPluginResponse(response) = packet else {
+            panic!("expected a login plugin response, got {packet:?}");
+        };
+        assert_eq!(response.message_id, 300);
+        assert!(response.successful);
+        assert_eq!(response.data.as_deref(), Some(&[1, 2, 3][..]));
+
+        let packet = round_trip(ServerBound::LoginPluginResponse(LoginPluginResponse {
+            message_id: 1,
+            successful: false,
+            data: None,
+        }));
+        let ServerBound::LoginPluginResponse(response) = packet else {
+            panic!("expected a login plugin response, got {packet:?}");
+        };
+        assert!(!response.successful);
+        assert_eq!(response.data, None);
+    }
+
+    #[test]
+    fn decodes_the_empty_login_acknowledged() {
+        let packet = ServerBound::decode_packet(3, &mut DecoderState::new(&[])).unwrap();
+        assert!(matches!(packet, ServerBound::LoginAcknowledged));
+        assert!(matches!(
+            round_trip(ServerBound::LoginAcknowledged),
+            ServerBound::LoginAcknowledged
+        ));
+        assert!(matches!(
+            ServerBound::decode_packet(4, &mut DecoderState::new(&[])),
+            Err(ProtocolError::UnknownPacket { id: 4, .. })
+        ));
+    }
+}
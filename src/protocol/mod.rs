@@ -1,33 +1,71 @@
 use std::{
+    fmt::{self, Display, Formatter},
     io::{self, Read, Write},
     marker::PhantomData,
-    mem,
     ops::{Deref, DerefMut},
 };
 
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{Duration, timeout},
+};
 use tokio_util::{
     bytes::{Bytes, BytesMut},
     codec::{Decoder, Encoder},
 };
 
-use crate::protocol::types::{read_var_int, var_int_size, write_var_int};
+use crate::{
+    error::Error,
+    protocol::types::{read_var_int, var_int_size, write_var_int},
+};
+
+pub use encryption::EncryptedStream;
+pub use error::ProtocolError;
 
+pub mod chat;
+mod compression;
+mod encryption;
+mod error;
+pub mod legacy;
 pub mod types;
 
+pub mod configuration;
 pub mod handshake;
 pub mod login;
 pub mod status;
 
+/// The state of a connection, which determines how packet ids are interpreted.
+#[derive(Debug, Clone, Copy)]
+pub enum ProtocolState {
+    Handshaking,
+    Status,
+    Login,
+    Configuration,
+}
+
+impl Display for ProtocolState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolState::Handshaking => write!(f, "handshaking"),
+            ProtocolState::Status => write!(f, "status"),
+            ProtocolState::Login => write!(f, "login"),
+            ProtocolState::Configuration => write!(f, "configuration"),
+        }
+    }
+}
+
+/// A set of packets that can be sent in one state of a connection. Packets may borrow their fields
+/// for encoding, but decoded packets own them, so they do not depend on the receive buffer.
 pub trait Protocol<'a>: Sized {
-    fn decode_packet(number: i32, src: &mut DecoderState<'a>) -> io::Result<Self>;
+    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> Result<Self, ProtocolError>;
     fn packet_number(&self) -> i32;
     fn encoded_size(&self) -> usize;
-    fn encode_packet(&self, writer: &mut impl Write) -> io::Result<()>;
+    fn encode_packet(&self, writer: &mut impl Write) -> Result<(), ProtocolError>;
 }
 
 /// A container for any packet type.
-/// The container keeps a reference to the underlying buffer for zero-copy packets, so it should not
-/// be longe lived.
+/// The container keeps the frame the packet was decoded from, so it can be forwarded as it was
+/// received.
 pub struct Packet<T> {
     data: T,
     bytes: Bytes,
@@ -37,6 +75,12 @@ impl<T> Packet<T> {
     pub fn buffer(&self) -> Bytes {
         self.bytes.clone()
     }
+
+    /// Returns the decoded packet without the frame it was decoded from.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn into_inner(self) -> T {
+        self.data
+    }
 }
 
 impl<T> Deref for Packet<T> {
@@ -59,6 +103,31 @@ pub struct DecoderState<'a> {
 }
 
 impl<'a> DecoderState<'a> {
+    /// Creates a state that reads the fields of a packet from the start of `buffer`.
+    pub fn new(buffer: &'a [u8]) -> DecoderState<'a> {
+        DecoderState { buffer, offset: 0 }
+    }
+
+    /// Returns the number of bytes left in the current packet.
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+
+    pub fn has_remaining(&self) -> bool {
+        self.remaining() > 0
+    }
+
+    /// Restricts all further reads to the next `len` bytes.
+    /// Fails with `UnexpectedEof` if fewer than `len` bytes are left.
+    pub fn limit(&mut self, len: usize) -> io::Result<()> {
+        if self.remaining() < len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+
+        self.buffer = &self.buffer[..self.offset + len];
+        Ok(())
+    }
+
     pub fn bytes(&mut self, count: usize) -> Result<&'a [u8], io::Error> {
         let start = self.offset;
         let end = self.offset + count;
@@ -70,6 +139,13 @@ impl<'a> DecoderState<'a> {
         self.offset += count;
         Ok(&self.buffer[start..end])
     }
+
+    /// Returns everything left in the current packet, for fields that extend to the end of it.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.buffer[self.offset..];
+        self.offset = self.buffer.len();
+        rest
+    }
 }
 
 impl<'a> Read for DecoderState<'a> {
@@ -80,24 +156,46 @@ impl<'a> Read for DecoderState<'a> {
         }
 
         let bytes = self.bytes(len).unwrap();
-        buf.copy_from_slice(bytes);
+        buf[..len].copy_from_slice(bytes);
         Ok(len)
     }
 }
 
+/// The default upper bound for the size of a single packet.
+/// This is well above the size of any legitimate handshake, status or login packet.
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 2 * 1024 * 1024;
+
 #[derive(Debug)]
 pub struct PacketDecoder<T> {
     needed: Option<usize>,
+    max_packet_size: usize,
+    compression_threshold: Option<usize>,
     _phantom: PhantomData<T>,
 }
 
 impl<T> PacketDecoder<T> {
     pub fn new() -> PacketDecoder<T> {
+        PacketDecoder::with_max_size(DEFAULT_MAX_PACKET_SIZE)
+    }
+
+    /// Creates a decoder that rejects packets larger than `max_packet_size` bytes.
+    pub fn with_max_size(max_packet_size: usize) -> PacketDecoder<T> {
         PacketDecoder {
             needed: None,
+            max_packet_size,
+            compression_threshold: None,
             _phantom: PhantomData,
         }
     }
+
+    /// Switches the decoder to the compressed packet format, using the `threshold` announced to
+    /// the sender.
+    /// Packets below the threshold may still be sent uncompressed, but compressed packets with less
+    /// data than the threshold are rejected, like the vanilla server does.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn enable_compression(&mut self, threshold: usize) {
+        self.compression_threshold = Some(threshold);
+    }
 }
 
 impl<'a, T> Decoder for PacketDecoder<T>
@@ -105,8 +203,7 @@ where
     T: Protocol<'a>,
 {
     type Item = Packet<T>;
-    // TODO: Make a custom error here?
-    type Error = io::Error;
+    type Error = ProtocolError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         if let Some(n) = self.needed
@@ -115,51 +212,72 @@ where
             return Ok(None);
         }
 
-        // SAFETY: This transmute is used to convert the (unknown) lifetime of src to the lifetime
-        // we expect fo all of our references in the packet, which we return.
-        // By tapping the packet in the Packet struct alongside an owning buffer to that memory,
-        // this is safe, even if rust does not agree due to the unsafe in the bytes crate.
-        let mut state: DecoderState<'a> = DecoderState::<'a> {
-            buffer: unsafe { mem::transmute(&src[..]) },
-            offset: 0,
-        };
-
-        let raw_len = match read_var_int(&mut state) {
+        // The length prefix is read from a temporary view of the receive buffer. Nothing from this
+        // view escapes, since the packet itself is decoded from the frozen frame below.
+        let mut prefix = DecoderState::new(&src[..]);
+        let raw_len = match read_var_int(&mut prefix) {
             Ok(l) => l,
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(ProtocolError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None);
+            }
             Err(e) => return Err(e),
         };
         if raw_len < 0 {
-            return Err(io::Error::from(io::ErrorKind::InvalidData));
+            return Err(ProtocolError::InvalidField("packet length"));
         }
         let len = raw_len as usize;
+        if len > self.max_packet_size {
+            return Err(ProtocolError::TooLarge { len });
+        }
+        let prefix_len = prefix.offset;
 
-        if len + state.offset > src.len() {
-            self.needed = Some(len);
+        if prefix.limit(len).is_err() {
+            // The length prefix has to be buffered again on the next call, so it counts towards
+            // the number of bytes needed.
+            self.needed = Some(prefix_len + len);
             return Ok(None);
         }
 
-        // Hack to shorten the buffer length available to decoder
-        // It would be better to have some form of reslice function to do that in a defined way
-        state.buffer = &state.buffer[..state.offset + len];
-
-        let kind = match read_var_int(&mut state) {
-            Ok(l) => l,
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
-            Err(e) => return Err(e),
+        // Splitting the complete frame off the receive buffer before decoding gives us an owned,
+        // immutable buffer. Growing or reusing `src` afterwards can not affect it.
+        self.needed = None;
+        let frame = src.split_to(prefix_len + len).freeze();
+        let mut state = DecoderState {
+            buffer: &frame,
+            offset: prefix_len,
         };
 
-        // We don't convert the EOF error here since we don't expect an EOF in a valid packet here.
+        // We don't convert EOF errors from here on, since the frame is known to be complete.
+        let mut data_len = 0;
+        if let Some(threshold) = self.compression_threshold {
+            let raw_data_len = read_var_int(&mut state)?;
+            if raw_data_len < 0 {
+                return Err(ProtocolError::InvalidField("data length"));
+            }
+            data_len = raw_data_len as usize;
+            if data_len > self.max_packet_size {
+                return Err(ProtocolError::TooLarge { len: data_len });
+            }
+            if data_len != 0 && data_len < threshold {
+                return Err(ProtocolError::InvalidField("data length"));
+            }
+        }
+
+        // A data length of zero marks a packet that was sent uncompressed.
+        let decompressed;
+        if data_len != 0 {
+            let remaining = state.remaining();
+            decompressed =
+                compression::decompress(DecoderState::bytes(&mut state, remaining)?, data_len)?;
+            state = DecoderState::new(&decompressed);
+        }
+
+        let kind = read_var_int(&mut state)?;
         let packet = T::decode_packet(kind, &mut state)?;
 
-        // By splitting the buffer here, we ensure that any pointer into the packet buffer should be
-        // remain valid even if the byte buffer is grown at some point.
-        // To ensure that the pointers remain valid, we wrap the packet in a Packet object, which
-        // keeps the byte object alive while allowing access to the inner types.
-        self.needed = None;
         Ok(Some(Packet {
             data: packet,
-            bytes: src.split_to(state.offset).freeze(),
+            bytes: frame,
         }))
     }
 }
@@ -182,44 +300,338 @@ impl<'a> Write for EncoderState<'a> {
 
 #[derive(Debug)]
 pub struct PacketEncoder<T> {
+    compression_threshold: Option<usize>,
     _phantom: PhantomData<T>,
 }
 
 impl<T> PacketEncoder<T> {
     pub fn new() -> PacketEncoder<T> {
         PacketEncoder {
+            compression_threshold: None,
             _phantom: PhantomData,
         }
     }
+
+    /// Switches the encoder to the compressed packet format.
+    /// Packets with at least `threshold` bytes of data are compressed, smaller ones are sent as is.
+    pub fn enable_compression(&mut self, threshold: usize) {
+        self.compression_threshold = Some(threshold);
+    }
 }
 
 impl<'a, T> Encoder<T> for PacketEncoder<T>
 where
     T: Protocol<'a>,
 {
-    type Error = io::Error;
+    type Error = ProtocolError;
 
     fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let id = item.packet_number();
-        let size = item.encoded_size();
+        encode_frame(&item, self.compression_threshold, dst)
+    }
+}
+
+/// Reads exactly one packet from `reader`, giving up after `duration`.
+/// This is meant for one-shot reads like the handshake, where a `FramedRead` would have to be torn
+/// down again afterwards.
+/// A client may pipeline its next packet into the same TCP segment as the first one, so the bytes
+/// read past the end of the packet are returned alongside it. Callers must feed them to the next
+/// stage, e.g. by chaining them in front of the reader.
+pub async fn read_single_packet<'a, T: Protocol<'a>>(
+    reader: &mut (impl AsyncRead + Unpin),
+    duration: Duration,
+) -> Result<(Packet<T>, BytesMut), Error> {
+    let mut decoder = PacketDecoder::new();
+    let mut buffer = BytesMut::with_capacity(256);
+    timeout(duration, async {
+        loop {
+            if let Some(packet) = decoder.decode(&mut buffer)? {
+                return Ok((packet, buffer));
+            }
+            if reader.read_buf(&mut buffer).await? == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    })
+    .await?
+}
+
+/// Encodes a single packet and writes it to `writer`.
+/// This is meant for sending individual packets where a `FramedWrite` would be overkill. Packets are
+/// always sent uncompressed. Returns the number of bytes written.
+pub async fn write_packet<'a, T: Protocol<'a>>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    packet: &T,
+) -> Result<usize, ProtocolError> {
+    let mut buffer = BytesMut::new();
+    encode_frame(packet, None, &mut buffer)?;
+    writer.write_all(&buffer).await?;
+    Ok(buffer.len())
+}
+
+/// Encodes a complete frame including the length prefix and, if enabled, the compression header.
+fn encode_frame<'a, T: Protocol<'a>>(
+    item: &T,
+    compression_threshold: Option<usize>,
+    dst: &mut BytesMut,
+) -> Result<(), ProtocolError> {
+    let id = item.packet_number();
+    let size = item.encoded_size();
+
+    let total_size = size + var_int_size(id);
+    assert!(total_size < i32::MAX as usize);
+
+    match compression_threshold {
+        None => {
+            dst.reserve(var_int_size(total_size as i32) + total_size);
+            let mut state = EncoderState { bytes: dst };
+            write_var_int(total_size as i32, &mut state)?;
+            encode_data(item, total_size, state.bytes)?;
+        }
+        Some(threshold) if total_size < threshold => {
+            // Packets below the threshold are prefixed with a data length of zero
+            dst.reserve(var_int_size(total_size as i32 + 1) + 1 + total_size);
+            let mut state = EncoderState { bytes: dst };
+            write_var_int(total_size as i32 + 1, &mut state)?;
+            write_var_int(0, &mut state)?;
+            encode_data(item, total_size, state.bytes)?;
+        }
+        Some(_) => {
+            let mut data = BytesMut::with_capacity(total_size);
+            encode_data(item, total_size, &mut data)?;
+            let compressed = compression::compress(&data)?;
+
+            let frame_size = var_int_size(total_size as i32) + compressed.len();
+            assert!(frame_size < i32::MAX as usize);
+            dst.reserve(var_int_size(frame_size as i32) + frame_size);
+            let mut state = EncoderState { bytes: dst };
+            write_var_int(frame_size as i32, &mut state)?;
+            write_var_int(total_size as i32, &mut state)?;
+            state.bytes.extend_from_slice(&compressed);
+        }
+    }
+    Ok(())
+}
 
-        let total_size = size + var_int_size(id);
-        assert!(total_size < i32::MAX as usize);
-        dst.reserve(var_int_size(total_size as i32) + total_size);
+/// Writes the packet id and body of a packet, checking that the size matches the expected one.
+fn encode_data<'a, T: Protocol<'a>>(
+    item: &T,
+    total_size: usize,
+    dst: &mut BytesMut,
+) -> Result<(), ProtocolError> {
+    let start_len = dst.len();
+    let mut state = EncoderState { bytes: dst };
+    write_var_int(item.packet_number(), &mut state)?;
+    item.encode_packet(&mut state)?;
+
+    assert!(
+        dst.len() - start_len == total_size,
+        "Packet size mismatch, expected: {}, actual: {}",
+        total_size,
+        dst.len() - start_len
+    );
+    Ok(())
+}
 
-        let mut state = EncoderState { bytes: dst };
-        write_var_int(total_size as i32, &mut state)?;
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
 
-        let start_len = state.bytes.len();
-        write_var_int(id, &mut state)?;
-        item.encode_packet(&mut state)?;
+    use super::*;
+    use crate::protocol::handshake::{HandshakePacket, NextState, ProtocolVersion};
 
-        assert!(
-            dst.len() - start_len == total_size,
-            "Packet size mismatch, expected: {}, actual: {}",
-            size,
-            dst.len() - start_len
+    fn handshake(address: &str) -> HandshakePacket<'_> {
+        HandshakePacket {
+            version: ProtocolVersion::V1_21_7,
+            address: Cow::Borrowed(address),
+            port: 25565,
+            next_state: NextState::Login,
+        }
+    }
+
+    #[test]
+    fn remaining_counts_down_with_partial_reads() {
+        let mut state = DecoderState::new(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(state.remaining(), 6);
+        assert!(state.has_remaining());
+
+        assert_eq!(DecoderState::bytes(&mut state, 2).unwrap(), [1, 2]);
+        assert_eq!(state.remaining(), 4);
+        let mut buf = [0; 3];
+        state.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [3, 4, 5]);
+        assert_eq!(state.remaining(), 1);
+
+        // A failed read consumes nothing
+        assert!(DecoderState::bytes(&mut state, 2).is_err());
+        assert_eq!(state.remaining(), 1);
+        state.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(state.remaining(), 0);
+        assert!(!state.has_remaining());
+    }
+
+    #[test]
+    fn decoded_fields_outlive_the_receive_buffer() {
+        let mut encoder = PacketEncoder::new();
+        let mut src = BytesMut::new();
+        encoder
+            .encode(handshake("first.example.com"), &mut src)
+            .unwrap();
+        encoder
+            .encode(handshake("second.example.com"), &mut src)
+            .unwrap();
+
+        let mut decoder = PacketDecoder::<HandshakePacket>::new();
+        let first = decoder.decode(&mut src).unwrap().unwrap();
+        // Growing the buffer moves its contents, overwriting them reuses the memory
+        src.reserve(1024 * 1024);
+        src.extend_from_slice(&[0xff; 64 * 1024]);
+        src[..].fill(0);
+        assert_eq!(first.address, "first.example.com");
+        assert_eq!(first.port, 25565);
+
+        let mut compressed = PacketDecoder::<HandshakePacket>::new();
+        compressed.enable_compression(0);
+        let mut encoder = PacketEncoder::new();
+        encoder.enable_compression(0);
+        let mut src = BytesMut::new();
+        let address = "a".repeat(1000);
+        encoder.encode(handshake(&address), &mut src).unwrap();
+        let packet = compressed.decode(&mut src).unwrap().unwrap();
+        drop(src);
+        assert_eq!(packet.address, address);
+    }
+
+    /// Returns a buffer holding only the length prefix of a packet.
+    fn length_prefix(len: i32) -> BytesMut {
+        let mut prefix = Vec::new();
+        write_var_int(len, &mut prefix).unwrap();
+        BytesMut::from(&prefix[..])
+    }
+
+    #[test]
+    fn rejects_absurd_packet_lengths_right_away() {
+        let mut decoder = PacketDecoder::<HandshakePacket>::new();
+        let mut src = length_prefix(i32::MAX);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ProtocolError::TooLarge { len }) if len == i32::MAX as usize
+        ));
+
+        let mut decoder = PacketDecoder::<HandshakePacket>::with_max_size(16);
+        let mut src = length_prefix(17);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ProtocolError::TooLarge { len: 17 })
+        ));
+
+        // -1 as a VarInt
+        let mut src = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 0x0f][..]);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ProtocolError::InvalidField("packet length"))
+        ));
+    }
+
+    #[test]
+    fn compressed_packets_round_trip() {
+        let mut encoder = PacketEncoder::new();
+        encoder.enable_compression(256);
+        let mut src = BytesMut::new();
+        encoder
+            .encode(handshake("small.example.com"), &mut src)
+            .unwrap();
+        // Packets below the threshold are sent as is, after a data length of zero
+        let small_len = src.len();
+        assert_eq!(src[1], 0);
+        assert_eq!(&src[6..23], b"small.example.com");
+
+        let address = "large".repeat(200);
+        encoder.encode(handshake(&address), &mut src).unwrap();
+        assert!(src.len() - small_len < address.len());
+        let encoded = src.clone();
+
+        let mut decoder = PacketDecoder::<HandshakePacket>::new();
+        decoder.enable_compression(256);
+        let small = decoder.decode(&mut src).unwrap().unwrap();
+        assert_eq!(small.address, "small.example.com");
+        let large = decoder.decode(&mut src).unwrap().unwrap();
+        assert_eq!(large.address, address);
+        assert!(matches!(large.next_state, NextState::Login));
+        assert!(src.is_empty());
+        // Both packets keep their frame as it was received, so they can be forwarded unchanged
+        assert_eq!(small.buffer(), encoded[..small_len]);
+        assert_eq!(large.buffer(), encoded[small_len..]);
+    }
+
+    #[test]
+    fn rejects_compressed_packets_below_the_threshold() {
+        let mut encoder = PacketEncoder::new();
+        encoder.enable_compression(0);
+        let mut src = BytesMut::new();
+        encoder
+            .encode(handshake("small.example.com"), &mut src)
+            .unwrap();
+
+        let mut decoder = PacketDecoder::<HandshakePacket>::new();
+        decoder.enable_compression(256);
+        assert!(matches!(
+            decoder.decode(&mut src),
+            Err(ProtocolError::InvalidField("data length"))
+        ));
+    }
+
+    #[test]
+    fn reads_stop_at_the_limit() {
+        let mut state = DecoderState::new(&[1, 2, 3, 4, 5, 6]);
+        DecoderState::bytes(&mut state, 1).unwrap();
+        state.limit(3).unwrap();
+        assert_eq!(state.remaining(), 3);
+
+        assert!(DecoderState::bytes(&mut state, 4).is_err());
+        assert_eq!(DecoderState::bytes(&mut state, 3).unwrap(), [2, 3, 4]);
+
+        let mut buf = [0; 4];
+        let mut state = DecoderState::new(&[1, 2, 3, 4, 5, 6]);
+        state.limit(3).unwrap();
+        assert!(DecoderState::bytes(&mut state, 4).is_err());
+        assert_eq!(DecoderState::bytes(&mut state, 3).unwrap(), [1, 2, 3]);
+        assert!(state.read(&mut buf).is_err());
+
+        let mut state = DecoderState::new(&[1, 2]);
+        let error = state.limit(3).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn writes_a_single_length_prefixed_packet() {
+        let mut written = Vec::new();
+        write_packet(&mut written, &handshake("mc")).await.unwrap();
+        assert_eq!(
+            written,
+            [9, 0x00, 0x84, 0x06, 2, b'm', b'c', 0x63, 0xdd, 2],
+            "length, id, version 772, address, port 25565 and login"
         );
-        Ok(())
+    }
+
+    #[test]
+    fn waits_for_packets_fed_in_small_chunks() {
+        let mut encoded = BytesMut::new();
+        let address = "a".repeat(200);
+        PacketEncoder::new()
+            .encode(handshake(&address), &mut encoded)
+            .unwrap();
+        assert!(encoded[0] & 0x80 != 0, "the length prefix takes two bytes");
+
+        let mut decoder = PacketDecoder::<HandshakePacket>::new();
+        let mut src = BytesMut::new();
+        for chunk in encoded[..encoded.len() - 1].chunks(3) {
+            src.extend_from_slice(chunk);
+            assert!(decoder.decode(&mut src).unwrap().is_none());
+        }
+        src.extend_from_slice(&encoded[encoded.len() - 1..]);
+        let packet = decoder.decode(&mut src).unwrap().unwrap();
+        assert_eq!(packet.address, address);
+        assert!(src.is_empty());
+        assert!(decoder.decode(&mut src).unwrap().is_none());
     }
 }
@@ -3,7 +3,7 @@ use std::{borrow::Cow, io, mem};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use crate::protocol::{
-    Protocol,
+    Protocol, ProtocolError, ProtocolState,
     types::{read_string, string_size, write_string},
 };
 
@@ -16,14 +16,14 @@ pub enum ServerBound {
 }
 
 impl Protocol<'_> for ServerBound {
-    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> io::Result<Self> {
+    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> Result<Self, ProtocolError> {
         match number {
             0 => Ok(ServerBound::StatusRequest),
             1 => Ok(ServerBound::PingRequest(src.read_i64::<BigEndian>()?)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "got an invalid packet number",
-            )),
+            _ => Err(ProtocolError::UnknownPacket {
+                state: ProtocolState::Status,
+                id: number,
+            }),
         }
     }
 
@@ -41,7 +41,7 @@ impl Protocol<'_> for ServerBound {
         }
     }
 
-    fn encode_packet(&self, writer: &mut impl io::Write) -> io::Result<()> {
+    fn encode_packet(&self, writer: &mut impl io::Write) -> Result<(), ProtocolError> {
         match self {
             ServerBound::StatusRequest => {}
             ServerBound::PingRequest(ts) => writer.write_i64::<BigEndian>(*ts)?,
@@ -57,16 +57,16 @@ pub enum ClientBound<'a> {
 }
 
 impl<'a> Protocol<'a> for ClientBound<'a> {
-    fn decode_packet(number: i32, src: &mut DecoderState<'a>) -> io::Result<Self> {
+    fn decode_packet(number: i32, src: &mut DecoderState<'_>) -> Result<Self, ProtocolError> {
         match number {
             0 => Ok(ClientBound::StatusResponse {
-                json_response: Cow::Borrowed(read_string(src)?),
+                json_response: Cow::Owned(read_string(src)?.to_owned()),
             }),
             1 => Ok(ClientBound::PingResponse(src.read_i64::<BigEndian>()?)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "got an invalid packet number",
-            )),
+            _ => Err(ProtocolError::UnknownPacket {
+                state: ProtocolState::Status,
+                id: number,
+            }),
         }
     }
 
@@ -79,14 +79,14 @@ impl<'a> Protocol<'a> for ClientBound<'a> {
 
     fn encoded_size(&self) -> usize {
         match self {
-            ClientBound::StatusResponse { json_response } => string_size(&json_response),
+            ClientBound::StatusResponse { json_response } => string_size(json_response),
             ClientBound::PingResponse(_) => mem::size_of::<i64>(),
         }
     }
 
-    fn encode_packet(&self, writer: &mut impl io::Write) -> io::Result<()> {
+    fn encode_packet(&self, writer: &mut impl io::Write) -> Result<(), ProtocolError> {
         match self {
-            ClientBound::StatusResponse { json_response } => write_string(&json_response, writer)?,
+            ClientBound::StatusResponse { json_response } => write_string(json_response, writer)?,
             ClientBound::PingResponse(ts) => writer.write_i64::<BigEndian>(*ts)?,
         }
         Ok(())
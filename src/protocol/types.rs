@@ -4,9 +4,9 @@ use std::{
     mem,
 };
 
-use crate::protocol::DecoderState;
+use crate::protocol::{DecoderState, ProtocolError};
 
-pub fn read_var_int(src: &mut impl Read) -> io::Result<i32> {
+pub fn read_var_int(src: &mut impl Read) -> Result<i32, ProtocolError> {
     let mut value = 0;
     for i in 0..5 {
         let byte = src.read_u8()? as i32;
@@ -15,19 +15,18 @@ pub fn read_var_int(src: &mut impl Read) -> io::Result<i32> {
             return Ok(value);
         }
     }
-    Err(io::Error::new(
-        io::ErrorKind::InvalidData,
-        "variant takes up too many bytes",
-    ))
+    Err(ProtocolError::VarIntTooLong)
 }
 
 // Return the size of a var int when encoded
 pub fn var_int_size(int: i32) -> usize {
     let bits = mem::size_of::<i32>() * 8 - int.leading_zeros() as usize;
-    usize::max((bits + 6) / 7, 1)
+    usize::max(bits.div_ceil(7), 1)
 }
 
-pub fn write_var_int(mut int: i32, dest: &mut impl Write) -> io::Result<()> {
+pub fn write_var_int(int: i32, dest: &mut impl Write) -> io::Result<()> {
+    // Negative values are written as their two's complement, which needs a logical shift
+    let mut int = int as u32;
     loop {
         let byte = (int & 0x7f) as u8;
         int >>= 7;
@@ -41,22 +40,22 @@ pub fn write_var_int(mut int: i32, dest: &mut impl Write) -> io::Result<()> {
     Ok(())
 }
 
-pub fn read_string<'a>(src: &mut DecoderState<'a>) -> io::Result<&'a str> {
+pub fn read_string<'a>(src: &mut DecoderState<'a>) -> Result<&'a str, ProtocolError> {
     let len = read_var_int(src)?;
     if len < 0 {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid length"));
+        return Err(ProtocolError::InvalidField("string length"));
     }
 
     let bytes = src.bytes(len as usize)?;
-    let result = str::from_utf8(&bytes[..len as usize])
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "string is not valid UTF-8"))?;
+    let result =
+        str::from_utf8(&bytes[..len as usize]).map_err(|_| ProtocolError::InvalidString)?;
 
     Ok(result)
 }
 
 pub fn string_size(string: &str) -> usize {
     assert!(string.len() < i32::MAX as usize);
-    return var_int_size(string.len() as i32) + string.len();
+    var_int_size(string.len() as i32) + string.len()
 }
 
 pub fn write_string(string: &str, dest: &mut impl Write) -> io::Result<()> {
@@ -65,3 +64,25 @@ pub fn write_string(string: &str, dest: &mut impl Write) -> io::Result<()> {
     dest.write_all(string.as_bytes())?;
     Ok(())
 }
+
+/// Reads a byte array prefixed with its length as a var int.
+pub fn read_byte_array<'a>(src: &mut DecoderState<'a>) -> Result<&'a [u8], ProtocolError> {
+    let len = read_var_int(src)?;
+    if len < 0 {
+        return Err(ProtocolError::InvalidField("byte array length"));
+    }
+
+    Ok(src.bytes(len as usize)?)
+}
+
+pub fn byte_array_size(bytes: &[u8]) -> usize {
+    assert!(bytes.len() < i32::MAX as usize);
+    var_int_size(bytes.len() as i32) + bytes.len()
+}
+
+pub fn write_byte_array(bytes: &[u8], dest: &mut impl Write) -> io::Result<()> {
+    assert!(bytes.len() < i32::MAX as usize);
+    write_var_int(bytes.len() as i32, dest)?;
+    dest.write_all(bytes)?;
+    Ok(())
+}
@@ -0,0 +1,267 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use byteorder::{BigEndian, WriteBytesExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpStream,
+};
+
+use crate::error::Error;
+
+/// The signature every version 2 header starts with
+pub const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2 in the high nibble and the `PROXY` command in the low nibble
+const V2_PROXY: u8 = 0x21;
+/// Version 2 and the `LOCAL` command, which tells the receiver to use the connection's addresses
+const V2_LOCAL: u8 = 0x20;
+const FAMILY_UNSPEC: u8 = 0x00;
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+/// The longest possible version 1 header including the line break
+const V1_MAX_LENGTH: usize = 107;
+
+/// Builds a PROXY protocol version 2 header telling the backend that `source` connected to
+/// `destination`. If only one of the addresses is an IPv6 address, the other one is sent as an
+/// IPv4-mapped IPv6 address.
+pub fn v2_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(V2_SIGNATURE.len() + 4 + 36);
+    header.extend_from_slice(V2_SIGNATURE);
+    header.push(V2_PROXY);
+    write_v2_addresses(&mut header, source, destination).expect("writing to a vector can not fail");
+    header
+}
+
+/// Builds a PROXY protocol version 2 header without addresses, for clients whose address is not
+/// known.
+pub fn v2_local_header() -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[V2_LOCAL, FAMILY_UNSPEC, 0, 0]);
+    header
+}
+
+fn write_v2_addresses(
+    header: &mut Vec<u8>,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> std::io::Result<()> {
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            header.write_u8(FAMILY_TCP4)?;
+            header.write_u16::<BigEndian>(12)?;
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&destination_ip.octets());
+        }
+        (source_ip, destination_ip) => {
+            let ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.write_u8(FAMILY_TCP6)?;
+            header.write_u16::<BigEndian>(36)?;
+            header.extend_from_slice(&ipv6(source_ip).octets());
+            header.extend_from_slice(&ipv6(destination_ip).octets());
+        }
+    }
+    header.write_u16::<BigEndian>(source.port())?;
+    header.write_u16::<BigEndian>(destination.port())?;
+    Ok(())
+}
+
+/// Returns whether the data at the start of the stream looks like a PROXY protocol header.
+pub async fn has_header(stream: &TcpStream) -> std::io::Result<bool> {
+    let mut start = [0; 12];
+    let read = stream.peek(&mut start).await?;
+    let start = &start[..read];
+    Ok(start.starts_with(b"PROXY ") || (read >= 6 && V2_SIGNATURE.starts_with(start)))
+}
+
+/// Reads a PROXY protocol header of either version from the start of the stream.
+/// Returns the address of the client, or `None` if the header does not contain one, like the
+/// headers used for health checks. Nothing beyond the header is read from the stream.
+pub async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<SocketAddr>, Error> {
+    let mut header = vec![0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut header).await?;
+    if header == V2_SIGNATURE {
+        return read_v2_header(stream).await;
+    }
+    if !header.starts_with(b"PROXY ") {
+        return Err("expected a PROXY protocol header".into());
+    }
+
+    // The line is read byte by byte so that nothing after it is consumed
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LENGTH {
+            return Err("PROXY protocol header is too long".into());
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&header[..header.len() - 2])
+        .map_err(|_| "PROXY protocol header is not valid text")?;
+    parse_v1(line)
+}
+
+/// Parses a version 1 header like `PROXY TCP4 192.168.0.1 192.168.0.11 56324 443`.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, Error> {
+    let invalid = || Error::from("invalid PROXY protocol header");
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid()),
+    }
+    let address = fields.next().ok_or_else(invalid)?;
+    let _destination = fields.next().ok_or_else(invalid)?;
+    let port = fields.next().ok_or_else(invalid)?;
+    let address = IpAddr::from_str(address).map_err(|_| invalid())?;
+    let port = u16::from_str(port).map_err(|_| invalid())?;
+    Ok(Some(SocketAddr::new(address, port)))
+}
+
+async fn read_v2_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<SocketAddr>, Error> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+    let mut addresses = vec![0; length as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err("unsupported PROXY protocol version".into());
+    }
+    match version_command & 0x0f {
+        // A connection made by the proxy itself, e.g. for a health check
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err("unsupported PROXY protocol command".into()),
+    }
+
+    // Additional data like TLVs may follow the addresses
+    let (address, port) = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let address: [u8; 4] = addresses[..4].try_into().expect("slice has the right size");
+            (IpAddr::from(address), &addresses[8..10])
+        }
+        2 if addresses.len() >= 36 => {
+            let address: [u8; 16] = addresses[..16]
+                .try_into()
+                .expect("slice has the right size");
+            (IpAddr::from(address), &addresses[32..34])
+        }
+        1 | 2 => return Err("PROXY protocol header is too short for its addresses".into()),
+        // Unspecified or Unix socket addresses, which are of no use for a TCP proxy
+        _ => return Ok(None),
+    };
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    Ok(Some(SocketAddr::new(address, port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn builds_v2_headers_for_ipv4() {
+        let source = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)), 51234);
+        let destination = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 25565);
+        let header = v2_header(source, destination);
+
+        let mut expected = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        // Version 2 and PROXY, TCP over IPv4, 12 bytes of addresses
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        expected.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1]);
+        expected.extend_from_slice(&[0xc8, 0x22, 0x63, 0xdd]);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn builds_v2_headers_for_ipv6() {
+        let source_ip = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 7);
+        let source = SocketAddr::new(IpAddr::V6(source_ip), 51234);
+        let destination = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 25565);
+        let header = v2_header(source, destination);
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        // Version 2 and PROXY, TCP over IPv6, 36 bytes of addresses
+        expected.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        expected.extend_from_slice(&source_ip.octets());
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&[0xc8, 0x22, 0x63, 0xdd]);
+        assert_eq!(header, expected);
+
+        // An IPv4 address is mapped if the other one is an IPv6 address
+        let source = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)), 51234);
+        let header = v2_header(source, destination);
+        assert_eq!(header[13], FAMILY_TCP6);
+        assert_eq!(
+            header[16..32],
+            Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets()
+        );
+    }
+
+    #[test]
+    fn builds_local_v2_headers_without_addresses() {
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(v2_local_header(), expected);
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let mut stream = &b"PROXY TCP4 203.0.113.7 192.0.2.1 51234 25565\r\nrest"[..];
+        let client = read_header(&mut stream).await.unwrap();
+        assert_eq!(client, Some("203.0.113.7:51234".parse().unwrap()));
+        // Nothing after the header is consumed
+        assert_eq!(stream, b"rest");
+
+        let mut stream = &b"PROXY TCP6 2001:db8::7 ::1 51234 25565\r\n"[..];
+        let client = read_header(&mut stream).await.unwrap();
+        assert_eq!(client, Some("[2001:db8::7]:51234".parse().unwrap()));
+
+        let mut stream = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+        let mut stream = &b"PROXY TCP4 203.0.113.7\r\n"[..];
+        assert!(read_header(&mut stream).await.is_err());
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH));
+        assert!(read_header(&mut long.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let source: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let destination: SocketAddr = "192.0.2.1:25565".parse().unwrap();
+        let mut header = v2_header(source, destination);
+        header.extend_from_slice(b"rest");
+        let mut stream = &header[..];
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some(source));
+        assert_eq!(stream, b"rest");
+
+        let source: SocketAddr = "[2001:db8::7]:51234".parse().unwrap();
+        let header = v2_header(source, "[::1]:25565".parse().unwrap());
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some(source));
+
+        // Health checks of the proxy in front do not have a client
+        let header = v2_local_header();
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), None);
+
+        // The addresses are followed by TLVs, which are skipped
+        let mut header = v2_header(source, "[::1]:25565".parse().unwrap());
+        header[15] += 3;
+        header.extend_from_slice(&[0x04, 0, 0]);
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some(source));
+    }
+
+    #[tokio::test]
+    async fn rejects_other_data() {
+        let mut stream = &b"GET / HTTP/1.1\r\n\r\n"[..];
+        assert!(read_header(&mut stream).await.is_err());
+    }
+}
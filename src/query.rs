@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use rand::Rng;
+use serde_json::Value;
+use tokio::{net::UdpSocket, task};
+
+use crate::error::Error;
+
+/// The first bytes of every query request
+const MAGIC: [u8; 2] = [0xfe, 0xfd];
+const HANDSHAKE: u8 = 9;
+const STAT: u8 = 0;
+/// How long a challenge token is accepted, the same as in the vanilla server
+const TOKEN_LIFETIME: Duration = Duration::from_secs(30);
+/// Precedes the fields of a full stat response
+const FIELDS_PADDING: &[u8] = b"splitnum\0\x80\0";
+/// Precedes the player names of a full stat response
+const PLAYERS_PADDING: &[u8] = b"\x01player_\0\0";
+const GAME_TYPE: &str = "SMP";
+const GAME_ID: &str = "MINECRAFT";
+/// The name of the world, which the proxy does not know
+const MAP: &str = "world";
+
+/// What query clients are told about the server
+#[derive(Debug, Default)]
+pub struct QueryStatus {
+    pub motd: String,
+    pub version: String,
+    pub online: u64,
+    pub max: u64,
+    pub players: Vec<String>,
+}
+
+impl QueryStatus {
+    /// Takes the fields from a status response as sent to clients, so queries report the same as
+    /// the server list.
+    pub fn from_status(status: &str) -> QueryStatus {
+        let Ok(status) = serde_json::from_str::<Value>(status) else {
+            return QueryStatus::default();
+        };
+        let players = &status["players"];
+        QueryStatus {
+            motd: plain_text(&status["description"]),
+            version: status["version"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            online: players["online"].as_u64().unwrap_or(0),
+            max: players["max"].as_u64().unwrap_or(0),
+            players: players["sample"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|player| player["name"].as_str())
+                .map(str::to_owned)
+                .collect(),
+        }
+    }
+}
+
+/// Returns the text of a chat component without its styling.
+fn plain_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(components) => components.iter().map(plain_text).collect(),
+        Value::Object(fields) => {
+            let mut text = fields
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_owned();
+            if let Some(extra) = fields.get("extra") {
+                text.push_str(&plain_text(extra));
+            }
+            text
+        }
+        _ => String::new(),
+    }
+}
+
+/// Looks up the current status of the server for a query
+pub type GetStatus = dyn Fn() -> BoxFuture<'static, Result<QueryStatus, Error>> + Send + Sync;
+
+#[derive(Debug)]
+enum Request {
+    Handshake {
+        session: [u8; 4],
+    },
+    Stat {
+        session: [u8; 4],
+        token: i32,
+        full: bool,
+    },
+}
+
+/// Parses a request, returning `None` for anything that is not a valid request.
+fn parse_request(data: &[u8]) -> Option<Request> {
+    let (magic, data) = data.split_first_chunk::<2>()?;
+    let (&kind, data) = data.split_first()?;
+    let (&session, data) = data.split_first_chunk::<4>()?;
+    if *magic != MAGIC {
+        return None;
+    }
+    match (kind, data.len()) {
+        (HANDSHAKE, 0) => Some(Request::Handshake { session }),
+        // Full stat requests are padded with four bytes
+        (STAT, 4 | 8) => Some(Request::Stat {
+            session,
+            token: i32::from_be_bytes(data[..4].try_into().unwrap()),
+            full: data.len() == 8,
+        }),
+        _ => None,
+    }
+}
+
+/// The challenge tokens handed out to clients, which have to be sent back with stat requests.
+/// They keep clients from having responses sent to spoofed addresses.
+struct ChallengeTokens {
+    tokens: HashMap<SocketAddr, (i32, Instant)>,
+}
+
+impl ChallengeTokens {
+    fn issue(&mut self, peer: SocketAddr) -> i32 {
+        self.tokens
+            .retain(|_, (_, issued)| issued.elapsed() < TOKEN_LIFETIME);
+        // Like the vanilla server, only positive tokens are used, as some clients expect them
+        let token = rand::thread_rng().gen_range(0..1 << 24);
+        self.tokens.insert(peer, (token, Instant::now()));
+        token
+    }
+
+    fn is_valid(&self, peer: SocketAddr, token: i32) -> bool {
+        self.tokens
+            .get(&peer)
+            .is_some_and(|&(issued_token, issued)| {
+                issued_token == token && issued.elapsed() < TOKEN_LIFETIME
+            })
+    }
+}
+
+fn handshake_response(session: [u8; 4], token: i32) -> Vec<u8> {
+    let mut response = vec![HANDSHAKE];
+    response.extend_from_slice(&session);
+    push_string(&mut response, &token.to_string());
+    response
+}
+
+/// Builds a stat response. `server` is the address players connect to.
+fn stat_response(
+    session: [u8; 4],
+    full: bool,
+    status: &QueryStatus,
+    server: SocketAddr,
+) -> Vec<u8> {
+    let mut response = vec![STAT];
+    response.extend_from_slice(&session);
+    let online = status.online.to_string();
+    let max = status.max.to_string();
+    let host_ip = server.ip().to_string();
+    if !full {
+        for field in [&status.motd, GAME_TYPE, MAP, &online, &max] {
+            push_string(&mut response, field);
+        }
+        response.extend_from_slice(&server.port().to_le_bytes());
+        push_string(&mut response, &host_ip);
+        return response;
+    }
+
+    response.extend_from_slice(FIELDS_PADDING);
+    let fields = [
+        ("hostname", status.motd.as_str()),
+        ("gametype", GAME_TYPE),
+        ("game_id", GAME_ID),
+        ("version", &status.version),
+        ("plugins", ""),
+        ("map", MAP),
+        ("numplayers", &online),
+        ("maxplayers", &max),
+        ("hostport", &server.port().to_string()),
+        ("hostip", &host_ip),
+    ];
+    for (key, value) in fields {
+        push_string(&mut response, key);
+        push_string(&mut response, value);
+    }
+    response.push(0);
+    response.extend_from_slice(PLAYERS_PADDING);
+    for player in &status.players {
+        push_string(&mut response, player);
+    }
+    response.push(0);
+    response
+}
+
+/// Appends a null terminated string, leaving out null characters within it.
+fn push_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend(value.bytes().filter(|&byte| byte != 0));
+    buffer.push(0);
+}
+
+/// Answers queries on the socket. `server` is the address reported to clients as the one players
+/// connect to.
+pub async fn serve(
+    socket: UdpSocket,
+    server: SocketAddr,
+    status: Arc<GetStatus>,
+) -> io::Result<()> {
+    let socket = Arc::new(socket);
+    let mut tokens = ChallengeTokens {
+        tokens: HashMap::new(),
+    };
+    let mut buffer = [0; 64];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buffer).await?;
+        match parse_request(&buffer[..len]) {
+            Some(Request::Handshake { session }) => {
+                let response = handshake_response(session, tokens.issue(peer));
+                if let Err(error) = socket.send_to(&response, peer).await {
+                    tracing::debug!(%peer, %error, "Could not answer query");
+                }
+            }
+            Some(Request::Stat {
+                session,
+                token,
+                full,
+            }) if tokens.is_valid(peer, token) => {
+                // Looking up the status may mean connecting to the backend
+                let socket = Arc::clone(&socket);
+                let status = Arc::clone(&status);
+                task::spawn(async move {
+                    let result = match status().await {
+                        Ok(status) => {
+                            let response = stat_response(session, full, &status, server);
+                            socket.send_to(&response, peer).await.map_err(Error::from)
+                        }
+                        Err(error) => Err(error),
+                    };
+                    if let Err(error) = result {
+                        tracing::debug!(%peer, %error, "Could not answer query");
+                    }
+                });
+            }
+            _ => tracing::trace!(%peer, "Ignoring invalid query"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+
+    const STATUS: &str = r#"{"version":{"name":"Paper 1.21.7","protocol":772},"players":{"max":20,"online":2,"sample":[{"name":"alex","id":"00000000-0000-0000-0000-000000000000"},{"name":"steve","id":"00000000-0000-0000-0000-000000000001"}]},"description":{"text":"A ","extra":[{"text":"Minecraft","color":"green"}," Server"]}}"#;
+    const SESSION: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
+
+    /// Answers queries with the status on a local socket, returning the socket of a client.
+    async fn query_server() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let status: Arc<GetStatus> =
+            Arc::new(|| Box::pin(async { Ok(QueryStatus::from_status(STATUS)) }));
+        let server = "192.0.2.1:25565".parse().unwrap();
+        task::spawn(serve(socket, server, status));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(address).await.unwrap();
+        client
+    }
+
+    /// Sends a request and returns the response, or `None` if there is none.
+    async fn request(client: &UdpSocket, request: &[u8]) -> Option<Vec<u8>> {
+        client.send(request).await.unwrap();
+        let mut buffer = [0; 1024];
+        let len = time::timeout(Duration::from_millis(200), client.recv(&mut buffer))
+            .await
+            .ok()?
+            .unwrap();
+        Some(buffer[..len].to_vec())
+    }
+
+    /// Hands out a challenge token to the client.
+    async fn challenge(client: &UdpSocket) -> i32 {
+        let response = request(client, &[0xfe, 0xfd, HANDSHAKE, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        assert_eq!(response[..5], [HANDSHAKE, 0, 0, 0, 1]);
+        let token = std::str::from_utf8(response[5..].strip_suffix(b"\0").unwrap()).unwrap();
+        token.parse().unwrap()
+    }
+
+    fn stat_request(token: i32, full: bool) -> Vec<u8> {
+        let mut request = vec![0xfe, 0xfd, STAT];
+        request.extend_from_slice(&SESSION);
+        request.extend_from_slice(&token.to_be_bytes());
+        if full {
+            request.extend_from_slice(&[0; 4]);
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn requires_the_challenge_token() {
+        let client = query_server().await;
+        let token = challenge(&client).await;
+        assert!(token >= 0);
+        assert!(
+            request(&client, &stat_request(token, false))
+                .await
+                .is_some()
+        );
+        assert!(
+            request(&client, &stat_request(token + 1, false))
+                .await
+                .is_none()
+        );
+        // A new challenge replaces the old token
+        let new_token = challenge(&client).await;
+        if new_token != token {
+            assert!(
+                request(&client, &stat_request(token, false))
+                    .await
+                    .is_none()
+            );
+        }
+        assert!(
+            request(&client, &stat_request(new_token, false))
+                .await
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_basic_stat_requests() {
+        let client = query_server().await;
+        let token = challenge(&client).await;
+        let response = request(&client, &stat_request(token, false)).await.unwrap();
+
+        assert_eq!(response[..5], [STAT, 0, 0, 0, 1]);
+        let mut expected = b"A Minecraft Server\0SMP\0world\x002\x0020\0".to_vec();
+        expected.extend_from_slice(&25565_u16.to_le_bytes());
+        expected.extend_from_slice(b"192.0.2.1\0");
+        assert_eq!(response[5..], expected);
+    }
+
+    #[tokio::test]
+    async fn answers_full_stat_requests() {
+        let client = query_server().await;
+        let token = challenge(&client).await;
+        let response = request(&client, &stat_request(token, true)).await.unwrap();
+
+        let response = response[5..].strip_prefix(FIELDS_PADDING).unwrap();
+        // The fields end with an empty key
+        let end = response
+            .windows(PLAYERS_PADDING.len())
+            .position(|window| window == PLAYERS_PADDING)
+            .unwrap();
+        let (fields, players) = response.split_at(end);
+        let fields = fields
+            .strip_suffix(b"\0\0")
+            .unwrap()
+            .split(|&byte| byte == 0)
+            .map(|field| std::str::from_utf8(field).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields.chunks(2).collect::<Vec<_>>(),
+            [
+                ["hostname", "A Minecraft Server"],
+                ["gametype", "SMP"],
+                ["game_id", "MINECRAFT"],
+                ["version", "Paper 1.21.7"],
+                ["plugins", ""],
+                ["map", "world"],
+                ["numplayers", "2"],
+                ["maxplayers", "20"],
+                ["hostport", "25565"],
+                ["hostip", "192.0.2.1"],
+            ]
+        );
+        assert_eq!(
+            players.strip_prefix(PLAYERS_PADDING).unwrap(),
+            b"alex\0steve\0\0"
+        );
+    }
+
+    #[test]
+    fn ignores_invalid_requests() {
+        assert!(parse_request(&[0xfe, 0xfd, HANDSHAKE, 0, 0, 0]).is_none());
+        assert!(parse_request(&[0xfe, 0xfc, HANDSHAKE, 0, 0, 0, 1]).is_none());
+        assert!(parse_request(&[0xfe, 0xfd, STAT, 0, 0, 0, 1, 0, 0]).is_none());
+        assert!(parse_request(&[0xfe, 0xfd, 0x42, 0, 0, 0, 1]).is_none());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex, atomic::AtomicUsize},
+    time::Duration,
+};
+
+use tokio::{net::TcpStream, task, time::timeout};
+use tracing::{Instrument, instrument};
+
+use crate::{
+    balancer::Balancer,
+    error::Error,
+    external_process::StartStrategy,
+    idle::IdleMonitor,
+    lifecycle::{self, Event},
+    resolver::{BackendAddress, Resolver},
+    server_status::StatusCache,
+    sticky::{Player, StickySessions},
+};
+
+/// The id of the backend for hosts without a route
+pub const DEFAULT_BACKEND: &str = "default";
+
+/// A backend server along with the process or container that runs it
+pub struct Backend {
+    pub id: String,
+    /// The addresses of the servers running the backend, which are used in turn
+    pub addresses: Vec<BackendAddress>,
+    pub resolver: Arc<Resolver>,
+    pub balancer: Balancer,
+    /// Where players were forwarded to, if they should return to the same address
+    pub sticky: Option<StickySessions>,
+    /// How long connecting to each resolved address may take
+    pub connect_timeout: Duration,
+    pub process: Box<dyn StartStrategy>,
+    pub idle: IdleMonitor,
+    pub status_cache: StatusCache,
+    /// The number of players that tried to join since the start command was last run
+    pub waiting_players: AtomicUsize,
+}
+
+/// Creates the backend for an id
+type CreateBackend = dyn Fn(&str) -> Result<Backend, Error> + Send + Sync;
+
+/// Owns the backends by their id. A backend is created the first time it is needed, so each
+/// backend has its own process and idle state.
+pub struct ProcessRegistry {
+    create: Box<CreateBackend>,
+    backends: Mutex<HashMap<String, Arc<Backend>>>,
+}
+
+impl ProcessRegistry {
+    pub fn new(
+        create: impl Fn(&str) -> Result<Backend, Error> + Send + Sync + 'static,
+    ) -> ProcessRegistry {
+        ProcessRegistry {
+            create: Box::new(create),
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the backend with the given id, creating it if it does not exist yet.
+    /// A new backend is stopped whenever it becomes idle, unless its idle timeout is zero.
+    pub fn get(&self, id: &str) -> Result<Arc<Backend>, Error> {
+        let mut backends = self.backends.lock().unwrap();
+        if let Some(backend) = backends.get(id) {
+            return Ok(Arc::clone(backend));
+        }
+
+        let backend = Arc::new((self.create)(id)?);
+        if !backend.idle.timeout().is_zero() {
+            let span = tracing::info_span!("backend", id);
+            task::spawn(idle_handler(Arc::clone(&backend)).instrument(span));
+        }
+        backends.insert(id.to_owned(), Arc::clone(&backend));
+        Ok(backend)
+    }
+
+    /// Returns all backends that have been created so far.
+    pub fn backends(&self) -> Vec<Arc<Backend>> {
+        self.backends.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// Stops the backend whenever it has been without players for the idle timeout.
+#[instrument(skip_all)]
+async fn idle_handler(backend: Arc<Backend>) {
+    loop {
+        backend.idle.wait_idle().await;
+        if let Err(error) = backend.stop_idle().await {
+            tracing::error!(%error, "Could not stop the backend");
+        }
+    }
+}
+
+impl Backend {
+    /// Connects to the backend, trying the addresses it resolves to in the order chosen by the
+    /// balancer.
+    pub async fn connect(&self) -> io::Result<TcpStream> {
+        self.connect_player(None).await
+    }
+
+    /// Connects to the backend on behalf of a player, preferring the address the player was last
+    /// forwarded to if sticky sessions are enabled. Fails while the process has not logged that it
+    /// is ready.
+    pub async fn connect_player(&self, player: Option<&Player>) -> io::Result<TcpStream> {
+        // A server may accept connections before it is done loading
+        if !self.process.is_ready() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "the backend has not logged that it is ready yet",
+            ));
+        }
+        let mut resolved = Vec::new();
+        let mut last_error = None;
+        for address in &self.addresses {
+            match self.resolver.resolve(address).await {
+                Ok(addresses) => resolved.extend(addresses),
+                Err(error) => {
+                    tracing::debug!(%address, %error, "Could not resolve the backend");
+                    last_error = Some(io::Error::new(io::ErrorKind::NotFound, error.to_string()));
+                }
+            }
+        }
+
+        let sticky = self.sticky.as_ref().zip(player);
+        let preferred = sticky.and_then(|(sticky, player)| sticky.get(player));
+        for address in self.balancer.order(resolved, preferred) {
+            // A backend that silently drops packets would otherwise hold up the client for minutes
+            let error = match timeout(self.connect_timeout, TcpStream::connect(address)).await {
+                Ok(Ok(stream)) => {
+                    self.balancer.succeeded(address);
+                    if let Some((sticky, player)) = sticky {
+                        sticky.insert(player.clone(), address);
+                    }
+                    return Ok(stream);
+                }
+                Ok(Err(error)) => error,
+                Err(_) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connecting to the backend timed out",
+                ),
+            };
+            tracing::trace!(%address, %error, "Could not connect to the backend");
+            self.balancer.failed(address);
+            last_error = Some(error);
+        }
+        Err(last_error.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+    }
+
+    /// Stops the backend if it is running or reachable.
+    async fn stop_idle(&self) -> Result<(), Error> {
+        if !self.process.is_running() && self.connect().await.is_err() {
+            return Ok(());
+        }
+
+        tracing::info!("Stopping the idle backend");
+        lifecycle::emit(&self.id, Event::IdleStopRequested);
+        self.status_cache.clear();
+        if !self.process.stop().await? {
+            tracing::warn!(
+                "Backend is idle, but was not started by the proxy and no stop command is configured"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        external_process::{ExternalProcess, ProcessStatus},
+        testing::{self, MockBackend},
+    };
+
+    #[tokio::test]
+    async fn keeps_the_state_of_each_backend() {
+        let registry = ProcessRegistry::new(|id| {
+            Ok(Backend {
+                id: id.to_owned(),
+                process: Box::new(ExternalProcess::new("sleep 10".to_owned())?),
+                ..testing::backend(&[])
+            })
+        });
+        let first = registry.get("first").unwrap();
+        assert!(Arc::ptr_eq(&first, &registry.get("first").unwrap()));
+        let second = registry.get("second").unwrap();
+        assert_eq!(second.id, "second");
+
+        first.process.spawn_once(&testing::trigger()).await.unwrap();
+        assert_eq!(first.process.status(), ProcessStatus::Running);
+        assert_eq!(second.process.status(), ProcessStatus::NotStarted);
+        assert_eq!(registry.backends().len(), 2);
+        first.process.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_connecting_after_the_timeout() {
+        let black_hole = testing::BlackHole::new();
+        let backend = Backend {
+            connect_timeout: Duration::from_millis(200),
+            ..testing::backend(&[black_hole.address])
+        };
+        let start = tokio::time::Instant::now();
+        let error = backend.connect().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn alternates_between_the_addresses() {
+        let first = MockBackend::new().start();
+        let second = MockBackend::new().start();
+        let backend = testing::backend(&[first.address, second.address]);
+        let mut peers = Vec::new();
+        for _ in 0..4 {
+            peers.push(backend.connect().await.unwrap().peer_addr().unwrap());
+        }
+        assert_eq!(
+            peers,
+            [first.address, second.address, first.address, second.address]
+        );
+
+        // An address that refuses connections is skipped
+        let backend = testing::backend(&[testing::free_address(), second.address]);
+        for _ in 0..3 {
+            let stream = backend.connect().await.unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), second.address);
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_returning_players_to_the_same_address() {
+        let first = MockBackend::new().start();
+        let second = MockBackend::new().start();
+        let backend = Backend {
+            sticky: Some(StickySessions::new(Duration::from_secs(60))),
+            ..testing::backend(&[first.address, second.address])
+        };
+        let notch = Player::Uuid(uuid::Uuid::from_u128(0x069a79f444e94726a5befca90e38aaf5));
+        let alex = Player::Name("alex".to_owned());
+
+        let address = |stream: TcpStream| stream.peer_addr().unwrap();
+        let notch_address = address(backend.connect_player(Some(&notch)).await.unwrap());
+        for _ in 0..3 {
+            let stream = backend.connect_player(Some(&notch)).await.unwrap();
+            assert_eq!(address(stream), notch_address);
+        }
+        // Players without an entry are balanced as usual
+        let alex_address = address(backend.connect_player(Some(&alex)).await.unwrap());
+        let other_address = address(backend.connect().await.unwrap());
+        assert_ne!(alex_address, other_address);
+    }
+}
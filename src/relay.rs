@@ -0,0 +1,199 @@
+use std::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant},
+};
+
+/// What passed through the client side of a relayed connection
+struct Activity {
+    start: Instant,
+    /// Milliseconds since `start` at which bytes were last transferred
+    last_active: AtomicU64,
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl Activity {
+    fn transferred(&self, counter: &AtomicU64, bytes: usize) {
+        if bytes > 0 {
+            counter.fetch_add(bytes as u64, Ordering::Relaxed);
+            let elapsed = self.start.elapsed().as_millis() as u64;
+            self.last_active.store(elapsed, Ordering::Relaxed);
+        }
+    }
+
+    fn idle_since(&self) -> Instant {
+        self.start + Duration::from_millis(self.last_active.load(Ordering::Relaxed))
+    }
+}
+
+/// A stream adapter that records the activity of the client. Every byte relayed in either
+/// direction is either read from or written to the client, so this covers the whole connection.
+struct Watched<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Watched<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let activity = this.activity;
+        activity.transferred(&activity.received, buf.filled().len() - start);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Watched<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        let activity = this.activity;
+        activity.transferred(&activity.sent, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Copies data between the client and the backend until both directions are done and returns the
+/// bytes received from and sent to the client, which are also counted if copying fails. If an idle
+/// timeout is given, the connection is also closed once no bytes were transferred in either
+/// direction for that long.
+///
+/// A side that half-closes the connection only ends its own direction: the end of its data is
+/// passed on by shutting down the write half of the other side, while data still flows towards it
+/// until the other side closes as well.
+pub async fn copy(
+    client: impl AsyncRead + AsyncWrite + Unpin,
+    forward: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    idle_timeout: Option<Duration>,
+) -> (u64, u64, io::Result<()>) {
+    let activity = Activity {
+        start: Instant::now(),
+        last_active: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+        sent: AtomicU64::new(0),
+    };
+    let mut client = Watched {
+        inner: client,
+        activity: &activity,
+    };
+
+    let watchdog = async {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        // The deadline moves whenever there was activity while waiting for it
+        while activity.idle_since().elapsed() < idle_timeout {
+            time::sleep_until(activity.idle_since() + idle_timeout).await;
+        }
+        tracing::debug!(timeout = ?idle_timeout, "Closing idle connection");
+    };
+
+    let result = tokio::select! {
+        // Shuts down the write half of one side when the other reaches EOF, which keeps the
+        // opposite direction going
+        result = io::copy_bidirectional(&mut client, forward) => result.map(drop),
+        _ = watchdog => Ok(()),
+    };
+    (
+        activity.received.load(Ordering::Relaxed),
+        activity.sent.load(Ordering::Relaxed),
+        result,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_connections_that_are_idle() {
+        let (client, mut player) = io::duplex(1024);
+        let (mut forward, mut server) = io::duplex(1024);
+        let start = Instant::now();
+        let relay = tokio::spawn(async move {
+            let result = copy(client, &mut forward, Some(Duration::from_secs(10))).await;
+            (result, start.elapsed())
+        });
+
+        let mut buf = [0; 5];
+        player.write_all(b"hello").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        // Activity in either direction restarts the idle period
+        time::sleep(Duration::from_secs(5)).await;
+        server.write_all(b"world").await.unwrap();
+        player.read_exact(&mut buf).await.unwrap();
+
+        // The backend stops sending, but keeps the connection open
+        let ((received, sent, result), elapsed) = relay.await.unwrap();
+        assert!(result.is_ok());
+        assert_eq!((received, sent), (5, 5));
+        assert_eq!(elapsed, Duration::from_secs(15));
+        // Both sides are closed
+        assert_eq!(player.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_quiet_connections_without_a_timeout() {
+        let (client, _player) = io::duplex(1024);
+        let (mut forward, _server) = io::duplex(1024);
+        let relay = copy(client, &mut forward, None);
+        assert!(
+            time::timeout(Duration::from_secs(3600), relay)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_sending_after_the_client_half_closes() {
+        let (client, mut player) = io::duplex(1024);
+        let (mut forward, mut server) = io::duplex(1024);
+        let relay = tokio::spawn(async move { copy(client, &mut forward, None).await });
+
+        let backend = tokio::spawn(async move {
+            // The backend sees the end of the request, but still answers it
+            let mut request = Vec::new();
+            server.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request");
+            server.write_all(&[7; 64 * 1024]).await.unwrap();
+            server.shutdown().await.unwrap();
+        });
+
+        player.write_all(b"request").await.unwrap();
+        player.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        player.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, [7; 64 * 1024]);
+        backend.await.unwrap();
+        let (received, sent, result) = relay.await.unwrap();
+        assert!(result.is_ok());
+        assert_eq!((received, sent), (7, 64 * 1024));
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use hickory_resolver::{TokioResolver, proto::rr::RData};
+use tokio::time::Instant;
+
+use crate::error::Error;
+
+/// The port used if neither the address nor an SRV record names one
+pub const DEFAULT_PORT: u16 = 25565;
+/// How long resolved addresses are reused
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// The address of a backend, either fixed or a host name that is resolved when connecting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendAddress {
+    Socket(SocketAddr),
+    /// A host name, looked up using its `_minecraft._tcp` SRV record if no port is given
+    Host {
+        host: String,
+        port: Option<u16>,
+    },
+}
+
+impl FromStr for BackendAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = SocketAddr::from_str(s) {
+            return Ok(BackendAddress::Socket(address));
+        }
+        let ip = s
+            .strip_prefix('[')
+            .and_then(|ip| ip.strip_suffix(']'))
+            .unwrap_or(s);
+        if let Ok(ip) = IpAddr::from_str(ip) {
+            return Ok(BackendAddress::Socket(SocketAddr::new(ip, DEFAULT_PORT)));
+        }
+
+        let invalid = || Error::Other(format!("invalid backend address {}", s).into());
+        let (host, port) = match s.split_once(':') {
+            Some((host, port)) => (host, Some(port.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/') {
+            return Err(invalid());
+        }
+        Ok(BackendAddress::Host {
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+impl Display for BackendAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendAddress::Socket(address) => write!(f, "{}", address),
+            BackendAddress::Host {
+                host,
+                port: Some(port),
+            } => write!(f, "{}:{}", host, port),
+            BackendAddress::Host { host, port: None } => write!(f, "{}", host),
+        }
+    }
+}
+
+/// An SRV record pointing at a server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Looks up DNS records.
+/// This is a trait so the DNS can be replaced, e.g. for testing.
+pub trait DnsLookup: Send + Sync {
+    /// Returns the SRV records for the name, which is empty if there are none.
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<SrvRecord>, Error>>;
+    /// Returns the IPv4 and IPv6 addresses of the host.
+    fn ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>>;
+}
+
+/// The DNS configured for the system
+pub struct SystemDns {
+    resolver: TokioResolver,
+}
+
+impl SystemDns {
+    pub fn new() -> Result<SystemDns, Error> {
+        let resolver = TokioResolver::builder_tokio()
+            .and_then(|builder| builder.build())
+            .map_err(|error| Error::Other(error.into()))?;
+        Ok(SystemDns { resolver })
+    }
+}
+
+impl DnsLookup for SystemDns {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<SrvRecord>, Error>> {
+        Box::pin(async move {
+            let lookup = match self.resolver.srv_lookup(name).await {
+                Ok(lookup) => lookup,
+                Err(error) if error.is_no_records_found() => return Ok(Vec::new()),
+                Err(error) => return Err(Error::Other(error.into())),
+            };
+            let records = lookup
+                .answers()
+                .iter()
+                .filter_map(|record| match &record.data {
+                    RData::SRV(srv) => Some(SrvRecord {
+                        priority: srv.priority,
+                        weight: srv.weight,
+                        port: srv.port,
+                        target: srv.target.to_utf8(),
+                    }),
+                    _ => None,
+                })
+                .collect();
+            Ok(records)
+        })
+    }
+
+    fn ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>> {
+        Box::pin(async move {
+            let lookup = self
+                .resolver
+                .lookup_ip(host)
+                .await
+                .map_err(|error| Error::Other(error.into()))?;
+            Ok(lookup.iter().collect())
+        })
+    }
+}
+
+/// Resolves backend addresses the way the game does, remembering the results for a short time.
+pub struct Resolver {
+    dns: Box<dyn DnsLookup>,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>,
+}
+
+impl Resolver {
+    pub fn new(dns: Box<dyn DnsLookup>, ttl: Duration) -> Resolver {
+        Resolver {
+            dns,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the addresses to try for a backend, in the order they should be tried.
+    /// Host names without a port are looked up using their SRV records first, falling back to
+    /// their own addresses if there are none.
+    pub async fn resolve(&self, address: &BackendAddress) -> Result<Vec<SocketAddr>, Error> {
+        let (host, port) = match address {
+            BackendAddress::Socket(address) => return Ok(vec![*address]),
+            BackendAddress::Host { host, port } => (host, *port),
+        };
+        let key = address.to_string();
+        if let Some((resolved_at, addresses)) = self.cache.lock().unwrap().get(&key)
+            && resolved_at.elapsed() < self.ttl
+        {
+            return Ok(addresses.clone());
+        }
+
+        let mut addresses = Vec::new();
+        if port.is_none() {
+            let name = format!("_minecraft._tcp.{}", host);
+            let mut records = self.dns.srv(&name).await.unwrap_or_else(|error| {
+                tracing::debug!(%error, name, "Could not look up SRV records");
+                Vec::new()
+            });
+            // Records with a lower priority are preferred, and among them those with more weight
+            records.sort_by_key(|record| (record.priority, u16::MAX - record.weight));
+            for record in records {
+                let target = record.target.trim_end_matches('.');
+                match self.dns.ip(target).await {
+                    Ok(ips) => {
+                        addresses.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, record.port)))
+                    }
+                    Err(error) => tracing::debug!(%error, target, "Could not resolve SRV target"),
+                }
+            }
+        }
+        if addresses.is_empty() {
+            let port = port.unwrap_or(DEFAULT_PORT);
+            let ips = self.dns.ip(host).await?;
+            addresses.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port)));
+        }
+        if addresses.is_empty() {
+            return Err(Error::Other(
+                format!("could not resolve {}", address).into(),
+            ));
+        }
+
+        tracing::debug!(%address, ?addresses, "Resolved backend address");
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), addresses.clone()));
+        Ok(addresses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, atomic::Ordering};
+
+    use super::*;
+    use crate::testing::StubDns;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn srv(priority: u16, weight: u16, port: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port,
+            target: target.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parses_addresses_and_host_names() {
+        let parse = |s: &str| s.parse::<BackendAddress>().unwrap();
+        assert_eq!(
+            parse("10.0.0.1:25566"),
+            BackendAddress::Socket("10.0.0.1:25566".parse().unwrap())
+        );
+        assert_eq!(
+            parse("[::1]"),
+            BackendAddress::Socket("[::1]:25565".parse().unwrap())
+        );
+        assert_eq!(
+            parse("mc.example.com"),
+            BackendAddress::Host {
+                host: "mc.example.com".to_owned(),
+                port: None
+            }
+        );
+        assert_eq!(
+            parse("mc.example.com:25566").to_string(),
+            "mc.example.com:25566"
+        );
+        assert!("mc.example.com:port".parse::<BackendAddress>().is_err());
+        assert!("".parse::<BackendAddress>().is_err());
+    }
+
+    #[tokio::test]
+    async fn prefers_srv_records() {
+        let dns = StubDns {
+            srv: HashMap::from([(
+                "_minecraft._tcp.mc.example.com".to_owned(),
+                vec![
+                    srv(20, 0, 25567, "fallback.example.com."),
+                    srv(10, 1, 25566, "light.example.com."),
+                    srv(10, 5, 25565, "heavy.example.com."),
+                ],
+            )]),
+            ip: HashMap::from([
+                ("heavy.example.com".to_owned(), vec![ip("10.0.0.1")]),
+                ("light.example.com".to_owned(), vec![ip("10.0.0.2")]),
+                ("fallback.example.com".to_owned(), vec![ip("10.0.0.3")]),
+                ("mc.example.com".to_owned(), vec![ip("10.0.0.4")]),
+            ]),
+            ..StubDns::default()
+        };
+        let resolver = Resolver::new(Box::new(dns), DEFAULT_CACHE_TTL);
+        let addresses = resolver
+            .resolve(&"mc.example.com".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            addresses,
+            [
+                "10.0.0.1:25565".parse().unwrap(),
+                "10.0.0.2:25566".parse().unwrap(),
+                "10.0.0.3:25567".parse().unwrap()
+            ]
+        );
+
+        // A port skips the SRV lookup
+        let addresses = resolver
+            .resolve(&"mc.example.com:25570".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(addresses, ["10.0.0.4:25570".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_addresses_of_the_host() {
+        let dns = StubDns {
+            ip: HashMap::from([(
+                "mc.example.com".to_owned(),
+                vec![ip("10.0.0.4"), ip("2001:db8::4")],
+            )]),
+            ..StubDns::default()
+        };
+        let resolver = Resolver::new(Box::new(dns), DEFAULT_CACHE_TTL);
+        let addresses = resolver
+            .resolve(&"mc.example.com".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            addresses,
+            [
+                "10.0.0.4:25565".parse().unwrap(),
+                "[2001:db8::4]:25565".parse().unwrap()
+            ]
+        );
+        assert!(
+            resolver
+                .resolve(&"unknown.example.com".parse().unwrap())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reuses_results_for_the_ttl() {
+        let lookups = Arc::default();
+        let dns = StubDns {
+            ip: HashMap::from([("mc.example.com".to_owned(), vec![ip("10.0.0.4")])]),
+            lookups: Arc::clone(&lookups),
+            ..StubDns::default()
+        };
+        let resolver = Resolver::new(Box::new(dns), Duration::from_secs(30));
+        let address = "mc.example.com:25565".parse().unwrap();
+        resolver.resolve(&address).await.unwrap();
+        resolver.resolve(&address).await.unwrap();
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+
+        tokio::time::advance(Duration::from_secs(31)).await;
+        resolver.resolve(&address).await.unwrap();
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{cmp::Reverse, collections::HashSet};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::{error::Error, registry::DEFAULT_BACKEND};
+
+/// Chooses the backend for a connection from the host name the player connected through, which
+/// lets a single proxy serve several servers
+pub struct Router {
+    /// The hosts with a route of their own, whose backend id is the lowercase host name
+    hosts: HashSet<String>,
+    /// The ids of wildcard routes like `*.mc.example.com`, longest first so the most specific one
+    /// is tried first
+    wildcards: Vec<String>,
+    /// The routes whose host is a regular expression along with their id, in the order they were
+    /// given
+    patterns: Vec<(Regex, String)>,
+    /// Whether hosts without a route are sent to the default backend
+    default: bool,
+}
+
+impl Router {
+    /// Creates a router for the route hosts. Fails if the regular expression of a route does not
+    /// compile.
+    pub fn new<'a>(
+        hosts: impl IntoIterator<Item = &'a str>,
+        default: bool,
+    ) -> Result<Router, Error> {
+        let mut router = Router {
+            hosts: HashSet::new(),
+            wildcards: Vec::new(),
+            patterns: Vec::new(),
+            default,
+        };
+        for host in hosts {
+            let id = route_id(host);
+            if let Some(pattern) = host.strip_prefix('~') {
+                let pattern = route_pattern(pattern).map_err(|error| Error::Other(error.into()))?;
+                router.patterns.push((pattern, id));
+            } else if is_wildcard(&id) {
+                router.wildcards.push(id);
+            } else {
+                router.hosts.insert(id);
+            }
+        }
+        router.wildcards.sort_by_key(|id| Reverse(id.len()));
+        Ok(router)
+    }
+
+    /// Returns the id of the backend for a host name, or `None` if there is neither a route for
+    /// the host nor a default route. Host names are matched case-insensitively and a trailing dot
+    /// is ignored. A route for the exact host takes precedence over wildcard routes, of which the
+    /// one with the longest suffix is used, and those over the first matching regex route.
+    pub fn route(&self, host: &str) -> Option<&str> {
+        let host = host.to_ascii_lowercase();
+        let host = host.strip_suffix('.').unwrap_or(&host);
+        if let Some(id) = self.hosts.get(host) {
+            return Some(id);
+        }
+        // The wildcard stands for one or more labels, so `*.example.com` matches `a.b.example.com`
+        let wildcard = self.wildcards.iter().find(|id| {
+            let suffix = &id[1..];
+            host.len() > suffix.len() && host.ends_with(suffix)
+        });
+        if let Some(id) = wildcard {
+            return Some(id);
+        }
+        match self
+            .patterns
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(host))
+        {
+            Some((_, id)) => Some(id),
+            None => self.default.then_some(DEFAULT_BACKEND),
+        }
+    }
+}
+
+/// A list of host names, which are matched like the hosts of routes: exactly, by wildcard or by
+/// regular expression
+pub struct HostList(Router);
+
+impl HostList {
+    pub fn new<'a>(hosts: impl IntoIterator<Item = &'a str>) -> Result<HostList, Error> {
+        Router::new(hosts, false).map(HostList)
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.0.route(host).is_some()
+    }
+}
+
+/// Returns the id of the backend of the route for a host. Regular expressions are kept as they
+/// are, as their meaning depends on the case.
+pub fn route_id(host: &str) -> String {
+    if host.starts_with('~') {
+        return host.to_owned();
+    }
+    host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase()
+}
+
+/// Compiles the regular expression of a route host given as `~pattern`, which matches host names
+/// case-insensitively. It is not anchored, so it has to start with `^` and end with `$` to only
+/// match whole host names.
+pub fn route_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+/// Returns whether a route host is a wildcard like `*.mc.example.com`.
+pub fn is_wildcard(host: &str) -> bool {
+    host.starts_with("*.")
+}
+
+/// Checks that a route host is a valid regular expression if it starts with `~`, or otherwise
+/// that it is not empty and has a wildcard at most as its leftmost label.
+pub fn check_host(host: &str) -> Result<(), String> {
+    if let Some(pattern) = host.strip_prefix('~') {
+        return route_pattern(pattern)
+            .map(drop)
+            .map_err(|error| error.to_string());
+    }
+    let domain = host.strip_prefix("*.").unwrap_or(host);
+    if domain.contains('*') {
+        return Err("a wildcard can only replace the leftmost label".to_owned());
+    }
+    if domain.trim_end_matches('.').is_empty() || domain.starts_with('.') {
+        return Err("not a valid host name".to_owned());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_exact_hosts() {
+        let router = Router::new(["survival.example.com", "Creative.example.com."], true).unwrap();
+        assert_eq!(
+            router.route("survival.example.com"),
+            Some("survival.example.com")
+        );
+        // Matching ignores the case and a trailing dot
+        assert_eq!(
+            router.route("CREATIVE.example.com."),
+            Some("creative.example.com")
+        );
+    }
+
+    #[test]
+    fn sends_other_hosts_to_the_default_route() {
+        let router = Router::new(["survival.example.com"], true).unwrap();
+        assert_eq!(router.route("example.com"), Some(DEFAULT_BACKEND));
+        assert_eq!(
+            router.route("mc.survival.example.com"),
+            Some(DEFAULT_BACKEND)
+        );
+    }
+
+    #[test]
+    fn leaves_other_hosts_unrouted_without_a_default_route() {
+        let router = Router::new(["survival.example.com"], false).unwrap();
+        assert_eq!(router.route("creative.example.com"), None);
+        assert_eq!(router.route(""), None);
+    }
+
+    #[test]
+    fn prefers_exact_hosts_over_wildcards() {
+        let router = Router::new(
+            ["*.mc.example.com", "lobby.mc.example.com", "*.example.com"],
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            router.route("lobby.mc.example.com"),
+            Some("lobby.mc.example.com")
+        );
+        assert_eq!(
+            router.route("Survival.MC.example.com"),
+            Some("*.mc.example.com")
+        );
+        // The most specific wildcard wins
+        assert_eq!(router.route("mc.example.com"), Some("*.example.com"));
+        // The wildcard does not match the domain itself
+        assert_eq!(router.route("example.com"), Some(DEFAULT_BACKEND));
+    }
+
+    #[test]
+    fn matches_several_labels_with_a_wildcard() {
+        let router = Router::new(["*.mc.example.com"], false).unwrap();
+        assert_eq!(
+            router.route("eu.survival.mc.example.com."),
+            Some("*.mc.example.com")
+        );
+        assert_eq!(router.route("survival.mc.example.org"), None);
+        assert_eq!(router.route("xmc.example.com"), None);
+    }
+
+    #[test]
+    fn tries_regex_routes_last() {
+        let router = Router::new(
+            [
+                r"~^(eu|us)\d*\.example\.com$",
+                "eu1.example.com",
+                "*.us.example.com",
+                "~us",
+            ],
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            router.route("EU2.example.com"),
+            Some(r"~^(eu|us)\d*\.example\.com$")
+        );
+        // Exact and wildcard routes are checked first
+        assert_eq!(router.route("eu1.example.com"), Some("eu1.example.com"));
+        assert_eq!(router.route("a.us.example.com"), Some("*.us.example.com"));
+        // Of the regex routes, the first that matches is used
+        assert_eq!(
+            router.route("us.example.com"),
+            Some(r"~^(eu|us)\d*\.example\.com$")
+        );
+        assert_eq!(router.route("campus.example.org"), Some("~us"));
+        assert_eq!(router.route("asia.example.com"), Some(DEFAULT_BACKEND));
+    }
+
+    #[test]
+    fn rejects_invalid_regex_routes() {
+        assert!(Router::new(["~(eu"], true).is_err());
+        assert!(check_host("~(eu").is_err());
+        assert!(check_host(r"~^eu\.example\.com$").is_ok());
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde_json::Value;
+
+use crate::protocol::handshake::ProtocolVersion;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// The size of the server icon in pixels. Clients ignore icons of any other size.
+const FAVICON_SIZE: u32 = 64;
+
+/// The status response that is served if none is configured.
+pub const DEFAULT_STATUS: &str = r#"{
+    "version": {
+        "name": "1.21.7",
+        "protocol": 772
+    },
+    "players": {
+        "max": 0,
+        "online": 0
+    },
+    "description": "Server is offline",
+    "descriptions": {
+        "starting": "Server is starting, please try again in a moment",
+        "failed": "Server failed to start"
+    },
+    "enforceSecureProfile": false
+}"#;
+
+/// The placeholders that may be used in the description and players fields of the status
+const PLACEHOLDERS: &[&str] = &["{online}", "{max}", "{state}"];
+
+/// The state of the backend as shown in the status response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerState {
+    Offline,
+    Starting,
+    Online,
+    /// The backend kept exiting and is not started again until the proxy is told to
+    Failed,
+}
+
+impl Display for ServerState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerState::Offline => write!(f, "offline"),
+            ServerState::Starting => write!(f, "starting"),
+            ServerState::Online => write!(f, "online"),
+            ServerState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// A parsed status response whose description and players fields may contain placeholders.
+/// The template is parsed and checked once when it is loaded and rendered for every request.
+/// Besides the usual fields, the status may contain a `descriptions` object with a description for
+/// each server state, which takes precedence over the regular description in that state.
+pub struct StatusTemplate {
+    status: Value,
+    descriptions: Option<Value>,
+}
+
+impl StatusTemplate {
+    /// Creates a status that only shows a message in place of the description, e.g. for hosts
+    /// the proxy does not serve.
+    pub fn message(description: Value) -> StatusTemplate {
+        let mut status: Value =
+            serde_json::from_str(DEFAULT_STATUS).expect("default status is valid JSON");
+        if let Some(status) = status.as_object_mut() {
+            status.remove("descriptions");
+        }
+        status["description"] = description;
+        StatusTemplate {
+            status,
+            descriptions: None,
+        }
+    }
+
+    /// Replaces the description, which is still overridden by the description for the state.
+    pub fn set_description(&mut self, description: Value) {
+        self.status["description"] = description;
+    }
+
+    /// Renders the status response for a client.
+    /// The client's protocol version is echoed so the server is not listed as incompatible.
+    /// `online` is the number of players waiting for the backend.
+    pub fn render(&self, version: ProtocolVersion, state: ServerState, online: usize) -> String {
+        let mut status = self.status.clone();
+        status["version"]["protocol"] = version.0.into();
+        if let Some(description) = self
+            .descriptions
+            .as_ref()
+            .and_then(|descriptions| descriptions.get(state.to_string()))
+        {
+            status["description"] = description.clone();
+        }
+
+        let max = status["players"]["max"].as_u64().unwrap_or(0).to_string();
+        let online = online.to_string();
+        let state = state.to_string();
+        let substitute = |text: &str| {
+            text.replace("{online}", &online)
+                .replace("{max}", &max)
+                .replace("{state}", &state)
+        };
+        for field in ["description", "players"] {
+            if let Some(value) = status.get_mut(field) {
+                map_strings(value, &substitute);
+            }
+        }
+        status.to_string()
+    }
+}
+
+/// The status responses for all host names the proxy serves
+pub struct StatusMap {
+    default: StatusTemplate,
+    hosts: HashMap<String, StatusTemplate>,
+    /// The statuses of routes by the id of their backend, which also cover hosts matched by
+    /// wildcards
+    routes: HashMap<String, StatusTemplate>,
+}
+
+impl StatusMap {
+    pub fn new(default: StatusTemplate) -> StatusMap {
+        StatusMap {
+            default,
+            hosts: HashMap::new(),
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, host: &str, status: StatusTemplate) {
+        self.hosts.insert(host.to_ascii_lowercase(), status);
+    }
+
+    /// Sets the status for all hosts routed to a backend.
+    pub fn insert_route(&mut self, backend: &str, status: StatusTemplate) {
+        self.routes.insert(backend.to_owned(), status);
+    }
+
+    /// Returns the status for hosts without one of their own.
+    pub fn default_status(&self) -> &StatusTemplate {
+        &self.default
+    }
+
+    /// Returns the status for a host name routed to `backend`. A status for the host itself takes
+    /// precedence over the one of its route, and the default status is used if there is neither.
+    /// Host names are matched case-insensitively.
+    pub fn get(&self, host: &str, backend: &str) -> &StatusTemplate {
+        self.hosts
+            .get(&host.to_ascii_lowercase())
+            .or_else(|| self.routes.get(backend))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Applies `f` to all strings in a JSON value.
+fn map_strings(value: &mut Value, f: &impl Fn(&str) -> String) {
+    match value {
+        Value::String(text) => *text = f(text),
+        Value::Array(values) => values.iter_mut().for_each(|value| map_strings(value, f)),
+        Value::Object(values) => values.values_mut().for_each(|value| map_strings(value, f)),
+        _ => {}
+    }
+}
+
+/// Logs placeholders in the strings of a JSON value that will not be substituted.
+fn check_placeholders(value: &Value) {
+    match value {
+        Value::String(text) => {
+            let mut rest = text.as_str();
+            while let Some(start) = rest.find('{') {
+                let Some(len) = rest[start..].find('}') else {
+                    break;
+                };
+                let placeholder = &rest[start..start + len + 1];
+                if !PLACEHOLDERS.contains(&placeholder) {
+                    tracing::warn!(placeholder, "Unknown placeholder in status response");
+                }
+                rest = &rest[start + len + 1..];
+            }
+        }
+        Value::Array(values) => values.iter().for_each(check_placeholders),
+        Value::Object(values) => values.values().for_each(check_placeholders),
+        _ => {}
+    }
+}
+
+/// Loads the status response from `path` and adds the favicon from `favicon_path` to it.
+/// See [`read_status`] and [`read_favicon`] for how missing or invalid files are handled.
+pub fn load_status(path: Option<&Path>, favicon_path: Option<&Path>) -> StatusTemplate {
+    let mut status = read_status(path);
+    if let Some(favicon) = favicon_path.and_then(read_favicon) {
+        status["favicon"] = format!("data:image/png;base64,{}", favicon).into();
+    }
+
+    let descriptions = status
+        .as_object_mut()
+        .and_then(|status| status.remove("descriptions"));
+    for value in ["description", "players"]
+        .iter()
+        .filter_map(|field| status.get(field))
+        .chain(&descriptions)
+    {
+        check_placeholders(value);
+    }
+    StatusTemplate {
+        status,
+        descriptions,
+    }
+}
+
+/// Reads the status response from `path`, falling back to the default status if no path is given.
+/// An unreadable or invalid file is logged and replaced by the default, since a wrong status
+/// response should not keep the proxy from starting.
+fn read_status(path: Option<&Path>) -> Value {
+    let default = || serde_json::from_str(DEFAULT_STATUS).expect("default status is valid JSON");
+    let Some(path) = path else {
+        return default();
+    };
+
+    let status = match fs::read_to_string(path) {
+        Ok(status) => status,
+        Err(error) => {
+            tracing::error!(path = %path.display(), %error, "Could not read status file, using default");
+            return default();
+        }
+    };
+
+    match serde_json::from_str(&status) {
+        Ok(status) => {
+            tracing::info!(path = %path.display(), "Loaded status response");
+            status
+        }
+        Err(error) => {
+            tracing::error!(path = %path.display(), %error, "Status file is not valid JSON, using default");
+            default()
+        }
+    }
+}
+
+/// Reads a PNG image from `path` and returns it encoded as base64.
+/// Files that can not be read, are not PNG images or do not have the right size are logged and
+/// ignored.
+fn read_favicon(path: &Path) -> Option<String> {
+    let image = match fs::read(path) {
+        Ok(image) => image,
+        Err(error) => {
+            tracing::error!(path = %path.display(), %error, "Could not read favicon");
+            return None;
+        }
+    };
+
+    // The IHDR chunk, which contains the dimensions, always comes right after the signature
+    if image.len() < 24 || !image.starts_with(PNG_SIGNATURE) || &image[12..16] != b"IHDR" {
+        tracing::error!(path = %path.display(), "Favicon is not a PNG image");
+        return None;
+    }
+    let width = u32::from_be_bytes(image[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(image[20..24].try_into().unwrap());
+    if width != FAVICON_SIZE || height != FAVICON_SIZE {
+        tracing::warn!(
+            path = %path.display(),
+            width,
+            height,
+            "Favicon must be 64x64 pixels, ignoring it"
+        );
+        return None;
+    }
+
+    tracing::info!(path = %path.display(), "Loaded favicon");
+    Some(BASE64_STANDARD.encode(image))
+}
+
+/// How long the status of a running backend is reused by default
+pub const DEFAULT_STATUS_CACHE_TTL: Duration = Duration::from_secs(5);
+
+/// Caches the status response of a running backend, so clients refreshing their server list do not
+/// cause a status request to the backend each time. Once the status is older than its time to
+/// live, it is refreshed in the background while clients are still served the old one.
+pub struct StatusCache {
+    ttl: Duration,
+    entry: Mutex<Option<(Instant, Arc<str>)>>,
+    /// Set while a caller refreshes the status, so only one refresh runs at a time
+    refreshing: AtomicBool,
+}
+
+impl StatusCache {
+    pub fn new(ttl: Duration) -> StatusCache {
+        StatusCache {
+            ttl,
+            entry: Mutex::new(None),
+            refreshing: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the cached status along with whether the caller should refresh it. Only the first
+    /// caller after the time to live passed is asked to, and has to call `put` or `clear` once
+    /// done.
+    pub fn get(&self) -> Option<(Arc<str>, bool)> {
+        let entry = self.entry.lock().unwrap();
+        let (fetched, status) = entry.as_ref()?;
+        let refresh =
+            fetched.elapsed() >= self.ttl && !self.refreshing.swap(true, Ordering::AcqRel);
+        Some((Arc::clone(status), refresh))
+    }
+
+    /// Returns the cached status if it is not older than the time to live.
+    pub fn fresh(&self) -> Option<Arc<str>> {
+        let entry = self.entry.lock().unwrap();
+        match &*entry {
+            Some((fetched, status)) if fetched.elapsed() < self.ttl => Some(Arc::clone(status)),
+            _ => None,
+        }
+    }
+
+    pub fn put(&self, status: Arc<str>) {
+        *self.entry.lock().unwrap() = Some((Instant::now(), status));
+        self.refreshing.store(false, Ordering::Release);
+    }
+
+    /// Forgets the cached status, e.g. because the backend is no longer up.
+    pub fn clear(&self) {
+        *self.entry.lock().unwrap() = None;
+        self.refreshing.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockBackend, TestProxy, free_address, temp_dir};
+
+    /// Loads a status template from JSON, as if it was configured.
+    fn template(json: &str) -> StatusTemplate {
+        let dir = temp_dir("status");
+        let path = dir.join("status.json");
+        fs::write(&path, json).unwrap();
+        let template = load_status(Some(&path), None);
+        fs::remove_dir_all(&dir).unwrap();
+        template
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        let template = template(
+            r#"{
+                "version": {"name": "1.21.7", "protocol": 772},
+                "players": {"max": 20, "online": 0, "sample": [{"name": "{online} waiting", "id": "x"}]},
+                "description": {"text": "Server is {state}", "extra": ["{online}/{max} {unknown}"]}
+            }"#,
+        );
+        let status = template.render(ProtocolVersion(47), ServerState::Starting, 2);
+        let status: Value = serde_json::from_str(&status).unwrap();
+        assert_eq!(status["description"]["text"], "Server is starting");
+        assert_eq!(status["description"]["extra"][0], "2/20 {unknown}");
+        assert_eq!(status["players"]["sample"][0]["name"], "2 waiting");
+        assert_eq!(status["version"]["protocol"], 47);
+    }
+
+    fn description(template: &StatusTemplate, state: ServerState) -> Value {
+        let status = template.render(ProtocolVersion::V1_21_7, state, 0);
+        serde_json::from_str::<Value>(&status).unwrap()["description"].clone()
+    }
+
+    #[test]
+    fn selects_the_status_of_the_host() {
+        let message = |text| template(&format!(r#"{{"description": "{text}"}}"#));
+        let mut statuses = StatusMap::new(message("Default"));
+        statuses.insert("Survival.example.com", message("Survival"));
+        statuses.insert_route("creative", message("Creative"));
+        let get = |host, backend| description(statuses.get(host, backend), ServerState::Offline);
+
+        assert_eq!(get("survival.example.com", "default"), "Survival");
+        assert_eq!(get("SURVIVAL.example.com", "creative"), "Survival");
+        assert_eq!(get("build.creative.example.com", "creative"), "Creative");
+        assert_eq!(get("unknown.example.com", "default"), "Default");
+        assert_eq!(
+            description(statuses.default_status(), ServerState::Offline),
+            "Default"
+        );
+    }
+
+    #[test]
+    fn describes_starting_and_offline_differently() {
+        let template = load_status(None, None);
+        assert_eq!(
+            description(&template, ServerState::Offline),
+            "Server is offline"
+        );
+        assert_eq!(
+            description(&template, ServerState::Starting),
+            "Server is starting, please try again in a moment"
+        );
+        assert_eq!(
+            description(&template, ServerState::Failed),
+            "Server failed to start"
+        );
+        // Without a description of its own, a state falls back to the regular description
+        assert_eq!(
+            description(&template, ServerState::Online),
+            "Server is offline"
+        );
+    }
+
+    #[test]
+    fn reuses_the_status_within_the_ttl() {
+        let cache = StatusCache::new(Duration::from_millis(100));
+        assert_eq!(cache.get(), None);
+        cache.put(Arc::from("first"));
+        assert_eq!(cache.get(), Some((Arc::from("first"), false)));
+        assert_eq!(cache.fresh(), Some(Arc::from("first")));
+
+        std::thread::sleep(Duration::from_millis(100));
+        // The stale status is still served while one caller refreshes it
+        assert_eq!(cache.fresh(), None);
+        assert_eq!(cache.get(), Some((Arc::from("first"), true)));
+        assert_eq!(cache.get(), Some((Arc::from("first"), false)));
+        cache.put(Arc::from("second"));
+        assert_eq!(cache.get(), Some((Arc::from("second"), false)));
+
+        cache.clear();
+        assert_eq!(cache.get(), None);
+    }
+
+    #[tokio::test]
+    async fn renders_starting_once_the_start_command_was_spawned() {
+        let dir = temp_dir("status-state");
+        let path = dir.join("status.json");
+        fs::write(
+            &path,
+            r#"{"version": {"name": "1.21.7", "protocol": 772}, "description": "Server is {state}"}"#,
+        )
+        .unwrap();
+        let proxy = TestProxy::start(&format!(
+            r#"
+            forward = ["{}"]
+            start-command = "sleep 30"
+            status = "{}"
+            "#,
+            free_address(),
+            path.display()
+        ))
+        .await;
+
+        let status = proxy.client().status().await.unwrap();
+        assert_eq!(status["description"], "Server is starting");
+        proxy.shutdown().await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shows_the_status_of_each_route() {
+        let dir = temp_dir("route-status");
+        let status = |description: &str| {
+            format!(
+                r#"{{"version": {{"name": "1.21.7", "protocol": 772}}, "description": "{description}"}}"#
+            )
+        };
+        fs::write(dir.join("global.json"), status("Global")).unwrap();
+        fs::write(dir.join("creative.json"), status("Creative world")).unwrap();
+        let mut favicon = PNG_SIGNATURE.to_vec();
+        favicon.extend_from_slice(b"\0\0\0\x0dIHDR");
+        favicon.extend_from_slice(&FAVICON_SIZE.to_be_bytes());
+        favicon.extend_from_slice(&FAVICON_SIZE.to_be_bytes());
+        fs::write(dir.join("survival.png"), &favicon).unwrap();
+        let proxy = TestProxy::start(&format!(
+            r#"
+            forward = ["{address}"]
+            start-command = "true"
+            status = "{dir}/global.json"
+
+            [route."survival.example.com"]
+            forward = ["{address}"]
+            start-command = "true"
+            favicon = "{dir}/survival.png"
+            motd = "Survival"
+
+            [route."creative.example.com"]
+            forward = ["{address}"]
+            start-command = "true"
+            status = "{dir}/creative.json"
+            "#,
+            address = free_address(),
+            dir = dir.display()
+        ))
+        .await;
+        let status = async |host: &str| proxy.client().with_host(host).status().await.unwrap();
+
+        let survival = status("survival.example.com").await;
+        assert_eq!(survival["description"], "Survival");
+        assert_eq!(
+            survival["favicon"],
+            format!("data:image/png;base64,{}", BASE64_STANDARD.encode(&favicon))
+        );
+        let creative = status("creative.example.com").await;
+        assert_eq!(creative["description"], "Creative world");
+        assert_eq!(creative.get("favicon"), None);
+        assert_eq!(status("example.com").await["description"], "Global");
+        proxy.shutdown().await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn caches_the_status_of_the_backend() {
+        let backend = MockBackend::new()
+            .with_status(r#"{"version":{"name":"1.21.7","protocol":772},"description":"Live"}"#)
+            .start();
+        let proxy = TestProxy::start(&format!(
+            r#"
+            forward = ["{}"]
+            start-command = "true"
+            status-cache-ttl = 1
+            "#,
+            backend.address
+        ))
+        .await;
+        let description = async || proxy.client().status().await.unwrap()["description"].clone();
+
+        assert_eq!(description().await, "Live");
+        assert_eq!(description().await, "Live");
+        assert_eq!(backend.accepted(), 1);
+
+        // Once the status is stale, it is served one more time while it is refreshed
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(description().await, "Live");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while backend.accepted() < 2 {
+            assert!(Instant::now() < deadline, "the status was not refreshed");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // Without the backend, the configured status is shown once refreshing failed
+        drop(backend);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(description().await, "Live");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while description().await == "Live" {
+            assert!(Instant::now() < deadline, "the cached status was kept");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        proxy.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::{io, time::Duration};
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::TcpStream;
+
+/// How long a connection may be idle before keepalive probes are sent
+pub const DEFAULT_KEEPALIVE_TIME: Duration = Duration::from_secs(60);
+/// The interval between keepalive probes
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Options applied to the sockets of clients and backends
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Sends small packets right away instead of waiting for more data, as the game is latency
+    /// sensitive
+    pub nodelay: bool,
+    /// The idle time and probe interval of TCP keepalive, which detects dead peers
+    pub keepalive: Option<(Duration, Duration)>,
+}
+
+impl SocketOptions {
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        match self.keepalive {
+            Some((time, interval)) => {
+                let keepalive = TcpKeepalive::new().with_time(time).with_interval(interval);
+                socket.set_tcp_keepalive(&keepalive)?;
+            }
+            None => socket.set_keepalive(false)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Returns both ends of a local TCP connection.
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn applies_the_options() {
+        let (client, server) = connected_pair().await;
+        let options = SocketOptions {
+            nodelay: true,
+            keepalive: Some((Duration::from_secs(30), Duration::from_secs(5))),
+        };
+        for stream in [&client, &server] {
+            options.apply(stream).unwrap();
+            assert!(stream.nodelay().unwrap());
+            let socket = SockRef::from(stream);
+            assert!(socket.keepalive().unwrap());
+            assert_eq!(
+                socket.tcp_keepalive_time().unwrap(),
+                Duration::from_secs(30)
+            );
+            assert_eq!(
+                socket.tcp_keepalive_interval().unwrap(),
+                Duration::from_secs(5)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn disables_the_options() {
+        let (client, _server) = connected_pair().await;
+        SocketOptions {
+            nodelay: true,
+            keepalive: Some((DEFAULT_KEEPALIVE_TIME, DEFAULT_KEEPALIVE_INTERVAL)),
+        }
+        .apply(&client)
+        .unwrap();
+        SocketOptions {
+            nodelay: false,
+            keepalive: None,
+        }
+        .apply(&client)
+        .unwrap();
+        assert!(!client.nodelay().unwrap());
+        assert!(!SockRef::from(&client).keepalive().unwrap());
+    }
+}
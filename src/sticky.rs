@@ -0,0 +1,94 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    net::SocketAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Identifies a player across connections
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Player {
+    Uuid(Uuid),
+    /// Older clients do not send their UUID, so they are identified by their lowercase name
+    Name(String),
+}
+
+impl Player {
+    pub fn new(name: &str, uuid: Uuid) -> Player {
+        match uuid.is_nil() {
+            true => Player::Name(name.to_lowercase()),
+            false => Player::Uuid(uuid),
+        }
+    }
+}
+
+impl Display for Player {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Player::Uuid(uuid) => write!(f, "{}", uuid),
+            Player::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Remembers which address each player was forwarded to, so a returning player lands on the same
+/// server of a backend. Entries expire once the player has not connected for the TTL.
+pub struct StickySessions {
+    ttl: Duration,
+    entries: Mutex<HashMap<Player, (Instant, SocketAddr)>>,
+}
+
+impl StickySessions {
+    pub fn new(ttl: Duration) -> StickySessions {
+        StickySessions {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the address the player was last forwarded to, if it has not expired.
+    pub fn get(&self, player: &Player) -> Option<SocketAddr> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (used_at, _)| used_at.elapsed() < self.ttl);
+        entries.get(player).map(|(_, address)| *address)
+    }
+
+    pub fn insert(&self, player: Player, address: SocketAddr) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(player, (Instant::now(), address));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTCH: Uuid = Uuid::from_u128(0x069a79f444e94726a5befca90e38aaf5);
+
+    #[test]
+    fn identifies_players_by_uuid_or_name() {
+        assert_eq!(Player::new("Notch", NOTCH), Player::Uuid(NOTCH));
+        assert_eq!(
+            Player::new("Notch", Uuid::nil()),
+            Player::Name("notch".to_owned())
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forgets_players_after_the_ttl() {
+        let sessions = StickySessions::new(Duration::from_secs(60));
+        let address = "10.0.0.1:25565".parse().unwrap();
+        sessions.insert(Player::Uuid(NOTCH), address);
+        assert_eq!(sessions.get(&Player::Uuid(NOTCH)), Some(address));
+        assert_eq!(sessions.get(&Player::Name("alex".to_owned())), None);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(sessions.get(&Player::Uuid(NOTCH)), None);
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env, fs,
+    net::{self, IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use futures::{SinkExt, StreamExt, future::BoxFuture};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixStream},
+    sync::oneshot,
+    task::{self, JoinHandle},
+    time::{self, Duration, Instant},
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::subscriber::DefaultGuard;
+
+use crate::{
+    balancer::Balancer,
+    client::{RESPONSE_TIMEOUT, testing::TestClient},
+    config::Config,
+    error::Error,
+    external_process::{ExternalProcess, Trigger},
+    idle::IdleMonitor,
+    listener::ListenAddress,
+    protocol::{
+        PacketDecoder, PacketEncoder,
+        chat::Chat,
+        handshake::{HandshakePacket, NextState},
+        login, read_single_packet, status,
+    },
+    registry::{Backend, DEFAULT_BACKEND},
+    resolver::{BackendAddress, DnsLookup, Resolver, SrvRecord},
+    server_status::StatusCache,
+};
+
+/// How long the proxy may take to accept connections after it was started
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns a local address nothing is listening on, which is free to be bound by a test.
+pub fn free_address() -> SocketAddr {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// An address that drops connection attempts instead of refusing them, like a firewall would
+pub struct BlackHole {
+    pub address: SocketAddr,
+    _listener: socket2::Socket,
+    _queued: net::TcpStream,
+}
+
+impl BlackHole {
+    /// Listens without ever accepting, and fills the queue of pending connections so that further
+    /// attempts are dropped.
+    pub fn new() -> BlackHole {
+        let listener =
+            socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        listener
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let address = listener.local_addr().unwrap().as_socket().unwrap();
+        let queued = net::TcpStream::connect(address).unwrap();
+        BlackHole {
+            address,
+            _listener: listener,
+            _queued: queued,
+        }
+    }
+}
+
+/// Creates an empty directory for a test that is not shared with other tests or test runs.
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dir = env::temp_dir().join(format!("portal-{name}-{}-{count}", process::id()));
+    // Left over from an earlier run with the same process id
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Waits until the file exists, which a command of the test creates.
+pub async fn wait_for_file(path: &Path) {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !path.exists() {
+        assert!(
+            Instant::now() < deadline,
+            "{} was not created in time",
+            path.display()
+        );
+        time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Collects the logs of the current thread, so a test can look at them
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    /// Captures the logs of the current thread until the guard is dropped. Tasks log here as well
+    /// if the test runs on a single thread, which is the default.
+    pub fn install(&self) -> DefaultGuard {
+        let writer = self.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::set_default(subscriber)
+    }
+
+    /// Returns what was logged so far, one event per line.
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The proxy running in a task of the test
+pub struct TestProxy {
+    pub address: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl TestProxy {
+    /// Starts the proxy with a configuration given in TOML and waits until it accepts connections.
+    /// The proxy listens on a free local address instead of the ones configured.
+    pub async fn start(toml: &str) -> TestProxy {
+        TestProxy::start_listening(toml, vec![ListenAddress::Tcp(free_address())]).await
+    }
+
+    /// Starts the proxy listening on the addresses and waits until it accepts connections on all
+    /// of them. The first TCP address is the one clients connect to.
+    pub async fn start_listening(toml: &str, listen: Vec<ListenAddress>) -> TestProxy {
+        let address = listen
+            .iter()
+            .find_map(|address| match address {
+                ListenAddress::Tcp(address) => Some(*address),
+                ListenAddress::Unix(_) => None,
+            })
+            .expect("the proxy needs a TCP address");
+        let mut config: Config = toml::from_str(toml).unwrap();
+        config.listen = listen.clone();
+        if let Err(errors) = config.validate() {
+            panic!("invalid config: {}", errors.join(", "));
+        }
+        let (shutdown, shutdown_signal) = oneshot::channel();
+        let task = task::spawn(crate::run(config, async {
+            let _ = shutdown_signal.await;
+            Ok(())
+        }));
+
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        for address in &listen {
+            loop {
+                let connected = match address {
+                    ListenAddress::Tcp(address) => TcpStream::connect(address).await.is_ok(),
+                    ListenAddress::Unix(path) => UnixStream::connect(path).await.is_ok(),
+                };
+                if connected {
+                    break;
+                }
+                assert!(!task.is_finished(), "the proxy exited during startup");
+                assert!(Instant::now() < deadline, "the proxy did not start in time");
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        TestProxy {
+            address,
+            shutdown: Some(shutdown),
+            task,
+        }
+    }
+
+    /// Returns a client connecting to the proxy through the host `localhost`.
+    pub fn client(&self) -> TestClient {
+        TestClient::new(self.address)
+    }
+
+    /// Shuts the proxy down like a signal would and returns what it exited with.
+    pub async fn shutdown(mut self) -> Result<(), Error> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        (&mut self.task).await.expect("Panic in the proxy")
+    }
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        // Dropping the backends kills the processes the proxy started
+        self.task.abort();
+    }
+}
+
+/// A backend that answers like a Minecraft server as far as it is scripted to. Connections are
+/// closed where the script does not say what to do.
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    /// How long after starting the backend it begins accepting connections
+    delay: Duration,
+    /// The JSON the status is answered with
+    status: Option<String>,
+    /// The message logins are disconnected with
+    login_disconnect: Option<String>,
+    /// Whether everything received is sent back instead of being read as packets
+    echo: bool,
+}
+
+/// A running [`MockBackend`], which stops once it is dropped
+pub struct RunningBackend {
+    pub address: SocketAddr,
+    shared: Arc<BackendShared>,
+    task: JoinHandle<()>,
+}
+
+/// What a running backend saw
+#[derive(Default)]
+struct BackendShared {
+    accepted: AtomicUsize,
+    handshakes: Mutex<Vec<HandshakePacket<'static>>>,
+}
+
+impl MockBackend {
+    pub fn new() -> MockBackend {
+        MockBackend::default()
+    }
+
+    /// Waits this long after starting before accepting connections, like a server that loads.
+    pub fn with_delay(mut self, delay: Duration) -> MockBackend {
+        self.delay = delay;
+        self
+    }
+
+    /// Answers status requests with the status JSON and pings with their payload.
+    pub fn with_status(mut self, status: &str) -> MockBackend {
+        self.status = Some(status.to_owned());
+        self
+    }
+
+    /// Disconnects players with the plain text message once they sent their login start.
+    pub fn with_login_disconnect(mut self, message: &str) -> MockBackend {
+        self.login_disconnect = Some(message.to_owned());
+        self
+    }
+
+    /// Sends back all bytes it receives, starting with the handshake.
+    pub fn echo(mut self) -> MockBackend {
+        self.echo = true;
+        self
+    }
+
+    /// Starts accepting connections on a free local address, after the delay if one is set.
+    pub fn start(self) -> RunningBackend {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // Until the delay passed, connections to the address are refused
+        let listener = match self.delay.is_zero() {
+            true => {
+                listener.set_nonblocking(true).unwrap();
+                Some(TcpListener::from_std(listener).unwrap())
+            }
+            false => None,
+        };
+        let shared = Arc::new(BackendShared::default());
+        let task = task::spawn(self.accept(address, listener, Arc::clone(&shared)));
+        RunningBackend {
+            address,
+            shared,
+            task,
+        }
+    }
+
+    async fn accept(
+        self,
+        address: SocketAddr,
+        listener: Option<TcpListener>,
+        shared: Arc<BackendShared>,
+    ) {
+        let listener = match listener {
+            Some(listener) => listener,
+            None => {
+                time::sleep(self.delay).await;
+                TcpListener::bind(address).await.unwrap()
+            }
+        };
+        let script = Arc::new(self);
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            shared.accepted.fetch_add(1, Ordering::Relaxed);
+            let script = Arc::clone(&script);
+            let shared = Arc::clone(&shared);
+            // Errors only mean that the proxy closed the connection
+            task::spawn(async move { script.handle(stream, &shared).await.ok() });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream, shared: &BackendShared) -> Result<(), Error> {
+        if self.echo {
+            let (mut reader, mut writer) = stream.split();
+            io::copy(&mut reader, &mut writer).await?;
+            return Ok(());
+        }
+
+        let (handshake, leftover) =
+            read_single_packet::<HandshakePacket>(&mut stream, RESPONSE_TIMEOUT).await?;
+        let next_state = handshake.next_state;
+        shared
+            .handshakes
+            .lock()
+            .unwrap()
+            .push(handshake.into_inner());
+        let (reader, writer) = stream.split();
+        let reader = std::io::Cursor::new(leftover).chain(reader);
+        match (next_state, &self.status, &self.login_disconnect) {
+            (NextState::Status, Some(json), _) => {
+                let mut reader =
+                    FramedRead::new(reader, PacketDecoder::<status::ServerBound>::new());
+                let mut writer = FramedWrite::new(writer, PacketEncoder::new());
+                while let Some(packet) = reader.next().await {
+                    let response = match packet?.into_inner() {
+                        status::ServerBound::StatusRequest => status::ClientBound::StatusResponse {
+                            json_response: Cow::Borrowed(json),
+                        },
+                        status::ServerBound::PingRequest(payload) => {
+                            status::ClientBound::PingResponse(payload)
+                        }
+                    };
+                    writer.send(response).await?;
+                }
+            }
+            (NextState::Login | NextState::Transfer, _, Some(message)) => {
+                let mut reader =
+                    FramedRead::new(reader, PacketDecoder::<login::ServerBound>::new());
+                let mut writer = FramedWrite::new(writer, PacketEncoder::new());
+                if let Some(packet) = reader.next().await {
+                    packet?;
+                    let reason = Chat::Text(Cow::Borrowed(message));
+                    writer.send(login::ClientBound::Disconnect(reason)).await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl RunningBackend {
+    /// Returns the number of connections accepted so far.
+    pub fn accepted(&self) -> usize {
+        self.shared.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Returns the handshakes received so far, unless the backend echoes.
+    pub fn handshakes(&self) -> Vec<HandshakePacket<'static>> {
+        let handshakes = self.shared.handshakes.lock().unwrap();
+        handshakes
+            .iter()
+            .map(|handshake| HandshakePacket {
+                address: Cow::Owned(handshake.address.to_string()),
+                ..*handshake
+            })
+            .collect()
+    }
+}
+
+impl Drop for RunningBackend {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// DNS records given by the test, where names without records resolve to nothing
+#[derive(Default)]
+pub struct StubDns {
+    pub srv: HashMap<String, Vec<SrvRecord>>,
+    pub ip: HashMap<String, Vec<IpAddr>>,
+    /// The number of lookups of either kind so far
+    pub lookups: Arc<AtomicUsize>,
+}
+
+impl DnsLookup for StubDns {
+    fn srv<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<SrvRecord>, Error>> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let records = self.srv.get(name).cloned().unwrap_or_default();
+        Box::pin(async move { Ok(records) })
+    }
+
+    fn ip<'a>(&'a self, host: &'a str) -> BoxFuture<'a, Result<Vec<IpAddr>, Error>> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let addresses = self.ip.get(host).cloned().unwrap_or_default();
+        Box::pin(async move { Ok(addresses) })
+    }
+}
+
+/// Requests `path` from the HTTP server at the address and returns the status line and the body.
+pub async fn http_get(address: SocketAddr, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.lines().next().unwrap().to_owned();
+    (status, body.to_owned())
+}
+
+/// Returns the trigger of a player joining through `mc.example.com` from the local host.
+pub fn trigger() -> Trigger {
+    Trigger {
+        host: "mc.example.com".to_owned(),
+        port: 25565,
+        client_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        waiting: 1,
+    }
+}
+
+/// Returns a backend forwarding to the addresses, whose start command does nothing and which is
+/// never stopped for being idle.
+pub fn backend(addresses: &[SocketAddr]) -> Backend {
+    Backend {
+        id: DEFAULT_BACKEND.to_owned(),
+        addresses: addresses
+            .iter()
+            .copied()
+            .map(BackendAddress::Socket)
+            .collect(),
+        resolver: Arc::new(Resolver::new(
+            Box::new(StubDns::default()),
+            Duration::from_secs(60),
+        )),
+        balancer: Balancer::new(Duration::from_secs(10)),
+        sticky: None,
+        connect_timeout: Duration::from_secs(1),
+        process: Box::new(ExternalProcess::new("true".to_owned()).unwrap()),
+        idle: IdleMonitor::new(Duration::ZERO),
+        status_cache: StatusCache::new(Duration::ZERO),
+        waiting_players: AtomicUsize::new(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn echoes_what_it_receives() {
+        let backend = MockBackend::new().echo().start();
+        let mut stream = TcpStream::connect(backend.address).await.unwrap();
+        stream.write_all(b"not a packet").await.unwrap();
+        let mut echoed = [0; 12];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"not a packet");
+        assert!(backend.handshakes().is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+use std::fs;
+
+use tokio::time::Duration;
+
+use super::{STATUS, wait_for_log};
+use crate::{
+    NOT_WHITELISTED_MESSAGE,
+    protocol::{chat::Chat, login},
+    proxy_protocol,
+    testing::{CapturedLogs, MockBackend, TestProxy, free_address, temp_dir, wait_for_file},
+};
+
+#[tokio::test]
+async fn takes_the_client_address_from_trusted_proxies() {
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        trusted-proxy = ["127.0.0.0/8"]
+        "#,
+        free_address(),
+    ))
+    .await;
+
+    let header = b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 25565\r\n";
+    let client = proxy.client().with_proxy_header(header);
+    client.status().await.unwrap();
+    wait_for_log(&logs, "client=203.0.113.7").await;
+    let header = proxy_protocol::v2_header("198.51.100.7:51234".parse().unwrap(), proxy.address);
+    let client = proxy.client().with_proxy_header(&header);
+    client.status().await.unwrap();
+    wait_for_log(&logs, "client=198.51.100.7").await;
+    // Connections from a trusted proxy have to start with a header
+    assert!(proxy.client().status().await.is_err());
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn rejects_proxy_headers_from_untrusted_peers() {
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        trusted-proxy = ["10.0.0.0/8"]
+        "#,
+        free_address(),
+    ))
+    .await;
+
+    let header = b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 25565\r\n";
+    assert!(
+        proxy
+            .client()
+            .with_proxy_header(header)
+            .status()
+            .await
+            .is_err()
+    );
+    assert!(proxy.client().status().await.is_ok());
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn applies_the_deny_list_to_clients_behind_trusted_proxies() {
+    let dir = temp_dir("denied-behind-proxy");
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "touch started"
+        working-dir = "{}"
+        trusted-proxy = ["127.0.0.0/8"]
+        deny = ["203.0.113.0/24"]
+        "#,
+        free_address(),
+        dir.display()
+    ))
+    .await;
+
+    let header = b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 25565\r\n";
+    let client = proxy.client().with_proxy_header(header);
+    assert!(client.status().await.is_err());
+    assert!(client.login("steve").await.is_err());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!dir.join("started").exists());
+    let header = b"PROXY TCP4 198.51.100.7 127.0.0.1 51234 25565\r\n";
+    assert!(
+        proxy
+            .client()
+            .with_proxy_header(header)
+            .status()
+            .await
+            .is_ok()
+    );
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn applies_the_allow_and_deny_lists() {
+    // The client connects from 127.0.0.1
+    for (lists, allowed) in [
+        (r#"allow = ["127.0.0.1"]"#, true),
+        (r#"deny = ["127.0.0.0/8"]"#, false),
+        (
+            r#"allow = ["127.0.0.0/8"]
+        deny = ["127.0.0.1"]"#,
+            false,
+        ),
+        (r#"allow = ["10.0.0.0/8"]"#, false),
+    ] {
+        let proxy = TestProxy::start(&format!(
+            r#"
+            forward = ["{}"]
+            start-command = "true"
+            {lists}
+            "#,
+            free_address()
+        ))
+        .await;
+        assert_eq!(proxy.client().status().await.is_ok(), allowed, "{lists}");
+        proxy.shutdown().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn only_starts_the_backend_for_whitelisted_players() {
+    let dir = temp_dir("whitelisted-start");
+    fs::write(dir.join("whitelist.txt"), "alex\n").unwrap();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "touch started"
+        working-dir = "{}"
+        whitelist = "{}"
+        "#,
+        free_address(),
+        dir.display(),
+        dir.join("whitelist.txt").display()
+    ))
+    .await;
+
+    let disconnect_reason = |response| match response {
+        login::ClientBound::Disconnect(Chat::Json(reason)) => reason.into_owned(),
+        packet => panic!("expected a disconnect, got {packet:?}"),
+    };
+    let response = proxy.client().login("steve").await.unwrap();
+    assert!(disconnect_reason(response).contains(NOT_WHITELISTED_MESSAGE));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!dir.join("started").exists());
+
+    let response = proxy.client().login("alex").await.unwrap();
+    assert!(!disconnect_reason(response).contains(NOT_WHITELISTED_MESSAGE));
+    wait_for_file(&dir.join("started")).await;
+    proxy.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn does_not_start_a_whitelisted_backend_for_status_pings() {
+    let dir = temp_dir("whitelisted-status");
+    fs::write(dir.join("whitelist.txt"), "alex\n").unwrap();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "touch started"
+        working-dir = "{}"
+        whitelist = "{}"
+        "#,
+        free_address(),
+        dir.display(),
+        dir.join("whitelist.txt").display()
+    ))
+    .await;
+
+    let status = proxy.client().status().await.unwrap();
+    assert_eq!(status["description"], "Server is offline");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!dir.join("started").exists());
+    proxy.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn drops_blocked_hosts_before_starting_or_forwarding() {
+    let dir = temp_dir("block-host");
+    let backend = MockBackend::new().with_status(STATUS).start();
+    let config = format!(
+        r#"
+        forward = ["{}"]
+        start-command = "touch started"
+        working-dir = "{}"
+        block-host = ["*.scan.example.com", "~^\\d+\\."]
+        "#,
+        backend.address,
+        dir.display()
+    );
+    let proxy = TestProxy::start(&config).await;
+    let client = proxy.client().with_host("random.scan.example.com");
+    assert!(client.status().await.is_err());
+    assert!(client.login("alex").await.is_err());
+    let client = proxy.client().with_host("192.0.2.1");
+    assert!(client.login("alex").await.is_err());
+    proxy.shutdown().await.unwrap();
+
+    // Logins can be told why instead
+    let proxy = TestProxy::start(&format!("blocked-message = \"Go away\"\n{}", config)).await;
+    let client = proxy.client().with_host("random.scan.example.com");
+    let response = client.login("alex").await.unwrap();
+    let login::ClientBound::Disconnect(Chat::Json(reason)) = response else {
+        panic!("expected a disconnect, got {response:?}");
+    };
+    assert!(reason.contains("Go away"));
+    assert!(client.status().await.is_err());
+    proxy.shutdown().await.unwrap();
+
+    assert_eq!(backend.accepted(), 0);
+    assert!(!dir.join("started").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,221 @@
+use std::{borrow::Cow, fs};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{Duration, Instant, timeout},
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use super::STATUS;
+use crate::{
+    DEFAULT_READ_TIMEOUT,
+    client::testing::TestClient,
+    config::Config,
+    listener::ListenAddress,
+    protocol::{
+        PacketDecoder, PacketEncoder,
+        chat::Chat,
+        handshake::{HandshakePacket, NextState, ProtocolVersion},
+        login, status, write_packet,
+    },
+    run,
+    testing::{MockBackend, TestProxy, free_address, temp_dir},
+};
+
+#[tokio::test]
+async fn names_the_address_that_could_not_be_bound() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let taken_address = taken.local_addr().unwrap();
+    let mut config: Config = toml::from_str(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        free_address()
+    ))
+    .unwrap();
+    config.listen = vec![
+        ListenAddress::Tcp(free_address()),
+        ListenAddress::Tcp(taken_address),
+    ];
+
+    let error = run(config, std::future::pending()).await.unwrap_err();
+    assert!(
+        error.to_string().contains(&taken_address.to_string()),
+        "{error}"
+    );
+}
+
+#[tokio::test]
+async fn drops_connections_beyond_the_limit() {
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        max-connections = 2
+        "#,
+        free_address()
+    ))
+    .await;
+
+    // Connections that have not sent anything yet hold their permits
+    let first = TcpStream::connect(proxy.address).await.unwrap();
+    let mut second = TcpStream::connect(proxy.address).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut third = TcpStream::connect(proxy.address).await.unwrap();
+    let mut buf = [0; 1];
+    let read = timeout(Duration::from_secs(5), third.read(&mut buf)).await;
+    assert!(matches!(read, Ok(Ok(0) | Err(_))), "{read:?}");
+    assert!(
+        timeout(Duration::from_millis(100), second.read(&mut buf))
+            .await
+            .is_err()
+    );
+
+    // Closing a connection makes room for another one
+    drop(first);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while proxy.client().status().await.is_err() {
+        assert!(Instant::now() < deadline, "the permit was not released");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    drop(second);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn closes_connections_of_slow_clients_after_the_handshake_timeout() {
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        handshake-timeout = 1
+        "#,
+        free_address()
+    ))
+    .await;
+
+    // The client starts the handshake, but never finishes it
+    let mut stream = TcpStream::connect(proxy.address).await.unwrap();
+    stream.write_all(&[0x10, 0x00]).await.unwrap();
+    let start = Instant::now();
+    let mut buf = [0; 16];
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{:?}", elapsed);
+    assert!(elapsed < DEFAULT_READ_TIMEOUT, "{:?}", elapsed);
+    drop(stream);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn accepts_connections_on_every_listener() {
+    let backend = MockBackend::new()
+        .with_login_disconnect("Hello from the backend")
+        .start();
+    let (first, second) = (free_address(), free_address());
+    let proxy = TestProxy::start_listening(
+        &format!(
+            r#"
+            forward = ["{}"]
+            start-command = "true"
+            "#,
+            backend.address
+        ),
+        vec![ListenAddress::Tcp(first), ListenAddress::Tcp(second)],
+    )
+    .await;
+
+    for address in [first, second] {
+        let response = TestClient::new(address).login("alex").await.unwrap();
+        assert!(matches!(
+            response,
+            login::ClientBound::Disconnect(Chat::Json(_))
+        ));
+    }
+    // Both listeners forward to the same backend
+    assert_eq!(backend.accepted(), 2);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_clients_on_unix_sockets() {
+    let backend = MockBackend::new().with_status(STATUS).start();
+    let dir = temp_dir("unix-socket");
+    let path = dir.join("portal.sock");
+    let proxy = TestProxy::start_listening(
+        &format!(
+            r#"
+            forward = ["{}"]
+            start-command = "true"
+            "#,
+            backend.address
+        ),
+        vec![
+            ListenAddress::Tcp(free_address()),
+            ListenAddress::Unix(path.clone()),
+        ],
+    )
+    .await;
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let handshake = HandshakePacket {
+        version: ProtocolVersion::V1_21_7,
+        address: Cow::Borrowed("localhost"),
+        port: 25565,
+        next_state: NextState::Status,
+    };
+    write_packet(&mut stream, &handshake).await.unwrap();
+    let (reader, writer) = io::split(stream);
+    let mut writer = FramedWrite::new(writer, PacketEncoder::new());
+    writer
+        .send(status::ServerBound::StatusRequest)
+        .await
+        .unwrap();
+    let mut reader = FramedRead::new(reader, PacketDecoder::<status::ClientBound>::new());
+    match reader.next().await.unwrap().unwrap().into_inner() {
+        status::ClientBound::StatusResponse { json_response } => {
+            assert!(json_response.contains("Live"), "{json_response}");
+        }
+        packet => panic!("expected a status response, got {packet:?}"),
+    }
+    // Open connections would hold up the shutdown
+    drop((reader, writer));
+    proxy.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn closes_connections_that_are_not_minecraft_right_away() {
+    let backend = MockBackend::new().start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        backend.address
+    ))
+    .await;
+
+    let requests: [&[u8]; 2] = [
+        b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        // The start of a TLS ClientHello
+        &[
+            0x16, 0x03, 0x01, 0x02, 0x00, 0x01, 0x00, 0x01, 0xfc, 0x03, 0x03,
+        ],
+    ];
+    for request in requests {
+        let mut stream = TcpStream::connect(proxy.address).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let start = Instant::now();
+        let mut buf = [0; 16];
+        // Depending on whether the request was read, the connection is closed or reset
+        let read = stream.read(&mut buf).await;
+        assert!(matches!(read, Ok(0) | Err(_)), "{:?}", read);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+    proxy.shutdown().await.unwrap();
+    assert_eq!(backend.accepted(), 0);
+}
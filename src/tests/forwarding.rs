@@ -0,0 +1,86 @@
+use tokio::time::{self, Duration, Instant};
+
+use super::STATUS;
+use crate::{
+    client::testing::TestClient,
+    protocol::{
+        chat::Chat,
+        handshake::{NextState, ProtocolVersion},
+        login,
+    },
+    testing::{MockBackend, STARTUP_TIMEOUT, TestProxy},
+};
+
+/// Logs in and returns the JSON of the disconnect message.
+async fn login_disconnect(client: &TestClient) -> String {
+    match client.login("alex").await.unwrap() {
+        login::ClientBound::Disconnect(Chat::Json(reason)) => reason.into_owned(),
+        packet => panic!("expected a disconnect, got {packet:?}"),
+    }
+}
+
+#[tokio::test]
+async fn forwards_to_the_backend_when_it_is_up() {
+    let backend = MockBackend::new()
+        .with_status(STATUS)
+        .with_login_disconnect("Hello from the backend")
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "sleep 30"
+        "#,
+        backend.address
+    ))
+    .await;
+    let client = proxy.client().with_host("mc.example.com");
+
+    assert_eq!(
+        login_disconnect(&client).await,
+        r#""Hello from the backend""#
+    );
+    let handshakes = backend.handshakes();
+    assert_eq!(handshakes.len(), 1);
+    assert_eq!(handshakes[0].address, "mc.example.com");
+    assert_eq!(handshakes[0].next_state, NextState::Login);
+    assert_eq!(handshakes[0].version, ProtocolVersion(772));
+
+    let status = client.status().await.unwrap();
+    assert_eq!(status["description"], "Live");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn forwards_once_a_delayed_backend_is_up() {
+    let backend = MockBackend::new()
+        .with_delay(Duration::from_millis(500))
+        .with_login_disconnect("Hello from the backend")
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "sleep 30"
+        "#,
+        backend.address
+    ))
+    .await;
+    let client = proxy.client();
+
+    assert!(
+        login_disconnect(&client)
+            .await
+            .contains("Server is starting")
+    );
+    assert_eq!(backend.accepted(), 0);
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    while !login_disconnect(&client)
+        .await
+        .contains("Hello from the backend")
+    {
+        assert!(Instant::now() < deadline, "the login was never forwarded");
+        time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(backend.accepted(), 1);
+    proxy.shutdown().await.unwrap();
+}
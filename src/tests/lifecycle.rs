@@ -0,0 +1,227 @@
+use std::fs;
+
+use tokio::{
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    FAILED_MESSAGE, STARTING_MESSAGE,
+    protocol::{chat::Chat, handshake::NextState, login},
+    testing::{
+        BlackHole, CapturedLogs, MockBackend, TestProxy, free_address, temp_dir, wait_for_file,
+    },
+};
+
+#[tokio::test]
+async fn shutdown_stops_every_backend_and_the_listeners() {
+    let default_dir = temp_dir("shutdown-default");
+    let route_dir = temp_dir("shutdown-route");
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "sh -c 'touch started && exec sleep 30'"
+        stop-command = "touch stopped"
+        stop-timeout = 1
+        working-dir = "{}"
+
+        [route."other.example.com"]
+        forward = ["{}"]
+        start-command = "sh -c 'touch started && exec sleep 30'"
+        working-dir = "{}"
+        "#,
+        free_address(),
+        default_dir.display(),
+        free_address(),
+        route_dir.display()
+    ))
+    .await;
+
+    // Asking for the status starts the backends
+    proxy.client().status().await.unwrap();
+    proxy
+        .client()
+        .with_host("other.example.com")
+        .status()
+        .await
+        .unwrap();
+    wait_for_file(&default_dir.join("started")).await;
+    wait_for_file(&route_dir.join("started")).await;
+    // The stop command of the route can not run without its working directory
+    fs::remove_dir_all(&route_dir).unwrap();
+
+    let address = proxy.address;
+    proxy.shutdown().await.unwrap();
+    assert!(default_dir.join("stopped").exists());
+    assert!(TcpStream::connect(address).await.is_err());
+    fs::remove_dir_all(&default_dir).unwrap();
+}
+
+#[tokio::test]
+async fn stops_a_backend_that_does_not_become_reachable() {
+    let dir = temp_dir("start-timeout");
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "sh -c 'trap \"touch terminated; exit\" TERM; while true; do sleep 0.05; done'"
+        working-dir = "{}"
+        start-timeout = 1
+        kill-on-start-timeout = true
+        "#,
+        free_address(),
+        dir.display()
+    ))
+    .await;
+
+    // The status ping runs the start command, which never opens the port
+    proxy.client().status().await.unwrap();
+    wait_for_file(&dir.join("terminated")).await;
+    assert!(
+        logs.contents()
+            .contains("Backend did not become reachable after starting it")
+    );
+    proxy.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn tells_players_once_the_backend_failed() {
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "false"
+        restart = "on-failure"
+        max-restarts = 0
+        "#,
+        free_address(),
+    ))
+    .await;
+
+    // The first login runs the start command, which exits right away and is not restarted
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let response = proxy.client().login("alex").await.unwrap();
+        let login::ClientBound::Disconnect(Chat::Json(reason)) = response else {
+            panic!("expected a disconnect, got {response:?}");
+        };
+        if reason.contains(FAILED_MESSAGE) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "the backend did not fail");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn disconnects_held_players_if_the_backend_does_not_come_up() {
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "sleep 10"
+        hold = 1
+        "#,
+        free_address()
+    ))
+    .await;
+
+    match proxy.client().login("alex").await.unwrap() {
+        login::ClientBound::Disconnect(Chat::Json(reason)) => {
+            assert!(reason.contains(STARTING_MESSAGE), "{reason}");
+        }
+        packet => panic!("expected a disconnect, got {packet:?}"),
+    }
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn starts_the_backend_if_connecting_times_out() {
+    let black_hole = BlackHole::new();
+    let dir = temp_dir("connect-timeout");
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "touch started"
+        working-dir = "{}"
+        connect-timeout = 1
+        "#,
+        black_hole.address,
+        dir.display()
+    ))
+    .await;
+
+    let start = Instant::now();
+    match proxy.client().login("alex").await.unwrap() {
+        login::ClientBound::Disconnect(Chat::Json(reason)) => {
+            assert!(reason.contains(STARTING_MESSAGE), "{reason}");
+        }
+        packet => panic!("expected a disconnect, got {packet:?}"),
+    }
+    assert!(start.elapsed() < Duration::from_secs(3));
+    wait_for_file(&dir.join("started")).await;
+    proxy.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn holds_players_until_the_backend_is_up() {
+    let backend = MockBackend::new()
+        .with_delay(Duration::from_millis(1500))
+        .with_login_disconnect("Hello from the backend")
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "sleep 10"
+        hold = 10
+        "#,
+        backend.address
+    ))
+    .await;
+
+    // The player stays connected and ends up on the backend without reconnecting
+    match proxy.client().login("alex").await.unwrap() {
+        login::ClientBound::Disconnect(Chat::Json(reason)) => {
+            assert!(reason.contains("Hello from the backend"), "{reason}");
+        }
+        packet => panic!("expected a disconnect, got {packet:?}"),
+    }
+    let handshakes = backend.handshakes();
+    assert_eq!(handshakes.len(), 1);
+    assert_eq!(handshakes[0].next_state, NextState::Login);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn stops_the_backend_once_it_is_idle() {
+    let dir = temp_dir("idle");
+    let backend = MockBackend::new()
+        .with_login_disconnect("Hello from the backend")
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        stop-command = "touch stopped"
+        idle-timeout = 1
+        working-dir = "{}"
+        "#,
+        backend.address,
+        dir.display()
+    ))
+    .await;
+
+    let response = proxy.client().login("alex").await.unwrap();
+    assert!(matches!(
+        response,
+        login::ClientBound::Disconnect(Chat::Json(_))
+    ));
+    assert_eq!(backend.accepted(), 1);
+    assert!(!dir.join("stopped").exists());
+    // The backend is reachable but without players, so it is stopped after a second
+    wait_for_file(&dir.join("stopped")).await;
+    proxy.shutdown().await.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
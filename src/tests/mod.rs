@@ -0,0 +1,41 @@
+use std::net::SocketAddr;
+
+use tokio::time::{Duration, Instant};
+
+use crate::testing::{CapturedLogs, http_get};
+
+mod access;
+mod connections;
+mod forwarding;
+mod lifecycle;
+mod observability;
+mod routing;
+mod status;
+
+/// The status a backend answers with
+pub(super) const STATUS: &str = r#"{"version":{"name":"Paper 1.21.7","protocol":772},"players":{"max":20,"online":3},"description":"Live"}"#;
+
+/// Waits until the logs contain the text, failing the test if it takes too long.
+pub(super) async fn wait_for_log(logs: &CapturedLogs, text: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !logs.contents().contains(text) {
+        assert!(Instant::now() < deadline, "{text} was not logged");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Waits until the metrics served at the address contain the line.
+pub(super) async fn wait_for_metric(address: SocketAddr, expected: &str) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (_, body) = http_get(address, "/metrics").await;
+        if body.lines().any(|line| line == expected) {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "{expected} is missing in\n{body}"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
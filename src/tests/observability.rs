@@ -0,0 +1,321 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use super::{wait_for_log, wait_for_metric};
+use crate::{
+    protocol::handshake::NextState,
+    testing::{CapturedLogs, MockBackend, TestProxy, free_address, http_get},
+};
+
+#[tokio::test]
+async fn tags_events_with_the_connection_id() {
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        free_address()
+    ))
+    .await;
+
+    proxy.client().status().await.unwrap();
+    proxy.client().status().await.unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let ids = loop {
+        let ids = logs
+            .contents()
+            .lines()
+            .filter(|line| line.contains("Connection closed"))
+            .map(|line| {
+                let (_, id) = line
+                    .split_once("conn_id=")
+                    .expect("event has a connection id");
+                id.split(|c: char| !c.is_ascii_digit())
+                    .next()
+                    .unwrap()
+                    .parse::<u64>()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        // The startup check of the test proxy is a connection as well
+        if ids.len() >= 3 {
+            break ids;
+        }
+        assert!(Instant::now() < deadline, "connections were not logged");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let mut unique = ids.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), ids.len(), "{ids:?}");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_connections_in_the_metrics() {
+    let metrics_address = free_address();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        metrics-address = "{}"
+        "#,
+        free_address(),
+        metrics_address
+    ))
+    .await;
+
+    proxy.client().status().await.unwrap();
+    // Connections are counted once they are closed
+    let expected = r#"portal_handled_connections_total{next_state="status"} 1"#;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (_, body) = http_get(metrics_address, "/metrics").await;
+        if body.lines().any(|line| line == expected) {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "{expected} is missing in\n{body}"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn shows_the_state_of_the_backend_in_the_metrics() {
+    let metrics_address = free_address();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "sh -c 'trap \"\" TERM; while true; do sleep 0.05; done'"
+        ready-pattern = "Done"
+        start-timeout = 2
+        kill-on-start-timeout = true
+        stop-timeout = 2
+        metrics-address = "{}"
+        "#,
+        free_address(),
+        metrics_address
+    ))
+    .await;
+
+    wait_for_metric(
+        metrics_address,
+        r#"portal_backend_state{backend="default"} 0"#,
+    )
+    .await;
+    // The process never logs that it is ready, so the backend keeps starting
+    proxy.client().login("alex").await.unwrap();
+    wait_for_metric(
+        metrics_address,
+        r#"portal_backend_state{backend="default"} 1"#,
+    )
+    .await;
+    // It is stopped after the start timeout, and ignores SIGTERM until it is killed
+    wait_for_metric(
+        metrics_address,
+        r#"portal_backend_state{backend="default"} 4"#,
+    )
+    .await;
+    wait_for_metric(
+        metrics_address,
+        r#"portal_backend_state{backend="default"} 0"#,
+    )
+    .await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn records_the_duration_of_short_connections() {
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let metrics_address = free_address();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        metrics-address = "{}"
+        "#,
+        free_address(),
+        metrics_address
+    ))
+    .await;
+
+    // The client goes away before sending a handshake, like the one checking that the proxy is
+    // listening did
+    drop(TcpStream::connect(proxy.address).await.unwrap());
+    wait_for_metric(
+        metrics_address,
+        r#"portal_connection_duration_seconds_count{next_state="none"} 2"#,
+    )
+    .await;
+    let line = logs
+        .contents()
+        .lines()
+        .find(|line| line.contains("access: Connection closed"))
+        .unwrap()
+        .to_owned();
+    assert!(line.contains(" duration_ms="), "{line}");
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn logs_the_access_of_forwarded_connections() {
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let backend = MockBackend::new()
+        .with_login_disconnect("Hello from the backend")
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        backend.address
+    ))
+    .await;
+
+    proxy.client().login("alex").await.unwrap();
+    wait_for_log(&logs, "action=forwarded").await;
+    let line = logs
+        .contents()
+        .lines()
+        .find(|line| line.contains("action=forwarded"))
+        .unwrap()
+        .to_owned();
+    for field in [
+        "client=127.0.0.1",
+        r#"host="localhost""#,
+        "next_state=login",
+        "bytes_received=",
+        "bytes_sent=",
+        "duration_ms=",
+    ] {
+        assert!(line.contains(field), "{field} is missing in {line}");
+    }
+    // Both directions carried packets
+    assert!(!line.contains("bytes_received=0 "), "{line}");
+    assert!(!line.contains("bytes_sent=0 "), "{line}");
+    assert!(line.contains("access:"), "{line}");
+
+    // Logins the proxy answers itself are logged with the player's name
+    drop(backend);
+    proxy.client().login("alex").await.unwrap();
+    wait_for_log(&logs, "action=started").await;
+    assert!(logs.contents().contains(r#"name="alex""#));
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_forwarded_connections_until_they_end() {
+    let backend = MockBackend::new().echo().start();
+    let metrics_address = free_address();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        metrics-address = "{}"
+        connection-idle-timeout = 1
+        "#,
+        backend.address, metrics_address
+    ))
+    .await;
+
+    let client = proxy.client().connect(NextState::Login).await.unwrap();
+    wait_for_metric(metrics_address, "portal_forwarded_connections 1").await;
+    drop(client);
+    wait_for_metric(metrics_address, "portal_forwarded_connections 0").await;
+
+    // A connection that ends with an error is not counted anymore either
+    let _client = proxy.client().connect(NextState::Login).await.unwrap();
+    wait_for_metric(metrics_address, "portal_forwarded_connections 1").await;
+    // Nothing is sent, so the connection times out after a second
+    wait_for_metric(metrics_address, "portal_forwarded_connections 0").await;
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn records_how_long_the_backend_took_to_start() {
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let metrics_address = free_address();
+    // The backend is reachable two and a half seconds after the start command was run, which
+    // the proxy notices with the probe after three seconds
+    let backend = MockBackend::new()
+        .with_delay(Duration::from_millis(2500))
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        metrics-address = "{}"
+        "#,
+        backend.address, metrics_address
+    ))
+    .await;
+
+    proxy.client().login("alex").await.unwrap();
+    let count = r#"portal_start_duration_seconds_count{backend="default"} 1"#;
+    wait_for_metric(metrics_address, count).await;
+    let (_, body) = http_get(metrics_address, "/metrics").await;
+    for line in [
+        r#"portal_start_duration_seconds_bucket{le="2.0",backend="default"} 0"#,
+        r#"portal_start_duration_seconds_bucket{le="4.0",backend="default"} 1"#,
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "{line} is missing in\n{body}"
+        );
+    }
+    wait_for_log(&logs, "Backend is ready").await;
+    assert!(
+        logs.contents().contains("elapsed=3."),
+        "{}",
+        logs.contents()
+    );
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn counts_the_bytes_forwarded_in_each_direction() {
+    let logs = CapturedLogs::default();
+    let _guard = logs.install();
+    let backend = MockBackend::new().echo().start();
+    let metrics_address = free_address();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        metrics-address = "{}"
+        "#,
+        backend.address, metrics_address
+    ))
+    .await;
+
+    let mut client = proxy.client().connect(NextState::Login).await.unwrap();
+    client.write_all(&[7; 10_000]).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).await.unwrap();
+    // The handshake is forwarded as it was received and echoed before the data
+    let forwarded = echoed.len();
+    assert!(forwarded > 10_000);
+    assert!(echoed.ends_with(&[7; 10_000]));
+
+    wait_for_log(&logs, "action=forwarded").await;
+    let bytes = format!("bytes_received={forwarded} bytes_sent={forwarded} ");
+    assert!(logs.contents().contains(&bytes), "{}", logs.contents());
+    for direction in ["to_backend", "to_client"] {
+        let line =
+            format!(r#"portal_forwarded_bytes_total{{direction="{direction}"}} {forwarded}"#);
+        wait_for_metric(metrics_address, &line).await;
+    }
+    proxy.shutdown().await.unwrap();
+}
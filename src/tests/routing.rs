@@ -0,0 +1,217 @@
+use std::fs;
+
+use super::STATUS;
+use crate::{
+    UNKNOWN_SERVER_MESSAGE,
+    protocol::{chat::Chat, login},
+    testing::{MockBackend, TestProxy, free_address, temp_dir, wait_for_file},
+};
+
+#[tokio::test]
+async fn runs_the_start_command_of_the_route() {
+    let survival_dir = temp_dir("route-survival");
+    let creative_dir = temp_dir("route-creative");
+    let proxy = TestProxy::start(&format!(
+        r#"
+        env = {{ WORLD = "default" }}
+
+        [route."survival.example.com"]
+        forward = ["{}"]
+        start-command = "sh -c 'echo survival $WORLD > started'"
+        working-dir = "{}"
+        env = {{ WORLD = "survival" }}
+
+        [route."creative.example.com"]
+        forward = ["{}"]
+        start-command = "sh -c 'echo creative $WORLD > started'"
+        working-dir = "{}"
+        "#,
+        free_address(),
+        survival_dir.display(),
+        free_address(),
+        creative_dir.display()
+    ))
+    .await;
+
+    proxy
+        .client()
+        .with_host("survival.example.com")
+        .login("alex")
+        .await
+        .unwrap();
+    wait_for_file(&survival_dir.join("started")).await;
+    assert!(!creative_dir.join("started").exists());
+    proxy
+        .client()
+        .with_host("creative.example.com")
+        .login("steve")
+        .await
+        .unwrap();
+    wait_for_file(&creative_dir.join("started")).await;
+    proxy.shutdown().await.unwrap();
+
+    let started = |dir: &std::path::Path| fs::read_to_string(dir.join("started")).unwrap();
+    assert_eq!(started(&survival_dir), "survival survival\n");
+    assert_eq!(started(&creative_dir), "creative default\n");
+    fs::remove_dir_all(&survival_dir).unwrap();
+    fs::remove_dir_all(&creative_dir).unwrap();
+}
+
+#[tokio::test]
+async fn sends_returning_players_to_the_same_backend() {
+    let first = MockBackend::new()
+        .with_login_disconnect("Hello from the first backend")
+        .start();
+    let second = MockBackend::new()
+        .with_login_disconnect("Hello from the second backend")
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}", "{}"]
+        start-command = "true"
+        sticky-sessions = 60
+        "#,
+        first.address, second.address
+    ))
+    .await;
+
+    let login = |name: &'static str| {
+        let client = proxy.client();
+        async move {
+            match client.login(name).await.unwrap() {
+                login::ClientBound::Disconnect(Chat::Json(reason)) => reason.into_owned(),
+                packet => panic!("expected a disconnect, got {packet:?}"),
+            }
+        }
+    };
+    let alex = login("alex").await;
+    // Without sticky sessions, the next player would be sent to the other backend
+    assert_eq!(login("alex").await, alex);
+    assert_eq!(login("Alex").await, alex);
+    assert_ne!(login("steve").await, alex);
+    proxy.shutdown().await.unwrap();
+}
+
+/// Returns the description of the status the proxy shows for a host.
+async fn description(proxy: &TestProxy, host: &str) -> serde_json::Value {
+    let status = proxy.client().with_host(host).status().await.unwrap();
+    status["description"].clone()
+}
+
+#[tokio::test]
+async fn routes_players_by_host() {
+    let default = MockBackend::new()
+        .with_status(&STATUS.replace("Live", "Default"))
+        .start();
+    let creative = MockBackend::new()
+        .with_status(&STATUS.replace("Live", "Creative"))
+        .start();
+    let routes = format!(
+        r#"
+        [route."creative.example.com"]
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        creative.address
+    );
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        {}
+        "#,
+        default.address, routes
+    ))
+    .await;
+
+    assert_eq!(
+        description(&proxy, "Creative.example.com").await,
+        "Creative"
+    );
+    assert_eq!(description(&proxy, "survival.example.com").await, "Default");
+    proxy.shutdown().await.unwrap();
+
+    // Without a default route, other hosts are not served
+    let proxy = TestProxy::start(&routes).await;
+    assert_eq!(
+        description(&proxy, "creative.example.com").await,
+        "Creative"
+    );
+    assert_eq!(
+        description(&proxy, "survival.example.com").await,
+        UNKNOWN_SERVER_MESSAGE
+    );
+    proxy.shutdown().await.unwrap();
+    assert_eq!((default.accepted(), creative.accepted()), (1, 2));
+}
+
+#[tokio::test]
+async fn routes_forge_clients_by_their_host() {
+    let backend = MockBackend::new()
+        .with_status(&STATUS.replace("Live", "Modded"))
+        .start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        [route."*.mc.example.com"]
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        backend.address
+    ))
+    .await;
+
+    // Forge appends its marker to the host, which is left out for routing
+    assert_eq!(
+        description(&proxy, "modded.MC.example.com\0FML3\0").await,
+        "Modded"
+    );
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn disconnects_players_without_a_route() {
+    let backend = MockBackend::new().with_login_disconnect("Routed").start();
+    let route = format!(
+        r#"
+        [route."survival.example.com"]
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        backend.address
+    );
+    let login = async |proxy: &TestProxy, host: &str| {
+        let response = proxy.client().with_host(host).login("alex").await.unwrap();
+        let login::ClientBound::Disconnect(Chat::Json(reason)) = response else {
+            panic!("expected a disconnect, got {response:?}");
+        };
+        reason
+    };
+
+    let proxy = TestProxy::start(&route).await;
+    assert!(
+        login(&proxy, "creative.example.com")
+            .await
+            .contains(UNKNOWN_SERVER_MESSAGE)
+    );
+    proxy.shutdown().await.unwrap();
+    assert_eq!(backend.accepted(), 0);
+
+    // The message can be configured
+    let proxy = TestProxy::start(&format!(
+        "unknown-server-message = \"No such server\"\n{}",
+        route
+    ))
+    .await;
+    assert!(
+        login(&proxy, "creative.example.com")
+            .await
+            .contains("No such server")
+    );
+    assert!(
+        login(&proxy, "survival.example.com")
+            .await
+            .contains("Routed")
+    );
+    proxy.shutdown().await.unwrap();
+    assert_eq!(backend.accepted(), 1);
+}
@@ -0,0 +1,139 @@
+use std::fs;
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task,
+    time::Duration,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use super::STATUS;
+use crate::{
+    LEGACY_MOTD, LEGACY_VERSION, STATUS_BUDGET,
+    protocol::{PacketDecoder, PacketEncoder, status},
+    status_handler,
+    testing::{MockBackend, TestProxy, free_address, temp_dir},
+};
+
+/// Runs the status handler for the packets the client sends and returns what it answered.
+async fn status_exchange(requests: Vec<status::ServerBound>) -> Vec<status::ClientBound<'static>> {
+    let (client, server) = io::duplex(4096);
+    let (server_reader, server_writer) = io::split(server);
+    let handler = task::spawn(async move {
+        status_handler(
+            FramedRead::new(server_reader, PacketDecoder::new()),
+            FramedWrite::new(server_writer, PacketEncoder::new()),
+            "{}",
+            Duration::from_secs(5),
+            STATUS_BUDGET,
+        )
+        .await
+    });
+
+    let (client_reader, client_writer) = io::split(client);
+    let mut writer = FramedWrite::new(client_writer, PacketEncoder::new());
+    for request in requests {
+        writer.send(request).await.unwrap();
+    }
+    let responses = FramedRead::new(client_reader, PacketDecoder::<status::ClientBound>::new())
+        .map(|packet| packet.unwrap().into_inner())
+        .collect()
+        .await;
+    handler.await.unwrap().unwrap();
+    responses
+}
+
+#[tokio::test]
+async fn closes_status_connections_after_the_ping() {
+    let responses = status_exchange(vec![
+        status::ServerBound::StatusRequest,
+        status::ServerBound::PingRequest(42),
+        status::ServerBound::StatusRequest,
+    ])
+    .await;
+    assert!(matches!(
+        responses[..],
+        [
+            status::ClientBound::StatusResponse { .. },
+            status::ClientBound::PingResponse(42)
+        ]
+    ));
+
+    // A second status request is not answered either
+    let responses = status_exchange(vec![
+        status::ServerBound::StatusRequest,
+        status::ServerBound::StatusRequest,
+    ])
+    .await;
+    assert!(matches!(
+        responses[..],
+        [status::ClientBound::StatusResponse { .. }]
+    ));
+}
+
+#[tokio::test]
+async fn answers_legacy_pings_from_1_6_clients() {
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "true"
+        "#,
+        free_address()
+    ))
+    .await;
+
+    // A 1.6 client pinging localhost:25565 with protocol 74
+    let mut ping = vec![0xfe, 0x01, 0xfa, 0x00, 0x0b];
+    ping.extend("MC|PingHost".encode_utf16().flat_map(u16::to_be_bytes));
+    ping.extend([0x00, 0x19, 0x4a, 0x00, 0x09]);
+    ping.extend("localhost".encode_utf16().flat_map(u16::to_be_bytes));
+    ping.extend(25565_i32.to_be_bytes());
+    let mut stream = TcpStream::connect(proxy.address).await.unwrap();
+    stream.write_all(&ping).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+
+    assert_eq!(response[0], 0xff);
+    let units = response[3..]
+        .chunks(2)
+        .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        usize::from(u16::from_be_bytes([response[1], response[2]])),
+        units.len()
+    );
+    let message = String::from_utf16(&units).unwrap();
+    let fields = message.split('\0').collect::<Vec<_>>();
+    assert_eq!(fields[..3], ["§1", "74", LEGACY_VERSION]);
+    assert_eq!(fields[3..], [LEGACY_MOTD, "0", "0"]);
+    proxy.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_the_live_status_of_a_backend_that_is_up() {
+    let dir = temp_dir("live-status");
+    let backend = MockBackend::new().with_status(STATUS).start();
+    let proxy = TestProxy::start(&format!(
+        r#"
+        forward = ["{}"]
+        start-command = "touch started"
+        working-dir = "{}"
+        "#,
+        backend.address,
+        dir.display()
+    ))
+    .await;
+
+    let status = proxy.client().status().await.unwrap();
+    assert_eq!(
+        status,
+        serde_json::from_str::<serde_json::Value>(STATUS).unwrap()
+    );
+    assert_eq!(backend.accepted(), 1);
+    proxy.shutdown().await.unwrap();
+    // The backend was up, so it was not started
+    assert!(!dir.join("started").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,178 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
+
+/// A token bucket that refills at a fixed number of bytes per second and holds at most one second
+/// worth of bytes.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+            sleep: Box::pin(time::sleep(Duration::ZERO)),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+
+    /// Waits until at least one byte may be transferred and returns how many may be.
+    fn poll_available(&mut self, cx: &mut Context<'_>) -> Poll<usize> {
+        loop {
+            self.refill();
+            if self.tokens >= 1.0 {
+                return Poll::Ready(self.tokens as usize);
+            }
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            self.sleep.as_mut().reset(self.refilled + wait);
+            ready!(self.sleep.as_mut().poll(cx));
+        }
+    }
+
+    /// Takes the bytes from the bucket. The bucket may go into debt, which is paid back before the
+    /// next transfer.
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// A stream adapter that limits how many bytes per second are read from and written to the inner
+/// stream. Each direction has its own limit.
+pub struct Throttled<S> {
+    inner: S,
+    read: TokenBucket,
+    write: TokenBucket,
+}
+
+impl<S> Throttled<S> {
+    /// Wraps a stream, allowing `rate` bytes per second in each direction.
+    pub fn new(inner: S, rate: u64) -> Throttled<S> {
+        Throttled {
+            inner,
+            read: TokenBucket::new(rate),
+            write: TokenBucket::new(rate),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.read.poll_available(cx));
+        // How much the inner stream returns can not be limited without copying, so a large read
+        // is paid for by waiting longer before the next one
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.read.consume(buf.filled().len() - start);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let available = ready!(this.write.poll_available(cx));
+        let len = buf.len().min(available);
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.write.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const PAYLOAD: usize = 5000;
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_writes() {
+        let (client, mut server) = io::duplex(64 * 1024);
+        let mut client = Throttled::new(client, 1000);
+        let start = Instant::now();
+        let reader = tokio::spawn(async move {
+            let mut read = vec![0; PAYLOAD];
+            server.read_exact(&mut read).await.unwrap();
+            read
+        });
+        client.write_all(&[7; PAYLOAD]).await.unwrap();
+        client.flush().await.unwrap();
+        assert_eq!(reader.await.unwrap(), [7; PAYLOAD]);
+        // The first second worth of bytes is sent right away
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(4), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_reads() {
+        let (client, mut server) = io::duplex(64 * 1024);
+        let mut client = Throttled::new(client, 1000);
+        server.write_all(&[7; PAYLOAD]).await.unwrap();
+        let start = Instant::now();
+        let mut read = 0;
+        let mut chunk = [0; 500];
+        while read < PAYLOAD {
+            read += client.read(&mut chunk).await.unwrap();
+        }
+        // The first second worth of bytes is read right away, the rest is read in steps
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(3500), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "{elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_after_large_reads() {
+        let (client, mut server) = io::duplex(64 * 1024);
+        let mut client = Throttled::new(client, 1000);
+        server.write_all(&[7; 3000]).await.unwrap();
+        let mut read = vec![0; 3000];
+        assert_eq!(client.read(&mut read).await.unwrap(), 3000);
+
+        // The bucket is 2000 bytes in debt, which takes two seconds to pay back
+        server.write_all(&[7]).await.unwrap();
+        let start = Instant::now();
+        assert_eq!(client.read(&mut read).await.unwrap(), 1);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(2), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(2100), "{elapsed:?}");
+    }
+}
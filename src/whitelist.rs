@@ -0,0 +1,116 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// The players that may start the backend, loaded from a file with one name or UUID per line.
+/// Empty lines and lines starting with `#` are ignored.
+pub struct Whitelist {
+    path: PathBuf,
+    entries: RwLock<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Names in lowercase, as names are not case sensitive
+    names: HashSet<String>,
+    uuids: HashSet<Uuid>,
+}
+
+impl Whitelist {
+    pub fn load(path: PathBuf) -> Result<Whitelist, Error> {
+        let entries = read_entries(&path)?;
+        Ok(Whitelist {
+            path,
+            entries: RwLock::new(entries),
+        })
+    }
+
+    /// Reads the file again. The previous entries are kept if it can not be read.
+    pub fn reload(&self) -> Result<(), Error> {
+        let entries = read_entries(&self.path)?;
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+
+    /// Returns whether the player is on the whitelist by either their name or their UUID.
+    pub fn contains(&self, name: &str, uuid: Uuid) -> bool {
+        let entries = self.entries.read().unwrap();
+        entries.uuids.contains(&uuid) || entries.names.contains(&name.to_lowercase())
+    }
+
+    pub fn len(&self) -> usize {
+        let entries = self.entries.read().unwrap();
+        entries.names.len() + entries.uuids.len()
+    }
+}
+
+fn read_entries(path: &Path) -> Result<Entries, Error> {
+    let contents = fs::read_to_string(path).map_err(|error| {
+        Error::Other(format!("could not read whitelist {}: {}", path.display(), error).into())
+    })?;
+
+    let mut entries = Entries::default();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match Uuid::parse_str(line) {
+            Ok(uuid) => entries.uuids.insert(uuid),
+            Err(_) => entries.names.insert(line.to_lowercase()),
+        };
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    const NOTCH: Uuid = Uuid::from_u128(0x069a79f444e94726a5befca90e38aaf5);
+
+    #[test]
+    fn matches_names_and_uuids() {
+        let dir = temp_dir("whitelist");
+        let path = dir.join("whitelist.txt");
+        fs::write(
+            &path,
+            "# Admins\nAlex\n\n  069a79f4-44e9-4726-a5be-fca90e38aaf5  \n",
+        )
+        .unwrap();
+        let whitelist = Whitelist::load(path).unwrap();
+        assert_eq!(whitelist.len(), 2);
+        // Names are not case sensitive
+        assert!(whitelist.contains("alex", Uuid::nil()));
+        assert!(whitelist.contains("Notch", NOTCH));
+        assert!(whitelist.contains("renamed", NOTCH));
+        assert!(!whitelist.contains("steve", Uuid::nil()));
+        assert!(!whitelist.contains("# Admins", Uuid::nil()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_entries_if_reloading_fails() {
+        let dir = temp_dir("whitelist-reload");
+        let path = dir.join("whitelist.txt");
+        fs::write(&path, "alex\n").unwrap();
+        let whitelist = Whitelist::load(path.clone()).unwrap();
+
+        fs::write(&path, "steve\n").unwrap();
+        whitelist.reload().unwrap();
+        assert!(whitelist.contains("steve", Uuid::nil()));
+        assert!(!whitelist.contains("alex", Uuid::nil()));
+
+        fs::remove_file(&path).unwrap();
+        assert!(whitelist.reload().is_err());
+        assert!(whitelist.contains("steve", Uuid::nil()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}